@@ -0,0 +1,150 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Benchmarks of the hot paths on a corpus synthesized from the test fixtures
+
+use std::fs;
+use std::path::Path;
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use tokio::runtime::Runtime;
+
+use mastotg::as2::{Page, Post};
+use mastotg::cons::IdMap;
+use mastotg::db::DbConn;
+use mastotg::labels::Labels;
+use mastotg::render::render_post;
+use mastotg::runner::init_db;
+
+const CORPUS_SIZE: usize = 5000;
+const FIXTURES: &[&str] = &[
+    "post_text",
+    "post_link",
+    "post_tag",
+    "post_multi_grouped_images",
+];
+
+fn fixture(name: &str) -> Value {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name.to_owned() + ".json");
+    serde_json::from_slice(&fs::read(path).unwrap()).unwrap()
+}
+
+fn status_id(i: usize) -> String {
+    format!(
+        "https://social.myl.moe/users/myl/statuses/{}",
+        110000000000000000 + i
+    )
+}
+
+/// Posts cycling through the fixtures with distinct IDs, from the newest to the oldest
+fn corpus() -> Vec<Value> {
+    let fixtures: Vec<_> = FIXTURES.iter().map(|name| fixture(name)).collect();
+    (0..CORPUS_SIZE)
+        .rev()
+        .map(|i| {
+            let mut post = fixtures[i % fixtures.len()].clone();
+            post["id"] = json!(status_id(i));
+            post
+        })
+        .collect()
+}
+
+fn page_json(posts: &[Value]) -> Vec<u8> {
+    let items: Vec<_> = posts
+        .iter()
+        .map(|post| {
+            json!({
+                "id": format!("{}/activity", post["id"].as_str().unwrap()),
+                "type": "Create",
+                "object": post,
+            })
+        })
+        .collect();
+    serde_json::to_vec(&json!({
+        "@context": ["https://www.w3.org/ns/activitystreams", {}],
+        "id": "https://social.myl.moe/users/myl/outbox?page=true",
+        "type": "OrderedCollectionPage",
+        "orderedItems": items,
+    }))
+    .unwrap()
+}
+
+fn bench_render(c: &mut Criterion) {
+    let posts: Vec<Post> = corpus()
+        .into_iter()
+        .map(|post| serde_json::from_value(post).unwrap())
+        .collect();
+    let labels = Labels::default();
+    let mut group = c.benchmark_group("render");
+    group.throughput(Throughput::Elements(posts.len() as u64));
+    group.bench_function("render_post", |b| {
+        b.iter(|| {
+            for post in posts.iter() {
+                black_box(render_post(post, &labels).unwrap());
+            }
+        })
+    });
+    group.finish();
+}
+
+fn bench_de_page(c: &mut Criterion) {
+    let body = page_json(&corpus());
+    let mut group = c.benchmark_group("de");
+    group.throughput(Throughput::Bytes(body.len() as u64));
+    group.bench_function("page", |b| {
+        b.iter(|| black_box(serde_json::from_slice::<Page>(&body).unwrap()))
+    });
+    group.finish();
+}
+
+fn bench_id_map(c: &mut Criterion) {
+    let id_map: IdMap = (0..CORPUS_SIZE)
+        .map(|i| {
+            let tg_id = [(-1001234567890i64).to_be_bytes(), (i as i64).to_be_bytes()].concat();
+            (status_id(i), tg_id)
+        })
+        .collect();
+    let ids: Vec<_> = (0..CORPUS_SIZE).step_by(7).map(status_id).collect();
+
+    let rt = Runtime::new().unwrap();
+    let mut conn = Connection::open_in_memory().unwrap();
+    init_db(&mut conn).unwrap();
+    let db = DbConn::new(conn);
+    rt.block_on(db.save_id_map(id_map.clone())).unwrap();
+
+    let mut group = c.benchmark_group("id_map");
+    group.throughput(Throughput::Elements(ids.len() as u64));
+    group.bench_function("memory", |b| {
+        b.iter(|| {
+            for id in ids.iter() {
+                black_box(id_map.get(id));
+            }
+        })
+    });
+    group.bench_function("db_query", |b| {
+        b.to_async(&rt).iter(|| async {
+            for id in ids.iter() {
+                black_box(db.query_id_map(id.to_owned()).await.unwrap());
+            }
+        })
+    });
+    group.bench_function("db_save", |b| {
+        b.to_async(&rt).iter_batched(
+            || {
+                let mut conn = Connection::open_in_memory().unwrap();
+                init_db(&mut conn).unwrap();
+                (DbConn::new(conn), id_map.clone())
+            },
+            |(db, id_map)| async move { db.save_id_map(id_map).await.unwrap() },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_render, bench_de_page, bench_id_map);
+criterion_main!(benches);
@@ -0,0 +1,13 @@
+CREATE TABLE
+  audit (
+    pk INTEGER PRIMARY KEY,
+    time INTEGER NOT NULL DEFAULT (strftime ('%s', 'now')),
+    id TEXT NOT NULL,
+    action TEXT NOT NULL,
+    dest TEXT,
+    tg_id BLOB,
+    duration_ms INTEGER,
+    error TEXT
+  );
+
+CREATE INDEX audit_id ON audit (id);
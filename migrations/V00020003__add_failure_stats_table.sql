@@ -0,0 +1,5 @@
+CREATE TABLE
+  failure_stats (
+    class TEXT PRIMARY KEY,
+    count INTEGER NOT NULL DEFAULT 0
+  );
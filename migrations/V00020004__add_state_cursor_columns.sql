@@ -0,0 +1,5 @@
+ALTER TABLE state
+ADD COLUMN cursor_id TEXT;
+
+ALTER TABLE state
+ADD COLUMN cursor_published TEXT;
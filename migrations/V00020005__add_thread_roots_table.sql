@@ -0,0 +1,2 @@
+CREATE TABLE
+  thread_roots (thread TEXT PRIMARY KEY, tg_id BLOB NOT NULL);
@@ -0,0 +1,2 @@
+CREATE TABLE
+  pending_polls (id TEXT PRIMARY KEY, end_at INTEGER NOT NULL);
@@ -0,0 +1,2 @@
+CREATE TABLE
+  pins (id TEXT PRIMARY KEY, tg_id BLOB NOT NULL);
@@ -0,0 +1,2 @@
+CREATE TABLE
+  thread_positions (id TEXT PRIMARY KEY, pos INTEGER NOT NULL);
@@ -0,0 +1,6 @@
+CREATE TABLE
+  dead_letters (
+    id TEXT PRIMARY KEY,
+    activity TEXT NOT NULL,
+    error TEXT NOT NULL
+  );
@@ -0,0 +1,2 @@
+CREATE TABLE
+  canonical_urls (input TEXT PRIMARY KEY, url TEXT NOT NULL);
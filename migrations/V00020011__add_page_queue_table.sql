@@ -0,0 +1,5 @@
+CREATE TABLE
+  page_queue (
+    seq INTEGER PRIMARY KEY AUTOINCREMENT,
+    page TEXT NOT NULL
+  );
@@ -0,0 +1,6 @@
+CREATE TABLE
+  page_hashes (
+    url TEXT PRIMARY KEY,
+    hash TEXT NOT NULL,
+    prev TEXT
+  );
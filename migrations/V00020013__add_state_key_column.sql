@@ -0,0 +1,4 @@
+ALTER TABLE state
+ADD COLUMN key TEXT NOT NULL DEFAULT '';
+
+CREATE UNIQUE INDEX state_key ON state (key);
@@ -0,0 +1,8 @@
+CREATE TABLE
+  credentials (
+    host TEXT PRIMARY KEY,
+    client_id TEXT NOT NULL,
+    client_secret TEXT NOT NULL,
+    access_token TEXT NOT NULL,
+    scopes TEXT NOT NULL
+  );
@@ -0,0 +1,6 @@
+CREATE TABLE
+  deliveries (
+    output TEXT NOT NULL,
+    id TEXT NOT NULL,
+    PRIMARY KEY (output, id)
+  );
@@ -0,0 +1,105 @@
+CREATE TABLE
+  id_map_chan (
+    chan TEXT NOT NULL DEFAULT '',
+    id TEXT NOT NULL,
+    tg_id BLOB NOT NULL,
+    PRIMARY KEY (chan, id),
+    UNIQUE (chan, tg_id)
+  );
+
+INSERT INTO
+  id_map_chan (id, tg_id)
+SELECT
+  id,
+  tg_id
+FROM
+  id_map;
+
+DROP TABLE id_map;
+
+ALTER TABLE id_map_chan
+RENAME TO id_map;
+
+CREATE TABLE
+  thread_roots_chan (
+    chan TEXT NOT NULL DEFAULT '',
+    thread TEXT NOT NULL,
+    tg_id BLOB NOT NULL,
+    PRIMARY KEY (chan, thread)
+  );
+
+INSERT INTO
+  thread_roots_chan (thread, tg_id)
+SELECT
+  thread,
+  tg_id
+FROM
+  thread_roots;
+
+DROP TABLE thread_roots;
+
+ALTER TABLE thread_roots_chan
+RENAME TO thread_roots;
+
+CREATE TABLE
+  thread_positions_chan (
+    chan TEXT NOT NULL DEFAULT '',
+    id TEXT NOT NULL,
+    pos INTEGER NOT NULL,
+    PRIMARY KEY (chan, id)
+  );
+
+INSERT INTO
+  thread_positions_chan (id, pos)
+SELECT
+  id,
+  pos
+FROM
+  thread_positions;
+
+DROP TABLE thread_positions;
+
+ALTER TABLE thread_positions_chan
+RENAME TO thread_positions;
+
+CREATE TABLE
+  pending_polls_chan (
+    chan TEXT NOT NULL DEFAULT '',
+    id TEXT NOT NULL,
+    end_at INTEGER NOT NULL,
+    PRIMARY KEY (chan, id)
+  );
+
+INSERT INTO
+  pending_polls_chan (id, end_at)
+SELECT
+  id,
+  end_at
+FROM
+  pending_polls;
+
+DROP TABLE pending_polls;
+
+ALTER TABLE pending_polls_chan
+RENAME TO pending_polls;
+
+CREATE TABLE
+  pins_chan (
+    chan TEXT NOT NULL DEFAULT '',
+    id TEXT NOT NULL,
+    tg_id BLOB NOT NULL,
+    PRIMARY KEY (chan, id)
+  );
+
+INSERT INTO
+  pins_chan (id, tg_id)
+SELECT
+  id,
+  tg_id
+FROM
+  pins;
+
+DROP TABLE pins;
+
+ALTER TABLE pins_chan
+RENAME TO pins;
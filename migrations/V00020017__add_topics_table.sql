@@ -0,0 +1,7 @@
+CREATE TABLE
+  topics (
+    chan TEXT NOT NULL DEFAULT '',
+    tg_id BLOB NOT NULL,
+    topic INTEGER NOT NULL,
+    PRIMARY KEY (chan, tg_id)
+  );
@@ -10,11 +10,18 @@
 //!
 //! [ActivityStreams 2.0 types]: https://www.w3.org/TR/activitystreams-vocabulary/
 
+use std::collections::HashMap;
 use std::fmt;
+use std::sync::LazyLock;
 
 use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use serde_with::SerializeDisplay;
+use time::OffsetDateTime;
+
+use crate::utils::parse_time;
 
 /// Page of the outbox of a user.
 /// Many unused fields are ignored.
@@ -38,11 +45,81 @@ pub struct Page {
     /// So the order is kinda reversed.
     /// Bounded by an empty page.
     pub prev: Option<String>,
-    /// Posts in the page
-    pub ordered_items: Vec<Create>,
+    /// URL of the outbox collection the page belongs to
+    pub part_of: Option<String>,
+    /// Activities of posts in the page
+    pub ordered_items: Vec<Activity>,
+}
+
+impl Page {
+    /// Page with no items, which ends the fetching
+    pub fn empty(id: String) -> Self {
+        Self {
+            context: Context::Str(AS2_SCHEMA.to_owned()),
+            id,
+            r#type: "OrderedCollectionPage".to_owned(),
+            next: None,
+            prev: None,
+            part_of: None,
+            ordered_items: vec![],
+        }
+    }
+}
+
+/// Activity in the outbox.
+/// Activities of unknown types are tolerated, so they can be skipped instead of failing the page.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum Activity {
+    Create(Create),
+    /// Boost
+    Announce(Announce),
+    /// Edit
+    Update(Update),
+    /// Removal
+    Delete(Delete),
+    #[serde(untagged)]
+    Unknown(UnknownActivity),
+}
+
+impl Activity {
+    /// GUID of the activity
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Create(act) => &act.id,
+            Self::Announce(act) => &act.id,
+            Self::Update(act) => &act.id,
+            Self::Delete(act) => &act.id,
+            Self::Unknown(act) => &act.id,
+        }
+    }
+
+    pub fn type_name(&self) -> &str {
+        match self {
+            Self::Create(_) => "Create",
+            Self::Announce(_) => "Announce",
+            Self::Update(_) => "Update",
+            Self::Delete(_) => "Delete",
+            Self::Unknown(act) => &act.r#type,
+        }
+    }
+
+    pub fn as_create(&self) -> Option<&Create> {
+        match self {
+            Self::Create(act) => Some(act),
+            _ => None,
+        }
+    }
+
+    pub fn into_create(self) -> Option<Create> {
+        match self {
+            Self::Create(act) => Some(act),
+            _ => None,
+        }
+    }
 }
 
-/// Activity of a status. Only accept `Create`.
+/// Activity of a status.
 /// Many unused fields are ignored.
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
@@ -50,31 +127,147 @@ pub struct Create {
     /// GUID of the activity.
     /// Ignored when the ID of the post can work.
     pub id: String,
-    /// Always "Create"
-    pub r#type: String,
     /// Created post. Only accept `Note`.
     pub object: Post,
 }
 
-/// `Note` in the spec
+/// Activity of a boost.
+/// Many unused fields are ignored.
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
+pub struct Announce {
+    /// GUID of the activity
+    pub id: String,
+    /// GUID of the boosted post.
+    /// Mastodon only gives the GUID and the post has to be fetched.
+    pub object: String,
+    pub published: Option<String>,
+}
+
+/// Activity of an edit.
+/// Many unused fields are ignored.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Update {
+    /// GUID of the activity.
+    /// Mastodon appends `#updates/<timestamp>` to the post GUID, so it differs between edits.
+    pub id: String,
+    /// Revised post with `updated` set
+    pub object: Post,
+}
+
+/// Activity of a removal.
+/// Many unused fields are ignored.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Delete {
+    /// GUID of the activity
+    pub id: String,
+    /// Removed post
+    pub object: Deleted,
+}
+
+impl Delete {
+    /// Removal of the post, with the `#delete` GUID given by Mastodon
+    pub fn of(post_id: String) -> Self {
+        Self {
+            id: format!("{post_id}#delete"),
+            object: Deleted::Id(post_id),
+        }
+    }
+}
+
+/// Removed object of [`Delete`], either its GUID or a `Tombstone`
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Deleted {
+    Id(String),
+    Tombstone(Tombstone),
+}
+
+impl Deleted {
+    /// GUID of the removed post
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Id(id) => id,
+            Self::Tombstone(obj) => &obj.id,
+        }
+    }
+}
+
+/// Placeholder of a removed object
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Tombstone {
+    /// GUID of the removed post
+    pub id: String,
+    /// Always "Tombstone"
+    pub r#type: String,
+    /// Type of the object before removed, e.g., "Note"
+    pub former_type: Option<String>,
+    /// Same format as `published` of [`Post`]
+    pub deleted: Option<String>,
+}
+
+/// Activity of a type that is not supported.
+/// Only kept to be skipped.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct UnknownActivity {
+    pub id: String,
+    pub r#type: String,
+}
+
+impl UnknownActivity {
+    /// Activities of the supported types only fall back here when they are malformed
+    pub fn check_type(&self) -> Result<()> {
+        if ACTIVITY_TYPES.contains(&self.r#type.as_str()) {
+            bail!("malformed {} activity {}", self.r#type, self.id)
+        }
+        Ok(())
+    }
+}
+
+const ACTIVITY_TYPES: &[&str] = &["Create", "Announce", "Update", "Delete"];
+
+/// `Note` in the spec, `Question` for polls, `Article` for long-form posts, `Page` for link aggregators like Lemmy,
+/// `Audio` for music uploads of Funkwhale, or `Event` for events of Mobilizon
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(remote = "Self", rename_all = "camelCase")]
 pub struct Post {
     /// GUID of the post
     pub id: String,
-    /// Always "Note"
+    /// "Note", "Question", "Article", "Page", "Audio", or "Event"
     pub r#type: String,
-    // summary: Option<String>, // Always null
+    /// Title of the post.
+    /// Set on `Article`s, on `Note`s and `Page`s by Lemmy, Friendica, etc., and on `Audio` as the track title.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Content warning. Null or empty if none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub summary: Option<String>,
     /// GUID of the replied post
     pub in_reply_to: Option<String>,
     /// `xsd:dateTime` in the spec.
     /// RFC3339 like "2014-12-12T12:12:12Z" without omitting in Mastodon.
     pub published: String,
+    /// When the post was last edited.
+    /// Same format as `published`.
+    pub updated: Option<String>,
     /// URL of the post. Different from `id`.
+    /// Lists of links, e.g., the page and the files of Funkwhale tracks, are flattened to the page.
+    #[serde(skip_deserializing)]
     pub url: String,
-    // attributed_to: String,
-    // to: Vec<String>,
-    // cc: Vec<String>,
+    /// Authors of the post.
+    /// Groups like Lemmy communities may be listed along with the people.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attributed_to: Option<Attribution>,
+    /// Primary audience of the post.
+    /// With `cc`, used to derive the visibility.
+    #[serde(default, with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    pub to: Vec<String>,
+    /// Secondary audience of the post
+    #[serde(default, with = "one_or_many", skip_serializing_if = "Vec::is_empty")]
+    pub cc: Vec<String>,
     /// Extension. Used for spoilers.
     /// The current implementation is opinionated.
     /// Only media are spoiled and texts are never.
@@ -82,59 +275,837 @@ pub struct Post {
     pub sensitive: bool,
     // atom_uri: // Extension
     // in_reply_to_atom_uri: // Extension
-    // conversation: // Extension
-    /// The original post text content
+    /// Extension. Thread ID of Mastodon in the `tag:` URI form.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub conversation: Option<String>,
+    /// Extension. Thread ID of newer Mastodon, Pleroma, and others.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context: Option<IdRef>,
+    /// The original post text content.
+    /// Only `Audio` may miss it, i.e., Funkwhale tracks without descriptions, which is then empty.
+    #[serde(skip_deserializing)]
     pub content: String,
-    // content_map: HashMap<String, String>, // I18n, ignored
+    /// Content by the language tag, e.g., `{"en": "..."}`.
+    /// Only used to tell the languages of the post, and the content is still taken from `content`.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub content_map: HashMap<String, String>,
     /// Media attachments.
     /// Multiple grouped images, a video, or a audio.
+    /// The audio file of `Audio` is taken as its attachment.
+    /// Entries other than media, e.g., the metadata of Mobilizon events, are skipped on `Event`.
+    #[serde(skip_deserializing)]
     pub attachment: Vec<Document>,
-    /// List of hashtags
+    /// Hashtags, mentions, custom emojis, and others.
+    /// Only `Audio` may miss it.
+    #[serde(skip_deserializing)]
     pub tag: Vec<Tag>,
-    // replies: Vec<Reply>, // Comments, ignored
+    /// Options of a single-choice poll. Only for `Question`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub one_of: Vec<PollOption>,
+    /// Options of a multiple-choice poll. Only for `Question`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub any_of: Vec<PollOption>,
+    /// When the poll or the event ends. Only for `Question` and `Event`.
+    /// Same format as `published`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end_time: Option<String>,
+    /// When the event starts. Only for `Event`.
+    /// Same format as `published`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub start_time: Option<String>,
+    /// Where the event takes place. Only for `Event`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<Place>,
+    /// When the poll was closed, or whether it is closed. Only for `Question`.
+    /// Mastodon sets it to the end time once the poll ends.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closed: Option<serde_json::Value>,
+    /// Extension. Number of people who have voted. Only for `Question`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub voters_count: Option<u64>,
+    /// Replies collection.
+    /// Mastodon only embeds the first page without the count.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub replies: Option<Counter>,
+    /// Extension of Mastodon 4.3+ and others
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub likes: Option<Counter>,
+    /// Extension of Mastodon 4.3+ and others. Boosts.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shares: Option<Counter>,
+    /// Extension of Funkwhale. Track metadata of `Audio` in libraries.
+    /// Uploads of channels only have `name` as the title.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub track: Option<Track>,
+    // The fields above that depend on the type are read into the raw ones below
+    // and taken by `resolve` after the type is known
+    #[serde(
+        rename(deserialize = "url"),
+        deserialize_with = "link_url::deserialize_links",
+        skip_serializing
+    )]
+    raw_url: Vec<(String, String)>,
+    #[serde(rename(deserialize = "content"), default, skip_serializing)]
+    raw_content: Option<String>,
+    #[serde(rename(deserialize = "attachment"), default, skip_serializing)]
+    raw_attachment: Option<Vec<RawAttachment>>,
+    #[serde(rename(deserialize = "tag"), default, skip_serializing)]
+    raw_tag: Option<Vec<Tag>>,
+}
+
+/// Media attachment, or other entries that only `Event` may have
+#[derive(Deserialize, Clone)]
+#[serde(untagged)]
+enum RawAttachment {
+    Media(Document),
+    Other { r#type: Option<String> },
+}
+
+impl<'de> Deserialize<'de> for Post {
+    fn deserialize<D: serde::Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        let mut post = Post::deserialize(de)?;
+        post.resolve().map_err(serde::de::Error::custom)?;
+        Ok(post)
+    }
+}
+
+impl Serialize for Post {
+    fn serialize<S: serde::Serializer>(&self, ser: S) -> Result<S::Ok, S::Error> {
+        Post::serialize(self, ser)
+    }
+}
+
+impl Post {
+    /// Take the fields depending on the type from the raw ones
+    fn resolve(&mut self) -> Result<()> {
+        let links = std::mem::take(&mut self.raw_url);
+        self.url = link_url::page(&links).ok_or(anyhow!("no url of the post"))?;
+        let missing = |field| anyhow!("missing field `{field}`");
+        let lenient = self.is_audio();
+        self.content = match self.raw_content.take() {
+            Some(content) => content,
+            None if lenient => String::new(),
+            None => return Err(missing("content")),
+        };
+        self.tag = match self.raw_tag.take() {
+            Some(tag) => tag,
+            None if lenient => vec![],
+            None => return Err(missing("tag")),
+        };
+        let atts = match self.raw_attachment.take() {
+            Some(atts) => atts,
+            // Funkwhale `Audio` is the upload itself, whose `url` lists the audio file
+            None if lenient => {
+                let url = link_url::media(&links).ok_or(anyhow!("no audio file of the track"))?;
+                let audio = Document {
+                    r#type: "Audio".to_owned(),
+                    url,
+                    ..Default::default()
+                };
+                vec![RawAttachment::Media(audio)]
+            }
+            None => return Err(missing("attachment")),
+        };
+        // Mobilizon lists the online address and the metadata of events along with the banner
+        for att in atts {
+            match att {
+                RawAttachment::Media(att) => self.attachment.push(att),
+                RawAttachment::Other { r#type } => {
+                    let is_media = matches!(
+                        r#type.as_deref(),
+                        Some("Document" | "Image" | "Audio" | "Video")
+                    );
+                    if is_media || !self.is_event() {
+                        bail!("invalid attachment of type {}", r#type.unwrap_or_default())
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn is_poll(&self) -> bool {
+        self.r#type == "Question"
+    }
+
+    /// Whether the poll has ended and its results are final
+    pub fn is_closed(&self) -> bool {
+        self.closed
+            .as_ref()
+            .is_some_and(|v| !v.is_null() && v.as_bool() != Some(false))
+    }
+
+    pub fn engagement(&self) -> Engagement {
+        let count = |c: &Option<Counter>| c.as_ref().and_then(Counter::count);
+        Engagement {
+            replies: count(&self.replies),
+            likes: count(&self.likes),
+            shares: count(&self.shares),
+        }
+    }
+
+    /// Author of the post.
+    /// Groups are skipped if people are also listed.
+    pub fn author(&self) -> Option<&ActorRef> {
+        let actors = match self.attributed_to.as_ref()? {
+            Attribution::One(actor) => return Some(actor),
+            Attribution::Many(actors) => actors,
+        };
+        let is_group =
+            |a: &&ActorRef| matches!(a, ActorRef::Actor(a) if a.r#type.as_deref() == Some("Group"));
+        actors
+            .iter()
+            .find(|a| !is_group(a))
+            .or_else(|| actors.first())
+    }
+
+    /// Whether the post replies to a post of the same author, i.e., continues a self-thread.
+    /// The replied post is taken as the author's if its GUID is under the author's,
+    /// e.g., `/users/alice/statuses/1` under `/users/alice` like Mastodon.
+    pub fn is_self_reply(&self) -> bool {
+        let Some(target) = self.in_reply_to.as_deref() else {
+            return false;
+        };
+        let author = match self.author() {
+            Some(author) => author.id(),
+            None => match self.id.split_once("/statuses/") {
+                Some((author, _)) => author,
+                None => return false,
+            },
+        };
+        target
+            .strip_prefix(author)
+            .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    /// Visibility derived from the audience like Mastodon.
+    /// Posts without any public or followers audience are taken as direct.
+    pub fn visibility(&self) -> Visibility {
+        let is_public = |s: &String| PUBLIC_IRIS.contains(&s.as_str());
+        let is_followers = |s: &String| s.ends_with("/followers");
+        if self.to.iter().any(is_public) {
+            Visibility::Public
+        } else if self.cc.iter().any(is_public) {
+            Visibility::Unlisted
+        } else if self.to.iter().chain(self.cc.iter()).any(is_followers) {
+            Visibility::FollowersOnly
+        } else {
+            Visibility::Direct
+        }
+    }
+
+    /// ID of the thread the post belongs to
+    pub fn thread_id(&self) -> Option<&str> {
+        self.context
+            .as_ref()
+            .map(IdRef::id)
+            .or(self.conversation.as_deref())
+    }
+
+    pub fn published_at(&self) -> Option<OffsetDateTime> {
+        parse_time(&self.published)
+    }
+
+    /// Hashtags in the `tag` list
+    pub fn hashtags(&self) -> impl Iterator<Item = &Hashtag> {
+        self.tag.iter().filter_map(Tag::as_hashtag)
+    }
+
+    /// Names of the hashtags in the content without the leading `#`
+    pub fn inline_hashtags(&self) -> Vec<String> {
+        // Hashtag links wrap the names in `<span>`s, while other tags separate words
+        static RE_SPAN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"</?span[^>]*>").unwrap());
+        static RE_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+        let text = RE_SPAN.replace_all(&self.content, "");
+        let text = RE_TAG.replace_all(&text, " ");
+        // `&` and `/` exclude HTML entities like `&#39;` and URL fragments
+        static RE_HASHTAG: LazyLock<Regex> =
+            LazyLock::new(|| Regex::new(r"(?:^|[^\w&/#])#(\w+)").unwrap());
+        RE_HASHTAG
+            .captures_iter(&text)
+            .map(|c| c[1].to_owned())
+            .collect()
+    }
+
+    /// Names of the hashtags without the leading `#`, reconciled from the `tag` list and the content.
+    /// Servers may list tags that are not in the content, or leave tags in the content unlisted.
+    /// Deduplicated case-insensitively in the order of appearance, the content first.
+    pub fn hashtag_names(&self) -> Vec<String> {
+        let listed = self
+            .hashtags()
+            .map(|tag| tag.name.trim_start_matches('#').to_owned());
+        let mut names: Vec<String> = Vec::new();
+        for name in self.inline_hashtags().into_iter().chain(listed) {
+            if !names
+                .iter()
+                .any(|n| n.to_lowercase() == name.to_lowercase())
+            {
+                names.push(name);
+            }
+        }
+        names
+    }
+
+    /// Names of the listed hashtags that are not in the content
+    pub fn hidden_hashtags(&self) -> Vec<String> {
+        let inline: Vec<_> = self
+            .inline_hashtags()
+            .iter()
+            .map(|name| name.to_lowercase())
+            .collect();
+        self.hashtag_names()
+            .into_iter()
+            .filter(|name| !inline.contains(&name.to_lowercase()))
+            .collect()
+    }
+
+    /// Content warning text if set.
+    /// Some servers send empty strings instead of null.
+    pub fn content_warning(&self) -> Option<&str> {
+        self.summary.as_deref().filter(|s| !s.trim().is_empty())
+    }
+
+    /// Whether the post or any of its media is marked as sensitive
+    pub fn is_sensitive(&self) -> bool {
+        self.sensitive
+            || self
+                .attachment
+                .iter()
+                .any(|att| att.sensitive == Some(true))
+    }
+
+    /// Whether the attachment should be spoiled.
+    /// Per-attachment marks take precedence over the post-level one.
+    pub fn is_media_sensitive(&self, att: &Document) -> bool {
+        att.sensitive.unwrap_or(self.sensitive)
+    }
+
+    /// Long-form posts with titles, e.g., from WriteFreely or Lemmy
+    pub fn is_article(&self) -> bool {
+        self.r#type == "Article"
+    }
+
+    /// Music uploads, e.g., Funkwhale tracks
+    pub fn is_audio(&self) -> bool {
+        self.r#type == "Audio"
+    }
+
+    /// Events with times and places, e.g., from Mobilizon
+    pub fn is_event(&self) -> bool {
+        self.r#type == "Event"
+    }
+
+    /// Title, artist, and album of the uploaded track.
+    /// Artists fall back to the album ones and then the author.
+    pub fn track_meta(&self) -> Option<TrackMeta> {
+        if !self.is_audio() {
+            return None;
+        }
+        let track = self.track.as_ref();
+        let album = track.and_then(|t| t.album.as_ref());
+        let artist = track
+            .and_then(|t| t.artist())
+            .or_else(|| album.and_then(|a| join_artists(&a.artists)))
+            .or_else(|| match self.author()? {
+                ActorRef::Actor(actor) => actor.name.clone(),
+                ActorRef::Id(_) => None,
+            });
+        Some(TrackMeta {
+            title: self.title().map(str::to_owned),
+            artist,
+            album: album.map(|a| a.name.clone()),
+        })
+    }
+
+    /// Language tags of the post, e.g., `en` or `zh-Hant`, empty if unknown
+    pub fn languages(&self) -> impl Iterator<Item = &str> {
+        self.content_map.keys().map(String::as_str)
+    }
+
+    /// Title if set and not empty.
+    /// Polls are excluded since some servers set the question as `name`.
+    pub fn title(&self) -> Option<&str> {
+        if self.is_poll() {
+            return None;
+        }
+        // Funkwhale names the uploads of libraries like "artist - album - title"
+        let name = match &self.track {
+            Some(track) => Some(&track.name),
+            None => self.name.as_ref(),
+        };
+        name.map(String::as_str).filter(|s| !s.trim().is_empty())
+    }
+
+    /// Options of the poll, and whether multiple choices are allowed
+    pub fn poll_options(&self) -> (&[PollOption], bool) {
+        if self.any_of.is_empty() {
+            (&self.one_of, false)
+        } else {
+            (&self.any_of, true)
+        }
+    }
 }
 
-/// Inherits all props from `Object`
+/// Option of a poll.
+/// Many unused fields are ignored.
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
-pub struct Tag {
-    /// Always "Hashtag"
-    pub r#type: String,
-    // href: String,
+pub struct PollOption {
+    /// Text of the option
+    pub name: String,
+    /// Votes of the option
+    pub replies: Option<PollVotes>,
+}
+
+impl PollOption {
+    pub fn votes(&self) -> u64 {
+        self.replies.as_ref().map_or(0, |r| r.total_items)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PollVotes {
+    pub total_items: u64,
+}
+
+/// Extension of Funkwhale. Track of an `Audio` upload.
+/// Many unused fields are ignored.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Track {
+    /// Title of the track
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artists: Vec<Artist>,
+    /// Artists of Funkwhale 2.0+, which replace `artists`
+    #[serde(
+        default,
+        rename = "artist_credit",
+        skip_serializing_if = "Vec::is_empty"
+    )]
+    pub artist_credit: Vec<ArtistCredit>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub album: Option<Album>,
+}
+
+impl Track {
+    /// Names of the artists as credited
+    fn artist(&self) -> Option<String> {
+        if self.artist_credit.is_empty() {
+            return join_artists(&self.artists);
+        }
+        let credits: String = self
+            .artist_credit
+            .iter()
+            .map(|c| format!("{}{}", c.credit, c.joinphrase))
+            .collect();
+        Some(credits.trim().to_owned()).filter(|s| !s.is_empty())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Artist {
+    pub name: String,
+}
+
+/// Artist with the credited name and the phrase joining the next one, e.g., " feat. "
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ArtistCredit {
+    pub credit: String,
+    #[serde(default)]
+    pub joinphrase: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Album {
+    /// Title of the album
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub artists: Vec<Artist>,
+}
+
+/// Location of an event.
+/// Many unused fields are ignored.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Place {
+    /// Name of the venue
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub address: Option<Address>,
+}
+
+impl Place {
+    /// Name and address in one line, e.g., `Venue, 1 Main St, Paris`.
+    /// Parts repeating the previous ones are skipped.
+    pub fn text(&self) -> Option<String> {
+        let mut parts: Vec<&str> = Vec::new();
+        let address = match &self.address {
+            Some(Address::Text(text)) => vec![Some(text.as_str())],
+            Some(Address::Postal(addr)) => vec![
+                addr.street_address.as_deref(),
+                addr.address_locality.as_deref(),
+                addr.address_region.as_deref(),
+                addr.address_country.as_deref(),
+            ],
+            None => vec![],
+        };
+        for part in [self.name.as_deref()].into_iter().chain(address).flatten() {
+            let part = part.trim();
+            if !part.is_empty() && !parts.contains(&part) {
+                parts.push(part);
+            }
+        }
+        Some(parts.join(", ")).filter(|s| !s.is_empty())
+    }
+}
+
+/// `schema:PostalAddress` of Mobilizon, or the plain text of others like Gancio
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Address {
+    Text(String),
+    Postal(PostalAddress),
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PostalAddress {
+    pub street_address: Option<String>,
+    pub address_locality: Option<String>,
+    pub address_region: Option<String>,
+    pub address_country: Option<String>,
+}
+
+/// Metadata of the track sent with the audio
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct TrackMeta {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+}
+
+fn join_artists(artists: &[Artist]) -> Option<String> {
+    let names: Vec<_> = artists.iter().map(|a| a.name.as_str()).collect();
+    Some(names.join(", ")).filter(|s| !s.is_empty())
+}
+
+/// Special collection of everyone. Compact forms are also seen.
+const PUBLIC_IRIS: &[&str] = &[
+    "https://www.w3.org/ns/activitystreams#Public",
+    "as:Public",
+    "Public",
+];
+
+/// Who can see the post, from the least to the most visible
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Visibility {
+    /// Only the mentioned
+    Direct,
+    /// Only the followers
+    FollowersOnly,
+    /// Public but not listed in the public timelines
+    Unlisted,
+    Public,
+}
+
+/// `to` and `cc` may be a single IRI instead of a list
+mod one_or_many {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<Vec<String>, D::Error> {
+        Ok(match OneOrMany::deserialize(de)? {
+            OneOrMany::One(s) => vec![s],
+            OneOrMany::Many(v) => v,
+        })
+    }
+
+    pub fn serialize<S: Serializer>(v: &[String], ser: S) -> Result<S::Ok, S::Error> {
+        v.serialize(ser)
+    }
+}
+
+/// `url` may be a `Link` or a list of URLs and `Link`s, e.g., the files of PeerTube videos.
+/// The first link to media is taken, or the first link if none is known to be media.
+mod link_url {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum UrlRef {
+        Url(String),
+        Link {
+            href: String,
+            #[serde(default, rename = "mediaType")]
+            media_type: String,
+        },
+        Many(Vec<UrlRef>),
+    }
+
+    impl UrlRef {
+        fn links(self) -> Vec<(String, String)> {
+            match self {
+                Self::Url(url) => vec![(url, String::new())],
+                Self::Link { href, media_type } => vec![(href, media_type)],
+                Self::Many(v) => v.into_iter().flat_map(Self::links).collect(),
+            }
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(de: D) -> Result<String, D::Error> {
+        let links = deserialize_links(de)?;
+        media(&links).ok_or(D::Error::custom("no url of the attachment"))
+    }
+
+    /// URLs with their media types, which are empty if unknown
+    pub fn deserialize_links<'de, D: Deserializer<'de>>(
+        de: D,
+    ) -> Result<Vec<(String, String)>, D::Error> {
+        Ok(UrlRef::deserialize(de)?.links())
+    }
+
+    /// The first media link, or the first link if none is known to be media
+    pub fn media(links: &[(String, String)]) -> Option<String> {
+        let is_media = |media_type: &str| {
+            ["image/", "video/", "audio/"]
+                .iter()
+                .any(|prefix| media_type.starts_with(prefix))
+        };
+        links
+            .iter()
+            .find(|(_, media_type)| is_media(media_type))
+            .or(links.first())
+            .map(|(url, _)| url.to_owned())
+    }
+
+    /// The HTML page, or the first link if none is known to be HTML
+    pub fn page(links: &[(String, String)]) -> Option<String> {
+        links
+            .iter()
+            .find(|(_, media_type)| media_type == "text/html")
+            .or(links.first())
+            .map(|(url, _)| url.to_owned())
+    }
+
+    pub fn serialize<S: Serializer>(url: &str, ser: S) -> Result<S::Ok, S::Error> {
+        ser.serialize_str(url)
+    }
+}
+
+/// Object given by its GUID or embedded with it
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum IdRef {
+    Id(String),
+    Object { id: String },
+}
+
+impl IdRef {
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Id(id) | Self::Object { id } => id,
+        }
+    }
+}
+
+/// `attributedTo` that is either a single actor or a list of actors
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Attribution {
+    One(ActorRef),
+    Many(Vec<ActorRef>),
+}
+
+/// Actor given by its GUID or embedded
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum ActorRef {
+    Id(String),
+    Actor(Actor),
+}
+
+impl ActorRef {
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Id(id) => id,
+            Self::Actor(actor) => &actor.id,
+        }
+    }
+}
+
+/// Inherits all props from `Object`. Many unused fields are ignored.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Actor {
+    /// GUID of the actor
+    pub id: String,
+    /// "Person", "Group", "Service", etc.
+    pub r#type: Option<String>,
+    /// Display name
+    pub name: Option<String>,
+    /// Username without the host
+    pub preferred_username: Option<String>,
+}
+
+/// Collection only used for its size.
+/// Some servers link to the collection instead of embedding it.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum Counter {
+    Link(String),
+    #[serde(rename_all = "camelCase")]
+    Collection {
+        total_items: Option<u64>,
+    },
+}
+
+impl Counter {
+    /// Size of the collection if embedded
+    pub fn count(&self) -> Option<u64> {
+        match self {
+            Counter::Link(_) => None,
+            Counter::Collection { total_items } => *total_items,
+        }
+    }
+}
+
+/// Engagement stats from the time of fetching.
+/// `None` if unknown.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Engagement {
+    pub replies: Option<u64>,
+    pub likes: Option<u64>,
+    pub shares: Option<u64>,
+}
+
+/// Entry of the `tag` list of a post.
+/// Entries of unknown types are tolerated and ignored.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(tag = "type")]
+pub enum Tag {
+    Hashtag(Hashtag),
+    /// Extension
+    Mention(Mention),
+    /// Extension. Custom emoji.
+    Emoji(Emoji),
+    #[serde(untagged)]
+    Other(OtherTag),
+}
+
+impl Tag {
+    pub fn as_hashtag(&self) -> Option<&Hashtag> {
+        match self {
+            Self::Hashtag(tag) => Some(tag),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Hashtag {
+    /// URL of the tag timeline
+    pub href: Option<String>,
     /// Tag name incluing the leading `#`
     pub name: String,
 }
 
-/// Attachment of a post. Only accept `Document`.
-/// See [`Post`] for the limitations of Mastodon.
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(rename_all = "camelCase")]
+pub struct Mention {
+    /// GUID of the mentioned user
+    pub href: String,
+    /// Full handle like `@user@example.com`
+    pub name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Emoji {
+    /// Shortcode wrapped in colons like `:blobcat:`
+    pub name: String,
+    pub icon: Option<EmojiIcon>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct EmojiIcon {
+    pub media_type: Option<String>,
+    /// URL of the emoji image
+    pub url: String,
+}
+
+/// Tag of a type that is not supported
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OtherTag {
+    pub r#type: Option<String>,
+}
+
+/// Attachment of a post.
+/// Accept `Document` of Mastodon, and `Image`, `Audio`, and `Video` of Pixelfed, Funkwhale, PeerTube, etc.
+/// See [`Post`] for the limitations of Mastodon.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(rename_all = "camelCase")]
 pub struct Document {
-    /// Always "Document"
+    /// "Document", "Image", "Audio", or "Video"
     pub r#type: String,
     /// MIME media type like `A/B`. `A` is `(image|video|audio)`.
     /// `B` is ignored. We use the extension of the URL instead.
+    /// Empty if missing, which the producer then infers.
+    #[serde(default)]
     pub media_type: String,
-    /// URL of the attachment file
+    /// URL of the attachment file.
+    /// `Link`s and lists of them are flattened to the URL of the media.
+    #[serde(with = "link_url")]
     pub url: String,
     /// Used as the alt text by Mastodon.
     /// However, Telegram does not support alt texts so it is included but unused.
     pub name: Option<String>,
-    // blurhash: String, // Extension
+    /// Extension of Misskey and others that mark individual media.
+    /// The post-level `sensitive` applies if missing.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensitive: Option<bool>,
+    /// Extension. Compact blurred preview of images and videos.
+    /// Decoded into a placeholder when the media can not be fetched.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blurhash: Option<String>,
+    /// Extension. `[x, y]` in `[-1, 1]` with `[0, 0]` at the center and y pointing up.
+    /// The point to keep when the image is cropped.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub focal_point: Option<[f64; 2]>,
     // `width` and `height` are only valid for `Link`.
     // `Document`, `Image`, `Audio`, `Video` all can not have them.
     // So it is weird to see Mastodon uses them here. Or they may be extensions.
-    // width: u32, // Ignored
-    // height: u32, // Ignored
+    /// Used for the aspect ratio of placeholders
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
 }
 
-const TYPES: &[&str] = &[
-    "OrderedCollectionPage",
-    "Create",
-    "Note",
-    "Hashtag",
-    "Document",
-];
+impl Document {
+    /// Top-level media type implied by the object type, e.g., `image` for `Image`
+    pub fn media_kind(&self) -> Option<&'static str> {
+        match self.r#type.as_str() {
+            "Image" => Some("image"),
+            "Audio" => Some("audio"),
+            "Video" => Some("video"),
+            _ => None,
+        }
+    }
+}
+
+const TYPES: &[&str] = &["OrderedCollectionPage", "Note", "Document", "Tombstone"];
 
 pub trait CheckType<const TYPE_IDX: usize> {
     fn check_type(&self) -> Result<()>;
@@ -159,10 +1130,41 @@ macro_rules! impl_check_type {
 }
 
 impl_check_type!(Page, 0);
-impl_check_type!(Create, 1);
-impl_check_type!(Post, 2);
-impl_check_type!(Tag, 3);
-impl_check_type!(Document, 4);
+impl_check_type!(Tombstone, 3);
+
+impl CheckType<1> for Post {
+    fn check_type(&self) -> Result<()> {
+        if self.r#type == TYPES[1]
+            || self.is_poll()
+            || self.is_article()
+            || self.r#type == "Page"
+            || self.is_audio()
+            || self.is_event()
+        {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "invalid type {} (expected {}, Question, Article, Page, Audio, or Event)",
+                self.r#type.clone(),
+                TYPES[1],
+            ))
+        }
+    }
+}
+
+impl CheckType<2> for Document {
+    fn check_type(&self) -> Result<()> {
+        if self.r#type == TYPES[2] || self.media_kind().is_some() {
+            Ok(())
+        } else {
+            Err(anyhow!(
+                "invalid type {} (expected {}, Image, Audio, or Video)",
+                self.r#type.clone(),
+                TYPES[2],
+            ))
+        }
+    }
+}
 
 const AS2_SCHEMA: &str = "https://www.w3.org/ns/activitystreams";
 
@@ -174,22 +1176,14 @@ macro_rules! impl_check_context {
     ($t:ty) => {
         impl CheckContext for $t {
             fn check_context(&self) -> Result<()> {
-                let ctx = match &self.context {
-                    Context::Str(value) => value,
-                    Context::List(items) => {
-                        if items.len() <= 1 {
-                            bail!("invalid context that is a too short list")
-                        } else {
-                            match &items[0] {
-                                CtxItem::Str(value) => value,
-                                CtxItem::Obj(_) => {
-                                    bail!("invalid context of which the first item is an object")
-                                }
-                            }
-                        }
-                    }
+                // Lists may have a single item or put objects first, e.g., in GoToSocial
+                let found = match &self.context {
+                    Context::Str(value) => value == AS2_SCHEMA,
+                    Context::List(items) => items
+                        .iter()
+                        .any(|item| matches!(item, CtxItem::Str(value) if value == AS2_SCHEMA)),
                 };
-                if ctx == AS2_SCHEMA {
+                if found {
                     Ok(())
                 } else {
                     Err(anyhow!("invalid context that does not contain as2"))
@@ -236,6 +1230,26 @@ mod tests {
     use super::*;
     use crate::check_de;
 
+    #[test]
+    fn test_check_context() -> Result<()> {
+        let page = |ctx: &str| -> Result<Page> {
+            Ok(serde_json::from_str(&format!(
+                r#"{{"@context": {ctx}, "id": "https://example.com/outbox?page=true",
+                "type": "OrderedCollectionPage", "orderedItems": []}}"#
+            ))?)
+        };
+        page(r#""https://www.w3.org/ns/activitystreams""#)?.check_context()?;
+        page(r#"["https://www.w3.org/ns/activitystreams"]"#)?.check_context()?;
+        page(
+            r#"[{"toot": "http://joinmastodon.org/ns#"}, "https://www.w3.org/ns/activitystreams"]"#,
+        )?
+        .check_context()?;
+        assert!(page(r#"["https://w3id.org/security/v1"]"#)?
+            .check_context()
+            .is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_de_page() -> Result<()> {
         check_de!(Page, "page");
@@ -248,6 +1262,43 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_de_activities() -> Result<()> {
+        assert!(check_de!(Activity, "create").as_create().is_some());
+        let announce = check_de!(Activity, "announce");
+        assert!(
+            matches!(&announce, Activity::Announce(act) if act.object.ends_with("/110826550717756448"))
+        );
+        let update = check_de!(Activity, "update");
+        assert!(
+            matches!(&update, Activity::Update(act) if act.object.updated.as_deref() == Some("2023-08-03T17:00:00Z"))
+        );
+        let delete = check_de!(Activity, "delete");
+        assert!(
+            matches!(&delete, Activity::Delete(act) if act.object.id().ends_with("/110826550717756448"))
+        );
+        let delete: Activity = serde_json::from_str(
+            r#"{"id": "https://example.com/1#delete", "type": "Delete", "object": "https://example.com/1"}"#,
+        )?;
+        assert!(
+            matches!(&delete, Activity::Delete(act) if act.object.id() == "https://example.com/1")
+        );
+        let like: Activity =
+            serde_json::from_str(r#"{"id": "https://example.com/likes/1", "type": "Like"}"#)?;
+        assert_eq!(like.type_name(), "Like");
+        let Activity::Unknown(like) = like else {
+            panic!("unknown activity not tolerated");
+        };
+        like.check_type()?;
+        let malformed: Activity =
+            serde_json::from_str(r#"{"id": "https://example.com/1", "type": "Create"}"#)?;
+        let Activity::Unknown(malformed) = malformed else {
+            panic!("malformed activity parsed");
+        };
+        assert!(malformed.check_type().is_err());
+        Ok(())
+    }
+
     #[test]
     fn test_de_post() -> Result<()> {
         check_de!(Post, "post_text");
@@ -262,7 +1313,242 @@ mod tests {
 
     #[test]
     fn test_de_tag() -> Result<()> {
-        check_de!(Post, "post_tag");
+        let post = check_de!(Post, "post_tag");
+        assert_eq!(post.hashtags().count(), 1);
+        let post = check_de!(Post, "post_mention_emoji");
+        assert!(matches!(
+            post.tag.as_slice(),
+            [Tag::Mention(_), Tag::Emoji(_), Tag::Other(_)]
+        ));
+        assert_eq!(post.hashtags().count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_hashtag_names() -> Result<()> {
+        let mut post = check_de!(Post, "post_tag");
+        assert_eq!(post.inline_hashtags(), ["mygo"]);
+        assert_eq!(post.hashtag_names(), ["mygo"]);
+        assert!(post.hidden_hashtags().is_empty());
+        post.content += "<p>#AveMujica it&#39;s</p>";
+        post.tag.push(serde_json::from_str(
+            r##"{"type": "Hashtag", "name": "#MyGO"}"##,
+        )?);
+        post.tag.push(serde_json::from_str(
+            r##"{"type": "Hashtag", "name": "#anime"}"##,
+        )?);
+        assert_eq!(post.hashtag_names(), ["mygo", "AveMujica", "anime"]);
+        assert_eq!(post.hidden_hashtags(), ["anime"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_de_question() -> Result<()> {
+        let post = check_de!(Post, "post_question");
+        post.check_type()?;
+        assert!(post.is_poll());
+        let (options, multiple) = post.poll_options();
+        assert!(!multiple);
+        assert_eq!(
+            options.iter().map(|opt| opt.votes()).collect::<Vec<_>>(),
+            [3, 1]
+        );
+        assert!(!post.is_closed());
+        let mut post = post;
+        post.closed = Some(serde_json::json!("2023-08-17T12:00:00Z"));
+        assert!(post.is_closed());
+        post.closed = Some(serde_json::json!(false));
+        assert!(!post.is_closed());
+        Ok(())
+    }
+
+    #[test]
+    fn test_de_engagement() -> Result<()> {
+        let post = check_de!(Post, "post_text");
+        assert_eq!(post.engagement(), Engagement::default());
+        let post = check_de!(Post, "post_article");
+        assert_eq!(
+            post.engagement(),
+            Engagement {
+                replies: Some(2),
+                likes: Some(12),
+                shares: None,
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_visibility() -> Result<()> {
+        let mut post = check_de!(Post, "post_text");
+        assert_eq!(post.visibility(), Visibility::Public);
+        post.cc = std::mem::take(&mut post.to);
+        assert_eq!(post.visibility(), Visibility::Unlisted);
+        post.cc = vec!["https://social.myl.moe/users/myl/followers".to_owned()];
+        assert_eq!(post.visibility(), Visibility::FollowersOnly);
+        post.cc = vec![];
+        post.to = vec!["https://example.com/users/anon".to_owned()];
+        assert_eq!(post.visibility(), Visibility::Direct);
+        Ok(())
+    }
+
+    #[test]
+    fn test_thread_id() -> Result<()> {
+        let mut post = check_de!(Post, "post_text");
+        assert_eq!(
+            post.thread_id(),
+            Some("tag:myl.moe,2023-08-03:objectId=271868:objectType=Conversation")
+        );
+        post.context = serde_json::from_str(r#"{"id": "https://example.com/contexts/1"}"#)?;
+        assert_eq!(post.thread_id(), Some("https://example.com/contexts/1"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_self_reply() -> Result<()> {
+        let mut post = check_de!(Post, "post_link");
+        assert!(post.is_self_reply());
+        post.attributed_to = None;
+        assert!(post.is_self_reply());
+        post.in_reply_to = Some("https://social.myl.moe/users/mylx/statuses/1".to_owned());
+        assert!(!post.is_self_reply());
+        assert!(!check_de!(Post, "post_text").is_self_reply());
+        Ok(())
+    }
+
+    #[test]
+    fn test_de_media_objects() -> Result<()> {
+        let image: Document = serde_json::from_str(
+            r#"{"type": "Image", "mediaType": "image/jpeg", "url": "https://example.com/1.jpg"}"#,
+        )?;
+        image.check_type()?;
+        assert_eq!(image.media_kind(), Some("image"));
+        let video: Document = serde_json::from_str(
+            r#"{
+                "type": "Video",
+                "url": [
+                    {"type": "Link", "mediaType": "text/html", "href": "https://example.com/w/1"},
+                    {"type": "Link", "mediaType": "video/mp4", "href": "https://example.com/1.mp4"}
+                ]
+            }"#,
+        )?;
+        video.check_type()?;
+        assert_eq!(video.url, "https://example.com/1.mp4");
+        assert_eq!(video.media_type, "");
+        let audio: Document = serde_json::from_str(
+            r#"{"type": "Audio", "url": {"type": "Link", "href": "https://example.com/1.ogg"}}"#,
+        )?;
+        assert_eq!(audio.url, "https://example.com/1.ogg");
+        let link: Document =
+            serde_json::from_str(r#"{"type": "Link", "url": "https://example.com/1"}"#)?;
+        assert!(link.check_type().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_de_titled() -> Result<()> {
+        let post = check_de!(Post, "post_titled");
+        post.check_type()?;
+        assert_eq!(post.title(), Some("MyGO!!!!! episode 7 discussion"));
+        assert_eq!(check_de!(Post, "post_text").title(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_de_cw() -> Result<()> {
+        let post = check_de!(Post, "post_cw");
+        assert_eq!(post.content_warning(), Some("MyGO!!!!! ep 7 spoilers"));
+        let post = check_de!(Post, "post_text");
+        assert_eq!(post.content_warning(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_de_article() -> Result<()> {
+        let post = check_de!(Post, "post_article");
+        post.check_type()?;
+        assert!(post.is_article());
+        assert_eq!(post.name.as_deref(), Some("Notes on MyGO!!!!!"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_de_audio() -> Result<()> {
+        let post = check_de!(Post, "post_audio");
+        post.check_type()?;
+        assert_eq!(post.url, "https://tanukitunes.com/library/tracks/4213");
+        assert_eq!(post.attachment.len(), 1);
+        post.attachment[0].check_type()?;
+        assert!(post.attachment[0].url.contains("/api/v1/listen/"));
+        assert_eq!(post.title(), Some("Haruhikage"));
+        assert_eq!(
+            post.track_meta(),
+            Some(TrackMeta {
+                title: Some("Haruhikage".to_owned()),
+                artist: Some("MyGO!!!!!".to_owned()),
+                album: Some("Meikyuu Daydream".to_owned()),
+            })
+        );
+        // Uploads of channels
+        let post: Post = serde_json::from_str(
+            r#"{
+                "id": "https://tanukitunes.com/federation/music/uploads/1",
+                "type": "Audio",
+                "name": "Episode 1",
+                "attributedTo": {"id": "https://tanukitunes.com/federation/actors/cast", "name": "Cast"},
+                "published": "2023-08-20T09:00:00Z",
+                "url": [
+                    {"type": "Link", "mediaType": "text/html", "href": "https://tanukitunes.com/channels/cast/1"},
+                    {"type": "Link", "mediaType": "audio/ogg", "href": "https://tanukitunes.com/listen/1.ogg"}
+                ],
+                "content": "<p>Hi</p>",
+                "to": "https://www.w3.org/ns/activitystreams#Public"
+            }"#,
+        )?;
+        assert_eq!(post.url, "https://tanukitunes.com/channels/cast/1");
+        assert_eq!(
+            post.attachment[0].url,
+            "https://tanukitunes.com/listen/1.ogg"
+        );
+        let meta = post.track_meta().unwrap();
+        assert_eq!(meta.title.as_deref(), Some("Episode 1"));
+        assert_eq!(meta.artist.as_deref(), Some("Cast"));
+        assert_eq!(meta.album, None);
+        assert_eq!(check_de!(Post, "post_text").track_meta(), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_de_event() -> Result<()> {
+        let post = check_de!(Post, "post_event");
+        post.check_type()?;
+        assert!(post.is_event());
+        assert_eq!(post.attachment.len(), 1);
+        post.attachment[0].check_type()?;
+        assert_eq!(
+            post.start_time.as_deref(),
+            Some("2023-09-01T18:00:00+02:00")
+        );
+        assert_eq!(
+            post.location.as_ref().and_then(Place::text).as_deref(),
+            Some("Café Commun, 10 Rue de Rivoli, Paris, France")
+        );
+        let place: Place = serde_json::from_str(r#"{"name": "Online", "address": "Online"}"#)?;
+        assert_eq!(place.text().as_deref(), Some("Online"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_de_lenient_by_type() -> Result<()> {
+        let mut event = check_de!(serde_json::Value, "post_event");
+        event["type"] = "Note".into();
+        assert!(serde_json::from_value::<Post>(event).is_err());
+        let mut post = check_de!(serde_json::Value, "post_text");
+        post.as_object_mut().unwrap().remove("content");
+        assert!(serde_json::from_value::<Post>(post.clone()).is_err());
+        post["type"] = "Audio".into();
+        let audio: Post = serde_json::from_value(post)?;
+        assert_eq!(audio.content, "");
         Ok(())
     }
 
@@ -271,4 +1557,20 @@ mod tests {
         check_de!(Post, "post_multi_grouped_images");
         Ok(())
     }
+
+    #[test]
+    fn test_media_sensitive() -> Result<()> {
+        let mut post = check_de!(Post, "post_multi_grouped_images");
+        assert!(!post.is_sensitive());
+        post.attachment[1].sensitive = Some(true);
+        assert!(post.is_sensitive());
+        assert!(!post.is_media_sensitive(&post.attachment[0]));
+        assert!(post.is_media_sensitive(&post.attachment[1]));
+        post.sensitive = true;
+        post.attachment[0].sensitive = Some(false);
+        post.attachment[1].sensitive = None;
+        assert!(!post.is_media_sensitive(&post.attachment[0]));
+        assert!(post.is_media_sensitive(&post.attachment[1]));
+        Ok(())
+    }
 }
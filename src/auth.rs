@@ -0,0 +1,78 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! `mastotg auth`, the OAuth login to a Mastodon instance.
+//! An app is registered on the instance, the user authorizes it in the browser and pastes the code back,
+//! and the code is exchanged for the access token, which is stored in the database per instance.
+//!
+//! API-based producers take the stored token if `MASTODON_ACCESS_TOKEN` is not set.
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::http;
+use crate::utils::{check_res, with_scheme};
+
+/// Redirect URI of the out-of-band flow, where the code is shown to be copied
+pub const OOB_REDIRECT_URI: &str = "urn:ietf:wg:oauth:2.0:oob";
+
+/// Scopes needed by `--input api` and `--input timeline`
+pub const DEFAULT_SCOPES: &str = "read:accounts read:statuses";
+
+/// OAuth client registered on the instance
+#[derive(Clone, Deserialize)]
+pub struct App {
+    pub client_id: String,
+    pub client_secret: String,
+}
+
+/// Key of the instance to store the token by, e.g., `https://social.example.com`.
+/// Normalized like the CLI hosts, so `social.example.com/` gives the same key.
+pub fn host_key(host: &str) -> String {
+    with_scheme(host).trim_end_matches('/').to_owned()
+}
+
+/// Register the OAuth app with the scopes
+pub async fn register_app(host: &str, scopes: &str) -> Result<App> {
+    let form = [
+        ("client_name", "mastotg"),
+        ("redirect_uris", OOB_REDIRECT_URI),
+        ("scopes", scopes),
+        ("website", "https://github.com/myl7/mastotg"),
+    ];
+    let url = format!("{}/api/v1/apps", host_key(host));
+    let res = http::client().post(url).form(&form).send().await?;
+    Ok(check_res(res).await?.json().await?)
+}
+
+/// URL for the user to open and authorize the app
+pub fn authorize_url(host: &str, app: &App, scopes: &str) -> Result<String> {
+    let mut url = reqwest::Url::parse(&format!("{}/oauth/authorize", host_key(host)))?;
+    url.query_pairs_mut()
+        .append_pair("client_id", &app.client_id)
+        .append_pair("scope", scopes)
+        .append_pair("redirect_uri", OOB_REDIRECT_URI)
+        .append_pair("response_type", "code");
+    Ok(url.to_string())
+}
+
+/// Exchange the authorization code for the access token
+pub async fn obtain_token(host: &str, app: &App, code: &str, scopes: &str) -> Result<String> {
+    #[derive(Deserialize)]
+    struct Token {
+        access_token: String,
+    }
+
+    let form = [
+        ("grant_type", "authorization_code"),
+        ("client_id", &app.client_id),
+        ("client_secret", &app.client_secret),
+        ("redirect_uri", OOB_REDIRECT_URI),
+        ("code", code.trim()),
+        ("scope", scopes),
+    ];
+    let url = format!("{}/oauth/token", host_key(host));
+    let res = http::client().post(url).form(&form).send().await?;
+    let token: Token = check_res(res).await?.json().await?;
+    Ok(token.access_token)
+}
@@ -0,0 +1,214 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Per-host circuit breakers of rounds.
+//! A host failing round after round, e.g., a down Mastodon server, is left alone for a while
+//! instead of being requested every interval.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use reqwest::Url;
+#[cfg(feature = "telegram")]
+use teloxide::RequestError;
+
+use crate::cli::Cli;
+#[cfg(feature = "telegram")]
+use crate::cli::CliOutput;
+use crate::failure::FailureClass;
+use crate::utils::HttpStatusError;
+
+/// Host of the errors from the Bot API
+#[cfg(feature = "telegram")]
+const BOT_API_HOST: &str = "api.telegram.org";
+
+pub struct Breakers {
+    /// Number of consecutive failed rounds to open a breaker
+    threshold: u32,
+    /// First cooling-off period, doubled each time the breaker opens again
+    cooldown: Duration,
+    max_cooldown: Duration,
+    hosts: HashMap<String, Breaker>,
+}
+
+#[derive(Default)]
+struct Breaker {
+    /// Consecutive failed rounds.
+    /// Kept after the breaker opens, so a single failure after the cooling-off opens it again.
+    failures: u32,
+    /// Times opened since the last successful round
+    opened: u32,
+    /// Cooling-off period not waited yet
+    open: Option<Duration>,
+}
+
+impl Breakers {
+    pub fn new(threshold: u32, cooldown: Duration, max_cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            cooldown,
+            max_cooldown: max_cooldown.max(cooldown),
+            hosts: HashMap::new(),
+        }
+    }
+
+    /// Count the failed round against the host of the error if it is one of the hosts of the round.
+    /// Other hosts, e.g., of the media, are not counted, since no successful round closes their breakers,
+    /// and the failure is only left to the next round.
+    /// Returns false if the error is not a network error or a rate limit of a host,
+    /// which is not tolerated.
+    pub fn on_failure<S: AsRef<str>>(&mut self, e: &anyhow::Error, hosts: &[S]) -> bool {
+        if !matches!(
+            FailureClass::of(e),
+            FailureClass::Network | FailureClass::RateLimit
+        ) {
+            return false;
+        }
+        let Some(host) = host_of(e) else {
+            return false;
+        };
+        if !hosts.iter().any(|h| h.as_ref() == host) {
+            tracing::warn!("Round failed against {host}: {e:#}");
+            return true;
+        }
+        let breaker = self.hosts.entry(host.clone()).or_default();
+        breaker.failures += 1;
+        if breaker.failures < self.threshold {
+            tracing::warn!(
+                "Round failed against {host} ({}/{}): {e:#}",
+                breaker.failures,
+                self.threshold
+            );
+            return true;
+        }
+        let cooldown = self
+            .cooldown
+            .saturating_mul(2u32.saturating_pow(breaker.opened))
+            .min(self.max_cooldown);
+        breaker.opened += 1;
+        breaker.open = Some(cooldown);
+        tracing::error!(
+            "Circuit breaker for {host} opened for {} seconds after {} failed rounds: {e:#}",
+            cooldown.as_secs(),
+            breaker.failures
+        );
+        true
+    }
+
+    /// Close the breakers of the hosts requested by a successful round.
+    /// Breakers of the other hosts, e.g., of the other sources, are kept.
+    pub fn on_success<S: AsRef<str>>(&mut self, hosts: &[S]) {
+        for host in hosts {
+            self.hosts.remove(host.as_ref());
+        }
+    }
+
+    /// Take the longest cooling-off period of the open breakers.
+    /// The breakers are half-open after it until the next round.
+    pub fn take_cooldown(&mut self) -> Option<Duration> {
+        self.hosts
+            .values_mut()
+            .filter_map(|breaker| breaker.open.take())
+            .max()
+    }
+}
+
+/// Hosts requested by the rounds of the source, i.e., of its input and outputs
+pub fn round_hosts(cli: &Cli) -> Vec<String> {
+    let host = |s: &str| {
+        Url::parse(s)
+            .or_else(|_| Url::parse(&format!("https://{s}")))
+            .ok()
+            .and_then(|url| url.host_str().map(str::to_owned))
+    };
+    #[cfg(feature = "telegram")]
+    let bot_api = cli
+        .output
+        .contains(&CliOutput::TgSend)
+        .then(|| BOT_API_HOST.to_owned());
+    #[cfg(not(feature = "telegram"))]
+    let bot_api = None;
+    [
+        cli.host.as_deref(),
+        #[cfg(feature = "webhook")]
+        cli.webhook_url.as_deref(),
+        #[cfg(feature = "mastodon")]
+        cli.target_host.as_deref(),
+        #[cfg(feature = "bsky")]
+        Some(cli.bsky_pds.as_str()),
+        #[cfg(feature = "ntfy")]
+        Some(cli.ntfy_server.as_str()),
+    ]
+    .into_iter()
+    .flatten()
+    .filter_map(host)
+    .chain(bot_api)
+    .collect()
+}
+
+/// Host requested by the first error in the chain that tells it
+fn host_of(e: &anyhow::Error) -> Option<String> {
+    let host = |url: &Url| url.host_str().map(str::to_owned);
+    e.chain().find_map(|cause| {
+        #[cfg(feature = "telegram")]
+        if cause.is::<RequestError>() {
+            return Some(BOT_API_HOST.to_owned());
+        }
+        if let Some(e) = cause.downcast_ref::<HttpStatusError>() {
+            return Url::parse(&e.url).ok().as_ref().and_then(host);
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.url().and_then(host);
+        }
+        None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_breakers() {
+        let status = |url: &str, status| {
+            anyhow::Error::new(HttpStatusError {
+                url: url.to_owned(),
+                status,
+                body: String::new(),
+            })
+        };
+        let down = || status("https://mastodon.example/users/a/outbox", 502);
+        let other = || status("https://other.example/users/a/outbox", 503);
+        let hosts = ["mastodon.example", "other.example"];
+        let mut breakers = Breakers::new(2, Duration::from_secs(60), Duration::from_secs(200));
+        assert!(!breakers.on_failure(&status("https://mastodon.example", 401), &hosts));
+
+        assert!(breakers.on_failure(&down(), &hosts));
+        assert_eq!(breakers.take_cooldown(), None);
+        assert!(breakers.on_failure(&down(), &hosts));
+        assert_eq!(breakers.take_cooldown(), Some(Duration::from_secs(60)));
+        assert_eq!(breakers.take_cooldown(), None);
+        // Half-open after the cooling-off
+        assert!(breakers.on_failure(&down(), &hosts));
+        assert_eq!(breakers.take_cooldown(), Some(Duration::from_secs(120)));
+        assert!(breakers.on_failure(&down(), &hosts));
+        assert_eq!(breakers.take_cooldown(), Some(Duration::from_secs(200)));
+
+        // Hosts are counted separately
+        assert!(breakers.on_failure(&other(), &hosts));
+        assert_eq!(breakers.take_cooldown(), None);
+
+        // Only the hosts of the successful round are closed
+        breakers.on_success(&["mastodon.example"]);
+        assert!(breakers.on_failure(&down(), &hosts));
+        assert_eq!(breakers.take_cooldown(), None);
+        assert!(breakers.on_failure(&other(), &hosts));
+        assert_eq!(breakers.take_cooldown(), Some(Duration::from_secs(60)));
+
+        // Hosts not of the rounds are not counted
+        for _ in 0..3 {
+            assert!(breakers.on_failure(&status("https://media.example/a.png", 503), &hosts));
+        }
+        assert_eq!(breakers.take_cooldown(), None);
+    }
+}
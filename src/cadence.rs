@@ -0,0 +1,86 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Adaptive polling interval from the recent posting cadence of the source.
+//! Quiet accounts are polled less often, and active ones more often down to a floor.
+
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use time::OffsetDateTime;
+
+/// Number of the latest posts to estimate the cadence from
+const WINDOW: usize = 20;
+
+/// The interval is the fraction of the expected gap between posts,
+/// so a new post waits a quarter of the gap on average
+const GAP_DIVISOR: u32 = 4;
+
+pub struct Cadence {
+    /// Published times of the latest posts
+    published: BTreeSet<OffsetDateTime>,
+    min: Duration,
+    max: Duration,
+}
+
+impl Cadence {
+    pub fn new(min: Duration, max: Duration) -> Self {
+        Self {
+            published: BTreeSet::new(),
+            min,
+            max: max.max(min),
+        }
+    }
+
+    /// Record the published time of a fetched post
+    pub fn observe(&mut self, published: OffsetDateTime) {
+        self.published.insert(published);
+        while self.published.len() > WINDOW {
+            self.published.pop_first();
+        }
+    }
+
+    /// Interval to the next round.
+    /// Returns `None` before the cadence is known.
+    pub fn interval(&self, now: OffsetDateTime) -> Option<Duration> {
+        let first = *self.published.first()?;
+        let last = *self.published.last()?;
+        let since_last: Duration = (now - last).try_into().unwrap_or_default();
+        let mean_gap = match self.published.len() {
+            1 => since_last,
+            n => {
+                let span: Duration = (last - first).try_into().unwrap_or_default();
+                span / (n - 1) as u32
+            }
+        };
+        // A long silence lengthens the interval even if the account used to be active
+        let gap = mean_gap.max(since_last);
+        Some((gap / GAP_DIVISOR).clamp(self.min, self.max))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cadence() {
+        let min = Duration::from_secs(60);
+        let max = Duration::from_secs(3600);
+        let mut cadence = Cadence::new(min, max);
+        let now = OffsetDateTime::from_unix_timestamp(1_691_078_400).unwrap();
+        assert_eq!(cadence.interval(now), None);
+        // A post every 20 minutes
+        for i in 0..5 {
+            cadence.observe(now - time::Duration::minutes(20 * i));
+        }
+        assert_eq!(cadence.interval(now), Some(Duration::from_secs(300)));
+        // A post every minute
+        for i in 0..30 {
+            cadence.observe(now - time::Duration::minutes(i));
+        }
+        assert_eq!(cadence.interval(now), Some(min));
+        // Quiet for a day
+        assert_eq!(cadence.interval(now + time::Duration::days(1)), Some(max));
+    }
+}
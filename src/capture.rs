@@ -0,0 +1,92 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! HTTP debug capture.
+//! Dumps request/response pairs of the first round to a directory,
+//! so users can attach them to reports when an instance's JSON breaks parsing.
+//!
+//! Each pair is saved as `<seq>_<kind>.json`.
+//! Secrets are redacted, but posts are kept as they are, so check the files before sharing them.
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use anyhow::Result;
+use serde_json::{json, Value};
+use tokio::fs;
+
+use crate::redact::redact;
+
+pub struct HttpCapture {
+    dir: PathBuf,
+    seq: AtomicU64,
+    active: AtomicBool,
+}
+
+impl HttpCapture {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            seq: AtomicU64::new(0),
+            active: AtomicBool::new(true),
+        }
+    }
+
+    /// Whether pairs are still being captured
+    pub fn is_active(&self) -> bool {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// Stop capturing, e.g., after the first round
+    pub fn stop(&self) {
+        self.active.store(false, Ordering::Relaxed);
+    }
+
+    /// Save a pair of an HTTP request and its response body
+    pub async fn http(&self, kind: &str, method: &str, url: &str, status: u16, body: &[u8]) {
+        let pair = json!({
+            "request": {"method": method, "url": url},
+            "response": {"status": status, "body": body_value(body)},
+        });
+        self.dump(kind, &pair).await;
+    }
+
+    /// Save a pair of a bot API call and its result
+    pub async fn bot_api(&self, method: &str, payload: Value, res: Result<Value, String>) {
+        let response = match res {
+            Ok(value) => json!({"ok": true, "result": value}),
+            Err(e) => json!({"ok": false, "error": e}),
+        };
+        let pair = json!({
+            "request": {"method": method, "payload": payload},
+            "response": response,
+        });
+        self.dump("bot_api", &pair).await;
+    }
+
+    /// Failures are logged only, since the capture is best-effort
+    async fn dump(&self, kind: &str, pair: &Value) {
+        if !self.is_active() {
+            return;
+        }
+        let seq = self.seq.fetch_add(1, Ordering::Relaxed);
+        let path = self.dir.join(format!("{seq:04}_{kind}.json"));
+        let res: Result<()> = async {
+            let text = serde_json::to_string_pretty(pair)?;
+            fs::create_dir_all(&self.dir).await?;
+            fs::write(&path, redact(&text) + "\n").await?;
+            Ok(())
+        }
+        .await;
+        match res {
+            Ok(()) => tracing::debug!("Captured HTTP pair {}", path.display()),
+            Err(e) => tracing::warn!("Failed to capture HTTP pair {}: {e}", path.display()),
+        }
+    }
+}
+
+/// Keep JSON bodies structured and others as text
+fn body_value(body: &[u8]) -> Value {
+    serde_json::from_slice(body)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(body).into_owned()))
+}
@@ -3,19 +3,66 @@
 
 //! CLI definitions with its cleaning
 
-use anyhow::{anyhow, Result};
-use clap::{Parser, ValueEnum};
+use std::ffi::OsString;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, ensure, Result};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 use regex::Regex;
+use reqwest::{Client, Proxy};
+#[cfg(feature = "telegram")]
+use time::format_description;
+
+use crate::as2::Visibility;
+use crate::config::config_args;
+use crate::cons::ErrorPolicy;
+use crate::filter::{
+    And, Filter, FilterConfig, HasTag, IsReply, IsSelfReply, MinVisibility, Not, Or,
+};
+use crate::http;
+#[cfg(feature = "telegram")]
+use crate::labels::Labels;
+#[cfg(feature = "telegram")]
+use crate::render::{parse_offset, Footer, Template, TimestampStyle, DEFAULT_TIMESTAMP_FORMAT};
+use crate::utils::with_scheme;
 
+/// Command line of the program, which runs the mirror with the main options without a subcommand
 #[derive(Parser)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true
+)]
+pub struct MainCli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    #[command(flatten)]
+    pub cli: Option<Cli>,
+}
+
+/// Subcommands apart from the main options, which require the database
+#[derive(Subcommand)]
+pub enum Command {
+    /// Render a directory of captured posts as the message texts
+    Render(RenderCli),
+    /// Log in to a Mastodon instance and store the access token
+    Auth(AuthCli),
+}
+
+#[derive(Parser, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Cli {
     /// Where to get the ActivityPub outbox JSON
     #[clap(short, long)]
     pub input: Option<CliInput>,
-    /// According to `--input`, outbox JSON URL or web domain of the server,
-    /// e.g., `social.myl.moe/users/myl/outbox` or `mastodon.social`.
-    /// The protocol head default to `https://`.
+    /// According to `--input`, outbox JSON URL, feed URL, web domain of the server, or local path,
+    /// e.g., `social.myl.moe/users/myl/outbox`, `social.myl.moe/@myl.rss`, or `mastodon.social`.
+    /// The protocol head of URLs default to `https://`.
     #[clap(short = 's', long)]
     pub host: Option<String>,
     /// Webfinger account URI of the user to be fetched,
@@ -24,19 +71,81 @@ pub struct Cli {
     /// The domain default to the value of `--host` without the protocol head.
     #[clap(short = 'u', long)]
     pub acct: Option<String>,
-    /// Where to output the parsed posts
-    #[clap(short, long)]
-    pub output: Option<CliOutput>,
-    /// Telegram channel ID to send to, e.g., @myl7s.
-    /// The leading `@` is optional.
+    /// Extra source account to mirror in the same loop after the main one,
+    /// as comma-separated `name`, `input`, `host`, `acct`, and `min-id` pairs,
+    /// e.g., `name=gts,input=query-fetch,host=gts.myl.moe,acct=myl`.
+    /// Other options are shared with the main source.
+    /// States are saved by the names, so keep them unchanged.
+    /// Can be repeated.
     #[clap(long)]
-    pub tg_chan: Option<String>,
+    pub source: Vec<String>,
+    /// Where to output the parsed posts, default to print.
+    /// Can be repeated or comma-separated to deliver each page to all of them,
+    /// e.g., `tg-send,ndjson` to also archive the sent posts.
+    /// A page failed to deliver to some is retried only for them in the next round.
+    #[clap(short, long, value_delimiter = ',')]
+    pub output: Vec<CliOutput>,
+    /// Telegram chat to send to, as the channel username, e.g., @myl7s,
+    /// the numeric chat ID of a channel or a group, e.g., -1001234567890, or the public t.me link.
+    /// The leading `@` of usernames is optional.
+    /// Private invite links can not be resolved by bots, so use the numeric IDs of the chats instead.
+    /// The bots are checked to be able to post to the chats at startup.
+    /// Can be repeated to split the posts into topic-specific channels,
+    /// each only sent the posts allowed by its filter expression like `--filter` after `=` if any,
+    /// e.g., `@myl7s_mygo={"tag": "mygo"}`.
+    /// The first is the main channel keeping the mirrored msgs saved before, so keep it first.
+    /// The bot token is read from `TELOXIDE_TOKEN`.
+    /// For high-volume channels, tokens of more bots to send in turn can be in `TELOXIDE_EXTRA_TOKENS`,
+    /// separated by commas.
+    #[clap(long, allow_hyphen_values = true)]
+    pub tg_chan: Vec<String>,
     /// Path to the SQLite database file to persist states
     #[clap(short = 'f', long)]
     pub db_file: String,
+    /// File of extra options, one per line, e.g., `--filter {...}`.
+    /// On SIGHUP, the file is read again and the options of the filter, the rendering, the pacing,
+    /// and the outputs with their destinations take effect from the next page.
+    /// Changes of the options of the sources, the database, and the services of the process are rejected,
+    /// and the current config is kept, since they need a restart.
+    #[clap(long)]
+    pub config: Option<PathBuf>,
     /// Use builtin loop runner to run the program every fixed interval. Unit: Seconds.
     #[clap(long)]
     pub loop_interval: Option<u64>,
+    /// Run a single round and exit even if `--loop-interval` is set, e.g., from cron
+    #[clap(long)]
+    pub once: bool,
+    /// Hold the lock of the file while running.
+    /// Exit immediately without errors if another instance holds it,
+    /// so a cron run can not overlap a slow one and send posts twice.
+    #[clap(long)]
+    pub lock_file: Option<PathBuf>,
+    /// Adapt the loop interval to the recent posting cadence of the source.
+    /// `--loop-interval` is used until the cadence is known.
+    #[clap(long)]
+    pub adaptive_interval: bool,
+    /// Floor of the adaptive loop interval. Unit: Seconds.
+    #[clap(long, default_value_t = 60)]
+    pub min_loop_interval: u64,
+    /// Ceiling of the adaptive loop interval. Unit: Seconds.
+    #[clap(long, default_value_t = 3600)]
+    pub max_loop_interval: u64,
+    /// Abort a round that takes longer than the time. Unit: Seconds.
+    /// Posts of the unfinished page are retried in the next round.
+    #[clap(long)]
+    pub round_timeout: Option<u64>,
+    /// Tolerate rounds failed by network errors or rate limits of a host when looping,
+    /// e.g., the Mastodon server or the Bot API.
+    /// After the number of consecutive failed rounds, rounds are skipped for a cooling-off period,
+    /// which is doubled each time the host fails again.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub circuit_breaker: Option<u32>,
+    /// First cooling-off period of the circuit breaker. Unit: Seconds.
+    #[clap(long, default_value_t = 60)]
+    pub breaker_cooldown: u64,
+    /// Ceiling of the cooling-off period of the circuit breaker. Unit: Seconds.
+    #[clap(long, default_value_t = 3600)]
+    pub max_breaker_cooldown: u64,
     /// Minimum integer ID of the posts to fetch. The newer posts have larger IDs.
     /// If not specified, try reading from the database.
     /// If still not specified, ignore all previous posts.
@@ -44,6 +153,7 @@ pub struct Cli {
     /// If set to 0, fetch all existing posts.
     /// The stdin input is not affected.
     /// This overrides the states given by `--file`.
+    /// Servers without integer IDs like GoToSocial are tracked by the published time saved in the database.
     #[clap(short, long, default_value = "-1")]
     pub min_id: i64,
     /// Maximum integer ID of the posts to fetch.
@@ -56,9 +166,389 @@ pub struct Cli {
     /// Set this flag to disable the behavior.
     #[clap(long)]
     pub no_follow_paging: bool,
+    /// Skip pages whose bodies are identical to the ones in the last round without parsing them.
+    /// For servers without integer IDs or ETags polled frequently.
+    #[clap(long)]
+    pub skip_unchanged: bool,
+    /// Number of pages to fetch ahead while the earlier ones are being prepared, e.g., during backfills.
+    /// Pages are still sent and committed in order.
+    /// Helps servers whose attachments come without media types to be queried.
+    #[clap(long, default_value_t = 0)]
+    pub prefetch_pages: usize,
+    /// Keep the fetched pages waiting to be sent in the database instead of memory.
+    /// For large backfills, so memory is bounded and interrupted rounds resume from the queue.
+    /// The state is saved after each sent page.
+    #[clap(long)]
+    pub disk_queue: bool,
+    /// Parse the page from the stdin as a stream, and queue its items in chunks of the number,
+    /// e.g., for archive imports of tens of MB.
+    /// With `--disk-queue`, memory is bounded by a few chunks.
+    #[clap(long, value_parser = clap::value_parser!(u16).range(1..), conflicts_with = "record_fixtures")]
+    pub stream_items: Option<u16>,
+    /// Warn about posts taking longer than the time from fetched to sent,
+    /// e.g., large video uploads. Unit: Seconds.
+    #[clap(long, default_value = "300")]
+    pub slow_post_secs: u64,
+    /// Maximum number of fetched pages waiting to be sent.
+    /// Fetching pauses when the queue is full.
+    #[clap(long, default_value = "2", value_parser = clap::value_parser!(u16).range(1..))]
+    pub queue_size: u16,
+    /// Save anonymized copies of fetched posts that go through new code paths to the directory,
+    /// e.g., `tests/fixtures`.
+    /// Pages that fail to be parsed are also saved.
+    #[clap(long)]
+    pub record_fixtures: Option<PathBuf>,
+    /// Dump request/response pairs of the first round to the directory for bug reports,
+    /// including outbox fetches and bot API calls.
+    /// Secrets are redacted.
+    #[clap(long)]
+    pub debug_http: Option<PathBuf>,
+    /// Only forward posts with any of the hashtags.
+    /// The leading `#` is optional.
+    /// Can be specified multiple times.
+    #[clap(long)]
+    pub include_tag: Vec<String>,
+    /// Do not forward posts with any of the hashtags.
+    /// The leading `#` is optional.
+    /// Can be specified multiple times.
+    #[clap(long)]
+    pub exclude_tag: Vec<String>,
+    /// Do not forward replies
+    #[clap(long)]
+    pub no_replies: bool,
+    /// Keep the replies to the account's own posts, i.e., thread continuations,
+    /// with `--no-replies`
+    #[clap(long)]
+    pub keep_threads: bool,
+    /// Do not forward posts less visible than the visibility.
+    /// Followers-only and direct posts are not forwarded by default.
+    #[clap(long, value_enum, default_value = "unlisted")]
+    pub min_visibility: Visibility,
+    /// Filter expression in JSON, e.g., `{"or": [{"tag": "mygo"}, "media"]}`.
+    /// Available filters: `and`, `or`, `not`, `tag`, `lang`, `reply`, `self_reply`, `media`, `sensitive`, `cw`, `visibility`.
+    /// Combined with the other filter options.
+    #[clap(long)]
+    pub filter: Option<String>,
+    /// Timeout of HTTP requests to servers, e.g., outbox pages and media. Unit: Seconds.
+    /// Telegram Bot API requests are not affected.
+    #[clap(long, default_value_t = 60)]
+    pub http_timeout: u64,
+    /// Proxy of HTTP requests to servers, e.g., `socks5://127.0.0.1:1080`.
+    /// If not specified, the `HTTPS_PROXY`-like env vars are used.
+    #[clap(long)]
+    pub http_proxy: Option<String>,
+    /// `User-Agent` of HTTP requests to servers
+    #[clap(long, default_value = crate::http::USER_AGENT)]
+    pub user_agent: String,
+    /// URL to POST a JSON event to when a post is sent, carrying its GUID and Telegram msg link
+    #[clap(long)]
+    pub on_sent_webhook: Option<String>,
+    /// URL to POST a JSON event to when a post permanently fails to be sent or is sent in part, carrying its GUID and the error.
+    /// Posts failing under `--on-error fail` are retried by the next round, so they only fire once skipped or dead-lettered.
+    #[clap(long)]
+    pub on_error_webhook: Option<String>,
+    /// URL to POST the posts to when output=webhook.
+    /// Server errors are retried with backoff.
+    /// If `WEBHOOK_SECRET` is set, bodies are signed with HMAC-SHA256 in `X-Mastotg-Signature: sha256=<hex>`.
+    #[cfg(feature = "webhook")]
+    #[clap(long)]
+    pub webhook_url: Option<String>,
+    /// File to append the posts to when output=ndjson, one activity per line
+    #[cfg(feature = "ndjson")]
+    #[clap(long)]
+    pub ndjson_file: Option<PathBuf>,
+    /// Rotate the NDJSON file once it would exceed the size. Unit: MiB.
+    /// Rotated files are renamed with the suffixes `.1`, `.2`, and so on, from the newest.
+    #[cfg(feature = "ndjson")]
+    #[clap(long)]
+    pub ndjson_max_size: Option<u64>,
+    /// Number of rotated NDJSON files to keep
+    #[cfg(feature = "ndjson")]
+    #[clap(long, default_value_t = 5)]
+    pub ndjson_keep: usize,
+    /// Site dir to export the posts to when output=hugo.
+    /// Posts go to `content/posts/` and media go to `static/media/`.
+    #[cfg(feature = "hugo")]
+    #[clap(long)]
+    pub export_dir: Option<PathBuf>,
+    /// Mastodon or GoToSocial instance to re-post to when output=mastodon, e.g., `mastodon.social`.
+    /// The access token is read from `MASTODON_TARGET_ACCESS_TOKEN`,
+    /// or the one stored by `mastotg auth -s <host> --scopes "write:statuses write:media"` is used.
+    #[cfg(feature = "mastodon")]
+    #[clap(long)]
+    pub target_host: Option<String>,
+    /// Max length of statuses on the target instance. Longer texts are shortened with the link to the post.
+    #[cfg(feature = "mastodon")]
+    #[clap(long, default_value_t = 500)]
+    pub target_max_chars: usize,
+    /// Handle or DID of the Bluesky account to publish to when output=bsky, e.g., `myl7.bsky.social`.
+    /// The app password is read from `BSKY_APP_PASSWORD`.
+    #[cfg(feature = "bsky")]
+    #[clap(long)]
+    pub bsky_handle: Option<String>,
+    /// PDS of the Bluesky account
+    #[cfg(feature = "bsky")]
+    #[clap(long, default_value = "https://bsky.social")]
+    pub bsky_pds: String,
+    /// JID of the XMPP account to send from when output=xmpp, e.g., `bot@example.org`.
+    /// The password is read from `XMPP_PASSWORD`.
+    #[cfg(feature = "xmpp")]
+    #[clap(long)]
+    pub xmpp_jid: Option<String>,
+    /// JID to send to when output=xmpp, which is a MUC room with `--xmpp-room`
+    #[cfg(feature = "xmpp")]
+    #[clap(long)]
+    pub xmpp_to: Option<String>,
+    /// Join `--xmpp-to` as a MUC room and send to the room
+    #[cfg(feature = "xmpp")]
+    #[clap(long)]
+    pub xmpp_room: bool,
+    /// Nick to join the room with
+    #[cfg(feature = "xmpp")]
+    #[clap(long, default_value = "mastotg")]
+    pub xmpp_nick: String,
+    /// Server of the XMPP account as `host:port`, if not at port 5222 of the JID domain
+    #[cfg(feature = "xmpp")]
+    #[clap(long)]
+    pub xmpp_server: Option<String>,
+    /// ntfy topic to push to when output=ntfy.
+    /// The access token of protected topics is read from `NTFY_TOKEN`.
+    #[cfg(feature = "ntfy")]
+    #[clap(long)]
+    pub ntfy_topic: Option<String>,
+    /// ntfy server of the topic
+    #[cfg(feature = "ntfy")]
+    #[clap(long, default_value = "https://ntfy.sh")]
+    pub ntfy_server: String,
+    /// URL to GET after each successful round, e.g., a healthchecks.io check.
+    /// `/fail` is appended to it after a failed round.
+    #[clap(long)]
+    pub heartbeat_url: Option<String>,
+    /// Telegram chat to post a compact summary to after each round, e.g., an admin chat.
+    /// Taken in the forms of `--tg-chan`, e.g., the numeric chat ID, `@username`, or the public t.me link.
+    /// Rounds are summarized in the info logs regardless.
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub summary_chat: Option<String>,
+    /// Append the reply, boost, and like counts at the time of mirroring to the posts.
+    /// Only shown when the source server exposes them.
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub show_engagement: bool,
+    /// Render posts without media with the preview cards of their links from the Mastodon API,
+    /// sending the card images as photos, instead of the Telegram link previews
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub preview_cards: bool,
+    /// Send replies to posts that are not mirrored, e.g., in threads started by others,
+    /// as replies to the first mirrored post of the thread
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub conversation_threads: bool,
+    /// Download the media and upload them to Telegram, instead of letting Telegram fetch the URLs.
+    /// For servers Telegram can not reach, or media over the size limits of fetching by URLs.
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub reupload_media: bool,
+    /// Max number of concurrent downloads of the media of a post when re-uploading
+    #[cfg(feature = "telegram")]
+    #[clap(long, default_value_t = 4)]
+    pub download_concurrency: usize,
+    /// Dir to save the downloaded media to when re-uploading.
+    /// The files are removed after sent.
+    #[cfg(feature = "telegram")]
+    #[clap(long, default_value_os_t = std::env::temp_dir())]
+    pub spool_dir: PathBuf,
+    /// Dir to cache the downloaded media in when re-uploading.
+    /// If not specified, the media are not cached.
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub media_cache_dir: Option<PathBuf>,
+    /// Max total size of the media cache. Unit: MiB.
+    /// The least recently used media are evicted when exceeded.
+    #[cfg(feature = "telegram")]
+    #[clap(long, default_value_t = 1024)]
+    pub media_cache_size: u64,
+    /// Transcode videos Telegram can not preview, e.g., WebM/VP9, to H.264 MP4 when re-uploading.
+    /// Requires `ffmpeg` and `ffprobe`.
+    #[cfg(feature = "transcode")]
+    #[clap(long)]
+    pub transcode_videos: bool,
+    /// Path to the `ffmpeg` binary
+    #[cfg(feature = "transcode")]
+    #[clap(long, default_value = "ffmpeg")]
+    pub ffmpeg: String,
+    /// Path to the `ffprobe` binary
+    #[cfg(feature = "transcode")]
+    #[clap(long, default_value = "ffprobe")]
+    pub ffprobe: String,
+    /// Resize and re-encode images over the Telegram photo limits when re-uploading,
+    /// instead of letting Telegram reject or degrade them
+    #[cfg(feature = "reencode")]
+    #[clap(long)]
+    pub fit_photos: bool,
+    /// Remove EXIF of JPEG images when fitting images
+    #[cfg(feature = "reencode")]
+    #[clap(long)]
+    pub strip_exif: bool,
+    /// What to do with items of fetched pages that fail validation, e.g., malformed posts.
+    /// `fail` fails the whole page.
+    /// `dead-letter` keeps the raw items in the `dead_letters` table of the database.
+    #[clap(long, value_enum, default_value = "fail")]
+    pub on_invalid: ErrorPolicy,
+    /// What to do when a post fails to be sent.
+    /// `dead-letter` keeps the failed activities in the `dead_letters` table of the database.
+    /// `fail` keeps the posts sent before the failure and retries from the failed one in the next round.
+    #[clap(long, value_enum, default_value = "fail")]
+    pub on_error: ErrorPolicy,
+    /// Keep the pinned msgs of the channel in sync with the pinned posts of the account.
+    /// Only mirrored posts are pinned.
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub sync_pins: bool,
+    /// Forward the pinned posts of the account not mirrored yet, e.g., the ones older than `--min-id`,
+    /// so `--sync-pins` can pin them
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub forward_featured: bool,
+    /// Label thread continuations of the account with their positions, e.g., `🧵 (2/…)`
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub thread_labels: bool,
+    /// Thread ID of the topic of the forum supergroup to send into, e.g., 42 of `t.me/c/1234567890/42`.
+    /// The general topic is used if not specified.
+    /// Applied to all chats of `--tg-chan`, which are checked to be forums at startup.
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub tg_topic: Option<i32>,
+    /// Send posts with the hashtag into the topic instead of `--tg-topic`, e.g., `mygo=42`.
+    /// Can be repeated, and the first matched one is used for posts with multiple.
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub tg_topic_tag: Vec<String>,
+    /// Template of the texts sent to the chat of `--tg-chan`, e.g., `@myl7s={content}\n<a href="{url}">Source</a>`,
+    /// so that the chats fanned out to can be rendered differently.
+    /// `{author}`, `{content}`, `{footer}`, and `{url}` are filled in, and `\n` is a line break.
+    /// Chats without templates are rendered by the other options as before.
+    /// Can be repeated for different chats.
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub tg_template: Vec<String>,
+    /// Prepend the author to the posts, for sources mixing posts of multiple accounts like relays
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub attribution: bool,
+    /// Also send the posts to the Discord webhook, alongside the Telegram channel.
+    /// The webhook URL is read from `DISCORD_WEBHOOK_URL`.
+    /// Texts go to embeds and media are uploaded as files.
+    #[cfg(feature = "discord")]
+    #[clap(long)]
+    pub discord: bool,
+    /// Append the published time to the posts
+    #[cfg(feature = "telegram")]
+    #[clap(long, value_enum)]
+    pub timestamp: Option<TimestampStyle>,
+    /// Timezone of absolute timestamps as a UTC offset, e.g., `+08:00`.
+    /// IANA names like `Asia/Shanghai` are not supported.
+    #[cfg(feature = "telegram")]
+    #[clap(long, default_value = "UTC")]
+    pub timezone: String,
+    /// Format of absolute timestamps, e.g., `[day].[month].[year] [hour]:[minute]`.
+    /// See <https://time-rs.github.io/book/api/format-description.html> for the syntax.
+    #[cfg(feature = "telegram")]
+    #[clap(long, default_value = DEFAULT_TIMESTAMP_FORMAT)]
+    pub timestamp_format: String,
+    /// Language of the generated texts, e.g., `Read more` of shortened articles.
+    /// Built-in ones are `en`, `zh`, `ja`, `de`, `fr`, and `es`.
+    #[cfg(feature = "telegram")]
+    #[clap(long, default_value = "en")]
+    pub locale: String,
+    /// JSON object of the labels overriding the built-in ones of `--locale`,
+    /// e.g., `{"read_more": "Full post"}`.
+    /// `{n}` and `{time}` in the labels are filled in.
+    /// See the source for all fields.
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub labels: Option<String>,
+    /// Send images that Telegram fails to fetch as placeholders decoded from their blurhashes
+    #[cfg(feature = "telegram")]
+    #[clap(long)]
+    pub blurhash_placeholder: bool,
+    /// Public URL of the WebSub callback with a secret path, e.g., `https://example.com/websub/<random>`.
+    /// Requests are received at `--websub-addr`, e.g., via a reverse proxy.
+    /// Pushes must be signed with the secret in the env var `WEBSUB_SECRET`, or a random one per run.
+    #[cfg(feature = "websub")]
+    #[clap(long)]
+    pub websub_callback: Option<String>,
+    /// Address to receive the WebSub requests
+    #[cfg(feature = "websub")]
+    #[clap(long, default_value = "127.0.0.1:8380")]
+    pub websub_addr: String,
+    /// Lease of the WebSub subscription to ask the hub for, which is renewed before it expires.
+    /// Unit: Seconds.
+    #[cfg(feature = "websub")]
+    #[clap(long, default_value_t = 86400)]
+    pub websub_lease: u64,
+    /// Address to serve metrics in the Prometheus text format, e.g., `127.0.0.1:9184`
+    #[clap(long)]
+    pub metrics_addr: Option<String>,
+    /// Base URL of the OTLP/HTTP receiver to export traces and metrics to,
+    /// e.g., `http://localhost:4318`
+    #[cfg(feature = "otel")]
+    #[clap(long)]
+    pub otlp_endpoint: Option<String>,
     // TODO: Post command
 }
 
+/// `mastotg render`, which renders captured posts without the database or the network
+#[derive(Args)]
+pub struct RenderCli {
+    /// Directory of the captured posts as `<name>.json`,
+    /// e.g., the one of `--record-fixtures`.
+    /// Posts or their `Create` or `Update` activities are rendered, and other files are skipped.
+    #[clap(long)]
+    pub fixtures: PathBuf,
+    /// Directory to write the texts to as `<name>.txt`.
+    /// If not specified, print them.
+    #[clap(long)]
+    pub out: Option<PathBuf>,
+    /// Compare the texts with the existing ones in `--out` instead of writing them,
+    /// and fail if any differs.
+    /// Only the names with the text files are compared.
+    #[clap(long, requires = "out")]
+    pub check: bool,
+    /// Same as the main option
+    #[clap(long)]
+    pub attribution: bool,
+    /// Same as the main option
+    #[clap(long)]
+    pub show_engagement: bool,
+    /// Same as the main option
+    #[clap(long, default_value = "en")]
+    pub locale: String,
+    /// Same as the main option
+    #[clap(long)]
+    pub labels: Option<String>,
+    /// Template of the texts as the ones of `--tg-template`, to preview them
+    #[clap(long)]
+    pub template: Option<String>,
+}
+
+/// `mastotg auth`, which logs in to a Mastodon instance with OAuth and stores the access token
+#[derive(Args)]
+pub struct AuthCli {
+    /// Instance URL, e.g., `https://social.example.com`.
+    /// The same as `--host` of the API-based inputs, which then take the stored token.
+    #[clap(short = 's', long)]
+    pub host: String,
+    /// Same as the main option
+    #[clap(short = 'f', long)]
+    pub db_file: String,
+    /// OAuth scopes separated by spaces
+    #[clap(long, default_value = crate::auth::DEFAULT_SCOPES)]
+    pub scopes: String,
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum CliInput {
     /// From the stdin (default)
@@ -67,6 +557,39 @@ pub enum CliInput {
     Fetch,
     /// Get the outbox JSON URL from the WebFinger API and then fetch it
     QueryFetch,
+    /// Pull the statuses from the Mastodon REST API with the access token in `MASTODON_ACCESS_TOKEN`,
+    /// or the one stored by `mastotg auth` for the host if not set,
+    /// which includes unlisted and followers-only posts.
+    /// The token needs the `read:accounts` and `read:statuses` scopes.
+    Api,
+    /// Pull the local public timeline of the server of `--host` from the Mastodon REST API,
+    /// e.g., for community announcement channels, with the authors prepended to the posts.
+    /// The access token in `MASTODON_ACCESS_TOKEN` or the stored one is used if any, for servers requiring one.
+    Timeline,
+    /// Poll the RSS feed URL, e.g., `https://mastodon.social/@user.rss`
+    #[cfg(feature = "feeds")]
+    Rss,
+    /// Poll the Atom feed URL
+    #[cfg(feature = "feeds")]
+    Atom,
+    /// Pull the notes of `--acct` from the Misskey API of `--host`, which also works for Sharkey
+    #[cfg(feature = "misskey")]
+    Misskey,
+    /// Subscribe to the Atom or RSS feed URL via its WebSub hub, and run a round once entries are pushed.
+    /// The feed is still polled every `--loop-interval` as the fallback.
+    #[cfg(feature = "websub")]
+    Websub,
+    /// Take the `*page.json` and `*post.json` files dropped into the directory of `--host`,
+    /// and run a round once new ones are dropped.
+    /// The directory is watched via the OS file notifications,
+    /// or polled every second if built without the `watch` feature.
+    /// Files are removed after their posts are sent.
+    Dir,
+    /// Replay the history in the Mastodon account archive of `--host`,
+    /// which is the downloaded `.tar.gz` or the dir it is extracted to.
+    /// Media in the archive are uploaded. Use `--min-id 0` to replay from the first post.
+    #[cfg(feature = "archive")]
+    Archive,
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
@@ -74,27 +597,97 @@ pub enum CliOutput {
     /// Print to stdout (default)
     Print,
     /// Send to the Telegram channel
+    #[cfg(feature = "telegram")]
     TgSend,
+    /// POST the posts as JSON to `--webhook-url`, the same as printed
+    #[cfg(feature = "webhook")]
+    Webhook,
+    /// Append the posts to `--ndjson-file` as JSON lines
+    #[cfg(feature = "ndjson")]
+    Ndjson,
+    /// Export the posts as Markdown files with front matter and their media into `--export-dir`,
+    /// which is laid out as a Hugo site
+    #[cfg(feature = "hugo")]
+    Hugo,
+    /// Re-post to the account on `--target-host` via the Mastodon statuses API
+    #[cfg(feature = "mastodon")]
+    Mastodon,
+    /// Publish to the Bluesky account of `--bsky-handle`
+    #[cfg(feature = "bsky")]
+    Bsky,
+    /// Send to `--xmpp-to` from the XMPP account of `--xmpp-jid`
+    #[cfg(feature = "xmpp")]
+    Xmpp,
+    /// Push notifications to the ntfy topic of `--ntfy-topic`
+    #[cfg(feature = "ntfy")]
+    Ntfy,
+}
+
+impl CliOutput {
+    /// Name in the CLI, e.g., `tg-send`
+    pub fn name(&self) -> String {
+        self.to_possible_value().unwrap().get_name().to_owned()
+    }
+
+    /// Whether the consumer saves the mirrors of the posts to the ID map
+    pub fn saves_id_map(&self) -> bool {
+        match self {
+            #[cfg(feature = "telegram")]
+            CliOutput::TgSend => true,
+            #[cfg(feature = "mastodon")]
+            CliOutput::Mastodon => true,
+            #[cfg(feature = "bsky")]
+            CliOutput::Bsky => true,
+            #[cfg(feature = "xmpp")]
+            CliOutput::Xmpp => true,
+            _ => false,
+        }
+    }
 }
 
 impl Cli {
+    /// Parse the args followed by the options in the config file if any
+    pub fn try_parse_with_config(args: Vec<OsString>) -> Result<Self> {
+        let cli = Self::try_parse_from(&args)?;
+        let Some(path) = cli.config.as_ref() else {
+            return Ok(cli);
+        };
+        let extra = config_args(&fs::read_to_string(path)?);
+        Ok(Self::try_parse_from(
+            args.into_iter()
+                .chain(extra.into_iter().map(OsString::from)),
+        )?)
+    }
+
     pub fn clean(&mut self) -> Result<()> {
-        self.tg_chan = self.tg_chan.as_ref().map(|s| {
-            if !s.starts_with('@') {
-                format!("@{}", s)
-            } else {
-                s.to_owned()
-            }
-        });
+        self.tg_chan = self
+            .tg_chan
+            .iter()
+            .map(|s| match split_chat(s) {
+                Some((chat, expr)) => Ok(format!("{}={expr}", normalize_chat(chat)?)),
+                None => normalize_chat(s),
+            })
+            .collect::<Result<_>>()?;
+        #[cfg(feature = "telegram")]
+        {
+            self.summary_chat = self
+                .summary_chat
+                .as_deref()
+                .map(normalize_chat)
+                .transpose()?;
+        }
 
         self.host = self.host.as_ref().map(|s| match self.input {
-            Some(CliInput::Fetch) | Some(CliInput::QueryFetch) => {
-                if !s.starts_with("https://") && !s.starts_with("http://") {
-                    format!("https://{}", s)
-                } else {
-                    s.to_owned()
-                }
-            }
+            Some(CliInput::Fetch)
+            | Some(CliInput::QueryFetch)
+            | Some(CliInput::Api)
+            | Some(CliInput::Timeline) => with_scheme(s),
+            #[cfg(feature = "feeds")]
+            Some(CliInput::Rss) | Some(CliInput::Atom) => with_scheme(s),
+            #[cfg(feature = "misskey")]
+            Some(CliInput::Misskey) => with_scheme(s),
+            #[cfg(feature = "websub")]
+            Some(CliInput::Websub) => with_scheme(s),
             _ => s.to_owned(),
         });
 
@@ -115,14 +708,479 @@ impl Cli {
                     .as_ref()
                     .ok_or(anyhow!("option host is required when input=fetch"))?;
             }
+            #[cfg(feature = "feeds")]
+            Some(CliInput::Rss) | Some(CliInput::Atom) => {
+                self.host
+                    .as_ref()
+                    .ok_or(anyhow!("option host is required when input=rss or atom"))?;
+            }
+            Some(CliInput::Dir) => {
+                self.host
+                    .as_ref()
+                    .ok_or(anyhow!("option host is required when input=dir"))?;
+            }
+            #[cfg(feature = "archive")]
+            Some(CliInput::Archive) => {
+                self.host
+                    .as_ref()
+                    .ok_or(anyhow!("option host is required when input=archive"))?;
+            }
+            Some(CliInput::Timeline) => {
+                self.host
+                    .as_ref()
+                    .ok_or(anyhow!("option host is required when input=timeline"))?;
+                // Posts of the timeline are of many authors
+                #[cfg(feature = "telegram")]
+                {
+                    self.attribution = true;
+                }
+            }
             Some(CliInput::QueryFetch) => {
                 let err = || anyhow!("options host and acct are required when input=query-fetch");
                 self.host.as_ref().ok_or(err())?;
                 self.acct.as_ref().ok_or(err())?;
             }
+            #[cfg(feature = "websub")]
+            Some(CliInput::Websub) => {
+                let err = || {
+                    anyhow!("options host, websub-callback, and loop-interval are required when input=websub")
+                };
+                self.host.as_ref().ok_or(err())?;
+                self.websub_callback.as_ref().ok_or(err())?;
+                self.loop_interval.ok_or(err())?;
+            }
+            #[cfg(feature = "misskey")]
+            Some(CliInput::Misskey) => {
+                let err = || anyhow!("options host and acct are required when input=misskey");
+                self.host.as_ref().ok_or(err())?;
+                self.acct.as_ref().ok_or(err())?;
+            }
+            Some(CliInput::Api) => {
+                let err = || anyhow!("options host and acct are required when input=api");
+                self.host.as_ref().ok_or(err())?;
+                self.acct.as_ref().ok_or(err())?;
+            }
             _ => (),
         }
 
+        let mut outputs = Vec::with_capacity(self.output.len());
+        for output in self.output.drain(..) {
+            if !outputs.contains(&output) {
+                outputs.push(output);
+            }
+        }
+        self.output = outputs;
+        #[cfg(feature = "telegram")]
+        if self.output.contains(&CliOutput::TgSend) {
+            ensure!(
+                !self.tg_chan.is_empty(),
+                "option tg-chan is required when output=tg-send"
+            );
+            let chans = self.tg_chans()?;
+            for (i, (chan, _)) in chans.iter().enumerate() {
+                ensure!(
+                    chans[..i].iter().all(|(other, _)| other != chan),
+                    "channel {chan} is repeated in option tg-chan"
+                );
+            }
+            self.tg_topic_tags()?;
+            let templates = self.tg_templates()?;
+            for (chat, _) in templates.iter() {
+                ensure!(
+                    chans.iter().any(|(chan, _)| chan == chat),
+                    "chat {chat} of option tg-template is not in option tg-chan"
+                );
+            }
+        }
+        #[cfg(feature = "webhook")]
+        if self.output.contains(&CliOutput::Webhook) {
+            self.webhook_url.as_ref().ok_or(anyhow!(
+                "option webhook-url is required when output=webhook"
+            ))?;
+        }
+        #[cfg(feature = "ndjson")]
+        if self.output.contains(&CliOutput::Ndjson) {
+            self.ndjson_file
+                .as_ref()
+                .ok_or(anyhow!("option ndjson-file is required when output=ndjson"))?;
+        }
+        #[cfg(feature = "hugo")]
+        if self.output.contains(&CliOutput::Hugo) {
+            self.export_dir
+                .as_ref()
+                .ok_or(anyhow!("option export-dir is required when output=hugo"))?;
+        }
+        #[cfg(feature = "bsky")]
+        if self.output.contains(&CliOutput::Bsky) {
+            self.bsky_handle
+                .as_ref()
+                .ok_or(anyhow!("option bsky-handle is required when output=bsky"))?;
+        }
+        #[cfg(feature = "xmpp")]
+        if self.output.contains(&CliOutput::Xmpp) {
+            self.xmpp_jid
+                .as_ref()
+                .ok_or(anyhow!("option xmpp-jid is required when output=xmpp"))?;
+            self.xmpp_to
+                .as_ref()
+                .ok_or(anyhow!("option xmpp-to is required when output=xmpp"))?;
+        }
+        #[cfg(feature = "ntfy")]
+        if self.output.contains(&CliOutput::Ntfy) {
+            self.ntfy_topic
+                .as_ref()
+                .ok_or(anyhow!("option ntfy-topic is required when output=ntfy"))?;
+        }
+        #[cfg(feature = "mastodon")]
+        if self.output.contains(&CliOutput::Mastodon) {
+            let host = self.target_host.as_ref().ok_or(anyhow!(
+                "option target-host is required when output=mastodon"
+            ))?;
+            self.target_host = Some(with_scheme(host));
+        }
+
+        if self.once {
+            self.loop_interval = None;
+        }
+
+        if !self.source.is_empty() {
+            ensure!(
+                !self.disk_queue,
+                "option disk-queue is not supported with option source"
+            );
+            self.build_sources()?;
+        }
+
         Ok(())
     }
+
+    /// Options differing from the other CLI that can not be reloaded from the config file,
+    /// since the sources, the database, and the services of the process are set up with them at startup.
+    /// Returns the option names like `db-file`.
+    pub fn fixed_changes(&self, other: &Cli) -> Vec<String> {
+        let mut changed = Vec::new();
+        macro_rules! check {
+            ($($(#[$attr:meta])* $field:ident),* $(,)?) => {$(
+                $(#[$attr])*
+                if self.$field != other.$field {
+                    changed.push(stringify!($field).replace('_', "-"));
+                }
+            )*};
+        }
+        check!(
+            input,
+            host,
+            acct,
+            source,
+            db_file,
+            config,
+            once,
+            lock_file,
+            min_loop_interval,
+            max_loop_interval,
+            circuit_breaker,
+            breaker_cooldown,
+            max_breaker_cooldown,
+            min_id,
+            max_id,
+            no_follow_paging,
+            skip_unchanged,
+            prefetch_pages,
+            disk_queue,
+            stream_items,
+            slow_post_secs,
+            queue_size,
+            record_fixtures,
+            debug_http,
+            http_timeout,
+            http_proxy,
+            user_agent,
+            on_sent_webhook,
+            on_error_webhook,
+            heartbeat_url,
+            on_invalid,
+            #[cfg(feature = "websub")]
+            websub_callback,
+            #[cfg(feature = "websub")]
+            websub_addr,
+            #[cfg(feature = "websub")]
+            websub_lease,
+            metrics_addr,
+            #[cfg(feature = "otel")]
+            otlp_endpoint,
+        );
+        changed
+    }
+
+    /// Build the cleaned CLIs of the extra sources with their names
+    pub fn build_sources(&self) -> Result<Vec<(String, Cli)>> {
+        let mut sources: Vec<(String, Cli)> = Vec::new();
+        for source in self.source.iter() {
+            let mut cli = self.clone();
+            cli.source = vec![];
+            cli.input = None;
+            cli.host = None;
+            cli.acct = None;
+            cli.min_id = -1;
+            let mut name = None;
+            for pair in source.split(',') {
+                let (k, v) = pair
+                    .split_once('=')
+                    .ok_or(anyhow!("invalid source pair {pair}"))?;
+                let v = v.trim().to_owned();
+                match k.trim() {
+                    "name" => name = Some(v),
+                    "input" => {
+                        cli.input = Some(CliInput::from_str(&v, true).map_err(|e| anyhow!(e))?)
+                    }
+                    "host" => cli.host = Some(v),
+                    "acct" => cli.acct = Some(v),
+                    "min-id" => cli.min_id = v.parse()?,
+                    k => bail!("unknown source key {k}"),
+                }
+            }
+            let name = name
+                .filter(|name| !name.is_empty())
+                .ok_or(anyhow!("option name is required for source {source}"))?;
+            ensure!(
+                !sources.iter().any(|(other, _)| *other == name),
+                "duplicate source name {name}"
+            );
+            ensure!(
+                !matches!(cli.input, None | Some(CliInput::Stdin)),
+                "input of source {name} can not be stdin"
+            );
+            #[cfg(feature = "websub")]
+            ensure!(
+                cli.input != Some(CliInput::Websub),
+                "input of source {name} can not be websub"
+            );
+            cli.clean()
+                .map_err(|e| anyhow!("invalid source {name}: {e}"))?;
+            sources.push((name, cli));
+        }
+        Ok(sources)
+    }
+
+    /// Build the shared HTTP client from the HTTP options
+    pub fn build_http_client(&self) -> Result<Client> {
+        let mut builder = http::builder()
+            .user_agent(&self.user_agent)
+            .timeout(Duration::from_secs(self.http_timeout));
+        if let Some(proxy) = self.http_proxy.as_ref() {
+            builder = builder.proxy(Proxy::all(proxy)?);
+        }
+        Ok(builder.build()?)
+    }
+
+    /// Build the footer from the footer options
+    #[cfg(feature = "telegram")]
+    pub fn build_footer(&self) -> Result<Footer> {
+        Ok(Footer {
+            engagement: self.show_engagement,
+            timestamp: self.timestamp,
+            offset: parse_offset(&self.timezone)?,
+            format: format_description::parse_owned::<1>(&self.timestamp_format)
+                .map_err(|e| anyhow!("invalid timestamp format: {e}"))?,
+        })
+    }
+
+    /// Build the labels from the built-in ones of the locale and the overrides
+    #[cfg(feature = "telegram")]
+    pub fn build_labels(&self) -> Result<Labels> {
+        Labels::new(&self.locale, self.labels.as_deref())
+    }
+
+    /// Hashtags with the thread IDs of the topics to send the posts with them into
+    #[cfg(feature = "telegram")]
+    pub fn tg_topic_tags(&self) -> Result<Vec<(String, i32)>> {
+        self.tg_topic_tag
+            .iter()
+            .map(|s| {
+                let (tag, topic) = s
+                    .split_once('=')
+                    .ok_or(anyhow!("invalid topic mapping {s}, expected TAG=ID"))?;
+                let topic = topic
+                    .parse()
+                    .map_err(|e| anyhow!("invalid topic ID of hashtag {tag}: {e}"))?;
+                Ok((tag.to_owned(), topic))
+            })
+            .collect()
+    }
+
+    /// Templates of the texts by the chats, which are normalized like `--tg-chan`
+    #[cfg(feature = "telegram")]
+    pub fn tg_templates(&self) -> Result<Vec<(String, Template)>> {
+        self.tg_template
+            .iter()
+            .map(|s| {
+                let (chat, template) =
+                    split_chat(s).ok_or(anyhow!("invalid template {s}, expected CHAT=TEMPLATE"))?;
+                Ok((normalize_chat(chat)?, Template::parse(template)?))
+            })
+            .collect()
+    }
+
+    /// Telegram channels to send to, with the filters of the posts sent to them if any
+    pub fn tg_chans(&self) -> Result<Vec<(String, Option<FilterConfig>)>> {
+        self.tg_chan
+            .iter()
+            .map(|s| match split_chat(s) {
+                Some((chan, expr)) => {
+                    let config = serde_json::from_str(expr)
+                        .map_err(|e| anyhow!("invalid filter expression of channel {chan}: {e}"))?;
+                    Ok((chan.to_owned(), Some(config)))
+                }
+                None => Ok((s.to_owned(), None)),
+            })
+            .collect()
+    }
+
+    /// Build the filter combining all filter options
+    pub fn build_filter(&self) -> Result<Box<dyn Filter>> {
+        let mut filters: Vec<Box<dyn Filter>> = Vec::new();
+        if !self.include_tag.is_empty() {
+            filters.push(Box::new(Or(self
+                .include_tag
+                .iter()
+                .map(|name| Box::new(HasTag(name.to_owned())) as Box<dyn Filter>)
+                .collect())));
+        }
+        for name in self.exclude_tag.iter() {
+            filters.push(Box::new(Not(Box::new(HasTag(name.to_owned())))));
+        }
+        if self.no_replies {
+            let mut replies: Box<dyn Filter> = Box::new(IsReply);
+            if self.keep_threads {
+                replies = Box::new(And(vec![replies, Box::new(Not(Box::new(IsSelfReply)))]));
+            }
+            filters.push(Box::new(Not(replies)));
+        }
+        if self.min_visibility > Visibility::Direct {
+            filters.push(Box::new(MinVisibility(self.min_visibility)));
+        }
+        if let Some(expr) = self.filter.as_ref() {
+            let config: FilterConfig = serde_json::from_str(expr)
+                .map_err(|e| anyhow!("invalid filter expression: {e}"))?;
+            filters.push(config.build());
+        }
+        Ok(Box::new(And(filters)))
+    }
+}
+
+/// Split `CHAT=VALUE` at the first `=` after a valid chat.
+/// `=` in query strings of links like `https://t.me/myl7s?single=1` are kept in the chat.
+fn split_chat(s: &str) -> Option<(&str, &str)> {
+    s.match_indices('=')
+        .map(|(i, _)| (&s[..i], &s[i + 1..]))
+        .find(|(chat, _)| {
+            let in_query = chat
+                .split_once('?')
+                .is_some_and(|(_, query)| !query.rsplit('&').next().unwrap().contains('='));
+            !in_query && normalize_chat(chat).is_ok()
+        })
+}
+
+/// Normalize the Telegram chat to the `@username` or the numeric chat ID
+fn normalize_chat(chat: &str) -> Result<String> {
+    let chat = chat.trim();
+    if chat.parse::<i64>().is_ok() {
+        return Ok(chat.to_owned());
+    }
+    let link = chat
+        .strip_prefix("https://")
+        .or_else(|| chat.strip_prefix("http://"))
+        .unwrap_or(chat);
+    let path = ["t.me/", "telegram.me/", "telegram.dog/"]
+        .iter()
+        .find_map(|host| link.strip_prefix(host));
+    let name = match path {
+        Some(path) => {
+            ensure!(
+                !path.starts_with('+') && !path.starts_with("joinchat/"),
+                "invite link {chat} can not be resolved by bots, use the numeric chat ID instead"
+            );
+            // Links of msgs, previews, and query strings are allowed
+            let path = path.strip_prefix("s/").unwrap_or(path);
+            path.split(['/', '?']).next().unwrap_or_default()
+        }
+        None => chat.strip_prefix('@').unwrap_or(chat),
+    };
+    ensure!(
+        !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_'),
+        "invalid Telegram chat {chat}"
+    );
+    Ok(format!("@{name}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_output_options() {
+        let cases: &[(&str, &[&str], &str)] = &[
+            #[cfg(feature = "telegram")]
+            ("tg-send", &[], "tg-chan"),
+            #[cfg(feature = "webhook")]
+            ("webhook", &[], "webhook-url"),
+            #[cfg(feature = "ndjson")]
+            ("ndjson", &[], "ndjson-file"),
+            #[cfg(feature = "hugo")]
+            ("hugo", &[], "export-dir"),
+            #[cfg(feature = "mastodon")]
+            ("mastodon", &[], "target-host"),
+            #[cfg(feature = "bsky")]
+            ("bsky", &[], "bsky-handle"),
+            #[cfg(feature = "xmpp")]
+            ("xmpp", &[], "xmpp-jid"),
+            #[cfg(feature = "xmpp")]
+            ("xmpp", &["--xmpp-jid", "bot@xmpp.example"], "xmpp-to"),
+            #[cfg(feature = "ntfy")]
+            ("ntfy", &[], "ntfy-topic"),
+        ];
+        for (output, args, option) in cases {
+            let base = ["mastotg", "-f", ":memory:", "-o", output];
+            let mut cli = Cli::try_parse_from(base.iter().chain(*args)).unwrap();
+            assert_eq!(
+                cli.clean().unwrap_err().to_string(),
+                format!("option {option} is required when output={output}")
+            );
+        }
+    }
+
+    #[test]
+    fn test_subcommands() {
+        let cli = MainCli::try_parse_from(["mastotg", "-f", ":memory:", "-i", "stdin"]).unwrap();
+        assert!(cli.command.is_none());
+        assert_eq!(cli.cli.unwrap().db_file, ":memory:");
+
+        let cli = MainCli::try_parse_from(["mastotg", "render", "--fixtures", "fixtures"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Render(_))));
+        assert!(cli.cli.is_none());
+
+        let args = [
+            "mastotg",
+            "auth",
+            "-s",
+            "social.example.com",
+            "-f",
+            ":memory:",
+        ];
+        let cli = MainCli::try_parse_from(args).unwrap();
+        assert!(matches!(cli.command, Some(Command::Auth(_))));
+
+        // The main options are not taken by the subcommands
+        assert!(MainCli::try_parse_from(["mastotg", "-f", ":memory:", "render"]).is_err());
+        assert!(MainCli::try_parse_from(["mastotg"]).is_err());
+    }
+
+    #[cfg(feature = "telegram")]
+    #[test]
+    fn test_summary_chat() {
+        let args = ["mastotg", "-f", ":memory:", "--summary-chat", "t.me/myl7s"];
+        let mut cli = Cli::try_parse_from(args).unwrap();
+        cli.clean().unwrap();
+        assert_eq!(cli.summary_chat.as_deref(), Some("@myl7s"));
+    }
 }
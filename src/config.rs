@@ -0,0 +1,86 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Config file of extra CLI options, reloaded on SIGHUP.
+//! The filter, the rendering, the pacing, and the outputs with their destinations take effect on reloading.
+//! Other options like the input and the database need a restart, and reloads changing them are rejected.
+
+use std::future;
+
+use anyhow::Result;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+/// Split the config file into CLI args.
+/// Each non-empty line is an option like `--filter {...}` or `--timezone=+08:00`,
+/// and lines starting with `#` are comments.
+pub fn config_args(config: &str) -> Vec<String> {
+    let mut args = Vec::new();
+    for line in config.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((key, value)) if !key.contains('=') => {
+                args.push(key.to_owned());
+                args.push(value.trim().to_owned());
+            }
+            _ => args.push(line.to_owned()),
+        }
+    }
+    args
+}
+
+/// SIGHUP listener.
+/// Never fires if disabled or on non-Unix platforms.
+pub struct Hangup {
+    #[cfg(unix)]
+    signal: Option<Signal>,
+}
+
+impl Hangup {
+    #[cfg_attr(not(unix), allow(unused_variables))]
+    pub fn new(enabled: bool) -> Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            signal: match enabled {
+                true => Some(signal(SignalKind::hangup())?),
+                false => None,
+            },
+        })
+    }
+
+    pub async fn recv(&mut self) {
+        #[cfg(unix)]
+        if let Some(signal) = self.signal.as_mut() {
+            if signal.recv().await.is_some() {
+                return;
+            }
+        }
+        future::pending().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_args() {
+        let config = r#"
+# Only original posts
+--filter {"not": "reply"}
+--timezone=+08:00
+--no-replies
+"#;
+        assert_eq!(
+            config_args(config),
+            [
+                "--filter",
+                r#"{"not": "reply"}"#,
+                "--timezone=+08:00",
+                "--no-replies"
+            ]
+        );
+    }
+}
@@ -3,24 +3,94 @@
 
 //! Post consumers
 
-use std::collections::{HashMap, VecDeque};
+#[cfg(feature = "telegram")]
+mod bots;
+#[cfg(feature = "bsky")]
+mod bsky;
+#[cfg(feature = "discord")]
+mod discord;
+#[cfg(any(
+    feature = "webhook",
+    feature = "discord",
+    feature = "mastodon",
+    feature = "bsky",
+    feature = "xmpp",
+    feature = "ntfy"
+))]
+mod each;
+#[cfg(feature = "hugo")]
+mod hugo;
+#[cfg(feature = "mastodon")]
+mod mastodon;
+#[cfg(feature = "ndjson")]
+mod ndjson;
+#[cfg(feature = "ntfy")]
+mod ntfy;
+#[cfg(feature = "telegram")]
+mod tg;
+#[cfg(feature = "webhook")]
+mod webhook;
+#[cfg(feature = "xmpp")]
+mod xmpp;
 
-use anyhow::{anyhow, bail, ensure, Result};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::Arc;
+
+use anyhow::Result;
 use async_trait::async_trait;
-use quick_xml::events::Event;
-use quick_xml::name::QName;
-use quick_xml::reader::Reader;
-use reqwest::Url;
-use teloxide::prelude::*;
-use teloxide::types::{InputFile, InputMedia, InputMediaPhoto, MessageId, ParseMode};
-use teloxide::RequestError;
-use tokio::time;
-
-use crate::as2::{Create, Page, Post};
-use crate::db::DbConn;
+use clap::ValueEnum;
+use tokio::task::JoinSet;
+
+use crate::as2::{Activity, Create, Page};
+use crate::filter::Filter;
+
+#[cfg(feature = "telegram")]
+pub use bots::{extra_tokens, Bots, EXTRA_TOKENS_ENV};
+#[cfg(feature = "bsky")]
+pub use bsky::{BskyCon, BskyMirror, StrongRef, BSKY_PASSWORD_ENV};
+#[cfg(feature = "discord")]
+pub use discord::{DiscordCon, DISCORD_WEBHOOK_ENV};
+#[cfg(any(
+    feature = "webhook",
+    feature = "discord",
+    feature = "mastodon",
+    feature = "bsky",
+    feature = "xmpp",
+    feature = "ntfy"
+))]
+pub(crate) use each::{send_each, ConCtx};
+#[cfg(feature = "hugo")]
+pub use hugo::HugoCon;
+#[cfg(feature = "mastodon")]
+pub use mastodon::{MastodonCon, TARGET_ACCESS_TOKEN_ENV};
+#[cfg(feature = "ndjson")]
+pub use ndjson::NdjsonCon;
+#[cfg(feature = "ntfy")]
+pub use ntfy::{NtfyCon, NTFY_TOKEN_ENV};
+#[cfg(feature = "telegram")]
+pub use tg::{de_tg_msg_id, de_tg_msg_ids, ser_tg_msg_id, TgCon};
+#[cfg(feature = "webhook")]
+pub use webhook::{WebhookCon, SIGNATURE_HEADER, WEBHOOK_SECRET_ENV};
+#[cfg(feature = "xmpp")]
+pub use xmpp::{XmppCon, XMPP_PASSWORD_ENV};
 
 pub type IdMap = HashMap<String, Vec<u8>>;
 
+/// What to do when a post fails to be sent, e.g., being malformed or rejected by the destination.
+/// Retryable failures like flood control are always retried instead.
+/// Also used for items of fetched pages that fail validation.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ErrorPolicy {
+    /// Abort the round, so the post is retried in the next round
+    #[default]
+    Fail,
+    /// Log the failure and go on with the next post
+    Skip,
+    /// Like `skip`, and also keep the activity in the database to be inspected or replayed
+    DeadLetter,
+}
+
 /// Consumer trait
 #[async_trait]
 pub trait Con {
@@ -28,312 +98,280 @@ pub trait Con {
     /// Not send one-by-one directly in case collection-level cleaning is required.
     async fn send(&self, items: Vec<Create>) -> Result<IdMap>;
 
+    /// Act on posts removed from the source given their GUIDs.
+    /// Consumers that can not remove sent posts ignore the removals by default.
+    async fn remove(&self, ids: Vec<String>) -> Result<()> {
+        for id in ids {
+            tracing::info!(post = %id, "Ignored the removal of the post");
+        }
+        Ok(())
+    }
+
+    /// Release what is kept across sends, e.g., connections, once done with the page
+    async fn close(&self) {}
+
     /// Send a page of posts
     async fn send_page(&self, page: Page) -> Result<IdMap> {
-        self.send(page.ordered_items).await
+        let items = page
+            .ordered_items
+            .into_iter()
+            .filter_map(Activity::into_create)
+            .collect();
+        self.send(items).await
     }
 }
 
-pub struct TgCon {
-    bot: Bot,
-    tg_chan: String,
-    db: DbConn,
+/// Consumer delivering to multiple destinations concurrently.
+/// Destinations have independent rate limits, so a slow one does not hold up the others.
+/// All destinations are tried even if some fail, and then the first failure is returned.
+/// Each destination saves the posts it has sent to its own ID map and skips them when the page is retried,
+/// so a failure of one does not send the posts to the others again.
+/// The returned ID map is of the first destination.
+pub struct FanOut {
+    cons: Vec<Arc<dyn Con + Send + Sync>>,
 }
 
-impl TgCon {
-    pub fn new(tg_chan: String, db: DbConn) -> Self {
-        Self {
-            bot: Bot::from_env(),
-            tg_chan,
-            db,
-        }
+impl FanOut {
+    pub fn new(cons: Vec<Arc<dyn Con + Send + Sync>>) -> Self {
+        Self { cons }
     }
-}
 
-macro_rules! handle_reply {
-    ($send:ident, $db:expr, $id_map:ident, $post:ident) => {
-        if let Some(id) = $post.in_reply_to.as_ref() {
-            let mut tg_id_opt = $id_map.get(id).cloned();
-            if let None = tg_id_opt {
-                tg_id_opt = $db.query_id_map(id.to_owned()).await?;
-            }
-            if let Some(tg_id) = tg_id_opt {
-                let (_, msg_id) = de_tg_msg_id(&tg_id);
-                $send = $send
-                    .reply_to_message_id(MessageId(msg_id))
-                    .allow_sending_without_reply(true);
+    /// Run the op on all destinations concurrently.
+    /// Returns the results in the order of the destinations.
+    async fn join<T, F>(&self, op: impl Fn(Arc<dyn Con + Send + Sync>) -> F) -> Vec<Result<T>>
+    where
+        T: Send + 'static,
+        F: Future<Output = Result<T>> + Send + 'static,
+    {
+        let mut tasks = JoinSet::new();
+        for (i, con) in self.cons.iter().enumerate() {
+            let fut = op(con.clone());
+            tasks.spawn(async move { (i, fut.await) });
+        }
+        let mut results: Vec<_> = self.cons.iter().map(|_| None).collect();
+        while let Some(res) = tasks.join_next().await {
+            match res {
+                Ok((i, res)) => results[i] = Some(res),
+                Err(e) => std::panic::resume_unwind(e.into_panic()),
             }
         }
-    };
+        results.into_iter().map(Option::unwrap).collect()
+    }
 }
 
-impl TgCon {
-    async fn send_one(&self, id_map: &IdMap, mut act: Create) -> Result<Vec<u8>> {
-        act.object.content = clean_body(&act.object.content)?;
-        let post = &act.object;
-
-        if post.attachment.is_empty() {
-            let id = self.send_text(id_map, post).await?;
-            return Ok(id);
+/// Return the first failure after logging the others
+fn first_err<T>(results: Vec<Result<T>>) -> Result<Vec<T>> {
+    let mut oks = Vec::with_capacity(results.len());
+    let mut err = None;
+    for res in results {
+        match res {
+            Ok(v) => oks.push(v),
+            Err(e) if err.is_none() => err = Some(e),
+            Err(e) => tracing::error!("Failed to deliver to another destination: {e:#}"),
         }
-
-        if post.attachment.len() > 1 {
-            ensure!(
-                post.attachment
-                    .iter()
-                    .all(|att| att.media_type.starts_with("image/")),
-                "media type not all images for multiple media"
-            );
-            let id = self.send_multi_grouped_images(id_map, post).await?;
-            return Ok(id);
-        }
-
-        let att = &post.attachment[0];
-        let media_type = &att.media_type[..att
-            .media_type
-            .find('/')
-            .ok_or(anyhow!("invalid media type {}", att.media_type))?];
-        let id = match media_type {
-            "image" => self.send_image(id_map, post).await?,
-            "video" => self.send_video(id_map, post).await?,
-            "audio" => self.send_audio(id_map, post).await?,
-            _ => bail!("unknown media type {}", att.media_type),
-        };
-        Ok(id)
     }
-
-    async fn send_text(&self, id_map: &IdMap, post: &Post) -> Result<Vec<u8>> {
-        let mut send = self
-            .bot
-            .send_message(self.tg_chan.clone(), &post.content)
-            .parse_mode(ParseMode::Html);
-        handle_reply!(send, self.db, id_map, post);
-        let msg = send.await?;
-        Ok(ser_tg_msg_id(&msg))
+    match err {
+        Some(e) => Err(e),
+        None => Ok(oks),
     }
+}
 
-    async fn send_multi_grouped_images(&self, id_map: &IdMap, post: &Post) -> Result<Vec<u8>> {
-        let photos = post
-            .attachment
-            .iter()
-            .enumerate()
-            .map(|(i, att)| {
-                let mut photo = InputMediaPhoto::new(InputFile::url(Url::parse(&att.url)?));
-                if i == 0 {
-                    photo = photo
-                        .caption(post.content.clone())
-                        .parse_mode(ParseMode::Html);
-                }
-                if post.sensitive {
-                    photo = photo.spoiler();
-                }
-                Ok(InputMedia::Photo(photo))
+#[async_trait]
+impl Con for FanOut {
+    async fn send(&self, items: Vec<Create>) -> Result<IdMap> {
+        let results = self
+            .join(|con| {
+                let items = items.clone();
+                async move { con.send(items).await }
             })
-            .collect::<Result<Vec<_>>>()?;
-        let mut send = self.bot.send_media_group(self.tg_chan.clone(), photos);
-        handle_reply!(send, self.db, id_map, post);
-        let msgs = send.await?;
-        Ok(ser_tg_msg_id(&msgs[0]))
+            .await;
+        let id_maps = first_err(results)?;
+        Ok(id_maps.into_iter().next().unwrap_or_default())
     }
 
-    async fn send_image(&self, id_map: &IdMap, post: &Post) -> Result<Vec<u8>> {
-        let att = &post.attachment[0];
-        let mut send = self
-            .bot
-            .send_photo(self.tg_chan.clone(), InputFile::url(Url::parse(&att.url)?))
-            .caption(post.content.clone())
-            .parse_mode(ParseMode::Html);
-        handle_reply!(send, self.db, id_map, post);
-        send = send.has_spoiler(post.sensitive);
-        let msg = send.await?;
-        Ok(ser_tg_msg_id(&msg))
+    async fn remove(&self, ids: Vec<String>) -> Result<()> {
+        let results = self
+            .join(|con| {
+                let ids = ids.clone();
+                async move { con.remove(ids).await }
+            })
+            .await;
+        first_err(results)?;
+        Ok(())
     }
 
-    async fn send_video(&self, id_map: &IdMap, post: &Post) -> Result<Vec<u8>> {
-        let att = &post.attachment[0];
-        let mut send = self
-            .bot
-            .send_video(self.tg_chan.clone(), InputFile::url(Url::parse(&att.url)?))
-            .caption(post.content.clone())
-            .parse_mode(ParseMode::Html);
-        handle_reply!(send, self.db, id_map, post);
-        send = send.has_spoiler(post.sensitive);
-        let msg = send.await?;
-        Ok(ser_tg_msg_id(&msg))
+    async fn close(&self) {
+        self.join(|con| async move {
+            con.close().await;
+            Ok(())
+        })
+        .await;
     }
+}
 
-    async fn send_audio(&self, id_map: &IdMap, post: &Post) -> Result<Vec<u8>> {
-        let att = &post.attachment[0];
-        let mut send = self
-            .bot
-            .send_audio(self.tg_chan.clone(), InputFile::url(Url::parse(&att.url)?))
-            .caption(post.content.clone())
-            .parse_mode(ParseMode::Html);
-        handle_reply!(send, self.db, id_map, post);
-        let msg = send.await?;
-        Ok(ser_tg_msg_id(&msg))
+/// Consumer only sending the posts the filter allows, e.g., to split the posts into topic-specific channels.
+/// Removals are all passed on, since the consumer only removes the posts it has sent.
+pub struct Filtered<C> {
+    con: C,
+    filter: Box<dyn Filter>,
+}
+
+impl<C> Filtered<C> {
+    pub fn new(con: C, filter: Box<dyn Filter>) -> Self {
+        Self { con, filter }
     }
 }
 
 #[async_trait]
-impl Con for TgCon {
+impl<C: Con + Send + Sync> Con for Filtered<C> {
     async fn send(&self, items: Vec<Create>) -> Result<IdMap> {
-        let mut id_map = HashMap::new();
-        let mut queue: VecDeque<_> = items.into_iter().rev().collect();
-        while !queue.is_empty() {
-            let item = if let Some(x) = queue.pop_front() {
-                x
-            } else {
-                break;
-            };
-
-            match self.send_one(&id_map, item.clone()).await {
-                Err(e) => {
-                    if let Some(req_e) = e.downcast_ref::<RequestError>() {
-                        if let RequestError::RetryAfter(du) = req_e {
-                            log::warn!("Retry after {} seconds due to flood control", du.as_secs());
-                            queue.push_front(item);
-                            time::sleep(*du).await;
-                        }
-                    } else {
-                        bail!(e)
-                    }
-                }
-                Ok(tg_id) => {
-                    id_map.insert(item.object.id.clone(), tg_id);
-                }
-            }
-        }
-        Ok(id_map)
+        let items = items
+            .into_iter()
+            .filter(|item| self.filter.allow(&item.object).is_allowed())
+            .collect();
+        self.con.send(items).await
     }
-}
-
-/// Get the GUID from a Telegram msg
-pub fn ser_tg_msg_id(msg: &Message) -> Vec<u8> {
-    let chat_id = msg.chat.id.0;
-    let msg_id = msg.id.0 as i64;
-    [chat_id.to_be_bytes(), msg_id.to_be_bytes()].concat()
-}
 
-/// Extract the msg ID and chat ID from a Telegram msg GUID
-pub fn de_tg_msg_id(id: &[u8]) -> (i64, i32) {
-    assert_eq!(id.len(), 16);
-    let chat_id = i64::from_be_bytes(id[..8].try_into().unwrap());
-    let msg_id = i64::from_be_bytes(id[8..].try_into().unwrap()) as i32;
-    (chat_id, msg_id)
-}
+    async fn remove(&self, ids: Vec<String>) -> Result<()> {
+        self.con.remove(ids).await
+    }
 
-fn clean_body(body: &str) -> Result<String> {
-    let mut texts = String::new();
-    let mut reader = Reader::from_str(body);
-    // In a <a>. Texts inside ignored.
-    let mut in_link = false;
-    // In a <a> as a hashtag.
-    let mut in_hashtag = false;
-    loop {
-        #[allow(clippy::single_match)]
-        match reader.read_event()? {
-            Event::Eof => break,
-            Event::Start(elem) => match elem.name().as_ref() {
-                b"a" => {
-                    let mut is_hashtag = false;
-                    let mut href_opt = None;
-                    elem.html_attributes().try_for_each(|res| {
-                        let attr = res?;
-                        match attr.key {
-                            QName(b"class") => {
-                                is_hashtag = attr
-                                    .decode_and_unescape_value(&reader)?
-                                    .find("hashtag")
-                                    .is_some()
-                            }
-                            QName(b"href") => {
-                                href_opt = Some(attr.decode_and_unescape_value(&reader)?)
-                            }
-                            _ => (),
-                        }
-                        anyhow::Ok(())
-                    })?;
-                    if is_hashtag && !in_hashtag {
-                        in_hashtag = true;
-                    } else if !in_link {
-                        let href = href_opt.ok_or(anyhow!("no href in the <a> tag"))?;
-                        texts += &format!(r#"<a href="{}">{href}"#, href);
-                        in_link = true;
-                    } else {
-                        bail!("unknown <a> tag");
-                    }
-                }
-                _ => (),
-            },
-            Event::Text(elem) => {
-                if !in_link {
-                    texts += &elem.unescape()?;
-                }
-            }
-            Event::End(elem) => match elem.name().as_ref() {
-                b"a" => {
-                    if in_hashtag {
-                        in_hashtag = false;
-                    } else if in_link {
-                        texts += "</a>";
-                        in_link = false;
-                    } else {
-                        anyhow::bail!("unknown <a> tag");
-                    }
-                }
-                _ => (),
-            },
-            Event::Empty(elem) => match elem.name().as_ref() {
-                b"br" => texts += "\n",
-                _ => (),
-            },
-            _ => (),
-        }
+    async fn close(&self) {
+        self.con.close().await
     }
-    Ok(texts)
 }
 
 #[cfg(test)]
+// Helpers of the consumer tests are left unused without their features
+#[cfg_attr(
+    not(any(
+        feature = "webhook",
+        feature = "discord",
+        feature = "hugo",
+        feature = "ndjson",
+        feature = "mastodon",
+        feature = "bsky",
+        feature = "xmpp",
+        feature = "ntfy"
+    )),
+    allow(dead_code)
+)]
 mod tests {
+    use std::sync::Mutex;
+
+    use anyhow::anyhow;
+    use tokio::time::{self, Duration, Instant};
+
     use super::*;
+    use crate::as2::Post;
     use crate::check_de;
+    use crate::db::DbConn;
+    use crate::filter::Decision;
+    use crate::hooks::Hooks;
+    use crate::runner::init_db;
 
-    #[test]
-    fn test_body_text() -> Result<()> {
-        let post = check_de!(Post, "post_text");
-        let body = clean_body(&post.content)?;
-        let body_expected = concat!(
-            "哈哈哈哈，追番的乐趣原来就是这样啊੭ ᐕ)੭\n",
-            "虽然还是没有更多的信息，但是实在是名场面啊，很很的破防！\n",
-            "mygo 好！"
-        );
-        assert_eq!(body, body_expected);
-        Ok(())
+    /// In-memory database and hooks doing nothing for the consumers under test
+    pub(super) fn db_hooks() -> Result<(DbConn, Hooks)> {
+        let mut conn = rusqlite::Connection::open_in_memory()?;
+        init_db(&mut conn)?;
+        Ok((DbConn::new(conn), Hooks::new(None, None, None)))
+    }
+
+    /// Activity of the post on the mock server, shaped like the ones of Mastodon outboxes
+    pub(super) fn create(base: &str, id: i64, in_reply_to: Option<i64>) -> serde_json::Value {
+        let status = |id| format!("{base}/users/myl/statuses/{id}");
+        serde_json::json!({
+            "id": status(id) + "/activity",
+            "type": "Create",
+            "object": {
+                "id": status(id),
+                "type": "Note",
+                "inReplyTo": in_reply_to.map(status),
+                "published": "2023-08-03T16:09:19Z",
+                "url": format!("{base}/@myl/{id}"),
+                "to": ["https://www.w3.org/ns/activitystreams#Public"],
+                "content": format!("<p>Post {id}</p>"),
+                "attachment": [],
+                "tag": [],
+            },
+        })
+    }
+
+    /// Activities of the values
+    pub(super) fn creates(values: Vec<serde_json::Value>) -> Result<Vec<Create>> {
+        Ok(values
+            .into_iter()
+            .map(serde_json::from_value)
+            .collect::<Result<_, _>>()?)
     }
 
-    #[test]
-    fn test_body_link() -> Result<()> {
-        let post = check_de!(Post, "post_link");
-        let body = clean_body(&post.content)?;
-        let body_expected = concat!(
-            r#"已经 deploy <a href="https://github.com/myl7/mastotg">https://github.com/myl7/mastotg</a> 了，应该是 generally available 了"#,
-            "\n",
-            "功能的话还差个 reply 关系（这条作为样例试试再看怎么处理"
-        );
-        assert_eq!(body, body_expected);
+    /// Consumer that takes a while to send and records the sent IDs
+    struct SlowCon {
+        sent: Mutex<Vec<String>>,
+        fail: bool,
+    }
+
+    #[async_trait]
+    impl Con for SlowCon {
+        async fn send(&self, items: Vec<Create>) -> Result<IdMap> {
+            time::sleep(Duration::from_millis(200)).await;
+            if self.fail {
+                return Err(anyhow!("failed"));
+            }
+            let mut sent = self.sent.lock().unwrap();
+            let id_map = items
+                .into_iter()
+                .map(|item| {
+                    sent.push(item.object.id.clone());
+                    (item.object.id, vec![sent.len() as u8])
+                })
+                .collect();
+            Ok(id_map)
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fan_out() -> Result<()> {
+        // The clock only advances by the sleeps, which take 400ms if sent one by one
+        time::pause();
+        let create = check_de!(Create, "create");
+        let cons: Vec<_> = (0..3)
+            .map(|i| {
+                Arc::new(SlowCon {
+                    sent: Mutex::new(vec![]),
+                    fail: i == 2,
+                })
+            })
+            .collect();
+        let fan_out = FanOut::new(cons[..2].iter().map(|con| con.clone() as _).collect());
+        let start = Instant::now();
+        let id_map = fan_out.send(vec![create.clone()]).await?;
+        assert!(start.elapsed() < Duration::from_millis(400));
+        assert_eq!(id_map.len(), 1);
+        assert!(cons[..2]
+            .iter()
+            .all(|con| con.sent.lock().unwrap().len() == 1));
+
+        let fan_out = FanOut::new(cons.iter().map(|con| con.clone() as _).collect());
+        assert!(fan_out.send(vec![create]).await.is_err());
+        assert!(cons[..2]
+            .iter()
+            .all(|con| con.sent.lock().unwrap().len() == 2));
         Ok(())
     }
 
-    #[test]
-    fn test_body_tag() -> Result<()> {
-        let post = check_de!(Post, "post_tag");
-        let body = clean_body(&post.content)?;
-        let body_expected = concat!(
-            "另：信息已经不重要了，具体的前因后果就等 ave mujica 里讲吧，或许可以 mygo 结尾留个引子？\n",
-            "#mygo"
-        );
-        assert_eq!(body, body_expected);
+    #[tokio::test]
+    async fn test_filtered() -> Result<()> {
+        let create = check_de!(Create, "create");
+        let con = SlowCon {
+            sent: Mutex::new(vec![]),
+            fail: false,
+        };
+        let filtered = Filtered::new(con, Box::new(|_: &Post| Decision::Deny));
+        assert!(filtered.send(vec![create.clone()]).await?.is_empty());
+        let filtered = Filtered::new(filtered.con, Box::new(|_: &Post| Decision::Allow));
+        assert_eq!(filtered.send(vec![create]).await?.len(), 1);
+        assert_eq!(filtered.con.sent.lock().unwrap().len(), 1);
         Ok(())
     }
 }
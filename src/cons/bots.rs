@@ -0,0 +1,136 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bots rotated for the destinations.
+//! Posts are sent by the bots in turn, and a bot blocked by flood control is skipped until its retry time,
+//! so very high-volume mirrors are not limited by the sustainable send rate of a single bot.
+//! All bots should be admins of the channel with the rights to post, edit, and delete messages.
+//! The bots of the env are shared by the process, so the flood control state outlives the consumers of a page.
+
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use teloxide::Bot;
+
+/// Env var of the tokens of the bots besides `TELOXIDE_TOKEN`, separated by commas
+pub const EXTRA_TOKENS_ENV: &str = "TELOXIDE_EXTRA_TOKENS";
+
+pub struct Bots {
+    bots: Vec<Bot>,
+    /// Index of the bot sending the current post
+    current: AtomicUsize,
+    /// Times until which the bots are blocked by flood control
+    blocked: Mutex<Vec<Option<Instant>>>,
+}
+
+impl Bots {
+    /// The bot of `TELOXIDE_TOKEN` followed by the ones of [`EXTRA_TOKENS_ENV`].
+    /// The others share the client and the API URL of the first.
+    pub fn from_env() -> Self {
+        let primary = Bot::from_env();
+        let extra: Vec<_> = extra_tokens()
+            .into_iter()
+            .map(|token| {
+                Bot::with_client(token, primary.client().clone()).set_api_url(primary.api_url())
+            })
+            .collect();
+        Self::new([vec![primary], extra].concat())
+    }
+
+    /// The bots of [`Bots::from_env`] built once per process
+    pub fn shared() -> Arc<Self> {
+        static BOTS: OnceLock<Arc<Bots>> = OnceLock::new();
+        BOTS.get_or_init(|| Arc::new(Self::from_env())).clone()
+    }
+
+    pub(super) fn new(bots: Vec<Bot>) -> Self {
+        Self {
+            current: AtomicUsize::new(0),
+            blocked: Mutex::new(vec![None; bots.len()]),
+            bots,
+        }
+    }
+
+    /// All of the bots, e.g., to be checked at startup
+    pub fn all(&self) -> &[Bot] {
+        &self.bots
+    }
+
+    /// Bot sending the current post
+    pub fn current(&self) -> &Bot {
+        &self.bots[self.current.load(Ordering::Relaxed)]
+    }
+
+    /// Move to the next bot not blocked for the next post.
+    /// If all bots are blocked, move to the one unblocked first and return the time to wait for it.
+    pub fn rotate(&self) -> Option<Duration> {
+        let blocked = self.blocked.lock().unwrap();
+        let now = Instant::now();
+        let start = self.current.load(Ordering::Relaxed);
+        let len = self.bots.len();
+        let free = (1..=len)
+            .map(|i| (start + i) % len)
+            .find(|&i| blocked[i].is_none_or(|until| until <= now));
+        if let Some(i) = free {
+            self.current.store(i, Ordering::Relaxed);
+            return None;
+        }
+        let (i, until) = blocked
+            .iter()
+            .enumerate()
+            .filter_map(|(i, until)| until.map(|until| (i, until)))
+            .min_by_key(|(_, until)| *until)?;
+        self.current.store(i, Ordering::Relaxed);
+        Some(until - now)
+    }
+
+    /// Block the current bot by flood control for the duration
+    pub fn block(&self, duration: Duration) {
+        let mut blocked = self.blocked.lock().unwrap();
+        blocked[self.current.load(Ordering::Relaxed)] = Some(Instant::now() + duration);
+    }
+}
+
+/// Tokens of [`EXTRA_TOKENS_ENV`], e.g., to be redacted
+pub fn extra_tokens() -> Vec<String> {
+    env::var(EXTRA_TOKENS_ENV)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rotate() {
+        let bots = Bots::new(vec![Bot::new("a"), Bot::new("b"), Bot::new("c")]);
+        let token = |bots: &Bots| bots.current().token().to_owned();
+        assert_eq!(token(&bots), "a");
+        assert_eq!(bots.rotate(), None);
+        assert_eq!(token(&bots), "b");
+
+        bots.block(Duration::from_secs(60));
+        assert_eq!(bots.rotate(), None);
+        assert_eq!(token(&bots), "c");
+        assert_eq!(bots.rotate(), None);
+        assert_eq!(token(&bots), "a");
+        assert_eq!(bots.rotate(), None);
+        // Skips the blocked
+        assert_eq!(token(&bots), "c");
+
+        bots.block(Duration::from_secs(30));
+        bots.rotate();
+        bots.block(Duration::from_secs(90));
+        // All blocked, so waits for the one unblocked first
+        let wait = bots.rotate().unwrap();
+        assert!(wait > Duration::from_secs(20) && wait <= Duration::from_secs(30));
+        assert_eq!(token(&bots), "c");
+    }
+}
@@ -0,0 +1,661 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Bluesky consumer.
+//! Posts are published to the account via `com.atproto.repo.createRecord` of its PDS.
+//!
+//! The text is converted into plain text like for Mastodon, with the links marked as facets.
+//! Texts over the length limit are split into a thread, and images are uploaded as blobs to the first post.
+//! The record refs are saved in the ID map, so replies to mirrored posts are threaded under their mirrors,
+//! and removals remove all posts of the thread.
+
+use std::collections::HashMap;
+use std::env;
+use std::sync::{LazyLock, OnceLock};
+
+use ::time::format_description::well_known::Rfc3339;
+use ::time::OffsetDateTime;
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use regex::Regex;
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use tokio::sync::Mutex;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use super::{send_each, Con, ConCtx, ErrorPolicy, IdMap};
+use crate::as2::{Create, Post};
+use crate::db::DbConn;
+use crate::hooks::Hooks;
+use crate::http;
+use crate::labels::Labels;
+use crate::media::local_path;
+use crate::metrics::{E2eTimer, METRICS};
+use crate::redact;
+use crate::render::{render_message, to_plain, Footer};
+use crate::utils::{
+    cancellable, check_res, rate_limit_wait, Cancelled, HttpStatusError, RATE_LIMIT_RETRIES,
+};
+
+/// Env var of the app password of the account
+pub const BSKY_PASSWORD_ENV: &str = "BSKY_APP_PASSWORD";
+
+/// Max length of Bluesky post texts. Unit: Graphemes.
+/// Texts are measured in chars, which are never fewer than the graphemes.
+const TEXT_LIMIT: usize = 300;
+
+/// Max number of images of a Bluesky post
+const IMAGE_LIMIT: usize = 4;
+
+/// Max size of blobs of images. Unit: Bytes.
+const BLOB_LIMIT: usize = 1_000_000;
+
+const POST_COLLECTION: &str = "app.bsky.feed.post";
+
+/// Sessions by the PDS and the identifier.
+/// Consumers are created for every page, while sessions are rate limited more strictly than posts.
+static SESSIONS: OnceLock<Mutex<HashMap<(String, String), Session>>> = OnceLock::new();
+
+#[derive(Clone, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Session {
+    access_jwt: String,
+    did: String,
+}
+
+/// Ref of a record, which replies point to
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StrongRef {
+    pub uri: String,
+    pub cid: String,
+}
+
+/// Mirror of a post saved in the ID map
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BskyMirror {
+    /// Root of the thread the mirror is in
+    pub root: StrongRef,
+    /// Last post of the mirror, which replies continue from
+    pub parent: StrongRef,
+    /// All posts of the mirror split from the text
+    pub uris: Vec<String>,
+}
+
+pub struct BskyCon {
+    pds: String,
+    identifier: String,
+    password: String,
+    db: DbConn,
+    hooks: Hooks,
+    timer: Option<E2eTimer>,
+    footer: Footer,
+    labels: Labels,
+    attribution: bool,
+    error_policy: ErrorPolicy,
+    cancel: CancellationToken,
+}
+
+impl BskyCon {
+    /// Log in to the PDS, e.g., `https://bsky.social`, with the handle or DID and the app password
+    pub fn new(
+        pds: &str,
+        identifier: String,
+        password: String,
+        db: DbConn,
+        hooks: Hooks,
+        cancel: CancellationToken,
+    ) -> Self {
+        Self {
+            pds: pds.trim_end_matches('/').to_owned(),
+            identifier,
+            password,
+            db,
+            hooks,
+            timer: None,
+            footer: Footer::default(),
+            labels: Labels::default(),
+            attribution: false,
+            error_policy: ErrorPolicy::default(),
+            cancel,
+        }
+    }
+
+    /// Take the app password from `BSKY_APP_PASSWORD`
+    pub fn from_env(
+        pds: &str,
+        identifier: String,
+        db: DbConn,
+        hooks: Hooks,
+        cancel: CancellationToken,
+    ) -> Result<Self> {
+        let password =
+            env::var(BSKY_PASSWORD_ENV).map_err(|_| anyhow!("{BSKY_PASSWORD_ENV} is not set"))?;
+        Ok(Self::new(pds, identifier, password, db, hooks, cancel))
+    }
+
+    /// Observe the end-to-end latency of the sent posts
+    pub fn with_timer(mut self, timer: E2eTimer) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// Append the footer to the posts
+    pub fn with_footer(mut self, footer: Footer) -> Self {
+        self.footer = footer;
+        self
+    }
+
+    /// Generated texts in the language
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Prepend the author to the posts
+    pub fn with_attribution(mut self, attribution: bool) -> Self {
+        self.attribution = attribution;
+        self
+    }
+
+    /// What to do when a post fails to be sent
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// The cached session, or a new one if none
+    async fn session(&self) -> Result<Session> {
+        let key = (self.pds.clone(), self.identifier.clone());
+        let mut sessions = SESSIONS.get_or_init(Default::default).lock().await;
+        if let Some(session) = sessions.get(&key) {
+            return Ok(session.clone());
+        }
+        let url = format!("{}/xrpc/com.atproto.server.createSession", self.pds);
+        let body = json!({ "identifier": self.identifier, "password": self.password });
+        let res = http::client().post(url).json(&body).send().await?;
+        let session: Session = check_res(res).await?.json().await?;
+        redact::register_secret(session.access_jwt.clone());
+        tracing::info!("Created the Bluesky session of {}", self.identifier);
+        sessions.insert(key, session.clone());
+        Ok(session)
+    }
+
+    async fn drop_session(&self) {
+        let key = (self.pds.clone(), self.identifier.clone());
+        SESSIONS
+            .get_or_init(Default::default)
+            .lock()
+            .await
+            .remove(&key);
+    }
+
+    /// Call the XRPC method with the session.
+    /// Expired sessions are created again, and rate limited calls are retried after the reset up to the max retries.
+    async fn call(
+        &self,
+        method: &str,
+        build: impl Fn(RequestBuilder, &Session) -> RequestBuilder,
+    ) -> Result<Response> {
+        let url = format!("{}/xrpc/{method}", self.pds);
+        let mut renewed = false;
+        let mut retries = 0;
+        loop {
+            let session = self.session().await?;
+            let req = build(http::client().post(&url), &session).bearer_auth(&session.access_jwt);
+            let res = req.send().await?;
+            if res.status() == StatusCode::TOO_MANY_REQUESTS && retries < RATE_LIMIT_RETRIES {
+                retries += 1;
+                let reset = res
+                    .headers()
+                    .get("ratelimit-reset")
+                    .and_then(|v| v.to_str().ok()?.parse::<i64>().ok())
+                    .and_then(|reset| OffsetDateTime::from_unix_timestamp(reset).ok());
+                let wait = rate_limit_wait(reset);
+                METRICS.inc_retried();
+                tracing::warn!(
+                    "Retry after {} seconds due to the Bluesky rate limit",
+                    wait.as_secs()
+                );
+                time::sleep(wait).await;
+                continue;
+            }
+            match check_res(res).await {
+                Err(e) if !renewed && expired(&e) => {
+                    renewed = true;
+                    self.drop_session().await;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    /// Upload the image as a blob.
+    /// Returns the blob object to embed.
+    async fn upload(&self, url: &str, media_type: &str) -> Result<Value> {
+        let body = match local_path(url) {
+            Some(path) => tokio::fs::read(path).await?,
+            None => {
+                let res = check_res(http::client().get(url).send().await?).await?;
+                res.bytes().await?.to_vec()
+            }
+        };
+        if body.len() > BLOB_LIMIT {
+            bail!("over the blob size limit of Bluesky");
+        }
+        let res = self
+            .call("com.atproto.repo.uploadBlob", |req, _| {
+                req.header(reqwest::header::CONTENT_TYPE, media_type)
+                    .body(body.clone())
+            })
+            .await?;
+        let mut res: Value = res.json().await?;
+        Ok(res["blob"].take())
+    }
+
+    /// Create the post record with the text, the reply refs, and the embed
+    async fn create_post(
+        &self,
+        text: &str,
+        reply: Option<(&StrongRef, &StrongRef)>,
+        embed: Option<&Value>,
+    ) -> Result<StrongRef> {
+        let mut record = json!({
+            "$type": POST_COLLECTION,
+            "text": text,
+            "createdAt": OffsetDateTime::now_utc().format(&Rfc3339)?,
+        });
+        let facets = link_facets(text);
+        if !facets.is_empty() {
+            record["facets"] = facets.into();
+        }
+        if let Some((root, parent)) = reply {
+            record["reply"] = json!({ "root": root, "parent": parent });
+        }
+        if let Some(embed) = embed {
+            record["embed"] = embed.clone();
+        }
+        let res = self
+            .call("com.atproto.repo.createRecord", |req, session| {
+                req.json(&json!({
+                    "repo": session.did,
+                    "collection": POST_COLLECTION,
+                    "record": record,
+                }))
+            })
+            .await?;
+        Ok(res.json().await?)
+    }
+
+    /// Delete the post record of the URI
+    async fn delete_post(&self, uri: &str) -> Result<()> {
+        let rkey = uri.rsplit('/').next().unwrap_or_default();
+        self.call("com.atproto.repo.deleteRecord", |req, session| {
+            req.json(&json!({
+                "repo": session.did,
+                "collection": POST_COLLECTION,
+                "rkey": rkey,
+            }))
+        })
+        .await?;
+        Ok(())
+    }
+
+    /// Publish the post as a thread of the split text.
+    /// Returns the mirror.
+    async fn send_one(&self, post: &Post) -> Result<BskyMirror> {
+        let text = render_message(
+            post,
+            self.attribution,
+            &self.footer,
+            &self.labels,
+            OffsetDateTime::now_utc(),
+        )?;
+        let mut text = to_plain(&text);
+        let mut images = Vec::new();
+        let mut unavailable = Vec::new();
+        for att in post.attachment.iter() {
+            let is_image = att.media_type.starts_with("image/") || att.r#type == "Image";
+            if !is_image || images.len() >= IMAGE_LIMIT {
+                unavailable.push(&att.url);
+                continue;
+            }
+            match self.upload(&att.url, &att.media_type).await {
+                Ok(blob) => images.push(json!({
+                    "alt": att.name.as_deref().unwrap_or_default(),
+                    "image": blob,
+                })),
+                Err(e) => {
+                    tracing::warn!("Linking the media {} instead: {e:#}", att.url);
+                    unavailable.push(&att.url);
+                }
+            }
+        }
+        for url in unavailable {
+            text = format!("{text}\n{}: {url}", self.labels.media_unavailable);
+        }
+        let embed = (!images.is_empty())
+            .then(|| json!({ "$type": "app.bsky.embed.images", "images": images }));
+
+        let mut thread = match post.in_reply_to.as_ref() {
+            Some(id) => self
+                .db
+                .query_id_map(id.clone())
+                .await?
+                .and_then(|mirror| serde_json::from_slice::<BskyMirror>(&mirror).ok())
+                .map(|mirror| (mirror.root, mirror.parent)),
+            None => None,
+        };
+        let mut uris: Vec<String> = Vec::new();
+        for (i, part) in split_text(&text, TEXT_LIMIT).iter().enumerate() {
+            let reply = thread.as_ref().map(|(root, parent)| (root, parent));
+            let embed = embed.as_ref().filter(|_| i == 0);
+            let created = match self.create_post(part, reply, embed).await {
+                Ok(created) => created,
+                Err(e) => {
+                    // The whole post is sent again when retried, so the created parts would be duplicated
+                    for uri in uris.iter() {
+                        if let Err(e) = self.delete_post(uri).await {
+                            tracing::warn!(
+                                "Failed to delete the part {uri} of the failed post: {e:#}"
+                            );
+                        }
+                    }
+                    return Err(e);
+                }
+            };
+            uris.push(created.uri.clone());
+            thread = Some(match thread {
+                Some((root, _)) => (root, created),
+                None => (created.clone(), created),
+            });
+        }
+        let (root, parent) = thread.ok_or(anyhow!("empty post"))?;
+        Ok(BskyMirror { root, parent, uris })
+    }
+}
+
+#[async_trait]
+impl Con for BskyCon {
+    async fn send(&self, items: Vec<Create>) -> Result<IdMap> {
+        let ctx = ConCtx {
+            db: &self.db,
+            hooks: &self.hooks,
+            timer: self.timer.as_ref(),
+            dest: &self.identifier,
+            id_map: true,
+            error_policy: self.error_policy,
+            cancel: &self.cancel,
+        };
+        send_each(&ctx, items, |item| async move {
+            let mirror = self.send_one(&item.object).await?;
+            Ok(serde_json::to_vec(&mirror)?)
+        })
+        .await
+    }
+
+    async fn remove(&self, ids: Vec<String>) -> Result<()> {
+        for id in ids {
+            let mirror = self.db.query_id_map(id.clone()).await?;
+            let Some(mirror) = mirror.and_then(|m| serde_json::from_slice::<BskyMirror>(&m).ok())
+            else {
+                tracing::info!(post = %id, "Ignored the removal of the post not mirrored");
+                continue;
+            };
+            for uri in mirror.uris.iter() {
+                match cancellable(&self.cancel, self.delete_post(uri)).await {
+                    Ok(_) => (),
+                    Err(e) if e.is::<Cancelled>() => return Err(e),
+                    Err(e) => tracing::warn!(post = %id, "Failed to remove the mirror: {e:#}"),
+                }
+            }
+            tracing::info!(post = %id, "Removed the mirror of the post");
+        }
+        Ok(())
+    }
+}
+
+/// Whether the call failed by the expired session
+fn expired(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<HttpStatusError>()
+        .is_some_and(|e| e.body.contains("ExpiredToken"))
+}
+
+/// Split the text into parts within the limit, preferably at line breaks or spaces
+pub fn split_text(text: &str, limit: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut rest = text.trim();
+    while rest.chars().count() > limit {
+        let end = rest
+            .char_indices()
+            .nth(limit)
+            .map_or(rest.len(), |(i, _)| i);
+        let head = &rest[..end];
+        // Cut at the last break in the later half, so parts are not too short
+        let half = head.char_indices().nth(limit / 2).map_or(0, |(i, _)| i);
+        let cut = head
+            .rfind('\n')
+            .filter(|i| *i >= half)
+            .or_else(|| head.rfind(' ').filter(|i| *i >= half))
+            .unwrap_or(end);
+        parts.push(rest[..cut].trim_end().to_owned());
+        rest = rest[cut..].trim_start();
+    }
+    if !rest.is_empty() || parts.is_empty() {
+        parts.push(rest.to_owned());
+    }
+    parts
+}
+
+/// Facets marking the URLs in the text as links, by their byte ranges
+fn link_facets(text: &str) -> Vec<Value> {
+    static RE_URL: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"https?://[^\s<>]+").unwrap());
+    RE_URL
+        .find_iter(text)
+        .map(|m| {
+            let uri = m.as_str().trim_end_matches(['.', ',', ')', '!', '?']);
+            json!({
+                "index": { "byteStart": m.start(), "byteEnd": m.start() + uri.len() },
+                "features": [{ "$type": "app.bsky.richtext.facet#link", "uri": uri }],
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{body_partial_json, body_string_contains, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::cons::tests::{create, creates, db_hooks};
+
+    #[test]
+    fn test_split_text() {
+        let text = format!("{}\n{}end", "a".repeat(200), "b ".repeat(100));
+        let parts = split_text(&text, 300);
+        assert_eq!(parts, ["a".repeat(200), format!("{}end", "b ".repeat(100))]);
+        assert_eq!(split_text("", 300), [""]);
+    }
+
+    #[test]
+    fn test_link_facets() {
+        let text = "é see https://a.example/x.";
+        let facets = link_facets(text);
+        assert_eq!(facets[0]["index"]["byteStart"], 7);
+        assert_eq!(facets[0]["index"]["byteEnd"], text.len() - 1);
+        assert_eq!(facets[0]["features"][0]["uri"], "https://a.example/x");
+    }
+
+    #[tokio::test]
+    async fn test_send() -> Result<()> {
+        let server = MockServer::start().await;
+        let base = server.uri();
+        let at = |rkey: &str| format!("at://did:plc:me/app.bsky.feed.post/{rkey}");
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.server.createSession"))
+            .and(body_partial_json(
+                json!({"identifier": "me.bsky.social", "password": "app-pw"}),
+            ))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(
+                    json!({"accessJwt": "jwt", "refreshJwt": "r", "did": "did:plc:me"}),
+                ),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/media/a.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"png".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.uploadBlob"))
+            .and(header("Authorization", "Bearer jwt"))
+            .and(header("Content-Type", "image/png"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(
+                json!({"blob": {"$type": "blob", "ref": {"$link": "bafy"}, "mimeType": "image/png", "size": 3}}),
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // The long post is split into a thread, with the images on the first post
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.createRecord"))
+            .and(body_partial_json(json!({
+                "repo": "did:plc:me",
+                "record": {"embed": {"$type": "app.bsky.embed.images"}},
+            })))
+            .and(body_string_contains(r#""alt":"A cat""#))
+            .and(body_string_contains(r#""$link":"bafy""#))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"uri": at("1"), "cid": "c1"})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.createRecord"))
+            .and(body_partial_json(json!({"record": {
+                "reply": {"root": {"uri": at("1"), "cid": "c1"}, "parent": {"uri": at("1"), "cid": "c1"}},
+            }})))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"uri": at("2"), "cid": "c2"})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        // Replies continue from the last post of the thread
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.createRecord"))
+            .and(body_partial_json(json!({"record": {
+                "text": "Post 200",
+                "reply": {"root": {"uri": at("1"), "cid": "c1"}, "parent": {"uri": at("2"), "cid": "c2"}},
+            }})))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"uri": at("3"), "cid": "c3"})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        for rkey in ["1", "2"] {
+            Mock::given(method("POST"))
+                .and(path("/xrpc/com.atproto.repo.deleteRecord"))
+                .and(body_partial_json(
+                    json!({"repo": "did:plc:me", "rkey": rkey}),
+                ))
+                .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+
+        let mut root = create(&base, 100, None);
+        root["object"]["content"] = json!(format!("<p>{}</p><p>end</p>", "a ".repeat(200)));
+        root["object"]["attachment"] = json!([{"type": "Document", "mediaType": "image/png", "url": format!("{base}/media/a.png"), "name": "A cat"}]);
+        let items = creates(vec![create(&base, 200, Some(100)), root])?;
+        let (db, hooks) = db_hooks()?;
+        let con = BskyCon::new(
+            &base,
+            "me.bsky.social".to_owned(),
+            "app-pw".to_owned(),
+            db.clone(),
+            hooks,
+            CancellationToken::new(),
+        )
+        .with_error_policy(ErrorPolicy::Fail);
+        con.send(items.clone()).await?;
+        let status = |id| format!("{base}/users/myl/statuses/{id}");
+        let mirror: BskyMirror =
+            serde_json::from_slice(&db.query_id_map(status(100)).await?.unwrap())?;
+        assert_eq!(mirror.uris, [at("1"), at("2")]);
+        // Posts sent before a retried page failed are not sent again
+        let id_map = con.send(items).await?;
+        assert_eq!(id_map.len(), 2);
+        con.remove(vec![status(100)]).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_failed_part() -> Result<()> {
+        let server = MockServer::start().await;
+        let base = server.uri();
+        let at = |rkey: &str| format!("at://did:plc:me/app.bsky.feed.post/{rkey}");
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.server.createSession"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(
+                    json!({"accessJwt": "jwt", "refreshJwt": "r", "did": "did:plc:me"}),
+                ),
+            )
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.createRecord"))
+            .and(body_partial_json(json!({"record": {
+                "reply": {"root": {"uri": at("1"), "cid": "c1"}},
+            }})))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({"error": "Invalid"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.createRecord"))
+            .respond_with(
+                ResponseTemplate::new(200).set_body_json(json!({"uri": at("1"), "cid": "c1"})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        // The created first part is deleted, so the retry does not duplicate it
+        Mock::given(method("POST"))
+            .and(path("/xrpc/com.atproto.repo.deleteRecord"))
+            .and(body_partial_json(
+                json!({"repo": "did:plc:me", "rkey": "1"}),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut root = create(&base, 100, None);
+        root["object"]["content"] = json!(format!("<p>{}</p><p>end</p>", "a ".repeat(200)));
+        let (db, hooks) = db_hooks()?;
+        let con = BskyCon::new(
+            &base,
+            "me.bsky.social".to_owned(),
+            "app-pw".to_owned(),
+            db.clone(),
+            hooks,
+            CancellationToken::new(),
+        )
+        .with_error_policy(ErrorPolicy::Fail);
+        assert!(con.send(creates(vec![root])?).await.is_err());
+        let status = format!("{base}/users/myl/statuses/100");
+        assert!(db.query_id_map(status).await?.is_none());
+        Ok(())
+    }
+}
@@ -0,0 +1,386 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Discord consumer.
+//! Posts are sent to a Discord webhook, each as a message with the text in an embed and the media as files.
+//! The text is rendered like for Telegram and then converted from the HTML into Discord markdown.
+//! Msg IDs are saved to the ID map of their own, so removals delete the msgs and retries skip the sent.
+
+use std::env;
+use std::time::Duration;
+
+use ::time::OffsetDateTime;
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use reqwest::multipart::{Form, Part};
+use reqwest::{StatusCode, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use super::{send_each, Con, ConCtx, ErrorPolicy, IdMap};
+use crate::as2::{Create, Post};
+use crate::db::DbConn;
+use crate::hooks::Hooks;
+use crate::http;
+use crate::labels::Labels;
+use crate::media::{file_name, local_path};
+use crate::metrics::{E2eTimer, METRICS};
+use crate::render::{render_message, to_markdown, Footer};
+use crate::utils::{cancellable, check_res, Cancelled, RATE_LIMIT_RETRIES};
+
+/// Env var of the webhook URL, which is a secret
+pub const DISCORD_WEBHOOK_ENV: &str = "DISCORD_WEBHOOK_URL";
+
+/// Max length of Discord embed descriptions. Unit: Chars.
+const DESCRIPTION_LIMIT: usize = 4096;
+
+/// Max number of files in a Discord message
+const FILE_COUNT_LIMIT: usize = 10;
+
+/// Max size of files uploaded via webhooks. Unit: Bytes.
+const FILE_SIZE_LIMIT: usize = 10 * 1024 * 1024;
+
+/// Destination name in audit records and logs, instead of the secret webhook URL.
+/// Also the channel of the ID map, apart from the Telegram ones.
+const DEST: &str = "discord";
+
+pub struct DiscordCon {
+    webhook: String,
+    db: DbConn,
+    hooks: Hooks,
+    timer: Option<E2eTimer>,
+    footer: Footer,
+    labels: Labels,
+    attribution: bool,
+    error_policy: ErrorPolicy,
+    cancel: CancellationToken,
+}
+
+impl DiscordCon {
+    pub fn new(webhook: String, db: DbConn, hooks: Hooks, cancel: CancellationToken) -> Self {
+        Self {
+            webhook,
+            db: db.with_chan(DEST.to_owned()),
+            hooks,
+            timer: None,
+            footer: Footer::default(),
+            labels: Labels::default(),
+            attribution: false,
+            error_policy: ErrorPolicy::default(),
+            cancel,
+        }
+    }
+
+    /// Take the webhook URL from `DISCORD_WEBHOOK_URL`
+    pub fn from_env(db: DbConn, hooks: Hooks, cancel: CancellationToken) -> Result<Self> {
+        let webhook = env::var(DISCORD_WEBHOOK_ENV)
+            .map_err(|_| anyhow!("{DISCORD_WEBHOOK_ENV} is not set"))?;
+        Ok(Self::new(webhook, db, hooks, cancel))
+    }
+
+    /// Observe the end-to-end latency of the sent posts
+    pub fn with_timer(mut self, timer: E2eTimer) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// Append the footer to the posts
+    pub fn with_footer(mut self, footer: Footer) -> Self {
+        self.footer = footer;
+        self
+    }
+
+    /// Generated texts in the language
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Prepend the author to the posts
+    pub fn with_attribution(mut self, attribution: bool) -> Self {
+        self.attribution = attribution;
+        self
+    }
+
+    /// What to do when a post fails to be sent
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Send the post as a message.
+    /// Returns the Discord message ID.
+    async fn send_one(&self, post: &Post) -> Result<Vec<u8>> {
+        let text = render_message(
+            post,
+            self.attribution,
+            &self.footer,
+            &self.labels,
+            OffsetDateTime::now_utc(),
+        )?;
+        let mut description = to_markdown(&text);
+        let (files, unavailable) = fetch_media(post).await;
+        if !unavailable.is_empty() {
+            let lines: Vec<_> = unavailable
+                .iter()
+                .map(|url| format!("{}: <{url}>", self.labels.media_unavailable))
+                .collect();
+            description = format!("{description}\n\n{}", lines.join("\n"));
+        }
+        let payload = json!({ "embeds": [self.embed(post, &description)] });
+
+        let mut retries = 0;
+        loop {
+            let req = http::client()
+                .post(&self.webhook)
+                .query(&[("wait", "true")]);
+            let req = match files.is_empty() {
+                true => req.json(&payload),
+                false => {
+                    let mut form = Form::new().text("payload_json", payload.to_string());
+                    for (i, (name, body)) in files.iter().enumerate() {
+                        let part = Part::bytes(body.clone()).file_name(name.clone());
+                        form = form.part(format!("files[{i}]"), part);
+                    }
+                    req.multipart(form)
+                }
+            };
+            let res = req.send().await?;
+            if res.status() == StatusCode::TOO_MANY_REQUESTS && retries < RATE_LIMIT_RETRIES {
+                retries += 1;
+                let limit: RateLimit = res.json().await?;
+                METRICS.inc_retried();
+                tracing::warn!(
+                    "Retry after {} seconds due to the Discord rate limit",
+                    limit.retry_after
+                );
+                time::sleep(Duration::from_secs_f64(limit.retry_after.max(0.0))).await;
+                continue;
+            }
+            let msg: Message = check_res(res).await?.json().await?;
+            return Ok(msg.id.into_bytes());
+        }
+    }
+
+    /// URL of the message sent by the webhook, keeping the query like `thread_id`
+    fn message_url(&self, msg_id: &str) -> Result<Url> {
+        let mut url = Url::parse(&self.webhook)?;
+        url.path_segments_mut()
+            .map_err(|()| anyhow!("invalid webhook URL"))?
+            .extend(["messages", msg_id]);
+        Ok(url)
+    }
+
+    /// Embed of the text, headed by the host of the post linking to it
+    fn embed(&self, post: &Post, description: &str) -> Value {
+        let mut embed = json!({
+            "description": shorten(description, &post.url, &self.labels.read_more),
+            "url": post.url,
+            "timestamp": post.published,
+        });
+        if let Some(host) = Url::parse(&post.url)
+            .ok()
+            .and_then(|u| Some(u.host_str()?.to_owned()))
+        {
+            embed["author"] = json!({ "name": host, "url": post.url });
+        }
+        embed
+    }
+}
+
+#[async_trait]
+impl Con for DiscordCon {
+    async fn send(&self, items: Vec<Create>) -> Result<IdMap> {
+        let ctx = ConCtx {
+            db: &self.db,
+            hooks: &self.hooks,
+            timer: self.timer.as_ref(),
+            dest: DEST,
+            id_map: true,
+            error_policy: self.error_policy,
+            cancel: &self.cancel,
+        };
+        send_each(&ctx, items, |item| async move {
+            self.send_one(&item.object).await
+        })
+        .await
+    }
+
+    async fn remove(&self, ids: Vec<String>) -> Result<()> {
+        for id in ids {
+            let Some(msg_id) = self.db.query_id_map(id.clone()).await? else {
+                tracing::info!(post = %id, "Ignored the removal of the post not mirrored");
+                continue;
+            };
+            let url = self.message_url(&String::from_utf8_lossy(&msg_id))?;
+            let res = cancellable(&self.cancel, async {
+                check_res(http::client().delete(url).send().await?).await
+            });
+            match res.await {
+                Ok(_) => tracing::info!(post = %id, "Removed the mirror of the post"),
+                Err(e) if e.is::<Cancelled>() => return Err(e),
+                Err(e) => tracing::warn!(post = %id, "Failed to remove the mirror: {e:#}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Message {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct RateLimit {
+    /// Unit: Seconds
+    retry_after: f64,
+}
+
+/// Fetch the media to upload, up to the Discord limits.
+/// Returns the files with their names, and the URLs of the media failed or over the limits to be linked instead.
+async fn fetch_media(post: &Post) -> (Vec<(String, Vec<u8>)>, Vec<String>) {
+    let mut files = Vec::new();
+    let mut unavailable = Vec::new();
+    for att in post.attachment.iter() {
+        if files.len() >= FILE_COUNT_LIMIT {
+            unavailable.push(att.url.clone());
+            continue;
+        }
+        match fetch_file(&att.url).await {
+            Ok(body) => files.push((file_name(&att.url), body)),
+            Err(e) => {
+                tracing::warn!("Linking the media {} instead: {e:#}", att.url);
+                unavailable.push(att.url.clone());
+            }
+        }
+    }
+    (files, unavailable)
+}
+
+async fn fetch_file(url: &str) -> Result<Vec<u8>> {
+    let body = match local_path(url) {
+        Some(path) => tokio::fs::read(path).await?,
+        None => {
+            let res = check_res(http::client().get(url).send().await?).await?;
+            if res.content_length().unwrap_or(0) as usize > FILE_SIZE_LIMIT {
+                bail!("over the size limit of Discord");
+            }
+            res.bytes().await?.to_vec()
+        }
+    };
+    if body.len() > FILE_SIZE_LIMIT {
+        bail!("over the size limit of Discord");
+    }
+    Ok(body)
+}
+
+/// Shorten the description to fit in the embed with a link to the post
+fn shorten(text: &str, url: &str, read_more: &str) -> String {
+    if text.chars().count() <= DESCRIPTION_LIMIT {
+        return text.to_owned();
+    }
+    let more = format!("…\n\n[{read_more}]({url})");
+    // URLs too long to fit are left to the link of the embed author
+    let more = match more.chars().count() < DESCRIPTION_LIMIT {
+        true => more,
+        false => "…".to_owned(),
+    };
+    let kept: String = text
+        .chars()
+        .take(DESCRIPTION_LIMIT.saturating_sub(more.chars().count()))
+        .collect();
+    kept + &more
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{body_partial_json, body_string_contains, method, path, query_param};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::cons::tests::{create, creates, db_hooks};
+    use crate::db::AuditAction;
+
+    #[test]
+    fn test_shorten() {
+        let text = "a".repeat(DESCRIPTION_LIMIT + 1);
+        let short = shorten(&text, "https://a.example", "Read more");
+        assert_eq!(short.chars().count(), DESCRIPTION_LIMIT);
+        assert!(short.ends_with("[Read more](https://a.example)"));
+        // Not over the limit with a URL over it
+        let url = format!("https://a.example/{}", "a".repeat(DESCRIPTION_LIMIT));
+        let short = shorten(&text, &url, "Read more");
+        assert!(short.chars().count() <= DESCRIPTION_LIMIT);
+        assert!(short.ends_with('…'));
+    }
+
+    #[tokio::test]
+    async fn test_send() -> Result<()> {
+        let server = MockServer::start().await;
+        let base = server.uri();
+        Mock::given(method("GET"))
+            .and(path("/media/a.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"png".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // Rate limited once, and then retried
+        Mock::given(method("POST"))
+            .and(path("/api/webhooks/1/token"))
+            .respond_with(ResponseTemplate::new(429).set_body_json(json!({"retry_after": 0.01})))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/webhooks/1/token"))
+            .and(query_param("wait", "true"))
+            .and(body_partial_json(json!({
+                "embeds": [{"description": "Post 100", "timestamp": "2023-08-03T16:09:19Z"}],
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"id": "9001"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/webhooks/1/token"))
+            .and(body_string_contains(r#"name="files[0]"; filename="a.png""#))
+            .and(body_string_contains(r#""description":"Post 200""#))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"id": "9002"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut with_media = create(&base, 200, None);
+        with_media["object"]["attachment"] = json!([{"type": "Document", "mediaType": "image/png", "url": format!("{base}/media/a.png")}]);
+        let items = creates(vec![with_media, create(&base, 100, None)])?;
+        let (db, hooks) = db_hooks()?;
+        let con = DiscordCon::new(
+            format!("{base}/api/webhooks/1/token"),
+            db.clone(),
+            hooks,
+            CancellationToken::new(),
+        );
+        let id_map = con.send(items.clone()).await?;
+        let status = |id| format!("{base}/users/myl/statuses/{id}");
+        assert_eq!(id_map[&status(100)], b"9001");
+        assert_eq!(id_map[&status(200)], b"9002");
+        // Retried pages skip the sent posts
+        assert_eq!(con.send(items).await?, id_map);
+        Mock::given(method("DELETE"))
+            .and(path("/api/webhooks/1/token/messages/9002"))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        con.remove(vec![status(200), status(300)]).await?;
+        let records = db.query_audit(status(200)).await?;
+        assert_eq!(records[0].action, AuditAction::Sent);
+        assert_eq!(records[0].dest.as_deref(), Some("discord"));
+        assert!(records[0].tg_id.is_none());
+        Ok(())
+    }
+}
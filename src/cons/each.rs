@@ -0,0 +1,124 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Send loop shared by the consumers sending the posts one by one
+
+use std::collections::HashMap;
+use std::future::Future;
+
+use anyhow::Result;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use super::{ErrorPolicy, IdMap};
+use crate::as2::{Activity, Create};
+use crate::db::{AuditAction, AuditRecord, DbConn};
+use crate::hooks::Hooks;
+use crate::metrics::{E2eTimer, METRICS};
+use crate::redact::redact;
+use crate::utils::{cancellable, Cancelled};
+
+/// What the consumers sending the posts one by one with [`send_each`] share
+pub(crate) struct ConCtx<'a> {
+    pub db: &'a DbConn,
+    pub hooks: &'a Hooks,
+    pub timer: Option<&'a E2eTimer>,
+    /// Destination in the logs and the audit records
+    pub dest: &'a str,
+    /// Whether the mirrors are saved to the ID map, and the posts already in it are skipped.
+    /// Consumers with nothing to map the posts to leave the sent to be tracked by the state.
+    pub id_map: bool,
+    pub error_policy: ErrorPolicy,
+    pub cancel: &'a CancellationToken,
+}
+
+/// Send the posts one by one from the oldest with `send_one`, which returns the mirror to save to the ID map.
+/// Posts already in the ID map are skipped, and failures are handled by the error policy.
+/// Without the ID map, the mirrors are dropped and an empty map is returned.
+pub(crate) async fn send_each<F, Fut>(
+    ctx: &ConCtx<'_>,
+    items: Vec<Create>,
+    send_one: F,
+) -> Result<IdMap>
+where
+    F: Fn(Create) -> Fut,
+    Fut: Future<Output = Result<Vec<u8>>>,
+{
+    let mut id_map = HashMap::new();
+    // Pages list the newest first
+    for item in items.into_iter().rev() {
+        // Sent before a failure of the page, which is retried as a whole
+        if ctx.id_map {
+            if let Some(mirror) = ctx.db.query_id_map(item.object.id.clone()).await? {
+                tracing::info!(post = %item.object.id, "Skipped the post already sent");
+                id_map.insert(item.object.id.clone(), mirror);
+                continue;
+            }
+        }
+        let span = tracing::info_span!("post", id = %item.object.id, dest = %ctx.dest);
+        let start = Instant::now();
+        let res = cancellable(ctx.cancel, send_one(item.clone()))
+            .instrument(span.clone())
+            .await;
+        let elapsed_ms = start.elapsed().as_millis() as u64;
+        match res {
+            Ok(mirror) => {
+                METRICS.observe_sent(elapsed_ms);
+                if let Some(timer) = ctx.timer {
+                    timer.observe(&item.object.id);
+                }
+                tracing::info!(parent: &span, elapsed_ms, "Sent the post");
+                audit(ctx, &item, elapsed_ms, None).await?;
+                if !ctx.id_map {
+                    continue;
+                }
+                // Saved one by one, so later replies in the page find their parents
+                let sent = HashMap::from([(item.object.id.clone(), mirror)]);
+                ctx.db.save_id_map(sent.clone()).await?;
+                id_map.extend(sent);
+            }
+            Err(e) if e.is::<Cancelled>() => return Err(e),
+            Err(e) => {
+                METRICS.inc_failed();
+                tracing::error!(parent: &span, elapsed_ms, "Failed to send the post: {e:#}");
+                let error = redact(&format!("{e:#}"));
+                audit(ctx, &item, elapsed_ms, Some(error.clone())).await?;
+                match ctx.error_policy {
+                    ErrorPolicy::Fail => return Err(e),
+                    ErrorPolicy::Skip => (),
+                    ErrorPolicy::DeadLetter => {
+                        let act = serde_json::to_string(&Activity::Create(item.clone()))?;
+                        ctx.db
+                            .save_dead_letter(item.object.id.clone(), act, error)
+                            .await?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(id_map)
+}
+
+/// Record the sent or failed post, with the redacted error if failed
+async fn audit(
+    ctx: &ConCtx<'_>,
+    item: &Create,
+    duration_ms: u64,
+    error: Option<String>,
+) -> Result<()> {
+    let action = match error {
+        None => AuditAction::Sent,
+        Some(_) => AuditAction::Failed,
+    };
+    let mut record = AuditRecord::new(item.object.id.clone(), action);
+    record.dest = Some(ctx.dest.to_owned());
+    record.duration_ms = Some(duration_ms);
+    record.error = error;
+    ctx.db.append_audit(record.clone()).await?;
+    // Failed posts are retried by the next round under the fail policy, so only the given up ones fire
+    if record.action != AuditAction::Failed || ctx.error_policy != ErrorPolicy::Fail {
+        ctx.hooks.fire(&record).await;
+    }
+    Ok(())
+}
@@ -0,0 +1,270 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Static site export consumer.
+//! Each post is written as a Markdown file with the front matter into `content/posts/` of the site dir,
+//! and its media are downloaded into `static/media/`, following the layout of Hugo sites.
+//! Exporting a post again overwrites its files, and removed posts have their files removed.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::fs;
+use tokio::time::Instant;
+
+use super::{Con, IdMap};
+use crate::as2::{Create, Post};
+use crate::db::{AuditAction, AuditRecord, DbConn};
+use crate::hooks::Hooks;
+use crate::http;
+use crate::media::{file_name, local_path};
+use crate::metrics::{E2eTimer, METRICS};
+use crate::render::{clean_body, to_markdown};
+use crate::utils::{check_res, fnv1a, int_id};
+
+pub struct HugoCon {
+    dir: PathBuf,
+    db: DbConn,
+    hooks: Hooks,
+    timer: Option<E2eTimer>,
+}
+
+impl HugoCon {
+    pub fn new(dir: PathBuf, db: DbConn, hooks: Hooks) -> Self {
+        Self {
+            dir,
+            db,
+            hooks,
+            timer: None,
+        }
+    }
+
+    /// Observe the end-to-end latency of the exported posts
+    pub fn with_timer(mut self, timer: E2eTimer) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    fn post_path(&self, key: &str) -> PathBuf {
+        self.dir.join("content/posts").join(format!("{key}.md"))
+    }
+
+    fn media_dir(&self, key: &str) -> PathBuf {
+        self.dir.join("static/media").join(key)
+    }
+
+    /// Write the post and its media
+    async fn export(&self, post: &Post) -> Result<()> {
+        let key = post_key(&post.id);
+        let mut body = hard_breaks(&to_markdown(&clean_body(&post.content)?));
+        let media_dir = self.media_dir(&key);
+        for (i, att) in post.attachment.iter().enumerate() {
+            let name = file_name(&att.url);
+            // Media of the same file name from different URLs are kept apart by the index
+            let file = format!("{i}-{name}");
+            // Media failed to download are linked to the originals
+            let link = match download(&att.url, &media_dir.join(&file)).await {
+                Ok(()) => format!("/media/{key}/{file}"),
+                Err(e) => {
+                    tracing::warn!("Linking the media {} instead: {e:#}", att.url);
+                    att.url.clone()
+                }
+            };
+            let alt = att
+                .name
+                .as_deref()
+                .unwrap_or_default()
+                .replace(['[', ']'], "");
+            body += "\n\n";
+            body += &match att.media_type.starts_with("image/") || att.r#type == "Image" {
+                true => format!("![{alt}]({link})"),
+                false => format!("[{name}]({link})"),
+            };
+        }
+        let path = self.post_path(&key);
+        fs::create_dir_all(path.parent().unwrap()).await?;
+        fs::write(&path, format!("{}\n{}\n", front_matter(post), body.trim())).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Con for HugoCon {
+    async fn send(&self, items: Vec<Create>) -> Result<IdMap> {
+        // Pages list the newest first
+        for item in items.into_iter().rev() {
+            let id = item.object.id.clone();
+            let start = Instant::now();
+            self.export(&item.object).await?;
+            let duration_ms = start.elapsed().as_millis() as u64;
+            METRICS.observe_sent(duration_ms);
+            if let Some(timer) = self.timer.as_ref() {
+                timer.observe(&id);
+            }
+            tracing::info!(post = %id, "Exported the post");
+            let mut record = AuditRecord::new(id, AuditAction::Printed);
+            record.dest = Some(self.dir.display().to_string());
+            record.duration_ms = Some(duration_ms);
+            self.db.append_audit(record.clone()).await?;
+            self.hooks.fire(&record).await;
+        }
+        // Nothing to map the posts to, and the state tracks the exported
+        Ok(HashMap::new())
+    }
+
+    async fn remove(&self, ids: Vec<String>) -> Result<()> {
+        for id in ids {
+            let key = post_key(&id);
+            ignore_not_found(fs::remove_file(self.post_path(&key)).await)?;
+            ignore_not_found(fs::remove_dir_all(self.media_dir(&key)).await)?;
+            tracing::info!(post = %id, "Removed the exported post");
+        }
+        Ok(())
+    }
+}
+
+/// Name of the files of the post, which is the integer ID if any or the hash of the GUID
+fn post_key(id: &str) -> String {
+    match int_id(id) {
+        Ok(id) => id.to_string(),
+        Err(_) => format!("{:016x}", fnv1a(id.as_bytes())),
+    }
+}
+
+/// YAML front matter with the title, the dates, the tags, and the original URL.
+/// Strings are quoted as JSON, which YAML takes as double-quoted scalars.
+fn front_matter(post: &Post) -> String {
+    let quote = |s: &str| serde_json::to_string(s).unwrap();
+    let mut lines = vec!["---".to_owned()];
+    if let Some(title) = post.title() {
+        lines.push(format!("title: {}", quote(title)));
+    }
+    lines.push(format!("date: {}", quote(&post.published)));
+    if let Some(updated) = post.updated.as_deref() {
+        lines.push(format!("lastmod: {}", quote(updated)));
+    }
+    let tags: Vec<_> = post
+        .hashtags()
+        .map(|tag| quote(tag.name.trim_start_matches('#')))
+        .collect();
+    if !tags.is_empty() {
+        lines.push(format!("tags: [{}]", tags.join(", ")));
+    }
+    if let Some(cw) = post.summary.as_deref().filter(|s| !s.is_empty()) {
+        lines.push(format!("content_warning: {}", quote(cw)));
+    }
+    lines.push(format!("original_url: {}", quote(&post.url)));
+    lines.push("---".to_owned());
+    lines.join("\n")
+}
+
+/// Keep the line breaks of the text, which Markdown otherwise joins, except in code blocks
+fn hard_breaks(md: &str) -> String {
+    let lines: Vec<_> = md.lines().collect();
+    let mut fenced = false;
+    let mut out = Vec::with_capacity(lines.len());
+    for (i, line) in lines.iter().enumerate() {
+        if line.starts_with("```") {
+            fenced = !fenced;
+            out.push(line.to_string());
+            continue;
+        }
+        let next = lines.get(i + 1).map_or("", |next| next.trim());
+        let joined = !line.trim().is_empty() && !next.is_empty() && !next.starts_with("```");
+        match !fenced && joined {
+            true => out.push(format!("{line}\\")),
+            false => out.push(line.to_string()),
+        }
+    }
+    out.join("\n")
+}
+
+/// Save the media to the path, copied if local or fetched otherwise
+async fn download(url: &str, path: &Path) -> Result<()> {
+    fs::create_dir_all(path.parent().unwrap()).await?;
+    match local_path(url) {
+        Some(src) => {
+            fs::copy(src, path).await?;
+        }
+        None => {
+            let res = check_res(http::client().get(url).send().await?).await?;
+            fs::write(path, res.bytes().await?).await?;
+        }
+    }
+    Ok(())
+}
+
+fn ignore_not_found(res: io::Result<()>) -> Result<()> {
+    match res {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::cons::tests::{create, creates, db_hooks};
+
+    #[test]
+    fn test_hard_breaks() {
+        let md = "a\nb\n\nc\n```\nx\ny\n```\n> d\n> e";
+        assert_eq!(hard_breaks(md), "a\\\nb\n\nc\n```\nx\ny\n```\n> d\\\n> e");
+    }
+
+    #[tokio::test]
+    async fn test_export() -> Result<()> {
+        let server = MockServer::start().await;
+        let base = server.uri();
+        Mock::given(method("GET"))
+            .and(path("/media/a.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"png".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/other/a.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"other".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        let mut item = create(&base, 100, None);
+        item["object"]["content"] = json!("<p>Hi <b>all</b><br>#rust</p>");
+        item["object"]["tag"] = json!([{"type": "Hashtag", "name": "#rust"}]);
+        item["object"]["attachment"] = json!([
+            {"type": "Document", "mediaType": "image/png", "url": format!("{base}/media/a.png"), "name": "A cat"},
+            {"type": "Document", "mediaType": "image/png", "url": format!("{base}/other/a.png"), "name": "A dog"},
+        ]);
+
+        let dir = std::env::temp_dir().join(format!("mastotg-hugo-{}", std::process::id()));
+        let (db, hooks) = db_hooks()?;
+        let con = HugoCon::new(dir.clone(), db, hooks);
+        con.send(creates(vec![item])?).await?;
+        let post = std::fs::read_to_string(dir.join("content/posts/100.md"));
+        let media = std::fs::read(dir.join("static/media/100/0-a.png"));
+        let other = std::fs::read(dir.join("static/media/100/1-a.png"));
+        con.remove(vec![format!("{base}/users/myl/statuses/100")])
+            .await?;
+        let removed =
+            !dir.join("content/posts/100.md").exists() && !dir.join("static/media/100").exists();
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(
+            post?,
+            format!(
+                "---\ndate: \"2023-08-03T16:09:19Z\"\ntags: [\"rust\"]\noriginal_url: \"{base}/@myl/100\"\n---\n\
+                 Hi **all**\\\n#rust\n\n![A cat](/media/100/0-a.png)\n\n![A dog](/media/100/1-a.png)\n"
+            )
+        );
+        assert_eq!(media?, b"png");
+        assert_eq!(other?, b"other");
+        assert!(removed);
+        Ok(())
+    }
+}
@@ -0,0 +1,448 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mastodon consumer.
+//! Posts are re-posted to another account via the statuses API of Mastodon, GoToSocial, and others,
+//! e.g., to mirror the account being migrated from.
+//!
+//! The text is rendered like for Telegram and then converted into plain text, which the statuses API takes.
+//! Media are re-uploaded, and replies to mirrored posts are threaded under their mirrors.
+//! Status IDs are saved to the database, so removals remove the mirrors too.
+
+use std::env;
+use std::time::Duration;
+
+use ::time::OffsetDateTime;
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use reqwest::multipart::{Form, Part};
+use reqwest::{RequestBuilder, Response, StatusCode};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use super::{send_each, Con, ConCtx, ErrorPolicy, IdMap};
+use crate::as2::{Create, Post, Visibility};
+use crate::auth::host_key;
+use crate::db::DbConn;
+use crate::hooks::Hooks;
+use crate::http;
+use crate::labels::Labels;
+use crate::media::{file_name, local_path};
+use crate::metrics::{E2eTimer, METRICS};
+use crate::redact;
+use crate::render::{render_message, to_plain, Footer};
+use crate::utils::{
+    cancellable, check_res, fnv1a, parse_time, rate_limit_wait, Cancelled, RATE_LIMIT_RETRIES,
+};
+
+/// Env var of the access token of the target account.
+/// The token stored by `mastotg auth` for the target host is used if not set.
+pub const TARGET_ACCESS_TOKEN_ENV: &str = "MASTODON_TARGET_ACCESS_TOKEN";
+
+/// Max number of media of a Mastodon status
+const MEDIA_LIMIT: usize = 4;
+
+/// Interval to check whether the uploaded media are processed
+const MEDIA_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Times to check whether the uploaded media are processed before giving up
+const MEDIA_POLL_LIMIT: u32 = 60;
+
+pub struct MastodonCon {
+    host: String,
+    token: String,
+    db: DbConn,
+    hooks: Hooks,
+    timer: Option<E2eTimer>,
+    footer: Footer,
+    labels: Labels,
+    attribution: bool,
+    max_chars: usize,
+    error_policy: ErrorPolicy,
+    cancel: CancellationToken,
+}
+
+impl MastodonCon {
+    pub fn new(
+        host: &str,
+        token: String,
+        db: DbConn,
+        hooks: Hooks,
+        cancel: CancellationToken,
+    ) -> Self {
+        Self {
+            host: host_key(host),
+            token,
+            db,
+            hooks,
+            timer: None,
+            footer: Footer::default(),
+            labels: Labels::default(),
+            attribution: false,
+            max_chars: 500,
+            error_policy: ErrorPolicy::default(),
+            cancel,
+        }
+    }
+
+    /// Take the token from `MASTODON_TARGET_ACCESS_TOKEN`, or the one stored for the host.
+    /// The token is registered as a secret.
+    pub async fn from_env(
+        host: &str,
+        db: DbConn,
+        hooks: Hooks,
+        cancel: CancellationToken,
+    ) -> Result<Self> {
+        let token = match env::var(TARGET_ACCESS_TOKEN_ENV) {
+            Ok(token) => token,
+            Err(_) => db.query_access_token(host_key(host)).await?.ok_or(anyhow!(
+                "{TARGET_ACCESS_TOKEN_ENV} is not set and no token is stored for {host}"
+            ))?,
+        };
+        redact::register_secret(token.clone());
+        Ok(Self::new(host, token, db, hooks, cancel))
+    }
+
+    /// Observe the end-to-end latency of the sent posts
+    pub fn with_timer(mut self, timer: E2eTimer) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// Append the footer to the posts
+    pub fn with_footer(mut self, footer: Footer) -> Self {
+        self.footer = footer;
+        self
+    }
+
+    /// Generated texts in the language
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Prepend the author to the posts
+    pub fn with_attribution(mut self, attribution: bool) -> Self {
+        self.attribution = attribution;
+        self
+    }
+
+    /// Max length of the statuses of the instance, beyond which texts are shortened with the link to the post
+    pub fn with_max_chars(mut self, max_chars: usize) -> Self {
+        self.max_chars = max_chars;
+        self
+    }
+
+    /// What to do when a post fails to be sent
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Send the request, waiting and retrying when rate limited up to the max retries
+    async fn request(&self, build: impl Fn() -> Result<RequestBuilder>) -> Result<Response> {
+        let mut retries = 0;
+        loop {
+            let res = build()?.bearer_auth(&self.token).send().await?;
+            if res.status() != StatusCode::TOO_MANY_REQUESTS || retries >= RATE_LIMIT_RETRIES {
+                return check_res(res).await;
+            }
+            retries += 1;
+            let reset = res
+                .headers()
+                .get("X-RateLimit-Reset")
+                .and_then(|v| parse_time(v.to_str().ok()?));
+            let wait = rate_limit_wait(reset);
+            METRICS.inc_retried();
+            tracing::warn!(
+                "Retry after {} seconds due to the rate limit of {}",
+                wait.as_secs(),
+                self.host
+            );
+            time::sleep(wait).await;
+        }
+    }
+
+    /// Re-post the post.
+    /// Returns the status ID.
+    async fn send_one(&self, post: &Post) -> Result<Vec<u8>> {
+        // The content warning is kept as the one of the status instead of the spoiler in the text
+        let mut uncovered = post.clone();
+        uncovered.summary = None;
+        let text = render_message(
+            &uncovered,
+            self.attribution,
+            &self.footer,
+            &self.labels,
+            OffsetDateTime::now_utc(),
+        )?;
+        let mut text = to_plain(&text);
+        let mut media_ids = Vec::new();
+        let mut unavailable = Vec::new();
+        for att in post.attachment.iter() {
+            if media_ids.len() >= MEDIA_LIMIT {
+                unavailable.push(&att.url);
+                continue;
+            }
+            match self
+                .upload(&att.url, &att.media_type, att.name.as_deref())
+                .await
+            {
+                Ok(id) => media_ids.push(id),
+                Err(e) => {
+                    tracing::warn!("Linking the media {} instead: {e:#}", att.url);
+                    unavailable.push(&att.url);
+                }
+            }
+        }
+        for url in unavailable {
+            text = format!("{text}\n{}: {url}", self.labels.media_unavailable);
+        }
+
+        let in_reply_to_id = match post.in_reply_to.as_ref() {
+            Some(id) => self
+                .db
+                .query_id_map(id.clone())
+                .await?
+                .and_then(|id| String::from_utf8(id).ok()),
+            None => None,
+        };
+        let body = json!({
+            "status": shorten(&text, &post.url, self.max_chars),
+            "media_ids": media_ids,
+            "spoiler_text": post.summary.as_deref().unwrap_or_default(),
+            "sensitive": post.sensitive,
+            "visibility": visibility(post.visibility()),
+            "in_reply_to_id": in_reply_to_id,
+        });
+        // Retried requests of the same post are not posted twice
+        let key = format!("{:016x}", fnv1a(post.id.as_bytes()));
+        let url = format!("{}/api/v1/statuses", self.host);
+        let res = self
+            .request(|| {
+                Ok(http::client()
+                    .post(&url)
+                    .header("Idempotency-Key", &key)
+                    .json(&body))
+            })
+            .await?;
+        let status: Status = res.json().await?;
+        Ok(status.id.into_bytes())
+    }
+
+    /// Upload the media and wait until it is processed.
+    /// Returns the media ID.
+    async fn upload(&self, url: &str, media_type: &str, alt: Option<&str>) -> Result<String> {
+        let body = match local_path(url) {
+            Some(path) => tokio::fs::read(path).await?,
+            None => {
+                let res = check_res(http::client().get(url).send().await?).await?;
+                res.bytes().await?.to_vec()
+            }
+        };
+        let api = format!("{}/api/v2/media", self.host);
+        let res = self
+            .request(|| {
+                let mut part = Part::bytes(body.clone()).file_name(file_name(url));
+                if !media_type.is_empty() {
+                    part = part.mime_str(media_type)?;
+                }
+                let mut form = Form::new().part("file", part);
+                if let Some(alt) = alt {
+                    form = form.text("description", alt.to_owned());
+                }
+                Ok(http::client().post(&api).multipart(form))
+            })
+            .await?;
+        // Large media are processed asynchronously and can not be attached until done
+        let mut media: Media = res.json().await?;
+        let mut polls = 0;
+        while media.url.is_none() {
+            if polls >= MEDIA_POLL_LIMIT {
+                bail!("media {} not processed in time", media.id);
+            }
+            polls += 1;
+            time::sleep(MEDIA_POLL_INTERVAL).await;
+            let api = format!("{}/api/v1/media/{}", self.host, media.id);
+            let res = self.request(|| Ok(http::client().get(&api))).await?;
+            if res.status() == StatusCode::PARTIAL_CONTENT {
+                continue;
+            }
+            media = res.json().await?;
+        }
+        Ok(media.id)
+    }
+}
+
+#[async_trait]
+impl Con for MastodonCon {
+    async fn send(&self, items: Vec<Create>) -> Result<IdMap> {
+        let ctx = ConCtx {
+            db: &self.db,
+            hooks: &self.hooks,
+            timer: self.timer.as_ref(),
+            dest: &self.host,
+            id_map: true,
+            error_policy: self.error_policy,
+            cancel: &self.cancel,
+        };
+        send_each(&ctx, items, |item| async move {
+            self.send_one(&item.object).await
+        })
+        .await
+    }
+
+    async fn remove(&self, ids: Vec<String>) -> Result<()> {
+        for id in ids {
+            let Some(status_id) = self.db.query_id_map(id.clone()).await? else {
+                tracing::info!(post = %id, "Ignored the removal of the post not mirrored");
+                continue;
+            };
+            let api = format!(
+                "{}/api/v1/statuses/{}",
+                self.host,
+                String::from_utf8_lossy(&status_id)
+            );
+            let res = cancellable(
+                &self.cancel,
+                self.request(|| Ok(http::client().delete(&api))),
+            );
+            match res.await {
+                Ok(_) => tracing::info!(post = %id, "Removed the mirror of the post"),
+                Err(e) if e.is::<Cancelled>() => return Err(e),
+                Err(e) => tracing::warn!(post = %id, "Failed to remove the mirror: {e:#}"),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+struct Status {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Media {
+    id: String,
+    /// Null until processed
+    url: Option<String>,
+}
+
+/// Visibility param of the statuses API
+fn visibility(visibility: Visibility) -> &'static str {
+    match visibility {
+        Visibility::Public => "public",
+        Visibility::Unlisted => "unlisted",
+        Visibility::FollowersOnly => "private",
+        Visibility::Direct => "direct",
+    }
+}
+
+/// Shorten the text to the max length with the link to the post
+fn shorten(text: &str, url: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        return text.to_owned();
+    }
+    let kept = max_chars.saturating_sub(url.chars().count() + 2);
+    let text: String = text.chars().take(kept).collect();
+    format!("{}…\n{url}", text.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{body_partial_json, body_string_contains, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::cons::tests::{create, creates, db_hooks};
+
+    #[test]
+    fn test_shorten() {
+        let short = shorten(&"a".repeat(600), "https://a.example/1", 500);
+        assert_eq!(short.chars().count(), 500);
+        assert!(short.ends_with("a…\nhttps://a.example/1"));
+    }
+
+    #[tokio::test]
+    async fn test_send() -> Result<()> {
+        let server = MockServer::start().await;
+        let base = server.uri();
+        Mock::given(method("GET"))
+            .and(path("/media/a.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"png".to_vec()))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // Processed asynchronously, and then checked until done
+        Mock::given(method("POST"))
+            .and(path("/api/v2/media"))
+            .and(header("Authorization", "Bearer target-token"))
+            .and(body_string_contains("A cat"))
+            .respond_with(
+                ResponseTemplate::new(202).set_body_json(json!({"id": "m1", "url": null})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET"))
+            .and(path("/api/v1/media/m1"))
+            .respond_with(
+                ResponseTemplate::new(200)
+                    .set_body_json(json!({"id": "m1", "url": format!("{base}/m1.png")})),
+            )
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/api/v1/statuses"))
+            .and(body_partial_json(json!({
+                "status": "Post 100",
+                "media_ids": ["m1"],
+                "visibility": "public",
+                "in_reply_to_id": null,
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"id": "s100"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // Replies are threaded under the mirrors
+        Mock::given(method("POST"))
+            .and(path("/api/v1/statuses"))
+            .and(body_partial_json(
+                json!({"status": "Post 200", "in_reply_to_id": "s100"}),
+            ))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"id": "s200"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("DELETE"))
+            .and(path("/api/v1/statuses/s200"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"id": "s200"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut root = create(&base, 100, None);
+        root["object"]["attachment"] = json!([{"type": "Document", "mediaType": "image/png", "url": format!("{base}/media/a.png"), "name": "A cat"}]);
+        let items = creates(vec![create(&base, 200, Some(100)), root])?;
+        let (db, hooks) = db_hooks()?;
+        let con = MastodonCon::new(
+            &base,
+            "target-token".to_owned(),
+            db.clone(),
+            hooks,
+            CancellationToken::new(),
+        );
+        con.send(items).await?;
+        let status = |id| format!("{base}/users/myl/statuses/{id}");
+        assert_eq!(
+            db.query_id_map(status(200)).await?.as_deref(),
+            Some(&b"s200"[..])
+        );
+        con.remove(vec![status(200), status(300)]).await?;
+        Ok(())
+    }
+}
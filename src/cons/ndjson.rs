@@ -0,0 +1,174 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! NDJSON file consumer.
+//! Each post is appended to the file as the JSON of its activity in one line, so other tools can tail the stream.
+//! Removals are appended as `Delete` activities.
+//!
+//! The file is rotated once it would exceed the max size,
+//! with the rotated renamed to `<file>.1`, `<file>.2`, ..., from the newest.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::time::Instant;
+
+use super::{Con, IdMap};
+use crate::as2::{Activity, Create, Delete};
+use crate::db::{AuditAction, AuditRecord, DbConn};
+use crate::hooks::Hooks;
+use crate::metrics::{E2eTimer, METRICS};
+
+pub struct NdjsonCon {
+    path: PathBuf,
+    db: DbConn,
+    hooks: Hooks,
+    timer: Option<E2eTimer>,
+    /// Unit: Bytes
+    max_size: Option<u64>,
+    keep: usize,
+}
+
+impl NdjsonCon {
+    pub fn new(path: PathBuf, db: DbConn, hooks: Hooks) -> Self {
+        Self {
+            path,
+            db,
+            hooks,
+            timer: None,
+            max_size: None,
+            keep: 5,
+        }
+    }
+
+    /// Observe the end-to-end latency of the written posts
+    pub fn with_timer(mut self, timer: E2eTimer) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// Rotate the file once it would exceed the size in bytes, keeping the number of rotated files.
+    /// Never rotate if `None`.
+    pub fn with_rotation(mut self, max_size: Option<u64>, keep: usize) -> Self {
+        self.max_size = max_size;
+        self.keep = keep;
+        self
+    }
+
+    /// Append the activity as a line, rotating the file first if needed
+    async fn append(&self, act: &Activity) -> Result<()> {
+        let mut line = serde_json::to_vec(act)?;
+        line.push(b'\n');
+        if let Some(max_size) = self.max_size {
+            let size = match fs::metadata(&self.path).await {
+                Ok(meta) => meta.len(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+                Err(e) => return Err(e.into()),
+            };
+            // A line longer than the max still goes to a file of its own
+            if size > 0 && size + line.len() as u64 > max_size {
+                self.rotate().await?;
+            }
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&line).await?;
+        file.flush().await?;
+        Ok(())
+    }
+
+    /// Shift the rotated files by one and rotate the current one to `.1`.
+    /// The oldest beyond the number to keep is overwritten.
+    async fn rotate(&self) -> Result<()> {
+        for i in (1..self.keep).rev() {
+            let from = rotated_path(&self.path, i);
+            if fs::try_exists(&from).await? {
+                fs::rename(from, rotated_path(&self.path, i + 1)).await?;
+            }
+        }
+        match self.keep {
+            0 => fs::remove_file(&self.path).await?,
+            _ => fs::rename(&self.path, rotated_path(&self.path, 1)).await?,
+        }
+        tracing::info!("Rotated the NDJSON file {}", self.path.display());
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Con for NdjsonCon {
+    async fn send(&self, items: Vec<Create>) -> Result<IdMap> {
+        // Pages list the newest first
+        for item in items.into_iter().rev() {
+            let id = item.object.id.clone();
+            let start = Instant::now();
+            self.append(&Activity::Create(item)).await?;
+            let duration_ms = start.elapsed().as_millis() as u64;
+            METRICS.observe_sent(duration_ms);
+            if let Some(timer) = self.timer.as_ref() {
+                timer.observe(&id);
+            }
+            let mut record = AuditRecord::new(id, AuditAction::Printed);
+            record.dest = Some(self.path.display().to_string());
+            record.duration_ms = Some(duration_ms);
+            self.db.append_audit(record.clone()).await?;
+            self.hooks.fire(&record).await;
+        }
+        // Nothing to map the posts to, and the state tracks the written
+        Ok(HashMap::new())
+    }
+
+    async fn remove(&self, ids: Vec<String>) -> Result<()> {
+        for id in ids {
+            self.append(&Activity::Delete(Delete::of(id))).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Path of the rotated file, e.g., `posts.ndjson.2`
+fn rotated_path(path: &Path, i: usize) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{i}"));
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cons::tests::{create, creates, db_hooks};
+
+    #[tokio::test]
+    async fn test_rotation() -> Result<()> {
+        let base = "https://mastodon.example";
+        let dir = std::env::temp_dir().join(format!("mastotg-ndjson-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let file = dir.join("posts.ndjson");
+        // Every line goes to a file of its own, and only one rotated file is kept
+        let (db, hooks) = db_hooks()?;
+        let con = NdjsonCon::new(file.clone(), db, hooks).with_rotation(Some(1), 1);
+        let items = creates(vec![
+            create(base, 500, None),
+            create(base, 400, None),
+            create(base, 300, None),
+        ])?;
+        con.send(items).await?;
+        let current = std::fs::read_to_string(&file)?;
+        let rotated = std::fs::read_to_string(dir.join("posts.ndjson.1"))?;
+        let older = dir.join("posts.ndjson.2").exists();
+        std::fs::remove_dir_all(&dir)?;
+        assert!(current.contains("statuses/500") && current.lines().count() == 1);
+        assert!(rotated.contains("statuses/400") && rotated.lines().count() == 1);
+        assert!(!older);
+        Ok(())
+    }
+}
@@ -0,0 +1,239 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! ntfy consumer.
+//! Each post is pushed to the topic as a compact notification,
+//! with the title or the author as the title, the excerpt of the text as the message,
+//! the post as the click URL, and the first image attached by its URL.
+//! Removals are ignored since pushed notifications stay on the devices.
+
+use std::env;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio_util::sync::CancellationToken;
+
+use super::{send_each, Con, ConCtx, ErrorPolicy, IdMap};
+use crate::as2::{Create, Post};
+use crate::db::DbConn;
+use crate::hooks::Hooks;
+use crate::http;
+use crate::media::local_path;
+use crate::metrics::E2eTimer;
+use crate::render::{clean_body, render_attribution, to_plain};
+use crate::utils::check_res;
+
+/// Env var of the access token of the server, for protected topics
+pub const NTFY_TOKEN_ENV: &str = "NTFY_TOKEN";
+
+/// Max length of the excerpts. Unit: Chars.
+const EXCERPT_CHARS: usize = 200;
+
+/// Destination name in audit records and logs, instead of the topic that anyone knowing can subscribe to
+const DEST: &str = "ntfy";
+
+/// Message of the ntfy JSON publishing API
+#[derive(Serialize)]
+struct Notification {
+    topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    message: String,
+    click: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    attach: Option<String>,
+}
+
+pub struct NtfyCon {
+    server: String,
+    topic: String,
+    token: Option<String>,
+    db: DbConn,
+    hooks: Hooks,
+    timer: Option<E2eTimer>,
+    error_policy: ErrorPolicy,
+    cancel: CancellationToken,
+}
+
+impl NtfyCon {
+    /// Push to the topic on the server, e.g., `https://ntfy.sh`.
+    /// The token is read from `NTFY_TOKEN` if set.
+    pub fn new(
+        server: &str,
+        topic: String,
+        db: DbConn,
+        hooks: Hooks,
+        cancel: CancellationToken,
+    ) -> Self {
+        Self {
+            server: server.trim_end_matches('/').to_owned(),
+            topic,
+            token: env::var(NTFY_TOKEN_ENV).ok(),
+            db,
+            hooks,
+            timer: None,
+            error_policy: ErrorPolicy::default(),
+            cancel,
+        }
+    }
+
+    /// Authorize with the access token, or not if `None`
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// Observe the end-to-end latency of the pushed posts
+    pub fn with_timer(mut self, timer: E2eTimer) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// What to do when a post fails to be pushed
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    async fn push(&self, post: &Post) -> Result<()> {
+        let mut req = http::client()
+            .post(&self.server)
+            .json(&notification(&self.topic, post)?);
+        if let Some(token) = self.token.as_ref() {
+            req = req.bearer_auth(token);
+        }
+        check_res(req.send().await?).await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Con for NtfyCon {
+    async fn send(&self, items: Vec<Create>) -> Result<IdMap> {
+        let ctx = ConCtx {
+            db: &self.db,
+            hooks: &self.hooks,
+            timer: self.timer.as_ref(),
+            dest: DEST,
+            // Nothing to map the posts to, and the state tracks the pushed
+            id_map: false,
+            error_policy: self.error_policy,
+            cancel: &self.cancel,
+        };
+        send_each(&ctx, items, |item| async move {
+            self.push(&item.object).await?;
+            Ok(vec![])
+        })
+        .await
+    }
+}
+
+/// Notification of the post.
+/// Posts with content warnings show only the warnings, and sensitive images are not attached.
+fn notification(topic: &str, post: &Post) -> Result<Notification> {
+    let title = match post.title() {
+        Some(title) => Some(title.to_owned()),
+        None => render_attribution(post).map(|author| to_plain(&author)),
+    };
+    let text = match post.content_warning() {
+        Some(cw) => format!("⚠️ {cw}"),
+        None => to_plain(&clean_body(&post.content)?),
+    };
+    let attach = post
+        .attachment
+        .iter()
+        .filter(|att| att.media_type.starts_with("image/") || att.r#type == "Image")
+        .filter(|att| !post.is_media_sensitive(att) && local_path(&att.url).is_none())
+        .map(|att| att.url.clone())
+        .next();
+    Ok(Notification {
+        topic: topic.to_owned(),
+        title,
+        message: excerpt(&text),
+        click: post.url.clone(),
+        attach,
+    })
+}
+
+/// Text cut to the max length at the last space if any, with an ellipsis
+fn excerpt(text: &str) -> String {
+    let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if text.chars().count() <= EXCERPT_CHARS {
+        return text;
+    }
+    let end = text.char_indices().nth(EXCERPT_CHARS - 1).unwrap().0;
+    let head = &text[..end];
+    let head = head.rfind(' ').map_or(head, |i| &head[..i]);
+    format!("{}…", head.trim_end())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::cons::tests::{create, creates, db_hooks};
+
+    #[test]
+    fn test_excerpt() {
+        assert_eq!(excerpt("a\n\nb  c"), "a b c");
+        let text = "word ".repeat(60);
+        let short = excerpt(&text);
+        assert!(short.chars().count() <= EXCERPT_CHARS);
+        assert!(short.ends_with("word…"));
+        assert_eq!(excerpt(&"a".repeat(300)).chars().count(), EXCERPT_CHARS);
+    }
+
+    #[tokio::test]
+    async fn test_send() -> Result<()> {
+        let server = MockServer::start().await;
+        let base = server.uri();
+        Mock::given(method("POST"))
+            .and(path("/"))
+            .and(header("Authorization", "Bearer tk"))
+            .and(body_partial_json(json!({
+                "topic": "posts",
+                "title": "@myl@127.0.0.1",
+                "message": "Post 100",
+                "click": format!("{base}/@myl/100"),
+                "attach": format!("{base}/media/a.png"),
+            })))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"id": "n1"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // Posts with content warnings hide the text and the sensitive media
+        Mock::given(method("POST"))
+            .and(path("/"))
+            .and(body_partial_json(json!({"message": "⚠️ spoilers"})))
+            .and(|req: &wiremock::Request| !req.body.windows(6).any(|w| w == b"attach"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(json!({"id": "n2"})))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut root = create(&base, 100, None);
+        root["object"]["attributedTo"] = json!(format!("{base}/users/myl"));
+        root["object"]["attachment"] = json!([{"type": "Document", "mediaType": "image/png", "url": format!("{base}/media/a.png")}]);
+        let mut cw = create(&base, 200, Some(100));
+        cw["object"]["summary"] = json!("spoilers");
+        cw["object"]["sensitive"] = json!(true);
+        cw["object"]["attachment"] = root["object"]["attachment"].clone();
+        let items = creates(vec![cw, root])?;
+        let (db, hooks) = db_hooks()?;
+        let con = NtfyCon::new(
+            &base,
+            "posts".to_owned(),
+            db.clone(),
+            hooks,
+            CancellationToken::new(),
+        )
+        .with_token(Some("tk".to_owned()))
+        .with_error_policy(ErrorPolicy::Fail);
+        con.send(items).await?;
+        Ok(())
+    }
+}
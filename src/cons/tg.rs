@@ -0,0 +1,1481 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Telegram consumer
+
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use ::time::OffsetDateTime;
+use anyhow::{bail, ensure, Context, Result};
+use async_trait::async_trait;
+use reqwest::Url;
+use serde::Serialize;
+use teloxide::prelude::*;
+use teloxide::requests::{Output, Payload};
+use teloxide::types::{
+    Chat, ChatKind, ChatMemberKind, ChatPublic, InputFile, InputMedia, InputMediaPhoto, MessageId,
+    ParseMode, PublicChatKind, Recipient,
+};
+use teloxide::RequestError;
+use tokio::task::JoinHandle;
+use tokio::time::{self, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use super::bots::Bots;
+use super::{Con, ErrorPolicy, IdMap};
+use crate::as2::{Activity, Create, Post};
+use crate::capture::HttpCapture;
+use crate::db::{AuditAction, AuditRecord, DbConn};
+use crate::failure::{DownloadError, FailureClass, MediaError};
+use crate::hooks::Hooks;
+use crate::labels::Labels;
+use crate::media::{download_all, file_name, local_path, placeholder_png, MediaCache, SpoolFile};
+use crate::metrics::{E2eTimer, METRICS};
+#[cfg(feature = "reencode")]
+use crate::photo::{fit_photo, PhotoOptions};
+use crate::query::{query_card, query_post, Card};
+use crate::redact::redact;
+use crate::render::{
+    render_card, render_message, render_thread_label, render_unavailable, split_text, Footer,
+    Template, CAPTION_LIMIT, TEXT_LIMIT,
+};
+#[cfg(feature = "transcode")]
+use crate::transcode::Transcoder;
+use crate::utils::{cancellable, parse_time, Cancelled, RATE_LIMIT_RETRIES};
+
+/// Max number of media in a Telegram media group
+const MEDIA_GROUP_LIMIT: usize = 10;
+
+/// Number of sent posts to save to the database at a time
+const ID_MAP_CHUNK: usize = 20;
+
+/// Downloading media of a post to be re-uploaded
+type Download = JoinHandle<Result<Vec<SpoolFile>>>;
+
+pub struct TgCon {
+    bots: Arc<Bots>,
+    tg_chan: Recipient,
+    db: DbConn,
+    hooks: Hooks,
+    capture: Option<Arc<HttpCapture>>,
+    timer: Option<E2eTimer>,
+    footer: Footer,
+    labels: Labels,
+    template: Option<Template>,
+    attribution: bool,
+    placeholder: bool,
+    topics: Topics,
+    threads: bool,
+    thread_labels: bool,
+    cards: bool,
+    reupload: Option<usize>,
+    spool_dir: PathBuf,
+    media_cache: Option<MediaCache>,
+    #[cfg(feature = "transcode")]
+    transcoder: Option<Transcoder>,
+    #[cfg(feature = "reencode")]
+    photo: Option<PhotoOptions>,
+    error_policy: ErrorPolicy,
+    cancel: CancellationToken,
+}
+
+impl TgCon {
+    /// Send to the chat, which is the `@username` or the numeric chat ID
+    pub fn new(tg_chan: &str, db: DbConn, hooks: Hooks, cancel: CancellationToken) -> Self {
+        Self {
+            bots: Bots::shared(),
+            tg_chan: recipient(tg_chan),
+            db,
+            hooks,
+            capture: None,
+            timer: None,
+            footer: Footer::default(),
+            labels: Labels::default(),
+            template: None,
+            attribution: false,
+            topics: Topics::default(),
+            threads: false,
+            thread_labels: false,
+            cards: false,
+            reupload: None,
+            spool_dir: env::temp_dir(),
+            media_cache: None,
+            #[cfg(feature = "transcode")]
+            transcoder: None,
+            #[cfg(feature = "reencode")]
+            photo: None,
+            error_policy: ErrorPolicy::default(),
+            placeholder: false,
+            cancel,
+        }
+    }
+
+    /// Send with the bots instead of the ones of the env shared by the process
+    pub fn with_bots(mut self, bots: Arc<Bots>) -> Self {
+        self.bots = bots;
+        self
+    }
+
+    /// Capture the bot API calls for debugging
+    pub fn with_capture(mut self, capture: Option<Arc<HttpCapture>>) -> Self {
+        self.capture = capture;
+        self
+    }
+
+    /// Observe the end-to-end latency of the posts
+    pub fn with_timer(mut self, timer: E2eTimer) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// Append the footer to the posts
+    pub fn with_footer(mut self, footer: Footer) -> Self {
+        self.footer = footer;
+        self
+    }
+
+    /// Labels of the generated texts in the language of the channel
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Download the media and upload them instead of letting Telegram fetch the URLs.
+    /// `reupload` is the max number of concurrent downloads of a post.
+    pub fn with_reupload(mut self, reupload: Option<usize>) -> Self {
+        self.reupload = reupload;
+        self
+    }
+
+    /// Dir to save the downloaded media to before re-uploading them
+    pub fn with_spool_dir(mut self, spool_dir: PathBuf) -> Self {
+        self.spool_dir = spool_dir;
+        self
+    }
+
+    /// Cache the downloaded media, so retried sends do not download them again
+    pub fn with_media_cache(mut self, media_cache: Option<MediaCache>) -> Self {
+        self.media_cache = media_cache;
+        self
+    }
+
+    /// Transcode videos Telegram can not preview when re-uploading
+    #[cfg(feature = "transcode")]
+    pub fn with_transcoder(mut self, transcoder: Option<Transcoder>) -> Self {
+        self.transcoder = transcoder;
+        self
+    }
+
+    /// Fit images into the Telegram photo limits when re-uploading
+    #[cfg(feature = "reencode")]
+    pub fn with_photo(mut self, photo: Option<PhotoOptions>) -> Self {
+        self.photo = photo;
+        self
+    }
+
+    /// What to do when a post fails to be sent
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Render link posts with the Mastodon preview cards
+    pub fn with_cards(mut self, cards: bool) -> Self {
+        self.cards = cards;
+        self
+    }
+
+    /// Render the posts with the template instead of the default layout
+    pub fn with_template(mut self, template: Option<Template>) -> Self {
+        self.template = template;
+        self
+    }
+
+    /// Send into the topic of the forum supergroup by its thread ID, or the general topic if `None`
+    pub fn with_topic(mut self, topic: Option<i32>) -> Self {
+        self.topics.default = topic;
+        self
+    }
+
+    /// Send posts with the hashtags into the topics instead, the first matched one for posts with multiple.
+    /// The leading `#` is optional and the match is case-insensitive.
+    pub fn with_topic_tags(mut self, topic_tags: Vec<(String, i32)>) -> Self {
+        self.topics.set_tags(topic_tags);
+        self
+    }
+
+    /// Reply to the thread roots when the replied posts are not mirrored
+    pub fn with_threads(mut self, threads: bool) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    /// Label thread continuations with their positions
+    pub fn with_thread_labels(mut self, thread_labels: bool) -> Self {
+        self.thread_labels = thread_labels;
+        self
+    }
+
+    /// Prepend the author to the posts
+    pub fn with_attribution(mut self, attribution: bool) -> Self {
+        self.attribution = attribution;
+        self
+    }
+
+    /// Send images that Telegram can not fetch as their blurhash placeholders
+    pub fn with_placeholder(mut self, placeholder: bool) -> Self {
+        self.placeholder = placeholder;
+        self
+    }
+
+    /// Topic of the forum supergroup to send the post into
+    fn topic_of(&self, post: &Post) -> Option<i32> {
+        self.topics.of(post)
+    }
+
+    /// Whether the msg is in the topic, so it can be replied to
+    async fn in_topic(&self, tg_id: &[u8], topic: Option<i32>) -> Result<bool> {
+        if !self.topics.is_set() {
+            return Ok(true);
+        }
+        Ok(self.db.query_topic(tg_id[..16].to_vec()).await? == topic)
+    }
+
+    /// Bot sending the current post
+    fn bot(&self) -> &Bot {
+        self.bots.current()
+    }
+
+    /// Send the bot API request, capturing it if enabled
+    async fn call<R>(&self, req: R) -> Result<Output<R>>
+    where
+        R: Request<Err = RequestError>,
+        R::Payload: Serialize,
+        Output<R>: Serialize,
+    {
+        let capture = match self.capture.as_deref() {
+            Some(capture) if capture.is_active() => capture,
+            _ => return Ok(req.send().await?),
+        };
+        let payload = serde_json::to_value(req.payload_ref())?;
+        let res = req.send().await;
+        let captured = match res.as_ref() {
+            Ok(output) => serde_json::to_value(output).map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        capture
+            .bot_api(<R::Payload as Payload>::NAME, payload, captured)
+            .await;
+        Ok(res?)
+    }
+
+    /// Send the bot API request of a later msg of the post,
+    /// waiting and retrying when flood controlled up to the max retries,
+    /// since the msgs sent before would be duplicated if the whole post is retried.
+    async fn call_waiting<R>(&self, req: R) -> Result<Output<R>>
+    where
+        R: Request<Err = RequestError> + Clone,
+        R::Payload: Serialize,
+        Output<R>: Serialize,
+    {
+        let mut retries = 0;
+        loop {
+            let e = match self.call(req.clone()).await {
+                Ok(output) => return Ok(output),
+                Err(e) => e,
+            };
+            let Some(&RequestError::RetryAfter(du)) = e.downcast_ref::<RequestError>() else {
+                return Err(e);
+            };
+            if retries >= RATE_LIMIT_RETRIES {
+                return Err(e);
+            }
+            retries += 1;
+            METRICS.inc_retried();
+            tracing::warn!(
+                "Retry the msg after {} seconds due to flood control",
+                du.as_secs()
+            );
+            cancellable(&self.cancel, async {
+                time::sleep(du).await;
+                Ok(())
+            })
+            .await?;
+        }
+    }
+
+    /// Delete the msgs sent for a post that then fails, so the retry does not duplicate them.
+    /// Failures are only logged.
+    async fn delete_sent(&self, sent: &[u8]) {
+        if sent.is_empty() {
+            return;
+        }
+        for (chat_id, msg_id) in de_tg_msg_ids(sent) {
+            let delete = self
+                .bot()
+                .delete_message(ChatId(chat_id), MessageId(msg_id));
+            if let Err(e) = self.call(delete).await {
+                tracing::warn!("Failed to delete the msg {msg_id} of the failed post: {e:#}");
+            }
+        }
+    }
+}
+
+macro_rules! handle_reply {
+    ($self:ident, $send:ident, $id_map:ident, $post:ident) => {{
+        let topic = $self.topic_of($post);
+        if let Some(topic) = topic {
+            $send = $send.message_thread_id(topic);
+        }
+        if let Some(tg_id) = $self.reply_target($id_map, $post).await? {
+            // Msgs can only reply to the ones in the same topic
+            if $self.in_topic(&tg_id, topic).await? {
+                let (_, msg_id) = de_tg_msg_id(&tg_id);
+                $send = $send
+                    .reply_to_message_id(MessageId(msg_id))
+                    .allow_sending_without_reply(true);
+            }
+        }
+    }};
+}
+
+impl TgCon {
+    /// Render the post with the template if set,
+    /// or with the attribution and the footer if enabled otherwise
+    fn render(&self, post: &Post) -> Result<String> {
+        let now = OffsetDateTime::now_utc();
+        match self.template.as_ref() {
+            Some(template) => template.render(post, &self.footer, &self.labels, now),
+            None => render_message(post, self.attribution, &self.footer, &self.labels, now),
+        }
+    }
+
+    /// Post a plain text notice, e.g., the round summary, to the chat.
+    /// Flood control is waited out like for the later msgs of posts.
+    pub async fn send_notice(&self, text: &str) -> Result<()> {
+        let send = self.bot().send_message(self.tg_chan.clone(), text);
+        self.call_waiting(send).await?;
+        Ok(())
+    }
+
+    /// Resolve the chat and check that all of the bots can post to it, e.g., at startup.
+    /// Bots should be admins with the right to post in channels, or not be restricted from sending in groups.
+    pub async fn check_chat(&self) -> Result<()> {
+        let chat = self.call(self.bot().get_chat(self.tg_chan.clone())).await?;
+        let kind = if chat.is_channel() {
+            "channel"
+        } else if chat.is_private() {
+            "private chat"
+        } else {
+            "group"
+        };
+        tracing::info!(
+            chat = %self.tg_chan,
+            id = chat.id.0,
+            title = chat.title().unwrap_or_default(),
+            "Resolved the {kind}"
+        );
+        if self.topics.is_set() {
+            ensure!(
+                is_forum(&chat),
+                "topics are set but {} is not a forum supergroup",
+                self.tg_chan
+            );
+        }
+        // Members of private chats are not queryable, and the bot can post unless blocked
+        if chat.is_private() {
+            return Ok(());
+        }
+        for bot in self.bots.all() {
+            let me = self.call(bot.get_me()).await?;
+            let member = self.call(bot.get_chat_member(chat.id, me.id)).await?;
+            let can_post = match &member.kind {
+                kind if chat.is_channel() => kind.can_post_messages(),
+                ChatMemberKind::Restricted(restricted) => {
+                    restricted.is_member && restricted.can_send_messages
+                }
+                kind => kind.is_present(),
+            };
+            ensure!(
+                can_post,
+                "bot @{} can not post to the {kind} {}",
+                me.username(),
+                self.tg_chan
+            );
+        }
+        Ok(())
+    }
+
+    /// Edit the msgs of the ended polls to show the final results.
+    /// Each poll is refreshed once, and a failed refresh is only logged.
+    pub async fn refresh_polls(&self) -> Result<()> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        for id in self.db.query_due_polls(now).await? {
+            let span = tracing::info_span!("poll", %id, dest = %self.tg_chan);
+            match self.refresh_poll(&id).instrument(span.clone()).await {
+                Ok(()) => tracing::info!(parent: &span, "Refreshed the poll results"),
+                Err(e) => tracing::warn!(parent: &span, "Failed to refresh the poll: {e:#}"),
+            }
+            self.db.delete_pending_poll(id).await?;
+        }
+        Ok(())
+    }
+
+    async fn refresh_poll(&self, id: &str) -> Result<()> {
+        let Some(tg_id) = self.db.query_id_map(id.to_owned()).await? else {
+            bail!("msg of the poll not found");
+        };
+        let (chat_id, msg_id) = de_tg_msg_id(&tg_id);
+        let post = query_post(id).await?;
+        let text = self.render(&post)?;
+        // Only the first msg is edited, which is cut like when sent
+        let first = |limit| split_text(&text, limit, TEXT_LIMIT).into_iter().next();
+        if !post.attachment.is_empty() {
+            let edit = self
+                .bot()
+                .edit_message_caption(ChatId(chat_id), MessageId(msg_id))
+                .caption(first(CAPTION_LIMIT).unwrap_or_default())
+                .parse_mode(ParseMode::Html);
+            match self.call(edit).await {
+                Ok(_) => return Ok(()),
+                // The post may have been sent without its media
+                Err(e) => tracing::debug!("Failed to edit the caption of the poll: {e:#}"),
+            }
+        }
+        let edit = self
+            .bot()
+            .edit_message_text(
+                ChatId(chat_id),
+                MessageId(msg_id),
+                first(TEXT_LIMIT).unwrap_or_default(),
+            )
+            .parse_mode(ParseMode::Html);
+        self.call(edit).await?;
+        Ok(())
+    }
+
+    /// Pin the msgs of the pinned posts and unpin the ones no longer pinned.
+    /// `featured` lists the post GUIDs from the latest pinned, and posts not mirrored are ignored.
+    pub async fn sync_pins(&self, featured: Vec<String>) -> Result<()> {
+        let mut wanted = Vec::new();
+        for id in featured {
+            if let Some(tg_id) = self.db.query_id_map(id.clone()).await? {
+                wanted.push((id, tg_id));
+            }
+        }
+        let pinned = self.db.query_pins().await?;
+
+        for (id, tg_id) in pinned.iter() {
+            if wanted.iter().any(|(wanted_id, _)| wanted_id == id) {
+                continue;
+            }
+            let (chat_id, msg_id) = de_tg_msg_id(tg_id);
+            let unpin = self
+                .bot()
+                .unpin_chat_message(ChatId(chat_id))
+                .message_id(MessageId(msg_id));
+            match self.call(unpin).await {
+                Ok(_) => tracing::info!(post = %id, "Unpinned the post"),
+                // E.g., the msg has been deleted
+                Err(e) => tracing::warn!(post = %id, "Failed to unpin the post: {e:#}"),
+            }
+            self.db.delete_pin(id.clone()).await?;
+        }
+        // Pinned last goes to the top in Telegram
+        for (id, tg_id) in wanted.into_iter().rev() {
+            if pinned.iter().any(|(pinned_id, _)| pinned_id == &id) {
+                continue;
+            }
+            let (chat_id, msg_id) = de_tg_msg_id(&tg_id);
+            let pin = self
+                .bot()
+                .pin_chat_message(ChatId(chat_id), MessageId(msg_id))
+                .disable_notification(true);
+            self.call(pin).await?;
+            tracing::info!(post = %id, "Pinned the post");
+            self.db.save_pin(id, tg_id).await?;
+        }
+        Ok(())
+    }
+
+    /// Position of the thread continuation in its self-thread.
+    /// The replied post is taken as the root if its position is unknown.
+    async fn thread_pos(&self, post: &Post) -> Result<Option<u32>> {
+        if !post.is_self_reply() {
+            return Ok(None);
+        }
+        let target = post.in_reply_to.clone().unwrap();
+        let pos = self.db.query_thread_pos(target).await?.unwrap_or(1);
+        Ok(Some(pos + 1))
+    }
+
+    /// Msg to reply to for replies.
+    /// Replies to posts that are not mirrored, e.g., posts of other accounts in the thread,
+    /// reply to the thread root if enabled.
+    async fn reply_target(&self, id_map: &IdMap, post: &Post) -> Result<Option<Vec<u8>>> {
+        let Some(id) = post.in_reply_to.as_ref() else {
+            return Ok(None);
+        };
+        if let Some(tg_id) = id_map.get(id) {
+            return Ok(Some(tg_id.clone()));
+        }
+        if let Some(tg_id) = self.db.query_id_map(id.to_owned()).await? {
+            return Ok(Some(tg_id));
+        }
+        match post.thread_id() {
+            Some(thread) if self.threads => self.db.query_thread_root(thread.to_owned()).await,
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the msg ID, and the error if the post is sent without its media
+    async fn send_one(
+        &self,
+        id_map: &IdMap,
+        mut act: Create,
+        thread_pos: Option<u32>,
+        download: Option<Download>,
+    ) -> Result<(Vec<u8>, Option<String>)> {
+        act.object.content = self.render(&act.object)?;
+        if let Some(pos) = thread_pos.filter(|_| self.thread_labels) {
+            act.object.content = format!("{}\n{}", render_thread_label(pos), act.object.content);
+        }
+        let (mut id, rest, mut degraded) = self.send_first(id_map, &act.object, download).await?;
+        // Long texts, e.g., of long-form posts, are continued in the following msgs
+        let topic = self.topic_of(&act.object);
+        if let Err(e) = self.send_rest(&mut id, topic, rest).await {
+            tracing::warn!("Failed to send the rest of the long post: {e:#}");
+            let e = redact(&format!("{e:#}"));
+            degraded = Some(match degraded {
+                Some(media) => format!("{media}; {e}"),
+                None => e,
+            });
+        }
+        Ok((id, degraded))
+    }
+
+    /// Send the post with the first part of the text, which is cut to fit in the msg sent.
+    /// Returns the msg ID, the rest parts of the text, and the error if the post is sent without its media.
+    async fn send_first(
+        &self,
+        id_map: &IdMap,
+        post: &Post,
+        download: Option<Download>,
+    ) -> Result<(Vec<u8>, Vec<String>, Option<String>)> {
+        if post.attachment.is_empty() {
+            if self.cards {
+                match query_card(post).await {
+                    Ok(Some(card)) => {
+                        let (id, rest) = self.send_card(id_map, post, &card).await?;
+                        return Ok((id, rest, None));
+                    }
+                    Ok(None) => (),
+                    Err(e) => tracing::warn!("Failed to query the preview card: {e:#}"),
+                }
+            }
+            let (first, rest) = first_part(post, &post.content, TEXT_LIMIT);
+            let id = self.send_text(id_map, &first).await?;
+            return Ok((id, rest, None));
+        }
+
+        let (first, rest) = first_part(post, &post.content, CAPTION_LIMIT);
+        // The spool files are removed after sent
+        let res = match media_files(&first, download).await {
+            Ok((files, _spool)) => self.send_media(id_map, &first, files).await,
+            Err(e) => Err(e),
+        };
+        match res {
+            // Dead URLs that Telegram or we fail to fetch
+            Err(e)
+                if (e.is::<RequestError>() || e.is::<DownloadError>())
+                    && FailureClass::of(&e) == FailureClass::Media =>
+            {
+                tracing::warn!("Sending the post without its media: {e:#}");
+                // The links to the media follow the whole text, which is split again for msgs
+                let text = unavailable_text(post, &self.labels);
+                let (first, rest) = first_part(post, &text, TEXT_LIMIT);
+                let id = self.send_text(id_map, &first).await?;
+                Ok((id, rest, Some(redact(&format!("{e:#}")))))
+            }
+            res => Ok((res?, rest, None)),
+        }
+    }
+
+    /// Send the rest parts of the text, each replying to the previous msg.
+    /// The sent msgs are appended to the ones of the post.
+    /// Flood control is waited out, and other failures stop sending with the number of the unsent parts,
+    /// since the post has been sent and would be duplicated if retried.
+    async fn send_rest(
+        &self,
+        sent: &mut Vec<u8>,
+        topic: Option<i32>,
+        parts: Vec<String>,
+    ) -> Result<()> {
+        let (_, mut msg_id) = de_tg_msg_id(sent);
+        let total = parts.len();
+        for (i, part) in parts.into_iter().enumerate() {
+            let mut send = self
+                .bot()
+                .send_message(self.tg_chan.clone(), part)
+                .parse_mode(ParseMode::Html)
+                .reply_to_message_id(MessageId(msg_id))
+                .allow_sending_without_reply(true);
+            if let Some(topic) = topic {
+                send = send.message_thread_id(topic);
+            }
+            let msg = self.call_waiting(send).await.with_context(|| {
+                format!("{} of {total} rest parts of the text unsent", total - i)
+            })?;
+            msg_id = msg.id.0;
+            sent.extend(ser_tg_msg_id(&msg));
+        }
+        Ok(())
+    }
+
+    /// Start downloading the media of the post to be re-uploaded if enabled
+    fn start_download(&self, post: &Post) -> Option<Download> {
+        let concurrency = self.reupload?;
+        if post.attachment.is_empty() {
+            return None;
+        }
+        let urls = post.attachment.iter().map(|att| att.url.clone()).collect();
+        let spool_dir = self.spool_dir.clone();
+        let media_cache = self.media_cache.clone();
+        #[cfg(feature = "transcode")]
+        let transcoder = self.transcoder.clone();
+        #[cfg(feature = "reencode")]
+        let photo = self.photo.clone();
+        #[cfg(any(feature = "transcode", feature = "reencode"))]
+        let media_types: Vec<_> = post
+            .attachment
+            .iter()
+            .map(|att| att.media_type.clone())
+            .collect();
+        Some(tokio::spawn(async move {
+            #[allow(unused_mut)]
+            let mut files = download_all(urls, concurrency, spool_dir.clone(), media_cache).await?;
+            // Converting failures are left to Telegram to decide
+            #[cfg(any(feature = "transcode", feature = "reencode"))]
+            for (file, media_type) in files.iter_mut().zip(media_types) {
+                #[cfg(feature = "transcode")]
+                if let Some(transcoder) = transcoder
+                    .as_ref()
+                    .filter(|_| media_type.starts_with("video/"))
+                {
+                    match transcoder.transcode(file, &spool_dir).await {
+                        Ok(Some(transcoded)) => *file = transcoded,
+                        Ok(None) => (),
+                        Err(e) => tracing::warn!("Uploading the video as is: {e:#}"),
+                    }
+                }
+                // GIFs would lose the animation
+                #[cfg(feature = "reencode")]
+                if let Some(photo) = photo
+                    .as_ref()
+                    .filter(|_| media_type.starts_with("image/") && media_type != "image/gif")
+                {
+                    match fit_photo(file, &spool_dir, photo).await {
+                        Ok(Some(fitted)) => *file = fitted,
+                        Ok(None) => (),
+                        Err(e) => tracing::warn!("Uploading the image as is: {e:#}"),
+                    }
+                }
+            }
+            Ok(files)
+        }))
+    }
+
+    async fn send_media(
+        &self,
+        id_map: &IdMap,
+        post: &Post,
+        files: Vec<InputFile>,
+    ) -> Result<Vec<u8>> {
+        if post.attachment.len() > 1 {
+            ensure!(
+                post.attachment
+                    .iter()
+                    .all(|att| att.media_type.starts_with("image/")),
+                MediaError("media type not all images for multiple media".to_owned())
+            );
+            let id = self.send_multi_grouped_images(id_map, post, files).await?;
+            return Ok(id);
+        }
+
+        let att = &post.attachment[0];
+        let media_type = &att.media_type[..att
+            .media_type
+            .find('/')
+            .ok_or(MediaError(format!("invalid media type {}", att.media_type)))?];
+        let file = files.into_iter().next().unwrap();
+        let id = match media_type {
+            "image" => match self.send_image(id_map, post, file).await {
+                Err(e)
+                    if self.placeholder
+                        && att.blurhash.is_some()
+                        && FailureClass::of(&e) == FailureClass::Media =>
+                {
+                    tracing::warn!("Sending the blurhash placeholder of the image: {e:#}");
+                    self.send_placeholder(id_map, post).await?
+                }
+                res => res?,
+            },
+            "video" => self.send_video(id_map, post, file).await?,
+            "audio" => self.send_audio(id_map, post, file).await?,
+            _ => bail!(MediaError(format!("unknown media type {}", att.media_type))),
+        };
+        Ok(id)
+    }
+
+    async fn send_text(&self, id_map: &IdMap, post: &Post) -> Result<Vec<u8>> {
+        let mut send = self
+            .bot()
+            .send_message(self.tg_chan.clone(), &post.content)
+            .parse_mode(ParseMode::Html);
+        handle_reply!(self, send, id_map, post);
+        let msg = self.call(send).await?;
+        Ok(ser_tg_msg_id(&msg))
+    }
+
+    /// Send the post with the card below instead of the Telegram link preview.
+    /// The card image is sent as the photo if any,
+    /// and the post is sent with the link preview instead if Telegram rejects the image.
+    /// Returns the msg ID and the rest parts of the text.
+    async fn send_card(
+        &self,
+        id_map: &IdMap,
+        post: &Post,
+        card: &Card,
+    ) -> Result<(Vec<u8>, Vec<String>)> {
+        let image = card.image.as_deref().map(Url::parse).transpose()?;
+        let mut parts = card_parts(&post.content, card).into_iter();
+        let first = parts.next().unwrap_or_default();
+        let msg = match image {
+            Some(image) => {
+                let mut send = self
+                    .bot()
+                    .send_photo(self.tg_chan.clone(), InputFile::url(image))
+                    .caption(first)
+                    .parse_mode(ParseMode::Html);
+                handle_reply!(self, send, id_map, post);
+                match self.call(send).await {
+                    Ok(msg) => msg,
+                    // E.g., Telegram fails to fetch the image or takes it as invalid
+                    Err(e) if FailureClass::of(&e) == FailureClass::Media => {
+                        tracing::warn!("Sending the post with the link preview instead: {e:#}");
+                        let (first, rest) = first_part(post, &post.content, TEXT_LIMIT);
+                        let id = self.send_text(id_map, &first).await?;
+                        return Ok((id, rest));
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            None => {
+                let mut send = self
+                    .bot()
+                    .send_message(self.tg_chan.clone(), first)
+                    .parse_mode(ParseMode::Html)
+                    .disable_web_page_preview(true);
+                handle_reply!(self, send, id_map, post);
+                self.call(send).await?
+            }
+        };
+        Ok((ser_tg_msg_id(&msg), parts.collect()))
+    }
+
+    /// Images more than a media group can hold are split into albums of similar sizes,
+    /// so no album has only one image.
+    /// The caption is on the first album and the rest reply to it.
+    /// The rest wait out flood control, and the sent albums are deleted if one of the rest fails.
+    async fn send_multi_grouped_images(
+        &self,
+        id_map: &IdMap,
+        post: &Post,
+        files: Vec<InputFile>,
+    ) -> Result<Vec<u8>> {
+        let albums = post.attachment.len().div_ceil(MEDIA_GROUP_LIMIT);
+        let album_size = post.attachment.len().div_ceil(albums);
+        let mut first: Option<Vec<u8>> = None;
+        let mut sent = Vec::new();
+        let media: Vec<_> = post.attachment.iter().zip(files).collect();
+        for (i, album) in media.chunks(album_size).enumerate() {
+            let photos = album
+                .iter()
+                .enumerate()
+                .map(|(j, (att, file))| {
+                    let mut photo = InputMediaPhoto::new(file.clone());
+                    if i == 0 && j == 0 {
+                        photo = photo
+                            .caption(post.content.clone())
+                            .parse_mode(ParseMode::Html);
+                    }
+                    if post.is_media_sensitive(att) {
+                        photo = photo.spoiler();
+                    }
+                    Ok(InputMedia::Photo(photo))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let mut send = self.bot().send_media_group(self.tg_chan.clone(), photos);
+            let res = match first.as_ref() {
+                None => {
+                    handle_reply!(self, send, id_map, post);
+                    self.call(send).await
+                }
+                Some(tg_id) => {
+                    let (_, msg_id) = de_tg_msg_id(tg_id);
+                    // Still sent if the first album has been deleted
+                    send = send
+                        .reply_to_message_id(MessageId(msg_id))
+                        .allow_sending_without_reply(true);
+                    if let Some(topic) = self.topic_of(post) {
+                        send = send.message_thread_id(topic);
+                    }
+                    self.call_waiting(send).await
+                }
+            };
+            let msgs = match res {
+                Ok(msgs) => msgs,
+                Err(e) => {
+                    // The whole post is sent again when retried, so the sent albums would be duplicated
+                    self.delete_sent(&sent).await;
+                    return Err(e);
+                }
+            };
+            first.get_or_insert_with(|| ser_tg_msg_id(&msgs[0]));
+            sent.extend(msgs.iter().flat_map(ser_tg_msg_id));
+        }
+        Ok(sent)
+    }
+
+    async fn send_image(&self, id_map: &IdMap, post: &Post, file: InputFile) -> Result<Vec<u8>> {
+        let att = &post.attachment[0];
+        let mut send = self
+            .bot()
+            .send_photo(self.tg_chan.clone(), file)
+            .caption(post.content.clone())
+            .parse_mode(ParseMode::Html);
+        handle_reply!(self, send, id_map, post);
+        send = send.has_spoiler(post.is_media_sensitive(att));
+        let msg = self.call(send).await?;
+        Ok(ser_tg_msg_id(&msg))
+    }
+
+    async fn send_placeholder(&self, id_map: &IdMap, post: &Post) -> Result<Vec<u8>> {
+        let att = &post.attachment[0];
+        let png = placeholder_png(att)?;
+        let mut send = self
+            .bot()
+            .send_photo(
+                self.tg_chan.clone(),
+                InputFile::memory(png).file_name("placeholder.png"),
+            )
+            .caption(post.content.clone())
+            .parse_mode(ParseMode::Html);
+        handle_reply!(self, send, id_map, post);
+        send = send.has_spoiler(post.is_media_sensitive(att));
+        let msg = self.call(send).await?;
+        Ok(ser_tg_msg_id(&msg))
+    }
+
+    async fn send_video(&self, id_map: &IdMap, post: &Post, file: InputFile) -> Result<Vec<u8>> {
+        let att = &post.attachment[0];
+        let mut send = self
+            .bot()
+            .send_video(self.tg_chan.clone(), file)
+            .caption(post.content.clone())
+            .parse_mode(ParseMode::Html);
+        handle_reply!(self, send, id_map, post);
+        send = send.has_spoiler(post.is_media_sensitive(att));
+        let msg = self.call(send).await?;
+        Ok(ser_tg_msg_id(&msg))
+    }
+
+    async fn send_audio(&self, id_map: &IdMap, post: &Post, file: InputFile) -> Result<Vec<u8>> {
+        let mut send = self
+            .bot()
+            .send_audio(self.tg_chan.clone(), file)
+            .caption(post.content.clone())
+            .parse_mode(ParseMode::Html);
+        handle_reply!(self, send, id_map, post);
+        // Telegram has no album field, which is shown in the caption instead
+        if let Some(meta) = post.track_meta() {
+            if let Some(title) = meta.title {
+                send = send.title(title);
+            }
+            if let Some(artist) = meta.artist {
+                send = send.performer(artist);
+            }
+        }
+        let msg = self.call(send).await?;
+        Ok(ser_tg_msg_id(&msg))
+    }
+}
+
+impl TgCon {
+    async fn audit(
+        &self,
+        item: &Create,
+        duration_ms: u64,
+        res: Result<(&Vec<u8>, Option<String>), &anyhow::Error>,
+    ) -> Result<()> {
+        let (action, tg_id, error) = match res {
+            Ok((tg_id, None)) => (AuditAction::Sent, Some(tg_id.clone()), None),
+            Ok((tg_id, degraded)) => (AuditAction::Degraded, Some(tg_id.clone()), degraded),
+            Err(e) => (AuditAction::Failed, None, Some(redact(&format!("{e:#}")))),
+        };
+        let mut record = AuditRecord::new(item.object.id.clone(), action);
+        record.dest = Some(self.tg_chan.to_string());
+        record.tg_id = tg_id;
+        record.duration_ms = Some(duration_ms);
+        record.error = error;
+        self.db.append_audit(record.clone()).await?;
+        // Failed posts are retried by the next round under the fail policy, so only the given up ones fire
+        if record.action != AuditAction::Failed || self.error_policy != ErrorPolicy::Fail {
+            self.hooks.fire(&record).await;
+        }
+        Ok(())
+    }
+
+    /// Send the posts in order.
+    /// The sent are saved to the database every chunk, so they are kept even if a long send fails.
+    async fn send_all(
+        &self,
+        items: Vec<Create>,
+        id_map: &mut IdMap,
+        unsaved: &mut IdMap,
+    ) -> Result<()> {
+        let mut queue: VecDeque<_> = items.into_iter().rev().collect();
+        let mut next_download: Option<(String, Download)> = None;
+        while !queue.is_empty() {
+            let item = if let Some(x) = queue.pop_front() {
+                x
+            } else {
+                break;
+            };
+            // Sent before a failure of the page, which is retried as a whole
+            if let Some(tg_id) = self.db.query_id_map(item.object.id.clone()).await? {
+                tracing::info!(post = %item.object.id, "Skipped the post already sent");
+                id_map.insert(item.object.id.clone(), tg_id);
+                continue;
+            }
+
+            let span = tracing::info_span!("post", id = %item.object.id, dest = %self.tg_chan);
+            // Posts are sent by the bots in turn
+            if let Some(wait) = self.bots.rotate() {
+                cancellable(&self.cancel, async {
+                    time::sleep(wait).await;
+                    Ok(())
+                })
+                .await?;
+            }
+            let thread_pos = self.thread_pos(&item.object).await?;
+            let download = match next_download.take() {
+                Some((id, download)) if id == item.object.id => Some(download),
+                Some((_, download)) => {
+                    download.abort();
+                    self.start_download(&item.object)
+                }
+                None => self.start_download(&item.object),
+            };
+            // Pre-fetch the media of the next post while the post is sent
+            next_download = queue.front().and_then(|next| {
+                let download = self.start_download(&next.object)?;
+                Some((next.object.id.clone(), download))
+            });
+            let start = Instant::now();
+            let res = cancellable(
+                &self.cancel,
+                self.send_one(id_map, item.clone(), thread_pos, download),
+            )
+            .instrument(span.clone())
+            .await;
+            let elapsed_ms = start.elapsed().as_millis() as u64;
+            match res {
+                Err(e) => {
+                    if let Some(RequestError::RetryAfter(du)) = e.downcast_ref::<RequestError>() {
+                        METRICS.inc_retried();
+                        FailureClass::RateLimit.record(&self.db).await?;
+                        tracing::warn!(
+                            parent: &span,
+                            elapsed_ms,
+                            "Retry after {} seconds due to flood control",
+                            du.as_secs()
+                        );
+                        // Retried by another bot, or after the time if all are blocked
+                        self.bots.block(*du);
+                        queue.push_front(item);
+                        continue;
+                    }
+                    if e.is::<Cancelled>() {
+                        return Err(e);
+                    }
+                    METRICS.inc_failed();
+                    FailureClass::of(&e).record(&self.db).await?;
+                    tracing::error!(parent: &span, elapsed_ms, "Failed to send the post: {e:#}");
+                    self.audit(&item, elapsed_ms, Err(&e)).await?;
+                    match self.error_policy {
+                        ErrorPolicy::Fail => return Err(e),
+                        ErrorPolicy::Skip => (),
+                        ErrorPolicy::DeadLetter => {
+                            let act = serde_json::to_string(&Activity::Create(item.clone()))?;
+                            let error = redact(&format!("{e:#}"));
+                            self.db
+                                .save_dead_letter(item.object.id.clone(), act, error)
+                                .await?;
+                        }
+                    }
+                }
+                Ok((tg_id, degraded)) => {
+                    METRICS.observe_sent(elapsed_ms);
+                    if let Some(timer) = self.timer.as_ref() {
+                        timer.observe(&item.object.id);
+                    }
+                    tracing::info!(parent: &span, elapsed_ms, "Sent the post");
+                    self.audit(&item, elapsed_ms, Ok((&tg_id, degraded)))
+                        .await?;
+                    // The first mirrored post of the thread is taken as the root
+                    if let Some(thread) = item.object.thread_id() {
+                        self.db
+                            .save_thread_root(thread.to_owned(), tg_id.clone())
+                            .await?;
+                    }
+                    // Open polls are refreshed after they end
+                    let poll_end = item
+                        .object
+                        .end_time
+                        .as_deref()
+                        .filter(|_| item.object.is_poll());
+                    if let Some(end_at) = poll_end.and_then(parse_time) {
+                        if end_at > OffsetDateTime::now_utc() {
+                            self.db
+                                .save_pending_poll(item.object.id.clone(), end_at.unix_timestamp())
+                                .await?;
+                        }
+                    }
+                    if let Some(pos) = thread_pos {
+                        self.db.save_thread_pos(item.object.id.clone(), pos).await?;
+                    }
+                    if let Some(topic) = self.topic_of(&item.object) {
+                        self.db.save_topic(tg_id[..16].to_vec(), topic).await?;
+                    }
+                    id_map.insert(item.object.id.clone(), tg_id.clone());
+                    unsaved.insert(item.object.id.clone(), tg_id);
+                    if unsaved.len() >= ID_MAP_CHUNK {
+                        self.db.save_id_map(mem::take(unsaved)).await?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Con for TgCon {
+    async fn send(&self, items: Vec<Create>) -> Result<IdMap> {
+        let mut id_map = HashMap::new();
+        let mut unsaved = HashMap::new();
+        let res = self.send_all(items, &mut id_map, &mut unsaved).await;
+        // Saved even if the rest fail, so the sent are not sent again
+        self.db.save_id_map(unsaved).await?;
+        res?;
+        Ok(id_map)
+    }
+
+    async fn remove(&self, ids: Vec<String>) -> Result<()> {
+        for id in ids {
+            let Some(tg_id) = self.db.query_id_map(id.clone()).await? else {
+                tracing::info!(post = %id, "Ignored the removal of the post not mirrored");
+                continue;
+            };
+            for (chat_id, msg_id) in de_tg_msg_ids(&tg_id) {
+                let delete = self
+                    .bot()
+                    .delete_message(ChatId(chat_id), MessageId(msg_id));
+                match cancellable(&self.cancel, self.call(delete)).await {
+                    Ok(_) => (),
+                    Err(e) if e.is::<Cancelled>() => return Err(e),
+                    // E.g., the msg has been deleted by hand
+                    Err(e) => tracing::warn!(post = %id, "Failed to delete the msg: {e:#}"),
+                }
+            }
+            tracing::info!(post = %id, "Deleted the msgs of the post");
+        }
+        Ok(())
+    }
+}
+
+/// Files of the attachments.
+/// Uploaded from the spool files if re-uploading, or fetched by Telegram from the URLs otherwise.
+/// The spool files have to be kept until the files are sent.
+async fn media_files(
+    post: &Post,
+    download: Option<Download>,
+) -> Result<(Vec<InputFile>, Vec<SpoolFile>)> {
+    let Some(download) = download else {
+        let files = post
+            .attachment
+            .iter()
+            .map(|att| match local_path(&att.url) {
+                // Telegram can not fetch local media, e.g., of archives
+                Some(path) => Ok(InputFile::file(path).file_name(file_name(&att.url))),
+                None => Ok(InputFile::url(Url::parse(&att.url)?)),
+            })
+            .collect::<Result<_>>()?;
+        return Ok((files, vec![]));
+    };
+    let spool = download.await??;
+    let files = post
+        .attachment
+        .iter()
+        .zip(spool.iter())
+        .map(|(att, file)| {
+            let mut name = PathBuf::from(file_name(&att.url));
+            // Converted media, e.g., transcoded videos, come with their own extensions
+            if let Some(ext) = file.path().extension() {
+                name.set_extension(ext);
+            }
+            InputFile::file(file.path()).file_name(name.to_string_lossy().into_owned())
+        })
+        .collect();
+    Ok((files, spool))
+}
+
+/// Post with the text cut to the first part fitting in the limit, and the rest parts of the text
+fn first_part(post: &Post, text: &str, limit: usize) -> (Post, Vec<String>) {
+    let mut parts = split_text(text, limit, TEXT_LIMIT).into_iter();
+    let mut first = post.clone();
+    first.content = parts.next().unwrap_or_default();
+    (first, parts.collect())
+}
+
+/// Text of the post with the links to the media below, for the post sent without its media
+fn unavailable_text(post: &Post, labels: &Labels) -> String {
+    format!("{}\n\n{}", post.content, render_unavailable(post, labels))
+}
+
+/// Parts of the text with the card below.
+/// The first part fits in a caption if the card has an image to be sent as the photo.
+fn card_parts(text: &str, card: &Card) -> Vec<String> {
+    let limit = match card.image {
+        Some(_) => CAPTION_LIMIT,
+        None => TEXT_LIMIT,
+    };
+    split_text(
+        &format!("{text}\n\n{}", render_card(card)),
+        limit,
+        TEXT_LIMIT,
+    )
+}
+
+/// Topics of the forum supergroup to send posts into
+#[derive(Default)]
+struct Topics {
+    /// Thread ID of the topic, or the general topic if `None`
+    default: Option<i32>,
+    /// Lowercase hashtags without the leading `#`, with the thread IDs of their topics
+    tags: Vec<(String, i32)>,
+}
+
+impl Topics {
+    fn set_tags(&mut self, tags: Vec<(String, i32)>) {
+        self.tags = tags
+            .into_iter()
+            .map(|(tag, topic)| (tag.trim_start_matches('#').to_lowercase(), topic))
+            .collect();
+    }
+
+    fn is_set(&self) -> bool {
+        self.default.is_some() || !self.tags.is_empty()
+    }
+
+    /// Topic of the post by the first matched hashtag, or the default one
+    fn of(&self, post: &Post) -> Option<i32> {
+        let names: Vec<_> = post
+            .hashtag_names()
+            .iter()
+            .map(|name| name.to_lowercase())
+            .collect();
+        self.tags
+            .iter()
+            .find(|(tag, _)| names.contains(tag))
+            .map(|(_, topic)| *topic)
+            .or(self.default)
+    }
+}
+
+/// Whether the chat is a supergroup with topics enabled
+fn is_forum(chat: &Chat) -> bool {
+    match &chat.kind {
+        ChatKind::Public(ChatPublic {
+            kind: PublicChatKind::Supergroup(supergroup),
+            ..
+        }) => supergroup.is_forum,
+        _ => false,
+    }
+}
+
+/// Recipient of the numeric chat ID, or the `@username` otherwise
+fn recipient(chat: &str) -> Recipient {
+    match chat.parse::<i64>() {
+        Ok(id) => Recipient::Id(ChatId(id)),
+        Err(_) => Recipient::ChannelUsername(chat.to_owned()),
+    }
+}
+
+/// Get the GUID from a Telegram msg
+pub fn ser_tg_msg_id(msg: &Message) -> Vec<u8> {
+    let chat_id = msg.chat.id.0;
+    let msg_id = msg.id.0 as i64;
+    [chat_id.to_be_bytes(), msg_id.to_be_bytes()].concat()
+}
+
+/// Extract the chat ID and msg ID of the first msg from the Telegram msg GUIDs of a post
+pub fn de_tg_msg_id(id: &[u8]) -> (i64, i32) {
+    de_tg_msg_ids(id)[0]
+}
+
+/// Extract the chat IDs and msg IDs of all msgs of a post, e.g., of the albums or the long text.
+/// They are saved as the concatenated GUIDs from the first msg.
+pub fn de_tg_msg_ids(id: &[u8]) -> Vec<(i64, i32)> {
+    assert!(!id.is_empty() && id.len().is_multiple_of(16));
+    id.chunks(16)
+        .map(|id| {
+            let chat_id = i64::from_be_bytes(id[..8].try_into().unwrap());
+            let msg_id = i64::from_be_bytes(id[8..].try_into().unwrap()) as i32;
+            (chat_id, msg_id)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::matchers::{body_string_contains, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::check_de;
+    use crate::cons::tests::{create, creates, db_hooks};
+
+    const CHAT_ID: i64 = -1001234567890;
+
+    /// Consumer sending with the bot of the mock server
+    fn mock_con(server: &MockServer) -> Result<TgCon> {
+        // The shared bots of the env are replaced but still built
+        std::env::set_var("TELOXIDE_TOKEN", "token");
+        let (db, hooks) = db_hooks()?;
+        let bot = Bot::new("token").set_api_url(Url::parse(&server.uri())?);
+        Ok(
+            TgCon::new(&CHAT_ID.to_string(), db, hooks, CancellationToken::new())
+                .with_bots(Arc::new(Bots::new(vec![bot])))
+                .with_error_policy(ErrorPolicy::Fail),
+        )
+    }
+
+    /// Bot API response of the sent msg
+    fn msg(msg_id: i32) -> serde_json::Value {
+        json!({
+            "message_id": msg_id,
+            "date": 0,
+            "chat": {"id": CHAT_ID, "type": "channel", "title": "MyGO"},
+            "text": "sent",
+        })
+    }
+
+    fn ok(result: serde_json::Value) -> ResponseTemplate {
+        ResponseTemplate::new(200).set_body_json(json!({"ok": true, "result": result}))
+    }
+
+    fn flood_controlled() -> ResponseTemplate {
+        ResponseTemplate::new(429).set_body_json(json!({
+            "ok": false,
+            "error_code": 429,
+            "description": "Too Many Requests: retry after 1",
+            "parameters": {"retry_after": 1},
+        }))
+    }
+
+    #[test]
+    fn test_topics() -> Result<()> {
+        let post = check_de!(Post, "post_tag");
+        let mut topics = Topics::default();
+        assert!(!topics.is_set());
+        assert_eq!(topics.of(&post), None);
+        topics.default = Some(1);
+        assert!(topics.is_set());
+        assert_eq!(topics.of(&post), Some(1));
+        topics.set_tags(vec![("#Other".to_owned(), 2), ("#MyGO".to_owned(), 3)]);
+        assert_eq!(
+            topics.tags,
+            [("other".to_owned(), 2), ("mygo".to_owned(), 3)]
+        );
+        assert_eq!(topics.of(&post), Some(3));
+        assert_eq!(topics.of(&check_de!(Post, "post_text")), Some(1));
+        Ok(())
+    }
+
+    #[test]
+    fn test_card_parts() {
+        let text = "<p>".to_owned() + &"word ".repeat(800) + "</p>";
+        let mut card = Card {
+            url: "https://a.example".to_owned(),
+            title: "Card".to_owned(),
+            description: String::new(),
+            image: Some("https://a.example/card.png".to_owned()),
+        };
+        let parts = card_parts(&text, &card);
+        assert!(parts[0].chars().count() <= CAPTION_LIMIT);
+        assert!(parts[1..].iter().all(|p| p.chars().count() <= TEXT_LIMIT));
+        assert!(parts.last().unwrap().ends_with("<b>Card</b></a>"));
+        card.image = None;
+        assert_eq!(card_parts(&text, &card).len(), 1);
+    }
+
+    #[test]
+    fn test_unavailable_text() -> Result<()> {
+        let mut post = check_de!(Post, "post_text");
+        post.content = "word ".repeat(800);
+        let att = check_de!(Post, "post_multi_grouped_images").attachment[0].clone();
+        post.attachment = vec![att.clone(); 20];
+        let text = unavailable_text(&post, &Labels::default());
+        let (first, rest) = first_part(&post, &text, TEXT_LIMIT);
+        assert!(first.content.chars().count() <= TEXT_LIMIT);
+        assert!(!rest.is_empty());
+        // The links follow the whole text instead of the first part
+        assert!(!first.content.contains(&att.url));
+        assert!(rest.last().unwrap().contains(&att.url));
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_forum() -> Result<()> {
+        let chat = |extra: serde_json::Value| -> Result<Chat> {
+            let mut chat = serde_json::json!({"id": -1001234567890i64, "title": "MyGO"});
+            chat.as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            Ok(serde_json::from_value(chat)?)
+        };
+        assert!(is_forum(&chat(
+            serde_json::json!({"type": "supergroup", "is_forum": true})
+        )?));
+        assert!(!is_forum(&chat(serde_json::json!({"type": "supergroup"}))?));
+        assert!(!is_forum(&chat(serde_json::json!({"type": "channel"}))?));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_notice_flood_controlled() -> Result<()> {
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/bottoken/SendMessage"))
+            .respond_with(flood_controlled())
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/bottoken/SendMessage"))
+            .and(body_string_contains("Round summary"))
+            .respond_with(ok(msg(1)))
+            .expect(1)
+            .mount(&server)
+            .await;
+        mock_con(&server)?.send_notice("Round summary").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_albums_flood_controlled() -> Result<()> {
+        let server = MockServer::start().await;
+        // The rest album is flood controlled once and then sent
+        Mock::given(method("POST"))
+            .and(path("/bottoken/SendMediaGroup"))
+            .and(body_string_contains("reply_to_message_id"))
+            .respond_with(flood_controlled())
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/bottoken/SendMediaGroup"))
+            .and(body_string_contains("reply_to_message_id"))
+            .respond_with(ok(json!((7..=11).map(msg).collect::<Vec<_>>())))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // The first album is not sent again
+        Mock::given(method("POST"))
+            .and(path("/bottoken/SendMediaGroup"))
+            .respond_with(ok(json!((1..=6).map(msg).collect::<Vec<_>>())))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let att = check_de!(Post, "post_multi_grouped_images").attachment[0].clone();
+        let mut items = creates(vec![create(&server.uri(), 100, None)])?;
+        items[0].object.attachment = vec![att; 11];
+        let id_map = mock_con(&server)?.send(items).await?;
+        let tg_id = id_map.values().next().unwrap();
+        assert_eq!(de_tg_msg_ids(tg_id).len(), 11);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_send_rest_flood_controlled() -> Result<()> {
+        let server = MockServer::start().await;
+        let send_msg = || Mock::given(method("POST")).and(path("/bottoken/SendMessage"));
+        send_msg()
+            .and(body_string_contains("bravo"))
+            .respond_with(flood_controlled())
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        send_msg()
+            .and(body_string_contains("bravo"))
+            .respond_with(ok(msg(2)))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // The last part fails, which leaves the post sent in part
+        send_msg()
+            .and(body_string_contains("charlie"))
+            .respond_with(ResponseTemplate::new(400).set_body_json(json!({
+                "ok": false,
+                "error_code": 400,
+                "description": "Bad Request: message is too long",
+            })))
+            .expect(1)
+            .mount(&server)
+            .await;
+        send_msg()
+            .respond_with(ok(msg(1)))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let mut item = create(&server.uri(), 100, None);
+        item["object"]["content"] = json!(["alpha", "bravo", "charlie"]
+            .map(|word| format!(
+                "<p>{}</p>",
+                format!("{word} ").repeat(3000 / (word.len() + 1))
+            ))
+            .concat());
+        let con = mock_con(&server)?;
+        let id_map = con.send(creates(vec![item])?).await?;
+        let tg_id = id_map.values().next().unwrap();
+        assert_eq!(de_tg_msg_ids(tg_id), [(CHAT_ID, 1), (CHAT_ID, 2)]);
+        let status = format!("{}/users/myl/statuses/100", server.uri());
+        let record = con.db.query_audit(status).await?.pop().unwrap();
+        assert_eq!(record.action, AuditAction::Degraded);
+        assert!(record.error.unwrap().contains("1 of 2 rest parts"));
+        Ok(())
+    }
+}
@@ -0,0 +1,219 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Generic webhook consumer.
+//! Each post is POSTed as the JSON of its activity, the same as `--output print` prints,
+//! so downstream systems can take the posts without a dedicated consumer.
+//! Removals are POSTed as `Delete` activities.
+//!
+//! With a secret, the body is signed with HMAC-SHA256 in `X-Mastotg-Signature: sha256=<hex>`.
+
+use std::env;
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use super::{send_each, Con, ConCtx, ErrorPolicy, IdMap};
+use crate::as2::{Activity, Create, Delete};
+use crate::db::DbConn;
+use crate::hooks::Hooks;
+use crate::http;
+use crate::metrics::{E2eTimer, METRICS};
+use crate::redact::redact;
+use crate::utils::{cancellable, check_res, sign, HttpStatusError};
+
+/// Env var of the secret to sign the bodies with
+pub const WEBHOOK_SECRET_ENV: &str = "WEBHOOK_SECRET";
+
+/// Header of the body signature
+pub const SIGNATURE_HEADER: &str = "X-Mastotg-Signature";
+
+/// Times to retry a request failed by a server error or the network
+const MAX_RETRIES: u32 = 3;
+
+/// Wait before the first retry, which is doubled for each next one
+const RETRY_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Destination name in audit records and logs, instead of the URL that may carry tokens
+const DEST: &str = "webhook";
+
+pub struct WebhookCon {
+    url: String,
+    secret: Option<String>,
+    db: DbConn,
+    hooks: Hooks,
+    timer: Option<E2eTimer>,
+    error_policy: ErrorPolicy,
+    cancel: CancellationToken,
+}
+
+impl WebhookCon {
+    /// The secret is read from `WEBHOOK_SECRET` if set
+    pub fn new(url: String, db: DbConn, hooks: Hooks, cancel: CancellationToken) -> Self {
+        Self {
+            url,
+            secret: env::var(WEBHOOK_SECRET_ENV).ok(),
+            db,
+            hooks,
+            timer: None,
+            error_policy: ErrorPolicy::default(),
+            cancel,
+        }
+    }
+
+    /// Sign the bodies with the secret, or not if `None`
+    pub fn with_secret(mut self, secret: Option<String>) -> Self {
+        self.secret = secret;
+        self
+    }
+
+    /// Observe the end-to-end latency of the sent posts
+    pub fn with_timer(mut self, timer: E2eTimer) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// What to do when a post fails to be sent
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// POST the activity, retrying server errors and network failures with backoff
+    async fn post(&self, act: &Activity) -> Result<()> {
+        let body = serde_json::to_vec(act)?;
+        let mut backoff = RETRY_BACKOFF;
+        let mut retries = 0;
+        loop {
+            let mut req = http::client()
+                .post(&self.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .body(body.clone());
+            if let Some(secret) = self.secret.as_ref() {
+                req = req.header(SIGNATURE_HEADER, sign(secret, &body));
+            }
+            let res = async { check_res(req.send().await?).await }.await;
+            match res {
+                Ok(_) => return Ok(()),
+                Err(e) if retries < MAX_RETRIES && retryable(&e) => {
+                    retries += 1;
+                    METRICS.inc_retried();
+                    tracing::warn!(
+                        "Retry in {} ms due to the failed request: {}",
+                        backoff.as_millis(),
+                        redact(&format!("{e:#}"))
+                    );
+                    time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Con for WebhookCon {
+    async fn send(&self, items: Vec<Create>) -> Result<IdMap> {
+        let ctx = ConCtx {
+            db: &self.db,
+            hooks: &self.hooks,
+            timer: self.timer.as_ref(),
+            dest: DEST,
+            // Nothing to map the posts to, and the state tracks the sent
+            id_map: false,
+            error_policy: self.error_policy,
+            cancel: &self.cancel,
+        };
+        send_each(&ctx, items, |item| async move {
+            self.post(&Activity::Create(item)).await?;
+            Ok(vec![])
+        })
+        .await
+    }
+
+    async fn remove(&self, ids: Vec<String>) -> Result<()> {
+        for id in ids {
+            let act = Activity::Delete(Delete::of(id.clone()));
+            cancellable(&self.cancel, self.post(&act)).await?;
+            tracing::info!(post = %id, "Sent the removal of the post");
+        }
+        Ok(())
+    }
+}
+
+/// Whether the failure is worth retrying, i.e., a server error or a network failure
+fn retryable(e: &anyhow::Error) -> bool {
+    if let Some(e) = e.downcast_ref::<HttpStatusError>() {
+        return e.status >= 500;
+    }
+    e.downcast_ref::<reqwest::Error>()
+        .is_some_and(|e| e.is_connect() || e.is_timeout())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use wiremock::matchers::{body_partial_json, header, method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::cons::tests::{create, creates, db_hooks};
+    use crate::db::AuditAction;
+
+    #[tokio::test]
+    async fn test_send() -> Result<()> {
+        let server = MockServer::start().await;
+        let base = server.uri();
+        let items = creates(vec![create(&base, 100, None)])?;
+        let body = serde_json::to_vec(&Activity::Create(items[0].clone()))?;
+        // A server error once, and then retried
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(1)
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .and(header(SIGNATURE_HEADER, sign("s3cret", &body).as_str()))
+            .and(body_partial_json(
+                json!({"type": "Create", "object": {"content": "<p>Post 100</p>"}}),
+            ))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .and(body_partial_json(
+                json!({"type": "Delete", "object": format!("{base}/users/myl/statuses/200")}),
+            ))
+            .respond_with(ResponseTemplate::new(204))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let (db, hooks) = db_hooks()?;
+        let con = WebhookCon::new(
+            format!("{base}/hook"),
+            db.clone(),
+            hooks,
+            CancellationToken::new(),
+        )
+        .with_secret(Some("s3cret".to_owned()));
+        con.send(items).await?;
+        con.remove(vec![format!("{base}/users/myl/statuses/200")])
+            .await?;
+        let records = db
+            .query_audit(format!("{base}/users/myl/statuses/100"))
+            .await?;
+        assert_eq!(records[0].action, AuditAction::Sent);
+        assert_eq!(records[0].dest.as_deref(), Some("webhook"));
+        Ok(())
+    }
+}
@@ -0,0 +1,1149 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! XMPP consumer.
+//! Posts are sent as messages to the JID, or to the MUC room joined with the nick, from the account as a client.
+//! The text is converted into plain text like for Mastodon.
+//! Media are uploaded via HTTP File Upload (XEP-0363) of the server and sent as OOB messages after the text,
+//! or linked in the text if failed to upload.
+//! If a media message fails, the sent messages of the post are retracted, so the retried post is not duplicated.
+//! Message IDs are saved in the ID map, so replies to mirrored posts are sent as replies (XEP-0461),
+//! and removals retract the messages (XEP-0424).
+//! The stream is kept across sends and removals until the consumer is closed.
+//!
+//! Only the part of the protocol a bot needs is implemented: STARTTLS, SASL PLAIN, and resource binding.
+//! The server is connected at port 5222 of the JID domain, as SRV records are not looked up.
+
+use std::env;
+use std::fmt;
+use std::net::IpAddr;
+use std::time::Duration;
+
+use ::time::OffsetDateTime;
+use anyhow::{anyhow, bail, Result};
+use async_trait::async_trait;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use quick_xml::escape::escape;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio::time;
+use tokio_native_tls::{native_tls, TlsConnector};
+use tokio_util::sync::CancellationToken;
+
+use super::{send_each, Con, ConCtx, ErrorPolicy, IdMap};
+use crate::as2::{Create, Document, Post};
+use crate::db::DbConn;
+use crate::hooks::Hooks;
+use crate::http;
+use crate::labels::Labels;
+use crate::media::{file_name, local_path};
+use crate::metrics::E2eTimer;
+use crate::render::{render_message, to_plain, Footer};
+use crate::utils::{cancellable, check_res, fnv1a};
+
+/// Env var of the password of the account
+pub const XMPP_PASSWORD_ENV: &str = "XMPP_PASSWORD";
+
+/// Wait for the server to connect or to respond
+const TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Wait for the room to reflect the sent message, after which the own message ID is used as the reference
+const REFLECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Max size of the unparsed data from the server, in case of a malformed stream. Unit: Bytes.
+const MAX_BUFFERED: usize = 1 << 20;
+
+/// Resource bound if the JID has none
+const DEFAULT_RESOURCE: &str = "mastotg";
+
+const NS_TLS: &str = "urn:ietf:params:xml:ns:xmpp-tls";
+const NS_SASL: &str = "urn:ietf:params:xml:ns:xmpp-sasl";
+const NS_BIND: &str = "urn:ietf:params:xml:ns:xmpp-bind";
+const NS_MUC: &str = "http://jabber.org/protocol/muc";
+const NS_DISCO_ITEMS: &str = "http://jabber.org/protocol/disco#items";
+const NS_DISCO_INFO: &str = "http://jabber.org/protocol/disco#info";
+const NS_UPLOAD: &str = "urn:xmpp:http:upload:0";
+const NS_PING: &str = "urn:xmpp:ping";
+const NS_RETRACT: &str = "urn:xmpp:message-retract:1";
+
+pub struct XmppCon {
+    jid: String,
+    password: String,
+    to: String,
+    /// Nick to join the room with, or `None` if the target is not a room
+    nick: Option<String>,
+    /// `host:port` of the server, or `None` to use the JID domain
+    server: Option<String>,
+    db: DbConn,
+    hooks: Hooks,
+    timer: Option<E2eTimer>,
+    footer: Footer,
+    labels: Labels,
+    attribution: bool,
+    error_policy: ErrorPolicy,
+    cancel: CancellationToken,
+    /// Stream kept until closed, or `None` if not connected yet or broken
+    session: Mutex<Option<Session>>,
+}
+
+impl XmppCon {
+    /// Send to the JID from the account JID, e.g., `bot@example.org`, with the password
+    pub fn new(
+        jid: String,
+        password: String,
+        to: String,
+        db: DbConn,
+        hooks: Hooks,
+        cancel: CancellationToken,
+    ) -> Self {
+        Self {
+            jid,
+            password,
+            to,
+            nick: None,
+            server: None,
+            db,
+            hooks,
+            timer: None,
+            footer: Footer::default(),
+            labels: Labels::default(),
+            attribution: false,
+            error_policy: ErrorPolicy::default(),
+            cancel,
+            session: Mutex::new(None),
+        }
+    }
+
+    /// Take the password from `XMPP_PASSWORD`
+    pub fn from_env(
+        jid: String,
+        to: String,
+        db: DbConn,
+        hooks: Hooks,
+        cancel: CancellationToken,
+    ) -> Result<Self> {
+        let password =
+            env::var(XMPP_PASSWORD_ENV).map_err(|_| anyhow!("{XMPP_PASSWORD_ENV} is not set"))?;
+        Ok(Self::new(jid, password, to, db, hooks, cancel))
+    }
+
+    /// Join the target as a MUC room with the nick, or send to it directly if `None`
+    pub fn with_room(mut self, nick: Option<String>) -> Self {
+        self.nick = nick;
+        self
+    }
+
+    /// Connect to the server at `host:port` instead of the JID domain, or the domain if `None`
+    pub fn with_server(mut self, server: Option<String>) -> Self {
+        self.server = server;
+        self
+    }
+
+    /// Observe the end-to-end latency of the sent posts
+    pub fn with_timer(mut self, timer: E2eTimer) -> Self {
+        self.timer = Some(timer);
+        self
+    }
+
+    /// Append the footer to the posts
+    pub fn with_footer(mut self, footer: Footer) -> Self {
+        self.footer = footer;
+        self
+    }
+
+    /// Generated texts in the language
+    pub fn with_labels(mut self, labels: Labels) -> Self {
+        self.labels = labels;
+        self
+    }
+
+    /// Prepend the author to the posts
+    pub fn with_attribution(mut self, attribution: bool) -> Self {
+        self.attribution = attribution;
+        self
+    }
+
+    /// What to do when a post fails to be sent
+    pub fn with_error_policy(mut self, error_policy: ErrorPolicy) -> Self {
+        self.error_policy = error_policy;
+        self
+    }
+
+    /// Connect and log in to the server, and join the room if any
+    async fn connect(&self) -> Result<Session> {
+        let (bare, resource) = match self.jid.split_once('/') {
+            Some((bare, resource)) => (bare, resource),
+            None => (self.jid.as_str(), DEFAULT_RESOURCE),
+        };
+        let (local, domain) = bare
+            .split_once('@')
+            .ok_or(anyhow!("invalid JID {}", self.jid))?;
+        let addr = self.server.clone().unwrap_or(format!("{domain}:5222"));
+        let tcp = time::timeout(TIMEOUT, TcpStream::connect(&addr))
+            .await
+            .map_err(|_| anyhow!("timed out connecting to {addr}"))??;
+        let mut session = Session::new(tcp);
+
+        let mut features = session.open(domain).await?;
+        if features.child("starttls").is_some() {
+            session
+                .write(&format!("<starttls xmlns='{NS_TLS}'/>"))
+                .await?;
+            let res = session.next_stanza().await?;
+            if res.name != "proceed" {
+                bail!("failed to start TLS");
+            }
+            let tls = TlsConnector::from(native_tls::TlsConnector::new()?);
+            let Io::Tcp(tcp) = session.io else {
+                unreachable!()
+            };
+            session.io = Io::Tls(Box::new(tls.connect(domain, tcp).await?));
+            features = session.open(domain).await?;
+        } else if !is_loopback(&addr) {
+            // Never send the password in plain text out of the host
+            bail!("{addr} does not offer STARTTLS");
+        }
+
+        let plain = features
+            .child("mechanisms")
+            .is_some_and(|mechs| mechs.children.iter().any(|mech| mech.text == "PLAIN"));
+        if !plain {
+            bail!("{addr} does not offer SASL PLAIN");
+        }
+        let auth = BASE64.encode(format!("\0{local}\0{}", self.password));
+        session
+            .write(&format!(
+                "<auth xmlns='{NS_SASL}' mechanism='PLAIN'>{auth}</auth>"
+            ))
+            .await?;
+        let res = session.next_stanza().await?;
+        if res.name != "success" {
+            bail!("failed to log in as {bare}: {}", condition_of(&res));
+        }
+
+        session.open(domain).await?;
+        let res = session
+            .iq(
+                "set",
+                None,
+                &format!(
+                    "<bind xmlns='{NS_BIND}'><resource>{}</resource></bind>",
+                    escape(resource)
+                ),
+            )
+            .await?;
+        session.jid = res
+            .child("bind")
+            .and_then(|bind| bind.child("jid"))
+            .map(|jid| jid.text.clone())
+            .ok_or(anyhow!("no bound JID"))?;
+        session.domain = domain.to_owned();
+        tracing::info!("Connected to XMPP as {}", session.jid);
+
+        if let Some(nick) = self.nick.as_ref() {
+            session.join(&self.to, nick).await?;
+        }
+        Ok(session)
+    }
+
+    /// Upload the media via HTTP File Upload.
+    /// Returns the URL to get it.
+    async fn upload(&self, session: &mut Session, att: &Document) -> Result<String> {
+        let body = match local_path(&att.url) {
+            Some(path) => tokio::fs::read(path).await?,
+            None => {
+                let res = check_res(http::client().get(&att.url).send().await?).await?;
+                res.bytes().await?.to_vec()
+            }
+        };
+        let service = session.upload_service().await?;
+        let slot = session
+            .iq(
+                "get",
+                Some(&service),
+                &format!(
+                    "<request xmlns='{NS_UPLOAD}' filename='{}' size='{}' content-type='{}'/>",
+                    escape(&file_name(&att.url)),
+                    body.len(),
+                    escape(&att.media_type)
+                ),
+            )
+            .await?;
+        let slot = slot.child("slot").ok_or(anyhow!("no upload slot"))?;
+        let put = slot.child("put").ok_or(anyhow!("no upload slot"))?;
+        let get = slot
+            .child("get")
+            .and_then(|get| get.attr("url"))
+            .ok_or(anyhow!("no upload slot"))?;
+        let mut req = http::client()
+            .put(put.attr("url").ok_or(anyhow!("no upload slot"))?)
+            .header(reqwest::header::CONTENT_TYPE, &att.media_type)
+            .body(body);
+        // Only the headers allowed by XEP-0363 are taken
+        for header in put.children.iter().filter(|child| child.name == "header") {
+            let name = header.attr("name").unwrap_or_default();
+            if ["authorization", "cookie", "expires"].contains(&name.to_lowercase().as_str()) {
+                req = req.header(name, header.text.trim());
+            }
+        }
+        check_res(req.send().await?).await?;
+        Ok(get.to_owned())
+    }
+
+    /// Send the message with the extra elements.
+    /// Returns the ID to refer to it, which is the one assigned by the room if any.
+    async fn send_message(&self, session: &mut Session, body: &str, extra: &str) -> Result<String> {
+        let id = session.next_id();
+        let kind = match self.nick {
+            Some(_) => "groupchat",
+            None => "chat",
+        };
+        session
+            .write(&format!(
+                "<message to='{}' type='{kind}' id='{id}'><body>{}</body>\
+                 <origin-id xmlns='urn:xmpp:sid:0' id='{id}'/>{extra}</message>",
+                escape(&self.to),
+                escape(body)
+            ))
+            .await?;
+        match self.nick {
+            Some(_) => session.reflected(&self.to, &id).await,
+            None => Ok(id),
+        }
+    }
+
+    /// Send the post as the text message followed by the media messages.
+    /// The IDs of the sent messages are pushed to `sent`, the text one first, which are kept if a later one fails.
+    async fn send_one(
+        &self,
+        session: &mut Session,
+        post: &Post,
+        sent: &mut Vec<String>,
+    ) -> Result<()> {
+        let text = render_message(
+            post,
+            self.attribution,
+            &self.footer,
+            &self.labels,
+            OffsetDateTime::now_utc(),
+        )?;
+        let mut text = to_plain(&text);
+        let mut urls = Vec::new();
+        for att in post.attachment.iter() {
+            match self.upload(session, att).await {
+                Ok(url) => urls.push(url),
+                Err(e) => {
+                    tracing::warn!("Linking the media {} instead: {e:#}", att.url);
+                    text = format!("{text}\n{}: {}", self.labels.media_unavailable, att.url);
+                }
+            }
+        }
+
+        let reply = match post.in_reply_to.as_ref() {
+            Some(id) => self.db.query_id_map(id.clone()).await?.and_then(|ids| {
+                serde_json::from_slice::<Vec<String>>(&ids)
+                    .ok()?
+                    .into_iter()
+                    .next()
+            }),
+            None => None,
+        };
+        let extra = match reply {
+            Some(reply) => {
+                // Replies refer to the author, who is the account itself or its occupant in the room
+                let author = match self.nick.as_ref() {
+                    Some(nick) => format!("{}/{nick}", self.to),
+                    None => bare_of(&session.jid).to_owned(),
+                };
+                format!(
+                    "<reply xmlns='urn:xmpp:reply:0' to='{}' id='{}'/>",
+                    escape(&author),
+                    escape(&reply)
+                )
+            }
+            None => String::new(),
+        };
+        sent.push(self.send_message(session, &text, &extra).await?);
+        for url in urls {
+            let oob = format!("<x xmlns='jabber:x:oob'><url>{}</url></x>", escape(&url));
+            sent.push(self.send_message(session, &url, &oob).await?);
+        }
+        Ok(())
+    }
+
+    /// Retract the message by the ID to refer to it
+    async fn retract(&self, session: &mut Session, msg_id: &str) -> Result<()> {
+        let retract = format!(
+            "<retract xmlns='{NS_RETRACT}' id='{}'/>\
+             <fallback xmlns='urn:xmpp:fallback:0' for='{NS_RETRACT}'/>\
+             <store xmlns='urn:xmpp:hints'/>",
+            escape(msg_id)
+        );
+        self.send_message(session, "Retracted a message", &retract)
+            .await?;
+        Ok(())
+    }
+
+    /// Retract the messages sent for a post that then fails, so the retry does not duplicate them.
+    /// The server is connected again if the stream is broken, and failures are only logged.
+    async fn retract_sent(&self, session: &mut Option<Session>, sent: &[String]) {
+        let res = async {
+            let session = match session.as_mut() {
+                Some(session) => session,
+                None => session.insert(cancellable(&self.cancel, self.connect()).await?),
+            };
+            for msg_id in sent {
+                cancellable(&self.cancel, self.retract(session, msg_id)).await?;
+            }
+            Ok::<_, anyhow::Error>(())
+        }
+        .await;
+        if let Err(e) = res {
+            tracing::warn!("Failed to retract the messages of the failed post: {e:#}");
+        }
+    }
+}
+
+#[async_trait]
+impl Con for XmppCon {
+    async fn send(&self, items: Vec<Create>) -> Result<IdMap> {
+        let dest = format!("xmpp:{}", self.to);
+        let ctx = ConCtx {
+            db: &self.db,
+            hooks: &self.hooks,
+            timer: self.timer.as_ref(),
+            dest: &dest,
+            id_map: true,
+            error_policy: self.error_policy,
+            cancel: &self.cancel,
+        };
+        send_each(&ctx, items, |item| async move {
+            let mut session = self.session.lock().await;
+            // Connected again if the stream of the previous post broke
+            let stream = match session.as_mut() {
+                Some(stream) => stream,
+                None => session.insert(self.connect().await?),
+            };
+            let mut sent = Vec::new();
+            let res = self.send_one(stream, &item.object, &mut sent).await;
+            if let Err(e) = res {
+                if !e.is::<StanzaError>() {
+                    stream.close().await;
+                    *session = None;
+                }
+                // The whole post is sent again when retried, so the sent messages would be duplicated
+                if !sent.is_empty() {
+                    self.retract_sent(&mut session, &sent).await;
+                }
+                return Err(e);
+            }
+            Ok(serde_json::to_vec(&sent)?)
+        })
+        .await
+    }
+
+    async fn remove(&self, ids: Vec<String>) -> Result<()> {
+        let mut session = self.session.lock().await;
+        for id in ids {
+            let mirror = self.db.query_id_map(id.clone()).await?;
+            let Some(msg_ids) = mirror.and_then(|m| serde_json::from_slice::<Vec<String>>(&m).ok())
+            else {
+                tracing::info!(post = %id, "Ignored the removal of the post not mirrored");
+                continue;
+            };
+            let session = match session.as_mut() {
+                Some(session) => session,
+                None => session.insert(cancellable(&self.cancel, self.connect()).await?),
+            };
+            for msg_id in msg_ids {
+                cancellable(&self.cancel, self.retract(session, &msg_id)).await?;
+            }
+            tracing::info!(post = %id, "Retracted the messages of the post");
+        }
+        Ok(())
+    }
+
+    async fn close(&self) {
+        if let Some(mut session) = self.session.lock().await.take() {
+            session.close().await;
+        }
+    }
+}
+
+/// Connection to the server, which is upgraded to TLS in place
+enum Io {
+    Tcp(TcpStream),
+    Tls(Box<tokio_native_tls::TlsStream<TcpStream>>),
+}
+
+impl Io {
+    async fn read_buf(&mut self, buf: &mut Vec<u8>) -> std::io::Result<usize> {
+        match self {
+            Io::Tcp(io) => io.read_buf(buf).await,
+            Io::Tls(io) => io.read_buf(buf).await,
+        }
+    }
+
+    async fn write_all(&mut self, data: &[u8]) -> std::io::Result<()> {
+        match self {
+            Io::Tcp(io) => write_all(io, data).await,
+            Io::Tls(io) => write_all(io, data).await,
+        }
+    }
+
+    async fn shutdown(&mut self) -> std::io::Result<()> {
+        match self {
+            Io::Tcp(io) => io.shutdown().await,
+            Io::Tls(io) => io.shutdown().await,
+        }
+    }
+}
+
+async fn write_all(io: &mut (impl AsyncWrite + Unpin), data: &[u8]) -> std::io::Result<()> {
+    io.write_all(data).await?;
+    io.flush().await
+}
+
+/// Error stanza the server replies with.
+/// The stream is still usable after it, unlike after other failures, e.g., of the connection.
+#[derive(Debug)]
+struct StanzaError(String);
+
+impl fmt::Display for StanzaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for StanzaError {}
+
+/// Logged-in client stream
+struct Session {
+    io: Io,
+    /// Received but not parsed yet
+    buf: Vec<u8>,
+    /// Full JID bound by the server
+    jid: String,
+    domain: String,
+    /// JID of the upload service once discovered
+    upload: Option<String>,
+    /// Prefix of the stanza IDs, which are unique across sessions
+    id_prefix: String,
+    id_seq: u64,
+}
+
+impl Session {
+    fn new(tcp: TcpStream) -> Self {
+        let now = OffsetDateTime::now_utc().unix_timestamp_nanos();
+        Self {
+            io: Io::Tcp(tcp),
+            buf: Vec::new(),
+            jid: String::new(),
+            domain: String::new(),
+            upload: None,
+            id_prefix: format!("mastotg-{:x}", fnv1a(&now.to_le_bytes())),
+            id_seq: 0,
+        }
+    }
+
+    fn next_id(&mut self) -> String {
+        self.id_seq += 1;
+        format!("{}-{}", self.id_prefix, self.id_seq)
+    }
+
+    async fn write(&mut self, xml: &str) -> Result<()> {
+        self.io.write_all(xml.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Open the stream, which is required again after TLS and SASL.
+    /// Returns the stream features.
+    async fn open(&mut self, domain: &str) -> Result<Element> {
+        self.buf.clear();
+        self.write(&format!(
+            "<?xml version='1.0'?><stream:stream xmlns='jabber:client' \
+             xmlns:stream='http://etherx.jabber.org/streams' to='{}' version='1.0'>",
+            escape(domain)
+        ))
+        .await?;
+        loop {
+            let el = self.next_stanza().await?;
+            if el.name == "features" {
+                return Ok(el);
+            }
+        }
+    }
+
+    /// Read the next top-level element, answering pings on the way
+    async fn next_stanza(&mut self) -> Result<Element> {
+        loop {
+            let el = self.read().await?;
+            match el.name.as_str() {
+                "error" => bail!("XMPP stream error: {}", condition_of(&el)),
+                "iq" if el.child("ping").and_then(|ping| ping.attr("xmlns")) == Some(NS_PING) => {
+                    let id = escape(el.attr("id").unwrap_or_default()).into_owned();
+                    let to = escape(el.attr("from").unwrap_or_default()).into_owned();
+                    self.write(&format!("<iq type='result' id='{id}' to='{to}'/>"))
+                        .await?;
+                }
+                _ => return Ok(el),
+            }
+        }
+    }
+
+    async fn read(&mut self) -> Result<Element> {
+        loop {
+            if let Some((el, len)) = parse_element(&self.buf)? {
+                self.buf.drain(..len);
+                return Ok(el);
+            }
+            if self.buf.len() > MAX_BUFFERED {
+                bail!("too large XMPP stanza");
+            }
+            let n = time::timeout(TIMEOUT, self.io.read_buf(&mut self.buf))
+                .await
+                .map_err(|_| anyhow!("timed out waiting for the XMPP server"))??;
+            if n == 0 {
+                bail!("the XMPP server closed the connection");
+            }
+        }
+    }
+
+    /// Send the IQ with the payload and wait for the result
+    async fn iq(&mut self, kind: &str, to: Option<&str>, payload: &str) -> Result<Element> {
+        let id = self.next_id();
+        let to = to.map_or(String::new(), |to| format!(" to='{}'", escape(to)));
+        self.write(&format!("<iq type='{kind}' id='{id}'{to}>{payload}</iq>"))
+            .await?;
+        loop {
+            let el = self.next_stanza().await?;
+            if el.name != "iq" || el.attr("id") != Some(&id) {
+                continue;
+            }
+            return match el.attr("type") {
+                Some("result") => Ok(el),
+                _ => Err(StanzaError(format!("XMPP request failed: {}", condition_of(&el))).into()),
+            };
+        }
+    }
+
+    /// Join the room and wait for the own presence
+    async fn join(&mut self, room: &str, nick: &str) -> Result<()> {
+        let occupant = format!("{room}/{nick}");
+        self.write(&format!(
+            "<presence to='{}'><x xmlns='{NS_MUC}'><history maxstanzas='0'/></x></presence>",
+            escape(&occupant)
+        ))
+        .await?;
+        loop {
+            let el = self.next_stanza().await?;
+            if el.name != "presence" || bare_of(el.attr("from").unwrap_or_default()) != room {
+                continue;
+            }
+            if el.attr("type") == Some("error") {
+                bail!("failed to join {room}: {}", condition_of(&el));
+            }
+            // Status 110 marks the own presence, which comes after those of the other occupants
+            let own = el
+                .children
+                .iter()
+                .any(|x| x.name == "x" && x.children.iter().any(|s| s.attr("code") == Some("110")));
+            if own {
+                tracing::info!("Joined the XMPP room {room} as {nick}");
+                return Ok(());
+            }
+        }
+    }
+
+    /// Wait for the room to reflect the message.
+    /// Returns the stanza ID the room assigns, which replies and retractions in rooms refer to.
+    async fn reflected(&mut self, room: &str, id: &str) -> Result<String> {
+        let wait = async {
+            loop {
+                let el = self.next_stanza().await?;
+                if el.name != "message" || el.attr("id") != Some(id) {
+                    continue;
+                }
+                if el.attr("type") == Some("error") {
+                    bail!(StanzaError(format!(
+                        "failed to send to {room}: {}",
+                        condition_of(&el)
+                    )));
+                }
+                let stanza_id = el
+                    .children
+                    .iter()
+                    .find(|child| child.name == "stanza-id" && child.attr("by") == Some(room))
+                    .and_then(|child| child.attr("id"));
+                return Ok(stanza_id.unwrap_or(id).to_owned());
+            }
+        };
+        match time::timeout(REFLECT_TIMEOUT, wait).await {
+            Ok(res) => res,
+            Err(_) => {
+                tracing::warn!("The XMPP room {room} did not reflect the message {id}");
+                Ok(id.to_owned())
+            }
+        }
+    }
+
+    /// Discover the upload service among the items of the server
+    async fn upload_service(&mut self) -> Result<String> {
+        if let Some(service) = self.upload.clone() {
+            return Ok(service);
+        }
+        let domain = self.domain.clone();
+        let items = self
+            .iq(
+                "get",
+                Some(&domain),
+                &format!("<query xmlns='{NS_DISCO_ITEMS}'/>"),
+            )
+            .await?;
+        let jids: Vec<_> = items
+            .child("query")
+            .map(|query| {
+                let items = query.children.iter();
+                items
+                    .filter_map(|item| Some(item.attr("jid")?.to_owned()))
+                    .collect()
+            })
+            .unwrap_or_default();
+        for jid in jids {
+            let payload = format!("<query xmlns='{NS_DISCO_INFO}'/>");
+            let Ok(info) = self.iq("get", Some(&jid), &payload).await else {
+                continue;
+            };
+            let supported = info.child("query").is_some_and(|query| {
+                let features = query.children.iter();
+                features
+                    .filter(|feature| feature.name == "feature")
+                    .any(|feature| feature.attr("var") == Some(NS_UPLOAD))
+            });
+            if supported {
+                self.upload = Some(jid.clone());
+                return Ok(jid);
+            }
+        }
+        bail!("no HTTP upload service on {domain}")
+    }
+
+    /// End the stream, ignoring failures since the messages are sent anyway
+    async fn close(&mut self) {
+        if self.write("</stream:stream>").await.is_ok() {
+            let _ = self.io.shutdown().await;
+        }
+    }
+}
+
+/// Element of the stream with the names without prefixes
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Element {
+    name: String,
+    attrs: Vec<(String, String)>,
+    children: Vec<Element>,
+    text: String,
+}
+
+impl Element {
+    fn from_start(start: &BytesStart) -> Result<Self> {
+        let mut attrs = Vec::new();
+        for attr in start.attributes() {
+            let attr = attr?;
+            let key = String::from_utf8_lossy(attr.key.as_ref()).into_owned();
+            attrs.push((key, attr.unescape_value()?.into_owned()));
+        }
+        Ok(Self {
+            name: String::from_utf8_lossy(start.local_name().as_ref()).into_owned(),
+            attrs,
+            ..Default::default()
+        })
+    }
+
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attrs
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|child| child.name == name)
+    }
+}
+
+/// Parse the first complete top-level element of the buffer.
+/// Returns it and the length of the buffer it takes, or `None` if more data are required.
+/// The stream header is taken as an element without children, since it is only closed at the end.
+fn parse_element(buf: &[u8]) -> Result<Option<(Element, usize)>> {
+    let mut reader = Reader::from_reader(buf);
+    reader.check_end_names(false);
+    let mut stack: Vec<Element> = Vec::new();
+    loop {
+        let event = match reader.read_event() {
+            Ok(event) => event,
+            // Stanzas split across reads are cut at the end
+            Err(quick_xml::Error::UnexpectedEof(_)) => return Ok(None),
+            Err(e) => bail!("invalid XMPP stream: {e}"),
+        };
+        let el = match event {
+            Event::Start(start) => {
+                let el = Element::from_start(&start)?;
+                if stack.is_empty() && el.name == "stream" {
+                    return Ok(Some((el, reader.buffer_position())));
+                }
+                stack.push(el);
+                continue;
+            }
+            Event::Empty(start) => Element::from_start(&start)?,
+            Event::End(_) => stack
+                .pop()
+                .ok_or(anyhow!("the XMPP server closed the stream"))?,
+            Event::Text(text) => {
+                if let Some(top) = stack.last_mut() {
+                    match text.unescape() {
+                        Ok(text) => top.text += &text,
+                        Err(_) if reader.buffer_position() >= buf.len() => return Ok(None),
+                        Err(e) => bail!("invalid XMPP stream: {e}"),
+                    }
+                }
+                continue;
+            }
+            Event::CData(data) => {
+                if let Some(top) = stack.last_mut() {
+                    top.text += &String::from_utf8_lossy(&data);
+                }
+                continue;
+            }
+            Event::Eof => return Ok(None),
+            _ => continue,
+        };
+        match stack.last_mut() {
+            Some(parent) => parent.children.push(el),
+            None => return Ok(Some((el, reader.buffer_position()))),
+        }
+    }
+}
+
+/// Defined condition of the stanza, stream, or SASL error
+fn condition_of(el: &Element) -> &str {
+    let el = el.child("error").unwrap_or(el);
+    el.children
+        .iter()
+        .find(|child| child.name != "text")
+        .map_or("unknown", |child| child.name.as_str())
+}
+
+fn bare_of(jid: &str) -> &str {
+    jid.split_once('/').map_or(jid, |(bare, _)| bare)
+}
+
+fn is_loopback(addr: &str) -> bool {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    host == "localhost" || host.parse::<IpAddr>().is_ok_and(|ip| ip.is_loopback())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpListener, TcpStream};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    use crate::cons::tests::{create, creates, db_hooks};
+
+    #[test]
+    fn test_parse_element() -> Result<()> {
+        let buf = b"<?xml version='1.0'?><stream:stream from='a.example' id='s'>\
+                    <stream:features><mechanisms xmlns='m'><mechanism>PLAIN</mechanism></mechanisms></stream:features>\
+                    <iq type='result' id='1'><bind><jid>bot@a.ex";
+        let (stream, len) = parse_element(buf)?.unwrap();
+        assert_eq!(
+            (stream.name.as_str(), stream.attr("id")),
+            ("stream", Some("s"))
+        );
+        let (features, n) = parse_element(&buf[len..])?.unwrap();
+        let mechs = features.child("mechanisms").unwrap();
+        assert_eq!(mechs.children[0].text, "PLAIN");
+        // Incomplete until closed
+        assert_eq!(parse_element(&buf[len + n..])?, None);
+        assert_eq!(parse_element(b"<message to='a&amp")?, None);
+        assert!(parse_element(b"</stream:stream>").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_loopback() {
+        assert!(is_loopback("127.0.0.1:5222"));
+        assert!(is_loopback("[::1]:5222"));
+        assert!(is_loopback("localhost:5222"));
+        assert!(!is_loopback("example.org:5222"));
+    }
+
+    /// Read the socket until the marker, and take the data up to it from the buffer
+    async fn read_until(sock: &mut TcpStream, buf: &mut String, marker: &str) -> Option<String> {
+        loop {
+            if let Some(i) = buf.find(marker) {
+                let rest = buf.split_off(i + marker.len());
+                return Some(std::mem::replace(buf, rest));
+            }
+            let mut chunk = [0; 4096];
+            match sock.read(&mut chunk).await.ok()? {
+                0 => return None,
+                n => buf.push_str(std::str::from_utf8(&chunk[..n]).unwrap()),
+            }
+        }
+    }
+
+    /// Serve a session of the XMPP client, which joins the room and gets its messages reflected.
+    /// The connection is dropped instead of reflecting the message at `drop_at` if given.
+    /// An upload slot of the HTTP server at `upload` is given after the join if given.
+    /// Returns the messages received.
+    async fn serve_xmpp(
+        mut sock: TcpStream,
+        drop_at: Option<usize>,
+        upload: Option<String>,
+    ) -> Vec<String> {
+        let header = "<?xml version='1.0'?><stream:stream xmlns='jabber:client' \
+                      xmlns:stream='http://etherx.jabber.org/streams' id='s' from='localhost' version='1.0'>";
+        let id_re = regex::Regex::new(r"id='([^']+)'").unwrap();
+        let mut buf = String::new();
+        read_until(&mut sock, &mut buf, "version='1.0'>")
+            .await
+            .unwrap();
+        let features = "<stream:features><mechanisms xmlns='urn:ietf:params:xml:ns:xmpp-sasl'>\
+                        <mechanism>PLAIN</mechanism></mechanisms></stream:features>";
+        sock.write_all(format!("{header}{features}").as_bytes())
+            .await
+            .unwrap();
+        let auth = read_until(&mut sock, &mut buf, "</auth>").await.unwrap();
+        assert!(auth.ends_with(">AGJvdABwdw==</auth>"));
+        sock.write_all(b"<success xmlns='urn:ietf:params:xml:ns:xmpp-sasl'/>")
+            .await
+            .unwrap();
+        read_until(&mut sock, &mut buf, "version='1.0'>")
+            .await
+            .unwrap();
+        let features =
+            "<stream:features><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'/></stream:features>";
+        sock.write_all(format!("{header}{features}").as_bytes())
+            .await
+            .unwrap();
+        let bind = read_until(&mut sock, &mut buf, "</iq>").await.unwrap();
+        let id = &id_re.captures(&bind).unwrap()[1];
+        let res = format!(
+            "<iq type='result' id='{id}'><bind xmlns='urn:ietf:params:xml:ns:xmpp-bind'>\
+             <jid>bot@localhost/mastotg</jid></bind></iq>"
+        );
+        sock.write_all(res.as_bytes()).await.unwrap();
+        let join = read_until(&mut sock, &mut buf, "</presence>")
+            .await
+            .unwrap();
+        assert!(join.contains("to='room@conf.localhost/mastotg'"));
+        let res = "<presence from='room@conf.localhost/other'/>\
+                   <presence from='room@conf.localhost/mastotg'><x xmlns='http://jabber.org/protocol/muc#user'>\
+                   <status code='110'/></x></presence>";
+        sock.write_all(res.as_bytes()).await.unwrap();
+        if let Some(upload) = upload {
+            let items =
+                format!("<query xmlns='{NS_DISCO_ITEMS}'><item jid='upload.localhost'/></query>");
+            let info =
+                format!("<query xmlns='{NS_DISCO_INFO}'><feature var='{NS_UPLOAD}'/></query>");
+            let slot = format!(
+                "<slot xmlns='{NS_UPLOAD}'><put url='{upload}/put'/><get url='{upload}/get'/></slot>"
+            );
+            for payload in [items, info, slot] {
+                let iq = read_until(&mut sock, &mut buf, "</iq>").await.unwrap();
+                let id = &id_re.captures(&iq).unwrap()[1];
+                let res = format!("<iq type='result' id='{id}'>{payload}</iq>");
+                sock.write_all(res.as_bytes()).await.unwrap();
+            }
+        }
+        let mut msgs = Vec::new();
+        while let Some(msg) = read_until(&mut sock, &mut buf, "</message>").await {
+            if drop_at == Some(msgs.len()) {
+                msgs.push(msg);
+                break;
+            }
+            let id = &id_re.captures(&msg).unwrap()[1];
+            let res = format!(
+                "<message from='room@conf.localhost/mastotg' type='groupchat' id='{id}'><body/>\
+                 <stanza-id xmlns='urn:xmpp:sid:0' by='room@conf.localhost' id='sid-{id}'/></message>"
+            );
+            sock.write_all(res.as_bytes()).await.unwrap();
+            msgs.push(msg);
+        }
+        msgs
+    }
+
+    #[tokio::test]
+    async fn test_send_room() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server = listener.local_addr()?.to_string();
+        let serve = tokio::spawn(async move {
+            let (sock, _) = listener.accept().await.unwrap();
+            serve_xmpp(sock, None, None).await
+        });
+
+        let base = "https://mastodon.example";
+        let items = creates(vec![create(base, 200, Some(100)), create(base, 100, None)])?;
+        let (db, hooks) = db_hooks()?;
+        let con = XmppCon::new(
+            "bot@localhost".to_owned(),
+            "pw".to_owned(),
+            "room@conf.localhost".to_owned(),
+            db.clone(),
+            hooks,
+            CancellationToken::new(),
+        )
+        .with_room(Some("mastotg".to_owned()))
+        .with_server(Some(server))
+        .with_error_policy(ErrorPolicy::Fail);
+        con.send(items.clone()).await?;
+        let status = |id| format!("{base}/users/myl/statuses/{id}");
+        let ids: Vec<String> =
+            serde_json::from_slice(&db.query_id_map(status(100)).await?.unwrap())?;
+        assert_eq!(ids.len(), 1);
+        assert!(ids[0].starts_with("sid-mastotg-"));
+        // Posts sent before a retried page failed are not sent again
+        assert_eq!(con.send(items).await?.len(), 2);
+        con.remove(vec![status(100)]).await?;
+        con.close().await;
+
+        // The removal is sent on the stream of the posts
+        let msgs = serve.await?;
+        let [root, reply, retract] = &msgs[..] else {
+            panic!("unexpected messages {msgs:?}");
+        };
+        assert!(root.contains("type='groupchat'") && root.contains("<body>Post 100"));
+        assert!(!root.contains("<reply"));
+        // Replies in rooms refer to the stanza IDs assigned by the room
+        assert!(reply.contains(&format!(
+            "<reply xmlns='urn:xmpp:reply:0' to='room@conf.localhost/mastotg' id='{}'/>",
+            ids[0]
+        )));
+        assert!(retract.contains(&format!(
+            "<retract xmlns='urn:xmpp:message-retract:1' id='{}'/>",
+            ids[0]
+        )));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reconnect() -> Result<()> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server = listener.local_addr()?.to_string();
+        let serve = tokio::spawn(async move {
+            let mut sessions = Vec::new();
+            for drop_at in [Some(0), None] {
+                let (sock, _) = listener.accept().await.unwrap();
+                sessions.push(serve_xmpp(sock, drop_at, None).await);
+            }
+            sessions
+        });
+
+        let base = "https://mastodon.example";
+        let items = creates(vec![create(base, 200, None), create(base, 100, None)])?;
+        let (db, hooks) = db_hooks()?;
+        let con = XmppCon::new(
+            "bot@localhost".to_owned(),
+            "pw".to_owned(),
+            "room@conf.localhost".to_owned(),
+            db.clone(),
+            hooks,
+            CancellationToken::new(),
+        )
+        .with_room(Some("mastotg".to_owned()))
+        .with_server(Some(server))
+        .with_error_policy(ErrorPolicy::Skip);
+        // The connection drops while sending the first post, and the next post is sent on a new one
+        let id_map = con.send(items).await?;
+        let status = |id| format!("{base}/users/myl/statuses/{id}");
+        assert!(!id_map.contains_key(&status(100)));
+        assert!(id_map.contains_key(&status(200)));
+        con.close().await;
+
+        let sessions = serve.await?;
+        assert!(sessions[0][0].contains("<body>Post 100"));
+        assert!(sessions[1][0].contains("<body>Post 200"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stream_dropped_after_text() -> Result<()> {
+        let http = MockServer::start().await;
+        Mock::given(method("GET"))
+            .and(path("/media.png"))
+            .respond_with(ResponseTemplate::new(200).set_body_bytes(b"png".to_vec()))
+            .mount(&http)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path("/put"))
+            .respond_with(ResponseTemplate::new(201))
+            .expect(1)
+            .mount(&http)
+            .await;
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let server = listener.local_addr()?.to_string();
+        let upload = http.uri();
+        let serve = tokio::spawn(async move {
+            let mut sessions = Vec::new();
+            // The stream drops after the text message is reflected
+            for (drop_at, upload) in [(Some(1), Some(upload)), (None, None)] {
+                let (sock, _) = listener.accept().await.unwrap();
+                sessions.push(serve_xmpp(sock, drop_at, upload).await);
+            }
+            sessions
+        });
+
+        let base = "https://mastodon.example";
+        let mut item = create(base, 100, None);
+        item["object"]["attachment"] = serde_json::json!([{
+            "type": "Document",
+            "mediaType": "image/png",
+            "url": format!("{}/media.png", http.uri()),
+        }]);
+        let (db, hooks) = db_hooks()?;
+        let con = XmppCon::new(
+            "bot@localhost".to_owned(),
+            "pw".to_owned(),
+            "room@conf.localhost".to_owned(),
+            db.clone(),
+            hooks,
+            CancellationToken::new(),
+        )
+        .with_room(Some("mastotg".to_owned()))
+        .with_server(Some(server))
+        .with_error_policy(ErrorPolicy::Fail);
+        assert!(con.send(creates(vec![item])?).await.is_err());
+        let status = format!("{base}/users/myl/statuses/100");
+        assert!(db.query_id_map(status).await?.is_none());
+        con.close().await;
+
+        // The text message is retracted on a new stream, so the retry does not duplicate it
+        let sessions = serve.await?;
+        let [text, media] = &sessions[0][..] else {
+            panic!("unexpected messages {:?}", sessions[0]);
+        };
+        assert!(text.contains("<body>Post 100"));
+        assert!(media.contains("<x xmlns='jabber:x:oob'>"));
+        let text_id = regex::Regex::new(r"id='([^']+)'")
+            .unwrap()
+            .captures(text)
+            .unwrap()[1]
+            .to_owned();
+        let [retract] = &sessions[1][..] else {
+            panic!("unexpected messages {:?}", sessions[1]);
+        };
+        assert!(retract.contains(&format!(
+            "<retract xmlns='urn:xmpp:message-retract:1' id='sid-{text_id}'/>"
+        )));
+        Ok(())
+    }
+}
@@ -0,0 +1,142 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Cursors marking the newest handled activity in the outbox.
+//!
+//! Mastodon GUIDs end with numeric IDs that the outbox can be queried by with `min_id`.
+//! Other servers like GoToSocial and Mitra use opaque GUIDs,
+//! so their activities are ordered by the published time instead.
+
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::as2::Activity;
+use crate::utils::{int_id, parse_time};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor {
+    /// GUID of the activity
+    pub id: String,
+    /// When the activity was published, or the post was last edited.
+    /// `None` for activities without timestamps like `Delete`.
+    pub published: Option<OffsetDateTime>,
+}
+
+impl Cursor {
+    pub fn new(id: String, published: Option<&str>) -> Self {
+        Self {
+            id,
+            published: published.and_then(parse_time),
+        }
+    }
+
+    /// Cursor of the activity
+    pub fn of(act: &Activity) -> Self {
+        let published = match act {
+            Activity::Create(act) => Some(&act.object.published),
+            Activity::Update(act) => act.object.updated.as_ref(),
+            Activity::Announce(act) => act.published.as_ref(),
+            Activity::Delete(_) | Activity::Unknown(_) => None,
+        };
+        Self::new(act.id().to_owned(), published.map(|s| s.as_str()))
+    }
+
+    /// Mastodon integer ID as the fast path
+    pub fn int_id(&self) -> Option<i64> {
+        int_id(&self.id).ok()
+    }
+
+    /// Cursor of the newest activity that can be ordered of the page listed from the newest,
+    /// or of the first one if none can be
+    pub fn latest(items: &[Activity]) -> Option<Self> {
+        let mut cursors = items.iter().map(Self::of);
+        let first = cursors.next()?;
+        if first.is_orderable() {
+            return Some(first);
+        }
+        Some(cursors.find(Self::is_orderable).unwrap_or(first))
+    }
+
+    /// Whether the cursor can be ordered by the integer ID or the time
+    pub fn is_orderable(&self) -> bool {
+        self.int_id().is_some() || self.published.is_some()
+    }
+
+    /// Whether the activity of the cursor comes after the other.
+    /// Integer IDs are compared first if both have them, and then the times.
+    /// Activities that can not be ordered are not taken as newer.
+    pub fn is_after(&self, other: &Cursor) -> bool {
+        if self.id == other.id {
+            return false;
+        }
+        if let (Some(a), Some(b)) = (self.int_id(), other.int_id()) {
+            return a > b;
+        }
+        match (self.published, other.published) {
+            (Some(a), Some(b)) => a > b,
+            _ => false,
+        }
+    }
+
+    /// RFC3339 text of the published time to be saved
+    pub fn published_text(&self) -> Option<String> {
+        self.published.and_then(|t| t.format(&Rfc3339).ok())
+    }
+}
+
+/// Keep the activities of the page listed from the newest that come after the cursor.
+/// Activities that can not be ordered, e.g., `Delete`s without timestamps,
+/// are kept only if listed before the first handled one.
+/// All are kept if the cursor itself can not be ordered.
+pub fn retain_after(items: &mut Vec<Activity>, cursor: &Cursor) {
+    if !cursor.is_orderable() {
+        return;
+    }
+    let mut reached = false;
+    items.retain(|item| {
+        let item = Cursor::of(item);
+        if !item.is_orderable() {
+            return !reached;
+        }
+        let after = item.is_after(cursor);
+        reached |= !after;
+        after
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cursor_order() {
+        let gts = |id: &str, published| Cursor::new(format!("https://gts.example/{id}"), published);
+        let old = gts("01H7Z", Some("2023-08-03T16:09:19Z"));
+        let new = gts("01H80", Some("2023-08-03T18:09:19+02:00"));
+        let newer = gts("01H81", Some("2023-08-03T16:09:20.5Z"));
+        assert_eq!(old.int_id(), None);
+        assert!(newer.is_after(&old));
+        assert!(!new.is_after(&old));
+        assert!(!old.is_after(&old));
+        assert!(!gts("01H82", None).is_after(&old));
+        assert!(!old.is_after(&gts("01H82", None)));
+        let mastodon = Cursor::new(
+            "https://social.myl.moe/users/myl/statuses/110826550717756448/activity".to_owned(),
+            None,
+        );
+        assert_eq!(mastodon.int_id(), Some(110826550717756448));
+        let older = Cursor::new(
+            "https://social.myl.moe/users/myl/statuses/110826550717756447".to_owned(),
+            Some("2023-08-03T16:09:19Z"),
+        );
+        assert!(mastodon.is_after(&older));
+        assert!(!older.is_after(&mastodon));
+
+        // Mixed integer and opaque IDs are ordered by the times, or not at all without them
+        let opaque = gts("01H83", Some("2023-08-03T16:09:20Z"));
+        assert!(opaque.is_after(&older));
+        assert!(!older.is_after(&opaque));
+        assert!(!opaque.is_after(&mastodon));
+        assert!(!mastodon.is_after(&opaque));
+    }
+}
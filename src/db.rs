@@ -5,6 +5,7 @@
 //! Since the application is async and database operations are blocking,
 //! you should only use the methods here to interact with the database.
 
+use std::collections::HashSet;
 use std::sync::{Arc, Mutex};
 
 use anyhow::Result;
@@ -12,6 +13,8 @@ use rusqlite::{Connection, OptionalExtension};
 use tokio::task;
 
 use crate::cons::IdMap;
+use crate::cursor::Cursor;
+use crate::failure::FailureClass;
 
 pub mod migration {
     refinery::embed_migrations!();
@@ -20,6 +23,8 @@ pub mod migration {
 #[derive(Clone)]
 pub struct DbConn {
     conn: Arc<Mutex<Connection>>,
+    /// Telegram channel the mirrored msgs are of, which is empty for the main channel
+    chan: String,
 }
 
 macro_rules! conn_blocking {
@@ -37,22 +42,45 @@ impl DbConn {
     pub fn new(conn: Connection) -> Self {
         Self {
             conn: Arc::new(Mutex::new(conn)),
+            chan: String::new(),
         }
     }
 
-    pub async fn save_state(&self, state: State) -> Result<()> {
+    /// Same connection with the mirrored msgs, e.g., the ID map and the pins, of the channel.
+    /// The channel is empty for the main channel.
+    pub fn with_chan(&self, chan: String) -> Self {
+        Self {
+            conn: self.conn.clone(),
+            chan,
+        }
+    }
+
+    /// Save the state of the source by its key, which is empty for the main source
+    pub async fn save_state(&self, key: String, state: State) -> Result<()> {
         conn_blocking!(self.conn, conn, {
-            conn.execute(SQL_REPLACE_STATE, (state.min_id,))?;
+            let cursor = state.cursor.as_ref();
+            conn.execute(
+                SQL_REPLACE_STATE,
+                (
+                    key,
+                    state.min_id,
+                    cursor.map(|c| &c.id),
+                    cursor.and_then(|c| c.published_text()),
+                ),
+            )?;
             anyhow::Ok(())
         });
         Ok(())
     }
 
-    pub async fn load_state(&self) -> Result<Option<State>> {
+    pub async fn load_state(&self, key: String) -> Result<Option<State>> {
         let state = conn_blocking!(self.conn, conn, {
-            conn.query_row(SQL_SELECT_STATE, (), |row| {
+            conn.query_row(SQL_SELECT_STATE, (key,), |row| {
+                let cursor_id: Option<String> = row.get(1)?;
+                let cursor_published: Option<String> = row.get(2)?;
                 Ok(State {
                     min_id: row.get(0)?,
+                    cursor: cursor_id.map(|id| Cursor::new(id, cursor_published.as_deref())),
                 })
             })
             .optional()
@@ -60,44 +88,593 @@ impl DbConn {
         Ok(state)
     }
 
+    /// Save the pairs in one transaction, which is much faster for large batches.
+    /// Existing pairs of the IDs are replaced, so saving again after a partial failure is fine.
     pub async fn save_id_map(&self, id_map: IdMap) -> Result<()> {
+        if id_map.is_empty() {
+            return Ok(());
+        }
+        let chan = self.chan.clone();
         conn_blocking!(self.conn, conn, {
-            let mut stmt = conn.prepare_cached(SQL_INSERT_ID_PAIR)?;
-            for (id, tg_id) in id_map.iter() {
-                stmt.execute((id, tg_id))?;
+            let tx = conn.unchecked_transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(SQL_INSERT_ID_PAIR)?;
+                for (id, tg_id) in id_map.iter() {
+                    stmt.execute((&chan, id, tg_id))?;
+                }
             }
+            tx.commit()?;
             anyhow::Ok(())
         });
         Ok(())
     }
 
     pub async fn query_id_map(&self, id: String) -> Result<Option<Vec<u8>>> {
+        let chan = self.chan.clone();
         let tg_id: Option<Vec<u8>> = conn_blocking!(self.conn, conn, {
-            conn.query_row(SQL_SELECT_ID_PAIR, (&id,), |row| row.get(0))
+            conn.query_row(SQL_SELECT_ID_PAIR, (&chan, &id), |row| row.get(0))
                 .optional()
         });
         Ok(tg_id)
     }
+
+    /// Save the msg as the root of the thread unless one exists
+    pub async fn save_thread_root(&self, thread: String, tg_id: Vec<u8>) -> Result<()> {
+        let chan = self.chan.clone();
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_INSERT_THREAD_ROOT)?
+                .execute((&chan, &thread, &tg_id))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    pub async fn query_thread_root(&self, thread: String) -> Result<Option<Vec<u8>>> {
+        let chan = self.chan.clone();
+        let tg_id: Option<Vec<u8>> = conn_blocking!(self.conn, conn, {
+            conn.query_row(SQL_SELECT_THREAD_ROOT, (&chan, &thread), |row| row.get(0))
+                .optional()
+        });
+        Ok(tg_id)
+    }
+
+    /// Save the forum topic the msg is sent into, which is the general topic if not saved
+    pub async fn save_topic(&self, tg_id: Vec<u8>, topic: i32) -> Result<()> {
+        let chan = self.chan.clone();
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_INSERT_TOPIC)?
+                .execute((&chan, &tg_id, topic))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    pub async fn query_topic(&self, tg_id: Vec<u8>) -> Result<Option<i32>> {
+        let chan = self.chan.clone();
+        let topic = conn_blocking!(self.conn, conn, {
+            conn.query_row(SQL_SELECT_TOPIC, (&chan, &tg_id), |row| row.get(0))
+                .optional()
+        });
+        Ok(topic)
+    }
+
+    /// Save the poll to be refreshed after it ends. Unit of `end_at`: Unix seconds.
+    pub async fn save_pending_poll(&self, id: String, end_at: i64) -> Result<()> {
+        let chan = self.chan.clone();
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_INSERT_PENDING_POLL)?
+                .execute((&chan, &id, end_at))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    /// IDs of the pending polls that have ended by `now`
+    pub async fn query_due_polls(&self, now: i64) -> Result<Vec<String>> {
+        let chan = self.chan.clone();
+        let ids = conn_blocking!(self.conn, conn, {
+            let mut stmt = conn.prepare_cached(SQL_SELECT_DUE_POLLS)?;
+            let rows = stmt.query_map((&chan, now), |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        });
+        Ok(ids)
+    }
+
+    pub async fn delete_pending_poll(&self, id: String) -> Result<()> {
+        let chan = self.chan.clone();
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_DELETE_PENDING_POLL)?
+                .execute((&chan, &id))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    /// Save the position of the thread continuation. The thread root is 1.
+    pub async fn save_thread_pos(&self, id: String, pos: u32) -> Result<()> {
+        let chan = self.chan.clone();
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_INSERT_THREAD_POS)?
+                .execute((&chan, &id, pos))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    pub async fn query_thread_pos(&self, id: String) -> Result<Option<u32>> {
+        let chan = self.chan.clone();
+        let pos = conn_blocking!(self.conn, conn, {
+            conn.query_row(SQL_SELECT_THREAD_POS, (&chan, &id), |row| row.get(0))
+                .optional()
+        });
+        Ok(pos)
+    }
+
+    /// Save the canonical URL resolved from the input, e.g., the outbox URL after redirects
+    pub async fn save_canonical_url(&self, input: String, url: String) -> Result<()> {
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_REPLACE_CANONICAL_URL)?
+                .execute((&input, &url))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    pub async fn query_canonical_url(&self, input: String) -> Result<Option<String>> {
+        let url = conn_blocking!(self.conn, conn, {
+            conn.query_row(SQL_SELECT_CANONICAL_URL, (&input,), |row| row.get(0))
+                .optional()
+        });
+        Ok(url)
+    }
+
+    pub async fn delete_canonical_url(&self, input: String) -> Result<()> {
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_DELETE_CANONICAL_URL)?
+                .execute((&input,))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    /// Save the hash of the page body and its `prev` link
+    pub async fn save_page_hash(
+        &self,
+        url: String,
+        hash: String,
+        prev: Option<String>,
+    ) -> Result<()> {
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_REPLACE_PAGE_HASH)?
+                .execute((&url, &hash, &prev))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    /// Returns the hash of the page body and its `prev` link
+    pub async fn query_page_hash(&self, url: String) -> Result<Option<(String, Option<String>)>> {
+        let res = conn_blocking!(self.conn, conn, {
+            conn.query_row(SQL_SELECT_PAGE_HASH, (&url,), |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .optional()
+        });
+        Ok(res)
+    }
+
+    /// Push the page JSON to the disk-backed queue.
+    /// Returns the sequence number of the page.
+    pub async fn enqueue_page(&self, page: String) -> Result<i64> {
+        let seq = conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_INSERT_QUEUED_PAGE)?
+                .execute((&page,))?;
+            anyhow::Ok(conn.last_insert_rowid())
+        });
+        Ok(seq)
+    }
+
+    /// Sequence numbers of the queued pages in the order they were pushed
+    pub async fn query_queued_seqs(&self) -> Result<Vec<i64>> {
+        let seqs = conn_blocking!(self.conn, conn, {
+            let mut stmt = conn.prepare_cached(SQL_SELECT_QUEUED_SEQS)?;
+            let rows = stmt.query_map((), |row| row.get(0))?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        });
+        Ok(seqs)
+    }
+
+    pub async fn query_queued_page(&self, seq: i64) -> Result<Option<String>> {
+        let page = conn_blocking!(self.conn, conn, {
+            conn.query_row(SQL_SELECT_QUEUED_PAGE, (seq,), |row| row.get(0))
+                .optional()
+        });
+        Ok(page)
+    }
+
+    pub async fn delete_queued_page(&self, seq: i64) -> Result<()> {
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_DELETE_QUEUED_PAGE)?
+                .execute((seq,))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    /// Keep the activity of the post that failed to be sent
+    pub async fn save_dead_letter(
+        &self,
+        id: String,
+        activity: String,
+        error: String,
+    ) -> Result<()> {
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_INSERT_DEAD_LETTER)?
+                .execute((&id, &activity, &error))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    /// Mark the activities as delivered to the output
+    pub async fn save_delivered(&self, output: String, ids: Vec<String>) -> Result<()> {
+        conn_blocking!(self.conn, conn, {
+            let tx = conn.unchecked_transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(SQL_INSERT_DELIVERY)?;
+                for id in ids.iter() {
+                    stmt.execute((&output, id))?;
+                }
+            }
+            tx.commit()?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    /// Activities of the IDs already delivered to the output
+    pub async fn query_delivered(
+        &self,
+        output: String,
+        ids: Vec<String>,
+    ) -> Result<HashSet<String>> {
+        let delivered = conn_blocking!(self.conn, conn, {
+            let mut stmt = conn.prepare_cached(SQL_SELECT_DELIVERY)?;
+            let mut delivered = HashSet::new();
+            for id in ids {
+                if stmt.exists((&output, &id))? {
+                    delivered.insert(id);
+                }
+            }
+            anyhow::Ok(delivered)
+        });
+        Ok(delivered)
+    }
+
+    /// Forget the deliveries of the activities to all outputs
+    pub async fn delete_delivered(&self, ids: Vec<String>) -> Result<()> {
+        conn_blocking!(self.conn, conn, {
+            let tx = conn.unchecked_transaction()?;
+            {
+                let mut stmt = tx.prepare_cached(SQL_DELETE_DELIVERIES)?;
+                for id in ids.iter() {
+                    stmt.execute((id,))?;
+                }
+            }
+            tx.commit()?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    /// Move the mirrored msgs saved to the empty channel, e.g., by older versions, to the channel.
+    /// Later calls find none to move, so it is done once.
+    pub async fn adopt_unkeyed(&self) -> Result<()> {
+        if self.chan.is_empty() {
+            return Ok(());
+        }
+        let chan = self.chan.clone();
+        conn_blocking!(self.conn, conn, {
+            let tx = conn.unchecked_transaction()?;
+            let mut moved = 0;
+            for sql in SQL_ADOPT_UNKEYED {
+                moved += tx.execute(sql, (&chan,))?;
+            }
+            tx.commit()?;
+            if moved > 0 {
+                tracing::info!("Moved {moved} mirrored msgs to the channel {chan}");
+            }
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    /// Mirrored posts currently pinned in the chat
+    pub async fn query_pins(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let chan = self.chan.clone();
+        let pins = conn_blocking!(self.conn, conn, {
+            let mut stmt = conn.prepare_cached(SQL_SELECT_PINS)?;
+            let rows = stmt.query_map((&chan,), |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        });
+        Ok(pins)
+    }
+
+    pub async fn save_pin(&self, id: String, tg_id: Vec<u8>) -> Result<()> {
+        let chan = self.chan.clone();
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_INSERT_PIN)?
+                .execute((&chan, &id, &tg_id))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    pub async fn delete_pin(&self, id: String) -> Result<()> {
+        let chan = self.chan.clone();
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_DELETE_PIN)?.execute((&chan, &id))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    /// Save the OAuth credential of the instance, replacing the old one
+    pub async fn save_credential(&self, cred: Credential) -> Result<()> {
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_REPLACE_CREDENTIAL)?.execute((
+                &cred.host,
+                &cred.client_id,
+                &cred.client_secret,
+                &cred.access_token,
+                &cred.scopes,
+            ))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    pub async fn query_access_token(&self, host: String) -> Result<Option<String>> {
+        let token = conn_blocking!(self.conn, conn, {
+            conn.query_row(SQL_SELECT_ACCESS_TOKEN, (&host,), |row| row.get(0))
+                .optional()
+        });
+        Ok(token)
+    }
+
+    pub async fn append_audit(&self, record: AuditRecord) -> Result<()> {
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_INSERT_AUDIT)?.execute((
+                &record.id,
+                record.action.as_str(),
+                &record.dest,
+                &record.tg_id,
+                record.duration_ms,
+                &record.error,
+            ))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    pub async fn inc_failure_stat(&self, class: FailureClass) -> Result<()> {
+        conn_blocking!(self.conn, conn, {
+            conn.prepare_cached(SQL_INC_FAILURE_STAT)?
+                .execute((class.as_str(),))?;
+            anyhow::Ok(())
+        });
+        Ok(())
+    }
+
+    /// Failure counts by class since the database was created
+    pub async fn query_failure_stats(&self) -> Result<Vec<(String, u64)>> {
+        let stats = conn_blocking!(self.conn, conn, {
+            let mut stmt = conn.prepare_cached(SQL_SELECT_FAILURE_STATS)?;
+            let rows = stmt.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        });
+        Ok(stats)
+    }
+
+    /// Position of the last audit record, to take the ones appended after it
+    pub async fn audit_mark(&self) -> Result<i64> {
+        let mark = conn_blocking!(self.conn, conn, {
+            conn.query_row(SQL_SELECT_AUDIT_MARK, (), |row| row.get(0))
+        });
+        Ok(mark)
+    }
+
+    /// Audit records appended after the mark from the oldest to the newest
+    pub async fn query_audit_since(&self, mark: i64) -> Result<Vec<AuditRecord>> {
+        let records = conn_blocking!(self.conn, conn, {
+            let mut stmt = conn.prepare_cached(SQL_SELECT_AUDIT_SINCE)?;
+            let rows = stmt.query_map((mark,), audit_record)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        });
+        Ok(records)
+    }
+
+    /// Audit records of the post from the oldest to the newest
+    pub async fn query_audit(&self, id: String) -> Result<Vec<AuditRecord>> {
+        let records = conn_blocking!(self.conn, conn, {
+            let mut stmt = conn.prepare_cached(SQL_SELECT_AUDIT)?;
+            let rows = stmt.query_map((&id,), audit_record)?;
+            rows.collect::<rusqlite::Result<Vec<_>>>()
+        });
+        Ok(records)
+    }
+}
+
+fn audit_record(row: &rusqlite::Row) -> rusqlite::Result<AuditRecord> {
+    let action: String = row.get(1)?;
+    Ok(AuditRecord {
+        id: row.get(0)?,
+        action: AuditAction::from_str(&action).unwrap_or(AuditAction::Failed),
+        dest: row.get(2)?,
+        tg_id: row.get(3)?,
+        duration_ms: row.get(4)?,
+        error: row.get(5)?,
+    })
 }
 
 #[derive(Debug, Clone)]
 pub struct State {
+    /// Mastodon integer ID to query the outbox with.
+    /// Negative to ignore all previous posts.
     pub min_id: i64,
+    /// Newest handled activity.
+    /// Used to skip handled posts of servers without integer IDs.
+    pub cursor: Option<Cursor>,
 }
 
 impl State {
     pub fn new(min_id: i64) -> Self {
-        Self { min_id }
+        Self {
+            min_id,
+            cursor: None,
+        }
     }
 }
 
 impl Default for State {
     fn default() -> Self {
-        Self { min_id: -1 }
+        Self::new(-1)
+    }
+}
+
+/// Append-only record of what happened to a post
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditRecord {
+    /// GUID of the post
+    pub id: String,
+    pub action: AuditAction,
+    /// Where the post is sent to, e.g., the Telegram channel
+    pub dest: Option<String>,
+    /// Telegram msg GUID of the sent post
+    pub tg_id: Option<Vec<u8>>,
+    /// Unit: Milliseconds
+    pub duration_ms: Option<u64>,
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    pub fn new(id: String, action: AuditAction) -> Self {
+        Self {
+            id,
+            action,
+            dest: None,
+            tg_id: None,
+            duration_ms: None,
+            error: None,
+        }
+    }
+}
+
+/// OAuth credential of a Mastodon instance obtained by `mastotg auth`
+pub struct Credential {
+    /// Instance URL by [`crate::auth::host_key`]
+    pub host: String,
+    pub client_id: String,
+    pub client_secret: String,
+    pub access_token: String,
+    /// Granted scopes separated by spaces
+    pub scopes: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    /// Printed to stdout
+    Printed,
+    /// Sent to the destination
+    Sent,
+    /// Sent to the destination without the media that can not be fetched
+    Degraded,
+    /// Skipped by the filter
+    Skipped,
+    /// Failed to be sent
+    Failed,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Printed => "printed",
+            AuditAction::Sent => "sent",
+            AuditAction::Degraded => "degraded",
+            AuditAction::Skipped => "skipped",
+            AuditAction::Failed => "failed",
+        }
+    }
+
+    fn from_str(s: &str) -> Option<Self> {
+        match s {
+            "printed" => Some(AuditAction::Printed),
+            "sent" => Some(AuditAction::Sent),
+            "degraded" => Some(AuditAction::Degraded),
+            "skipped" => Some(AuditAction::Skipped),
+            "failed" => Some(AuditAction::Failed),
+            _ => None,
+        }
     }
 }
 
-const SQL_REPLACE_STATE: &str = r#"INSERT OR REPLACE INTO state (pk, min_id) VALUES (1, ?1)"#;
-const SQL_SELECT_STATE: &str = r#"SELECT min_id FROM state WHERE pk = 1"#;
-const SQL_INSERT_ID_PAIR: &str = r#"INSERT INTO id_map (id, tg_id) VALUES (?1, ?2)"#;
-const SQL_SELECT_ID_PAIR: &str = r#"SELECT tg_id FROM id_map WHERE id = ?1"#;
+const SQL_REPLACE_STATE: &str = r#"INSERT OR REPLACE INTO state (key, min_id, cursor_id, cursor_published) VALUES (?1, ?2, ?3, ?4)"#;
+const SQL_SELECT_STATE: &str =
+    r#"SELECT min_id, cursor_id, cursor_published FROM state WHERE key = ?1"#;
+const SQL_INSERT_ID_PAIR: &str =
+    r#"INSERT OR REPLACE INTO id_map (chan, id, tg_id) VALUES (?1, ?2, ?3)"#;
+const SQL_SELECT_ID_PAIR: &str = r#"SELECT tg_id FROM id_map WHERE chan = ?1 AND id = ?2"#;
+const SQL_INSERT_THREAD_ROOT: &str =
+    r#"INSERT OR IGNORE INTO thread_roots (chan, thread, tg_id) VALUES (?1, ?2, ?3)"#;
+const SQL_SELECT_THREAD_ROOT: &str =
+    r#"SELECT tg_id FROM thread_roots WHERE chan = ?1 AND thread = ?2"#;
+const SQL_INSERT_TOPIC: &str =
+    r#"INSERT OR REPLACE INTO topics (chan, tg_id, topic) VALUES (?1, ?2, ?3)"#;
+const SQL_SELECT_TOPIC: &str = r#"SELECT topic FROM topics WHERE chan = ?1 AND tg_id = ?2"#;
+const SQL_INSERT_PENDING_POLL: &str =
+    r#"INSERT OR REPLACE INTO pending_polls (chan, id, end_at) VALUES (?1, ?2, ?3)"#;
+const SQL_SELECT_DUE_POLLS: &str =
+    r#"SELECT id FROM pending_polls WHERE chan = ?1 AND end_at <= ?2"#;
+const SQL_DELETE_PENDING_POLL: &str = r#"DELETE FROM pending_polls WHERE chan = ?1 AND id = ?2"#;
+const SQL_INSERT_THREAD_POS: &str =
+    r#"INSERT OR REPLACE INTO thread_positions (chan, id, pos) VALUES (?1, ?2, ?3)"#;
+const SQL_SELECT_THREAD_POS: &str =
+    r#"SELECT pos FROM thread_positions WHERE chan = ?1 AND id = ?2"#;
+const SQL_REPLACE_CANONICAL_URL: &str =
+    r#"INSERT OR REPLACE INTO canonical_urls (input, url) VALUES (?1, ?2)"#;
+const SQL_SELECT_CANONICAL_URL: &str = r#"SELECT url FROM canonical_urls WHERE input = ?1"#;
+const SQL_DELETE_CANONICAL_URL: &str = r#"DELETE FROM canonical_urls WHERE input = ?1"#;
+const SQL_REPLACE_PAGE_HASH: &str =
+    r#"INSERT OR REPLACE INTO page_hashes (url, hash, prev) VALUES (?1, ?2, ?3)"#;
+const SQL_SELECT_PAGE_HASH: &str = r#"SELECT hash, prev FROM page_hashes WHERE url = ?1"#;
+const SQL_INSERT_QUEUED_PAGE: &str = r#"INSERT INTO page_queue (page) VALUES (?1)"#;
+const SQL_SELECT_QUEUED_SEQS: &str = r#"SELECT seq FROM page_queue ORDER BY seq"#;
+const SQL_SELECT_QUEUED_PAGE: &str = r#"SELECT page FROM page_queue WHERE seq = ?1"#;
+const SQL_DELETE_QUEUED_PAGE: &str = r#"DELETE FROM page_queue WHERE seq = ?1"#;
+const SQL_INSERT_DEAD_LETTER: &str =
+    r#"INSERT OR REPLACE INTO dead_letters (id, activity, error) VALUES (?1, ?2, ?3)"#;
+const SQL_INSERT_DELIVERY: &str =
+    r#"INSERT OR IGNORE INTO deliveries (output, id) VALUES (?1, ?2)"#;
+const SQL_SELECT_DELIVERY: &str = r#"SELECT 1 FROM deliveries WHERE output = ?1 AND id = ?2"#;
+const SQL_DELETE_DELIVERIES: &str = r#"DELETE FROM deliveries WHERE id = ?1"#;
+const SQL_ADOPT_UNKEYED: [&str; 6] = [
+    r#"UPDATE OR IGNORE id_map SET chan = ?1 WHERE chan = ''"#,
+    r#"UPDATE OR IGNORE thread_roots SET chan = ?1 WHERE chan = ''"#,
+    r#"UPDATE OR IGNORE thread_positions SET chan = ?1 WHERE chan = ''"#,
+    r#"UPDATE OR IGNORE pending_polls SET chan = ?1 WHERE chan = ''"#,
+    r#"UPDATE OR IGNORE pins SET chan = ?1 WHERE chan = ''"#,
+    r#"UPDATE OR IGNORE topics SET chan = ?1 WHERE chan = ''"#,
+];
+const SQL_SELECT_PINS: &str = r#"SELECT id, tg_id FROM pins WHERE chan = ?1"#;
+const SQL_INSERT_PIN: &str = r#"INSERT OR REPLACE INTO pins (chan, id, tg_id) VALUES (?1, ?2, ?3)"#;
+const SQL_DELETE_PIN: &str = r#"DELETE FROM pins WHERE chan = ?1 AND id = ?2"#;
+const SQL_REPLACE_CREDENTIAL: &str = r#"INSERT OR REPLACE INTO credentials (host, client_id, client_secret, access_token, scopes) VALUES (?1, ?2, ?3, ?4, ?5)"#;
+const SQL_SELECT_ACCESS_TOKEN: &str = r#"SELECT access_token FROM credentials WHERE host = ?1"#;
+const SQL_INSERT_AUDIT: &str = r#"INSERT INTO audit (id, action, dest, tg_id, duration_ms, error) VALUES (?1, ?2, ?3, ?4, ?5, ?6)"#;
+const SQL_SELECT_AUDIT: &str =
+    r#"SELECT id, action, dest, tg_id, duration_ms, error FROM audit WHERE id = ?1 ORDER BY pk"#;
+const SQL_SELECT_AUDIT_MARK: &str = r#"SELECT COALESCE(MAX(pk), 0) FROM audit"#;
+const SQL_SELECT_AUDIT_SINCE: &str =
+    r#"SELECT id, action, dest, tg_id, duration_ms, error FROM audit WHERE pk > ?1 ORDER BY pk"#;
+const SQL_INC_FAILURE_STAT: &str = r#"INSERT INTO failure_stats (class, count) VALUES (?1, 1) ON CONFLICT (class) DO UPDATE SET count = count + 1"#;
+const SQL_SELECT_FAILURE_STATS: &str = r#"SELECT class, count FROM failure_stats ORDER BY class"#;
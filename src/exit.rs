@@ -0,0 +1,63 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Process exit codes of one-shot runs.
+//! Errors are tagged with [`Exit`] as their context, so wrapper scripts can tell what failed.
+
+use std::fmt;
+use std::process::ExitCode;
+
+/// Kind of the failure that ends the process
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// Invalid options. Same as the clap usage errors.
+    Config = 2,
+    /// Failed to fetch posts from the source
+    Source = 3,
+    /// Failed to send posts to the destination
+    Dest = 4,
+    /// Finished, but some posts failed to be sent and were skipped
+    Partial = 5,
+}
+
+impl Exit {
+    /// Exit code of the error.
+    /// Untagged errors get the generic 1.
+    pub fn code_of(e: &anyhow::Error) -> ExitCode {
+        match e.downcast_ref::<Exit>() {
+            Some(exit) => (*exit).into(),
+            None => ExitCode::FAILURE,
+        }
+    }
+}
+
+impl From<Exit> for ExitCode {
+    fn from(exit: Exit) -> Self {
+        ExitCode::from(exit as u8)
+    }
+}
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Config => "invalid configuration",
+            Self::Source => "failed to fetch from the source",
+            Self::Dest => "failed to send to the destination",
+            Self::Partial => "some posts failed to be sent",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn test_code_of() {
+        let e = anyhow!("connection reset").context(Exit::Source);
+        assert_eq!(Exit::code_of(&e), ExitCode::from(3));
+        assert_eq!(Exit::code_of(&anyhow!("unknown")), ExitCode::FAILURE);
+    }
+}
@@ -0,0 +1,189 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Failure classification.
+//! Counts per class are kept in the metrics and the database,
+//! so pattern changes like a spike of media failures after an instance update are visible.
+
+use std::fmt;
+
+#[cfg(feature = "telegram")]
+use teloxide::{ApiError, RequestError};
+
+use crate::db::DbConn;
+use crate::metrics::METRICS;
+use crate::utils::HttpStatusError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FailureClass {
+    /// Connection failures, timeouts, and server errors
+    Network,
+    /// Rate limits of the destination or the source
+    RateLimit,
+    /// Attachments that can not be fetched or processed
+    Media,
+    /// Responses that can not be parsed
+    Parse,
+    /// Invalid tokens or missing permissions
+    Auth,
+    Other,
+}
+
+impl FailureClass {
+    pub const ALL: [Self; 6] = [
+        Self::Network,
+        Self::RateLimit,
+        Self::Media,
+        Self::Parse,
+        Self::Auth,
+        Self::Other,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Network => "network",
+            Self::RateLimit => "rate_limit",
+            Self::Media => "media",
+            Self::Parse => "parse",
+            Self::Auth => "auth",
+            Self::Other => "other",
+        }
+    }
+
+    /// Index in [`Self::ALL`]
+    pub fn index(&self) -> usize {
+        *self as usize
+    }
+
+    /// Classify by the first error in the chain that tells the class
+    pub fn of(e: &anyhow::Error) -> Self {
+        e.chain()
+            .find_map(|cause| {
+                #[cfg(feature = "telegram")]
+                if let Some(e) = cause.downcast_ref::<RequestError>() {
+                    return Some(Self::of_request(e));
+                }
+                if let Some(e) = cause.downcast_ref::<HttpStatusError>() {
+                    return Some(Self::of_status(e.status));
+                }
+                if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+                    return Some(match e.status() {
+                        Some(status) => Self::of_status(status.as_u16()),
+                        None if e.is_decode() => Self::Parse,
+                        None => Self::Network,
+                    });
+                }
+                if cause.is::<serde_json::Error>() {
+                    return Some(Self::Parse);
+                }
+                if cause.is::<MediaError>() || cause.is::<DownloadError>() {
+                    return Some(Self::Media);
+                }
+                None
+            })
+            .unwrap_or(Self::Other)
+    }
+
+    fn of_status(status: u16) -> Self {
+        match status {
+            401 | 403 => Self::Auth,
+            429 => Self::RateLimit,
+            500.. => Self::Network,
+            _ => Self::Other,
+        }
+    }
+
+    #[cfg(feature = "telegram")]
+    fn of_request(e: &RequestError) -> Self {
+        match e {
+            RequestError::RetryAfter(_) => Self::RateLimit,
+            RequestError::Network(_) | RequestError::Io(_) => Self::Network,
+            RequestError::InvalidJson { .. } => Self::Parse,
+            RequestError::Api(e) => match e {
+                ApiError::WrongFileId
+                | ApiError::WrongFileIdOrUrl
+                | ApiError::FailedToGetUrlContent
+                | ApiError::ImageProcessFailed
+                | ApiError::PhotoAsInputFileRequired
+                | ApiError::FileIdInvalid
+                | ApiError::RequestEntityTooLarge => Self::Media,
+                // `NotFound` is the bot API `Unauthorized` for invalid tokens
+                ApiError::NotFound
+                | ApiError::BotBlocked
+                | ApiError::BotKicked
+                | ApiError::BotKickedFromSupergroup
+                | ApiError::NotEnoughRightsToPostMessages => Self::Auth,
+                ApiError::CantParseEntities => Self::Parse,
+                _ => Self::Other,
+            },
+            _ => Self::Other,
+        }
+    }
+
+    /// Count the failure in the metrics and the database
+    pub async fn record(self, db: &DbConn) -> anyhow::Result<()> {
+        METRICS.inc_failure(self);
+        db.inc_failure_stat(self).await
+    }
+}
+
+impl fmt::Display for FailureClass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Error of attachments that are not supported
+#[derive(Debug)]
+pub struct MediaError(pub String);
+
+impl fmt::Display for MediaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for MediaError {}
+
+/// Error of attachments that can not be downloaded to be re-uploaded
+#[derive(Debug)]
+pub struct DownloadError(pub String);
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+
+    use super::*;
+
+    #[test]
+    fn test_classify() {
+        let status = |status| {
+            anyhow::Error::new(HttpStatusError {
+                url: "https://example.com".to_owned(),
+                status,
+                body: String::new(),
+            })
+        };
+        assert_eq!(FailureClass::of(&status(401)), FailureClass::Auth);
+        assert_eq!(FailureClass::of(&status(429)), FailureClass::RateLimit);
+        assert_eq!(FailureClass::of(&status(502)), FailureClass::Network);
+        let parse = serde_json::from_str::<u8>("x").unwrap_err();
+        assert_eq!(
+            FailureClass::of(&anyhow::Error::new(parse).context("failed to parse the page")),
+            FailureClass::Parse
+        );
+        assert_eq!(
+            FailureClass::of(&MediaError("unknown media type".to_owned()).into()),
+            FailureClass::Media
+        );
+        assert_eq!(FailureClass::of(&anyhow!("unknown")), FailureClass::Other);
+    }
+}
@@ -0,0 +1,248 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Post filters deciding which posts are forwarded.
+//!
+//! Filters can be combined with [`And`], [`Or`], and [`Not`],
+//! or built from a [`FilterConfig`] expression like `{"and": [{"tag": "mygo"}, {"not": "reply"}]}`.
+//! Embedders can implement [`Filter`] or pass a closure.
+
+use serde::Deserialize;
+
+use crate::as2::{Post, Visibility};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+}
+
+impl Decision {
+    pub fn is_allowed(self) -> bool {
+        self == Decision::Allow
+    }
+}
+
+impl From<bool> for Decision {
+    fn from(allowed: bool) -> Self {
+        if allowed {
+            Decision::Allow
+        } else {
+            Decision::Deny
+        }
+    }
+}
+
+/// Filter trait
+pub trait Filter: Send + Sync {
+    /// Decide whether the post should be forwarded
+    fn allow(&self, post: &Post) -> Decision;
+}
+
+impl<F> Filter for F
+where
+    F: Fn(&Post) -> Decision + Send + Sync,
+{
+    fn allow(&self, post: &Post) -> Decision {
+        self(post)
+    }
+}
+
+/// Allow if all of the filters allow.
+/// Allow all if empty.
+pub struct And(pub Vec<Box<dyn Filter>>);
+
+impl Filter for And {
+    fn allow(&self, post: &Post) -> Decision {
+        self.0.iter().all(|f| f.allow(post).is_allowed()).into()
+    }
+}
+
+/// Allow if any of the filters allows.
+/// Deny all if empty.
+pub struct Or(pub Vec<Box<dyn Filter>>);
+
+impl Filter for Or {
+    fn allow(&self, post: &Post) -> Decision {
+        self.0.iter().any(|f| f.allow(post).is_allowed()).into()
+    }
+}
+
+/// Allow if the filter denies
+pub struct Not(pub Box<dyn Filter>);
+
+impl Filter for Not {
+    fn allow(&self, post: &Post) -> Decision {
+        (!self.0.allow(post).is_allowed()).into()
+    }
+}
+
+/// Allow posts with the hashtag, either listed or in the content.
+/// The leading `#` is optional and the match is case-insensitive like Mastodon.
+pub struct HasTag(pub String);
+
+impl Filter for HasTag {
+    fn allow(&self, post: &Post) -> Decision {
+        let name = self.0.strip_prefix('#').unwrap_or(&self.0).to_lowercase();
+        post.hashtag_names()
+            .iter()
+            .any(|tag_name| tag_name.to_lowercase() == name)
+            .into()
+    }
+}
+
+/// Allow posts replying to another post
+pub struct IsReply;
+
+impl Filter for IsReply {
+    fn allow(&self, post: &Post) -> Decision {
+        post.in_reply_to.is_some().into()
+    }
+}
+
+/// Allow posts replying to a post of the same author, i.e., thread continuations
+pub struct IsSelfReply;
+
+impl Filter for IsSelfReply {
+    fn allow(&self, post: &Post) -> Decision {
+        post.is_self_reply().into()
+    }
+}
+
+/// Allow posts with media attachments
+pub struct HasMedia;
+
+impl Filter for HasMedia {
+    fn allow(&self, post: &Post) -> Decision {
+        (!post.attachment.is_empty()).into()
+    }
+}
+
+/// Allow posts with the post or any of its media marked as sensitive
+pub struct IsSensitive;
+
+impl Filter for IsSensitive {
+    fn allow(&self, post: &Post) -> Decision {
+        post.is_sensitive().into()
+    }
+}
+
+/// Allow posts with content warnings
+pub struct HasCw;
+
+impl Filter for HasCw {
+    fn allow(&self, post: &Post) -> Decision {
+        post.content_warning().is_some().into()
+    }
+}
+
+/// Allow posts at least as visible as the visibility
+pub struct MinVisibility(pub Visibility);
+
+impl Filter for MinVisibility {
+    fn allow(&self, post: &Post) -> Decision {
+        (post.visibility() >= self.0).into()
+    }
+}
+
+/// Allow posts in the language, e.g., `en`.
+/// Only the primary subtags are compared case-insensitively, so `zh` matches `zh-Hant`.
+/// Posts of unknown languages are denied.
+pub struct HasLang(pub String);
+
+impl Filter for HasLang {
+    fn allow(&self, post: &Post) -> Decision {
+        let primary = |tag: &str| {
+            tag.split(['-', '_'])
+                .next()
+                .unwrap_or_default()
+                .to_lowercase()
+        };
+        let lang = primary(&self.0);
+        post.languages().any(|tag| primary(tag) == lang).into()
+    }
+}
+
+/// Config-driven filter expression
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterConfig {
+    And(Vec<FilterConfig>),
+    Or(Vec<FilterConfig>),
+    Not(Box<FilterConfig>),
+    Tag(String),
+    /// Language tag
+    Lang(String),
+    Reply,
+    SelfReply,
+    Media,
+    Sensitive,
+    Cw,
+    /// Minimum visibility
+    Visibility(Visibility),
+}
+
+impl FilterConfig {
+    pub fn build(&self) -> Box<dyn Filter> {
+        match self {
+            FilterConfig::And(items) => Box::new(And(items.iter().map(|c| c.build()).collect())),
+            FilterConfig::Or(items) => Box::new(Or(items.iter().map(|c| c.build()).collect())),
+            FilterConfig::Not(item) => Box::new(Not(item.build())),
+            FilterConfig::Tag(name) => Box::new(HasTag(name.to_owned())),
+            FilterConfig::Lang(lang) => Box::new(HasLang(lang.to_owned())),
+            FilterConfig::Reply => Box::new(IsReply),
+            FilterConfig::SelfReply => Box::new(IsSelfReply),
+            FilterConfig::Media => Box::new(HasMedia),
+            FilterConfig::Sensitive => Box::new(IsSensitive),
+            FilterConfig::Cw => Box::new(HasCw),
+            FilterConfig::Visibility(v) => Box::new(MinVisibility(*v)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::check_de;
+
+    #[test]
+    fn test_combinators() -> Result<()> {
+        let post_tag = check_de!(Post, "post_tag");
+        let post_text = check_de!(Post, "post_text");
+        let filter = And(vec![
+            Box::new(HasTag("#MyGO".to_owned())),
+            Box::new(IsReply),
+        ]);
+        assert_eq!(filter.allow(&post_tag), Decision::Allow);
+        assert_eq!(filter.allow(&post_text), Decision::Deny);
+        let filter = Or(vec![Box::new(Not(Box::new(IsReply))), Box::new(HasMedia)]);
+        assert_eq!(filter.allow(&post_tag), Decision::Deny);
+        assert_eq!(filter.allow(&post_text), Decision::Allow);
+        Ok(())
+    }
+
+    #[test]
+    fn test_config() -> Result<()> {
+        let post_tag = check_de!(Post, "post_tag");
+        let config: FilterConfig =
+            serde_json::from_str(r#"{"and": [{"tag": "mygo"}, {"not": "media"}]}"#)?;
+        assert_eq!(config.build().allow(&post_tag), Decision::Allow);
+        let config: FilterConfig = serde_json::from_str(r#"{"not": "reply"}"#)?;
+        assert_eq!(config.build().allow(&post_tag), Decision::Deny);
+        let config: FilterConfig = serde_json::from_str(r#"{"visibility": "unlisted"}"#)?;
+        assert_eq!(config.build().allow(&post_tag), Decision::Allow);
+        let config: FilterConfig = serde_json::from_str(r#"{"lang": "zh-CN"}"#)?;
+        assert_eq!(config.build().allow(&post_tag), Decision::Allow);
+        let config: FilterConfig = serde_json::from_str(r#"{"lang": "en"}"#)?;
+        assert_eq!(config.build().allow(&post_tag), Decision::Deny);
+        let config: FilterConfig = serde_json::from_str(r#"{"not": "cw"}"#)?;
+        assert_eq!(config.build().allow(&post_tag), Decision::Allow);
+        assert_eq!(
+            config.build().allow(&check_de!(Post, "post_cw")),
+            Decision::Deny
+        );
+        Ok(())
+    }
+}
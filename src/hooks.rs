@@ -0,0 +1,184 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Outbound webhooks fired on post events.
+//! External systems can track the mirror without polling the database.
+
+use anyhow::Result;
+use reqwest::{Client, Url};
+use serde::Serialize;
+
+use crate::db::{AuditAction, AuditRecord};
+use crate::http;
+use crate::redact::redact;
+use crate::utils::check_res;
+
+/// Webhook URLs to POST to
+#[derive(Debug, Clone, Default)]
+pub struct Hooks {
+    /// When a post is sent or printed
+    pub on_sent: Option<String>,
+    /// When a post permanently fails to be sent, i.e., is skipped or dead-lettered by the error policy
+    pub on_error: Option<String>,
+    /// Dead-man ping after each round in the healthchecks.io style
+    pub heartbeat: Option<String>,
+    client: Client,
+}
+
+/// JSON body of the webhook requests
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct HookEvent {
+    /// `sent`, `degraded`, or `failed`
+    pub event: &'static str,
+    /// GUID of the post
+    pub id: String,
+    pub dest: Option<String>,
+    /// Link to the Telegram msg
+    pub link: Option<String>,
+    pub error: Option<String>,
+}
+
+impl Hooks {
+    pub fn new(
+        on_sent: Option<String>,
+        on_error: Option<String>,
+        heartbeat: Option<String>,
+    ) -> Self {
+        Self {
+            on_sent,
+            on_error,
+            heartbeat,
+            client: http::client().clone(),
+        }
+    }
+
+    /// GET the heartbeat URL after a successful round, or its `/fail` variant after a failed one.
+    /// Failures are logged only, so an unreachable monitor does not stop the mirror.
+    pub async fn ping(&self, ok: bool) {
+        let Some(url) = self.heartbeat.as_ref() else {
+            return;
+        };
+        let res = async {
+            let url = match ok {
+                true => Url::parse(url)?,
+                false => fail_url(url)?,
+            };
+            check_res(self.client.get(url).send().await?).await
+        }
+        .await;
+        if let Err(e) = res {
+            tracing::warn!(
+                "Failed to ping the heartbeat: {}",
+                redact(&format!("{e:#}"))
+            );
+        }
+    }
+
+    /// Fire the webhooks matching the audit record if any.
+    /// Posts sent in part, e.g., without their media or the rest of their text, fire both.
+    /// Failures are logged only, since the post itself has been handled.
+    pub async fn fire(&self, record: &AuditRecord) {
+        let hooks = match record.action {
+            AuditAction::Sent | AuditAction::Printed => vec![(self.on_sent.as_ref(), "sent")],
+            AuditAction::Degraded => vec![
+                (self.on_sent.as_ref(), "sent"),
+                (self.on_error.as_ref(), "degraded"),
+            ],
+            AuditAction::Failed => vec![(self.on_error.as_ref(), "failed")],
+            AuditAction::Skipped => return,
+        };
+        for (url, event) in hooks {
+            let Some(url) = url else {
+                continue;
+            };
+            let body = HookEvent {
+                event,
+                id: record.id.clone(),
+                dest: record.dest.clone(),
+                link: tg_link(record),
+                error: record.error.clone(),
+            };
+            if let Err(e) = self.post(url, &body).await {
+                tracing::warn!(post = %record.id, "Failed to fire the {event} webhook: {}", redact(&format!("{e:#}")));
+            }
+        }
+    }
+
+    async fn post(&self, url: &str, body: &HookEvent) -> Result<()> {
+        check_res(self.client.post(url).json(body).send().await?).await?;
+        Ok(())
+    }
+}
+
+/// Public link of the Telegram msg in the record
+fn tg_link(record: &AuditRecord) -> Option<String> {
+    let dest = record.dest.as_ref()?;
+    let tg_id = record.tg_id.as_ref()?;
+    Some(tg_msg_link(dest, tg_id))
+}
+
+/// Link to the msg, e.g., `https://t.me/myl7s/42`.
+/// Private chats without usernames get the `t.me/c/` form that works for members.
+pub fn tg_msg_link(chan: &str, tg_id: &[u8]) -> String {
+    let chat_id = i64::from_be_bytes(tg_id[..8].try_into().unwrap());
+    // The first msg of the post, followed by the rest if any
+    let msg_id = i64::from_be_bytes(tg_id[8..16].try_into().unwrap());
+    match chan.strip_prefix('@') {
+        Some(name) => format!("https://t.me/{name}/{msg_id}"),
+        None => {
+            // Supergroup and channel IDs are prefixed with -100 in the bot API
+            let id = chat_id.to_string();
+            let id = id.strip_prefix("-100").unwrap_or(&id);
+            format!("https://t.me/c/{id}/{msg_id}")
+        }
+    }
+}
+
+/// Heartbeat URL of failed rounds, with `/fail` appended to the path and the query kept
+fn fail_url(url: &str) -> Result<Url> {
+    let mut url = Url::parse(url)?;
+    let path = format!("{}/fail", url.path().trim_end_matches('/'));
+    url.set_path(&path);
+    Ok(url)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tg_id(chat_id: i64, msg_id: i64) -> Vec<u8> {
+        [chat_id.to_be_bytes(), msg_id.to_be_bytes()].concat()
+    }
+
+    #[test]
+    fn test_fail_url() -> Result<()> {
+        let fail = |url| fail_url(url).map(String::from);
+        assert_eq!(
+            fail("https://hc.example/ping/abc")?,
+            "https://hc.example/ping/abc/fail"
+        );
+        assert_eq!(
+            fail("https://hc.example/ping/abc/")?,
+            "https://hc.example/ping/abc/fail"
+        );
+        assert_eq!(
+            fail("https://hc.example/ping/abc?rid=1")?,
+            "https://hc.example/ping/abc/fail?rid=1"
+        );
+        assert!(fail("hc.example/ping").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tg_msg_link() {
+        let id = tg_id(-1001234567890, 42);
+        assert_eq!(tg_msg_link("@myl7s", &id), "https://t.me/myl7s/42");
+        assert_eq!(
+            tg_msg_link("-1001234567890", &id),
+            "https://t.me/c/1234567890/42"
+        );
+        // Posts of several msgs link to the first
+        let ids = [id, tg_id(-1001234567890, 43)].concat();
+        assert_eq!(tg_msg_link("@myl7s", &ids), "https://t.me/myl7s/42");
+    }
+}
@@ -0,0 +1,41 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! The HTTP client shared by all requests to servers, e.g., outbox pages, WebFinger, and media.
+//! Connections are pooled so TLS handshakes are not repeated every round.
+
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use reqwest::{Client, ClientBuilder};
+
+/// Default `User-Agent` of requests
+pub const USER_AGENT: &str = concat!(env!("CARGO_PKG_NAME"), "/", env!("CARGO_PKG_VERSION"));
+
+/// `Accept` of ActivityPub objects.
+/// Servers like GoToSocial serve the web pages without it.
+pub const ACCEPT_AP: &str = "application/activity+json";
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Builder of the client with the defaults
+pub fn builder() -> ClientBuilder {
+    Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(Duration::from_secs(10))
+}
+
+/// Set the shared client, e.g., the one configured by the CLI.
+/// Should be called once before any requests.
+pub fn init(client: Client) -> Result<()> {
+    CLIENT
+        .set(client)
+        .map_err(|_| anyhow!("HTTP client already initialized"))
+}
+
+/// The shared client.
+/// Built with the defaults if not initialized.
+pub fn client() -> &'static Client {
+    CLIENT.get_or_init(|| builder().build().expect("default HTTP client"))
+}
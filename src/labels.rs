@@ -0,0 +1,188 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Labels of the generated texts, e.g., `Read more`, in the language of the channel.
+//! Built-in translations can be overridden field by field in the config.
+//!
+//! Labels are templates, where `{n}` and `{time}` are filled in.
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// Locales with built-in labels
+pub const LOCALES: &[&str] = &["en", "zh", "ja", "de", "fr", "es"];
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Labels {
+    /// Link to the full post after a shortened article
+    pub read_more: String,
+    /// Prefix of the links to the attachments that can not be sent
+    pub media_unavailable: String,
+    /// Line under an open poll
+    pub poll_ends: String,
+    /// Line under a closed poll
+    pub poll_ended: String,
+    /// Link to the page of an event to join it
+    pub event_join: String,
+    pub just_now: String,
+    pub minute_ago: String,
+    pub minutes_ago: String,
+    pub hour_ago: String,
+    pub hours_ago: String,
+    pub day_ago: String,
+    pub days_ago: String,
+}
+
+macro_rules! labels {
+    ($($field:ident: $value:literal),* $(,)?) => {
+        Labels { $($field: $value.to_owned()),* }
+    };
+}
+
+impl Labels {
+    /// Built-in labels of the locale, e.g., `zh` or `zh-CN`.
+    /// Only the language is used.
+    pub fn builtin(locale: &str) -> Option<Self> {
+        let lang = locale.split(['-', '_']).next()?.to_ascii_lowercase();
+        Some(match lang.as_str() {
+            "en" => labels! {
+                read_more: "Read more",
+                media_unavailable: "media unavailable",
+                poll_ends: "Ends at {time}",
+                poll_ended: "Ended at {time}",
+                event_join: "Join",
+                just_now: "just now",
+                minute_ago: "{n} minute ago",
+                minutes_ago: "{n} minutes ago",
+                hour_ago: "{n} hour ago",
+                hours_ago: "{n} hours ago",
+                day_ago: "{n} day ago",
+                days_ago: "{n} days ago",
+            },
+            "zh" => labels! {
+                read_more: "阅读全文",
+                media_unavailable: "媒体不可用",
+                poll_ends: "截止于 {time}",
+                poll_ended: "已于 {time} 截止",
+                event_join: "参加",
+                just_now: "刚刚",
+                minute_ago: "{n} 分钟前",
+                minutes_ago: "{n} 分钟前",
+                hour_ago: "{n} 小时前",
+                hours_ago: "{n} 小时前",
+                day_ago: "{n} 天前",
+                days_ago: "{n} 天前",
+            },
+            "ja" => labels! {
+                read_more: "続きを読む",
+                media_unavailable: "メディアを表示できません",
+                poll_ends: "{time} に終了",
+                poll_ended: "{time} に終了しました",
+                event_join: "参加する",
+                just_now: "たった今",
+                minute_ago: "{n} 分前",
+                minutes_ago: "{n} 分前",
+                hour_ago: "{n} 時間前",
+                hours_ago: "{n} 時間前",
+                day_ago: "{n} 日前",
+                days_ago: "{n} 日前",
+            },
+            "de" => labels! {
+                read_more: "Weiterlesen",
+                media_unavailable: "Medien nicht verfügbar",
+                poll_ends: "Endet am {time}",
+                poll_ended: "Beendet am {time}",
+                event_join: "Teilnehmen",
+                just_now: "gerade eben",
+                minute_ago: "vor {n} Minute",
+                minutes_ago: "vor {n} Minuten",
+                hour_ago: "vor {n} Stunde",
+                hours_ago: "vor {n} Stunden",
+                day_ago: "vor {n} Tag",
+                days_ago: "vor {n} Tagen",
+            },
+            "fr" => labels! {
+                read_more: "Lire la suite",
+                media_unavailable: "média indisponible",
+                poll_ends: "Se termine le {time}",
+                poll_ended: "Terminé le {time}",
+                event_join: "Participer",
+                just_now: "à l'instant",
+                minute_ago: "il y a {n} minute",
+                minutes_ago: "il y a {n} minutes",
+                hour_ago: "il y a {n} heure",
+                hours_ago: "il y a {n} heures",
+                day_ago: "il y a {n} jour",
+                days_ago: "il y a {n} jours",
+            },
+            "es" => labels! {
+                read_more: "Leer más",
+                media_unavailable: "contenido multimedia no disponible",
+                poll_ends: "Termina el {time}",
+                poll_ended: "Terminó el {time}",
+                event_join: "Participar",
+                just_now: "justo ahora",
+                minute_ago: "hace {n} minuto",
+                minutes_ago: "hace {n} minutos",
+                hour_ago: "hace {n} hora",
+                hours_ago: "hace {n} horas",
+                day_ago: "hace {n} día",
+                days_ago: "hace {n} días",
+            },
+            _ => return None,
+        })
+    }
+
+    /// Built-in labels of the locale overridden by the fields of the JSON object if any
+    pub fn new(locale: &str, overrides: Option<&str>) -> Result<Self> {
+        let labels = Self::builtin(locale).ok_or(anyhow!(
+            "unknown locale {locale} (expected one of {})",
+            LOCALES.join(", ")
+        ))?;
+        let Some(overrides) = overrides else {
+            return Ok(labels);
+        };
+        let overrides: serde_json::Map<String, Value> =
+            serde_json::from_str(overrides).context("invalid labels")?;
+        let mut value = serde_json::to_value(labels)?;
+        if let Value::Object(fields) = &mut value {
+            fields.extend(overrides);
+        }
+        serde_json::from_value(value).context("invalid labels")
+    }
+}
+
+impl Default for Labels {
+    fn default() -> Self {
+        Self::builtin("en").unwrap()
+    }
+}
+
+/// Fill the `{name}` in the label
+pub fn fill(label: &str, name: &str, value: &str) -> String {
+    label.replace(&format!("{{{name}}}"), value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labels() -> Result<()> {
+        assert_eq!(Labels::new("zh-CN", None)?.read_more, "阅读全文");
+        let labels = Labels::new("en", Some(r#"{"read_more": "More"}"#))?;
+        assert_eq!(labels.read_more, "More");
+        assert_eq!(labels.just_now, "just now");
+        assert!(Labels::new("en", Some(r#"{"read_mroe": "More"}"#)).is_err());
+        assert!(Labels::new("xx", None).is_err());
+        for locale in LOCALES {
+            let labels = Labels::builtin(locale).unwrap();
+            assert!(labels.minutes_ago.contains("{n}"));
+            assert!(labels.poll_ended.contains("{time}"));
+        }
+        assert_eq!(fill("{n} days ago", "n", "3"), "3 days ago");
+        Ok(())
+    }
+}
@@ -0,0 +1,43 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Forward posts from Mastodon to Telegram channels.
+//!
+//! The binary is a thin runner over the modules here.
+//! Embedders can plug in their own producers, consumers, and filters.
+
+pub mod as2;
+pub mod auth;
+pub mod breaker;
+pub mod cadence;
+pub mod capture;
+pub mod cli;
+pub mod config;
+pub mod cons;
+pub mod cursor;
+pub mod db;
+pub mod exit;
+pub mod failure;
+pub mod filter;
+pub mod hooks;
+pub mod http;
+pub mod labels;
+pub mod media;
+pub mod metrics;
+#[cfg(feature = "otel")]
+pub mod otel;
+#[cfg(feature = "reencode")]
+pub mod photo;
+pub mod pro;
+pub mod progress;
+pub mod query;
+pub mod record;
+pub mod redact;
+pub mod render;
+pub mod runner;
+pub mod snapshot;
+#[cfg(feature = "transcode")]
+pub mod transcode;
+pub mod utils;
+#[cfg(feature = "websub")]
+pub mod websub;
@@ -1,186 +1,217 @@
 // Copyright (C) myl7
 // SPDX-License-Identifier: Apache-2.0
 
-mod as2;
-mod cli;
-mod cons;
-mod db;
-mod pro;
-mod query;
-mod utils;
-
-use anyhow::Result;
+use std::env;
+use std::io::{self, BufRead, Write};
+use std::process::ExitCode;
+
+use anyhow::{Context, Result};
 use clap::Parser;
-use reqwest::Url;
 use rusqlite::Connection;
-use tokio::time::{self, Duration};
-
-use crate::as2::Page;
-use crate::cli::{Cli, CliInput, CliOutput};
-use crate::cons::{Con, TgCon};
-use crate::db::{migration, DbConn, State};
-use crate::pro::{Pro, UriPro};
-use crate::query::query_outbox_url;
-use crate::utils::int_id;
+#[cfg(feature = "otel")]
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{fmt, EnvFilter};
+
+use mastotg::auth::{self, host_key};
+use mastotg::cli::{AuthCli, Cli, Command, MainCli, RenderCli};
+#[cfg(feature = "telegram")]
+use mastotg::cons::extra_tokens;
+#[cfg(feature = "bsky")]
+use mastotg::cons::BSKY_PASSWORD_ENV;
+#[cfg(feature = "discord")]
+use mastotg::cons::DISCORD_WEBHOOK_ENV;
+#[cfg(feature = "ntfy")]
+use mastotg::cons::NTFY_TOKEN_ENV;
+#[cfg(feature = "mastodon")]
+use mastotg::cons::TARGET_ACCESS_TOKEN_ENV;
+#[cfg(feature = "webhook")]
+use mastotg::cons::WEBHOOK_SECRET_ENV;
+#[cfg(feature = "xmpp")]
+use mastotg::cons::XMPP_PASSWORD_ENV;
+use mastotg::db::{Credential, DbConn};
+use mastotg::exit::Exit;
+use mastotg::http;
+use mastotg::labels::Labels;
+use mastotg::metrics::METRICS;
+#[cfg(feature = "otel")]
+use mastotg::otel;
+use mastotg::pro::ACCESS_TOKEN_ENV;
+use mastotg::redact::{self, RedactingMakeWriter};
+use mastotg::render::{Footer, Template};
+use mastotg::runner::{self, init_db, Ctx};
+use mastotg::snapshot::{self, Snapshot};
+use mastotg::utils::try_lock_file;
+#[cfg(feature = "websub")]
+use mastotg::websub::WEBSUB_SECRET_ENV;
+
+fn main() -> ExitCode {
+    match try_main() {
+        Ok(code) => code,
+        Err(e) => {
+            // anyhow prints the whole chain with `Debug`, which may carry request URLs
+            eprintln!("Error: {}", redact::redact(&format!("{e:?}")));
+            Exit::code_of(&e)
+        }
+    }
+}
 
-fn main() -> Result<()> {
-    env_logger::init();
+fn try_main() -> Result<ExitCode> {
+    let mut cli = match MainCli::parse() {
+        MainCli {
+            command: Some(Command::Render(cli)),
+            ..
+        } => return render(cli),
+        MainCli {
+            command: Some(Command::Auth(cli)),
+            ..
+        } => return login(cli),
+        // Required options are checked by clap without a subcommand
+        MainCli { cli, .. } => cli.unwrap(),
+    };
+    if cli.config.is_some() {
+        cli = Cli::try_parse_with_config(env::args_os().collect()).context(Exit::Config)?;
+    }
 
-    let mut cli = Cli::parse();
-    cli.clean()?;
+    for var in [
+        "TELOXIDE_TOKEN",
+        ACCESS_TOKEN_ENV,
+        #[cfg(feature = "discord")]
+        DISCORD_WEBHOOK_ENV,
+        #[cfg(feature = "webhook")]
+        WEBHOOK_SECRET_ENV,
+        #[cfg(feature = "mastodon")]
+        TARGET_ACCESS_TOKEN_ENV,
+        #[cfg(feature = "bsky")]
+        BSKY_PASSWORD_ENV,
+        #[cfg(feature = "xmpp")]
+        XMPP_PASSWORD_ENV,
+        #[cfg(feature = "ntfy")]
+        NTFY_TOKEN_ENV,
+        #[cfg(feature = "websub")]
+        WEBSUB_SECRET_ENV,
+    ] {
+        if let Ok(token) = env::var(var) {
+            redact::register_secret(token);
+        }
+    }
+    #[cfg(feature = "telegram")]
+    extra_tokens().into_iter().for_each(redact::register_secret);
+
+    let registry = tracing_subscriber::registry().with(
+        fmt::layer()
+            .with_writer(RedactingMakeWriter::new(io::stderr))
+            .with_filter(EnvFilter::from_default_env()),
+    );
+    #[cfg(feature = "otel")]
+    let (registry, _otel_guard) = {
+        let (layer, guard) = match cli.otlp_endpoint.as_ref() {
+            Some(endpoint) => {
+                let (layer, guard) = otel::init(endpoint)?;
+                (Some(layer.with_filter(LevelFilter::INFO)), Some(guard))
+            }
+            None => (None, None),
+        };
+        (registry.with(layer), guard)
+    };
+    registry.init();
+
+    cli.clean().context(Exit::Config)?;
+    http::init(cli.build_http_client().context(Exit::Config)?)?;
+
+    // Held until the process exits
+    let _lock = match cli.lock_file.as_ref() {
+        Some(path) => match try_lock_file(path).context("failed to open the lock file")? {
+            Some(lock) => Some(lock),
+            None => {
+                tracing::info!("Another instance holds the lock file, exiting");
+                return Ok(ExitCode::SUCCESS);
+            }
+        },
+        None => None,
+    };
 
     let mut conn = Connection::open(&cli.db_file)?;
     init_db(&mut conn)?;
     let db = DbConn::new(conn);
 
-    let ctx = Ctx { cli, db };
+    let ctx = Ctx::new(cli, db).context(Exit::Config)?;
     run(&ctx)?;
-    Ok(())
-}
-
-struct Ctx {
-    cli: Cli,
-    db: DbConn,
-}
-
-#[tokio::main]
-async fn run(ctx: &Ctx) -> Result<()> {
-    let cli = &ctx.cli;
-    let db = &ctx.db;
-
-    let init_state = if cli.min_id >= 0 {
-        State::new(cli.min_id)
-    } else {
-        db.load_state()
-            .await?
-            .map(|s| {
-                log::debug!("Loaded state min_id {} from the database", s.min_id);
-                s
-            })
-            .unwrap_or_else(|| {
-                log::debug!("No state loaded from the database");
-                State::default()
-            })
-    };
-
-    let mut state = init_state;
-    loop {
-        state = run_round(ctx, state).await?;
-        db.save_state(state.clone()).await?;
-
-        if let Some(interval) = cli.loop_interval {
-            time::sleep(Duration::from_secs(interval)).await;
-        } else {
-            break;
-        }
+    if METRICS.snapshot().failed > 0 {
+        return Ok(Exit::Partial.into());
     }
-    Ok(())
+    Ok(ExitCode::SUCCESS)
 }
 
-async fn run_round(ctx: &Ctx, state: State) -> Result<State> {
-    log::debug!("Starts to run a round");
-
-    let min_id = state.min_id;
-    // Whether to fast forward to the latest post without sending.
-    // Use the mode to get the `min_id` that ignores all previous posts.
-    let ff_latest = min_id < 0;
-    let uri = match ctx.cli.input.as_ref() {
-        None | Some(CliInput::Stdin) => r"stdio://in".to_owned(),
-        input => {
-            let base_url = match input {
-                Some(CliInput::Fetch) => ctx.cli.host.as_ref().unwrap().to_owned(),
-                Some(CliInput::QueryFetch) => {
-                    let host = ctx.cli.host.as_ref().unwrap();
-                    let acct = ctx.cli.acct.as_ref().unwrap();
-                    query_outbox_url(host, acct).await?
-                }
-                _ => unreachable!(),
-            };
-            let min_id_query = if !ff_latest {
-                Some(("min_id", min_id.to_string()))
-            } else {
-                None
-            };
-            let max_id_query = ctx.cli.max_id.map(|id| ("max_id", id.to_string()));
-            let mut u = Url::parse(&base_url)?;
-            {
-                let mut q = u.query_pairs_mut();
-                if let Some((k, v)) = min_id_query {
-                    q.append_pair(k, &v);
-                }
-                if let Some((k, v)) = max_id_query {
-                    q.append_pair(k, &v);
-                }
-                q.append_pair("page", "true");
-            }
-            let url = u.to_string();
-            log::debug!("The page is at {url}");
-            url
-        }
+fn render(cli: RenderCli) -> Result<ExitCode> {
+    let snapshot = Snapshot {
+        attribution: cli.attribution,
+        footer: Footer {
+            engagement: cli.show_engagement,
+            ..Default::default()
+        },
+        labels: Labels::new(&cli.locale, cli.labels.as_deref()).context(Exit::Config)?,
+        template: cli
+            .template
+            .as_deref()
+            .map(Template::parse)
+            .transpose()
+            .context(Exit::Config)?,
     };
-
-    let mut pro = UriPro::new(uri);
-    let mut next_min_id = min_id;
-    loop {
-        let page = pro.fetch().await?;
-        let post_len = page.ordered_items.len();
-        if post_len == 0 {
-            break;
-        }
-
-        if ff_latest {
-            next_min_id = int_id(page.ordered_items.first().unwrap().id.as_ref())?;
-            log::info!("Ignore from the latest min_id {next_min_id}");
-            break;
-        }
-
-        log::info!("Fetched {post_len} posts from the page");
-        let iid = int_id(page.ordered_items.first().unwrap().id.as_ref())?;
-        consume(ctx, page).await?;
-        next_min_id = iid;
-
-        if ctx.cli.no_follow_paging {
-            break;
+    let outputs = snapshot.render_dir(&cli.fixtures)?;
+    let Some(out) = cli.out.as_ref() else {
+        for (name, text) in outputs {
+            println!("==> {name} <==\n{text}\n");
         }
+        return Ok(ExitCode::SUCCESS);
+    };
+    if !cli.check {
+        snapshot::write_dir(out, &outputs)?;
+        return Ok(ExitCode::SUCCESS);
     }
-
-    log::info!("Finished running a round with min_id {next_min_id}");
-    Ok(State {
-        min_id: next_min_id,
+    let diffs = snapshot::check_dir(out, &outputs)?;
+    for diff in diffs.iter() {
+        eprintln!("{diff}");
+    }
+    Ok(match diffs.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
     })
 }
 
-fn init_db(conn: &mut Connection) -> Result<()> {
-    let report = migration::migrations::runner().run(conn)?;
-    let migs = report.applied_migrations();
-    if !migs.is_empty() {
-        let s = migs
-            .iter()
-            .map(|m| format!("{m}"))
-            .collect::<Vec<_>>()
-            .join(", ");
-        log::info!("Applied migrations: {s}");
-    } else {
-        log::debug!("No migrations applied");
-    }
-    Ok(())
+#[tokio::main]
+async fn login(cli: AuthCli) -> Result<ExitCode> {
+    let mut conn = Connection::open(&cli.db_file)?;
+    init_db(&mut conn)?;
+    let db = DbConn::new(conn);
+
+    let app = auth::register_app(&cli.host, &cli.scopes).await?;
+    redact::register_secret(app.client_secret.clone());
+    println!(
+        "Open the URL to authorize mastotg:\n\n{}\n",
+        auth::authorize_url(&cli.host, &app, &cli.scopes)?
+    );
+    print!("Paste the authorization code: ");
+    io::stdout().flush()?;
+    let mut code = String::new();
+    io::stdin().lock().read_line(&mut code)?;
+    let access_token = auth::obtain_token(&cli.host, &app, &code, &cli.scopes).await?;
+    redact::register_secret(access_token.clone());
+
+    let host = host_key(&cli.host);
+    db.save_credential(Credential {
+        host: host.clone(),
+        client_id: app.client_id,
+        client_secret: app.client_secret,
+        access_token,
+        scopes: cli.scopes,
+    })
+    .await?;
+    println!("Stored the access token for {host}");
+    Ok(ExitCode::SUCCESS)
 }
 
-async fn consume(ctx: &Ctx, page: Page) -> Result<()> {
-    match ctx.cli.output.as_ref() {
-        None | Some(CliOutput::Print) => {
-            page.ordered_items.iter().try_for_each(|post| {
-                println!("{}", serde_json::to_string_pretty(post)?);
-                anyhow::Ok(())
-            })?;
-        }
-        Some(CliOutput::TgSend) => {
-            let post_len = page.ordered_items.len();
-            let con = TgCon::new(ctx.cli.tg_chan.clone().unwrap(), ctx.db.clone());
-            let id_map = con.send_page(page).await?;
-            ctx.db.save_id_map(id_map).await?;
-            log::info!("Sent {post_len} posts to the Telegram channel");
-        }
-    }
-    Ok(())
+#[tokio::main]
+async fn run(ctx: &Ctx) -> Result<()> {
+    runner::run(ctx).await
 }
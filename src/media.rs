@@ -0,0 +1,442 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Media helpers.
+//! Placeholders are decoded from [blurhash] strings locally, so no media have to be fetched.
+//! Media to be re-uploaded are downloaded concurrently and streamed to spool files,
+//! so large videos are not buffered in memory.
+//!
+//! [blurhash]: https://github.com/woltapp/blurhash/blob/master/Algorithm.md
+
+use std::f64::consts::PI;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, ensure, Result};
+use reqwest::Url;
+use tokio::fs::File;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Semaphore;
+use tokio::task::{self, JoinSet};
+
+#[cfg(feature = "telegram")]
+use crate::as2::Document;
+use crate::failure::DownloadError;
+use crate::http;
+use crate::redact::redact;
+use crate::utils::{check_res, fnv1a};
+
+const BASE83: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz#$%*+,-.:;=?@[]^_{|}~";
+
+/// Longer side of placeholders. Unit: Pixels.
+/// Blurhashes have no details, so larger sizes only cost more.
+#[cfg(feature = "telegram")]
+const PLACEHOLDER_SIZE: u32 = 256;
+
+/// Downloaded media in the spool dir.
+/// The file is removed when dropped, including when the download is aborted.
+#[derive(Debug)]
+pub struct SpoolFile {
+    path: PathBuf,
+}
+
+impl SpoolFile {
+    /// Reserve a unique path in the spool dir
+    fn new(dir: &Path) -> Self {
+        static SEQ: AtomicU64 = AtomicU64::new(0);
+        let seq = SEQ.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("mastotg-{}-{seq}", process::id()));
+        Self { path }
+    }
+
+    /// Reserve a unique path with the extension, e.g., for converted media
+    pub fn with_ext(dir: &Path, ext: &str) -> Self {
+        let mut file = Self::new(dir);
+        file.path.set_extension(ext);
+        file
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for SpoolFile {
+    fn drop(&mut self) {
+        if let Err(e) = fs::remove_file(&self.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                tracing::warn!(
+                    "Failed to remove the spool file {}: {e}",
+                    self.path.display()
+                );
+            }
+        }
+    }
+}
+
+/// On-disk cache of downloaded media keyed by the URL hashes.
+/// Retried sends do not download the same media again.
+/// The least recently used are evicted when the total size exceeds the max.
+#[derive(Debug, Clone)]
+pub struct MediaCache {
+    pub dir: PathBuf,
+    /// Unit: Bytes
+    pub max_size: u64,
+}
+
+impl MediaCache {
+    fn path(&self, url: &str) -> PathBuf {
+        self.dir.join(format!("{:016x}", fnv1a(url.as_bytes())))
+    }
+
+    /// Copy the cached media to a spool file if any
+    async fn get(&self, url: &str, spool_dir: &Path) -> Result<Option<SpoolFile>> {
+        let path = self.path(url);
+        let spool = SpoolFile::new(spool_dir);
+        match tokio::fs::copy(&path, spool.path()).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        }
+        // Mark as recently used
+        fs::File::options()
+            .write(true)
+            .open(&path)?
+            .set_modified(SystemTime::now())?;
+        Ok(Some(spool))
+    }
+
+    async fn put(&self, url: &str, file: &SpoolFile) -> Result<()> {
+        tokio::fs::create_dir_all(&self.dir).await?;
+        let path = self.path(url);
+        // Renamed after written, so readers never see partial files
+        let tmp = SpoolFile::with_ext(&self.dir, "tmp");
+        tokio::fs::copy(file.path(), tmp.path()).await?;
+        tokio::fs::rename(tmp.path(), &path).await?;
+        let cache = self.clone();
+        task::spawn_blocking(move || cache.evict()).await?
+    }
+
+    fn evict(&self) -> Result<()> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            // Skip the files being written
+            if !meta.is_file() || entry.path().extension().is_some() {
+                continue;
+            }
+            entries.push((meta.modified()?, meta.len(), entry.path()));
+        }
+        let mut total: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort();
+        for (_, len, path) in entries {
+            if total <= self.max_size {
+                break;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => total -= len,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => total -= len,
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Download the media to the spool dir with at most `concurrency` downloads at a time.
+/// Returns the files in the order of the URLs.
+/// Failing to download any fails all, and the other downloads are aborted.
+pub async fn download_all(
+    urls: Vec<String>,
+    concurrency: usize,
+    spool_dir: PathBuf,
+    cache: Option<MediaCache>,
+) -> Result<Vec<SpoolFile>> {
+    let client = http::client();
+    let semaphore = Arc::new(Semaphore::new(concurrency.max(1)));
+    let spool_dir = Arc::new(spool_dir);
+    let mut files: Vec<_> = urls.iter().map(|_| None).collect();
+    let mut downloads = JoinSet::new();
+    for (i, url) in urls.into_iter().enumerate() {
+        let client = client.clone();
+        let semaphore = semaphore.clone();
+        let spool_dir = spool_dir.clone();
+        let cache = cache.clone();
+        downloads.spawn(async move {
+            let _permit = semaphore.acquire_owned().await?;
+            if let Some(cache) = cache.as_ref() {
+                match cache.get(&url, &spool_dir).await {
+                    Ok(Some(file)) => return anyhow::Ok((i, file)),
+                    Ok(None) => (),
+                    Err(e) => tracing::warn!("Failed to read the media cache: {e:#}"),
+                }
+            }
+            let file = download(&client, &url, &spool_dir).await.map_err(|e| {
+                DownloadError(format!("failed to download {}: {e:#}", redact(&url)))
+            })?;
+            if let Some(cache) = cache.as_ref() {
+                if let Err(e) = cache.put(&url, &file).await {
+                    tracing::warn!("Failed to write the media cache: {e:#}");
+                }
+            }
+            anyhow::Ok((i, file))
+        });
+    }
+    while let Some(res) = downloads.join_next().await {
+        let (i, file) = res??;
+        files[i] = Some(file);
+    }
+    Ok(files.into_iter().map(Option::unwrap).collect())
+}
+
+/// Stream the body to a spool file chunk by chunk.
+/// Local `file://` media, e.g., of archives, are copied instead.
+async fn download(client: &reqwest::Client, url: &str, spool_dir: &Path) -> Result<SpoolFile> {
+    if let Some(path) = local_path(url) {
+        let spool = SpoolFile::new(spool_dir);
+        tokio::fs::copy(path, spool.path()).await?;
+        return Ok(spool);
+    }
+    let mut res = check_res(client.get(url).send().await?).await?;
+    let spool = SpoolFile::new(spool_dir);
+    let mut file = File::create(spool.path()).await?;
+    while let Some(chunk) = res.chunk().await? {
+        file.write_all(&chunk).await?;
+    }
+    file.flush().await?;
+    Ok(spool)
+}
+
+/// Canonical roots of the extracted archives, the only dirs local media are read from
+static LOCAL_ROOTS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Allow local media under the dir, which is returned canonicalized
+pub fn allow_local_root(root: &Path) -> Result<PathBuf> {
+    let root = fs::canonicalize(root)?;
+    let mut roots = LOCAL_ROOTS.lock().unwrap();
+    if !roots.contains(&root) {
+        roots.push(root.clone());
+    }
+    Ok(root)
+}
+
+/// Path of the local media given by the `file://` URL.
+/// Only files under the allowed roots are given, so posts can not upload arbitrary local files.
+pub fn local_path(url: &str) -> Option<PathBuf> {
+    let url = Url::parse(url).ok().filter(|u| u.scheme() == "file")?;
+    let path = url.to_file_path().ok()?;
+    let path = fs::canonicalize(&path).ok().filter(|path| {
+        let roots = LOCAL_ROOTS.lock().unwrap();
+        roots.iter().any(|root| path.starts_with(root))
+    });
+    if path.is_none() {
+        tracing::warn!("Refused the local media {url} out of the archives");
+    }
+    path
+}
+
+/// File name to upload the media as, taken from the URL.
+/// Telegram may tell the format by the extension.
+pub fn file_name(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| Some(u.path_segments()?.next_back()?.to_owned()))
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "media".to_owned())
+}
+
+/// PNG placeholder of the attachment decoded from its blurhash.
+/// The aspect ratio is kept when the size is known.
+#[cfg(feature = "telegram")]
+pub fn placeholder_png(att: &Document) -> Result<Vec<u8>> {
+    let hash = att
+        .blurhash
+        .as_deref()
+        .ok_or(anyhow!("no blurhash of the attachment"))?;
+    let (width, height) = match (att.width, att.height) {
+        (Some(w), Some(h)) if w > 0 && h > 0 => {
+            let scale = PLACEHOLDER_SIZE as f64 / w.max(h) as f64;
+            let fit = |n: u32| ((n as f64 * scale).round() as u32).max(1);
+            (fit(w), fit(h))
+        }
+        _ => (PLACEHOLDER_SIZE, PLACEHOLDER_SIZE),
+    };
+    let rgb = decode_blurhash(hash, width, height)?;
+    encode_png(&rgb, width, height)
+}
+
+/// Decode the blurhash into RGB pixels
+pub fn decode_blurhash(hash: &str, width: u32, height: u32) -> Result<Vec<u8>> {
+    // Sliced by bytes below
+    ensure!(hash.is_ascii(), "blurhash {hash} has invalid chars");
+    ensure!(hash.len() >= 6, "blurhash {hash} too short");
+    let size_flag = decode83(&hash[..1])?;
+    let (num_x, num_y) = ((size_flag % 9 + 1) as usize, (size_flag / 9 + 1) as usize);
+    ensure!(
+        hash.len() == 4 + 2 * num_x * num_y,
+        "blurhash {hash} has an invalid length"
+    );
+    let max_value = (decode83(&hash[1..2])? + 1) as f64 / 166.0;
+
+    let mut colors = Vec::with_capacity(num_x * num_y);
+    let dc = decode83(&hash[2..6])?;
+    colors.push([dc >> 16, (dc >> 8) & 255, dc & 255].map(|c| srgb_to_linear(c as u8)));
+    for i in 1..num_x * num_y {
+        let ac = decode83(&hash[4 + i * 2..6 + i * 2])?;
+        colors.push([ac / (19 * 19), (ac / 19) % 19, ac % 19].map(|q| {
+            let v = (q as f64 - 9.0) / 9.0;
+            v.signum() * v.abs().powi(2) * max_value
+        }));
+    }
+
+    let (w, h) = (width as usize, height as usize);
+    let mut pixels = Vec::with_capacity(w * h * 3);
+    for y in 0..h {
+        for x in 0..w {
+            let mut rgb = [0.0; 3];
+            for j in 0..num_y {
+                for i in 0..num_x {
+                    let basis = (PI * (x * i) as f64 / w as f64).cos()
+                        * (PI * (y * j) as f64 / h as f64).cos();
+                    let color = colors[i + j * num_x];
+                    rgb.iter_mut().zip(color).for_each(|(c, v)| *c += v * basis);
+                }
+            }
+            pixels.extend(rgb.map(linear_to_srgb));
+        }
+    }
+    Ok(pixels)
+}
+
+fn decode83(s: &str) -> Result<u32> {
+    s.bytes().try_fold(0, |acc, b| {
+        let digit = BASE83
+            .iter()
+            .position(|&c| c == b)
+            .ok_or(anyhow!("invalid blurhash char {}", b as char))?;
+        Ok(acc * 83 + digit as u32)
+    })
+}
+
+fn srgb_to_linear(v: u8) -> f64 {
+    let v = v as f64 / 255.0;
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+fn linear_to_srgb(v: f64) -> u8 {
+    let v = v.clamp(0.0, 1.0);
+    let v = if v <= 0.0031308 {
+        v * 12.92
+    } else {
+        1.055 * v.powf(1.0 / 2.4) - 0.055
+    };
+    (v * 255.0).round() as u8
+}
+
+#[cfg(feature = "telegram")]
+fn encode_png(rgb: &[u8], width: u32, height: u32) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    let mut encoder = png::Encoder::new(&mut buf, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(rgb)?;
+    writer.finish()?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use super::*;
+    #[cfg(feature = "telegram")]
+    use crate::as2::Post;
+    #[cfg(feature = "telegram")]
+    use crate::check_de;
+
+    #[cfg(feature = "telegram")]
+    #[test]
+    fn test_placeholder_png() -> Result<()> {
+        let post = check_de!(Post, "post_multi_grouped_images");
+        let png = placeholder_png(&post.attachment[0])?;
+        assert!(png.starts_with(b"\x89PNG"));
+        let rgb = decode_blurhash(post.attachment[0].blurhash.as_ref().unwrap(), 4, 3)?;
+        assert_eq!(rgb.len(), 4 * 3 * 3);
+        assert!(decode_blurhash("LEHV6nWB2yk8", 4, 3).is_err());
+        // Multi-byte chars are rejected instead of panicking at the char boundaries
+        assert!(decode_blurhash("éLEHV6nWB2yk8pyo0adR*.7kCMdnj", 4, 3).is_err());
+        assert!(decode_blurhash("LEHV6nWB2yk8pyo0adR*.7kCMdné", 4, 3).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_download_all() -> Result<()> {
+        let server = MockServer::start().await;
+        for i in 1..=3 {
+            Mock::given(method("GET"))
+                .and(path(format!("/media/{i}.png")))
+                .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![i as u8; i]))
+                .mount(&server)
+                .await;
+        }
+        let url = |i| format!("{}/media/{i}.png", server.uri());
+        let dir = std::env::temp_dir();
+        let files = download_all((1..=3).map(url).collect(), 2, dir.clone(), None).await?;
+        let bodies = files
+            .iter()
+            .map(|file| fs::read(file.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        assert_eq!(bodies, [vec![1], vec![2, 2], vec![3, 3, 3]]);
+        let path = files[0].path().to_owned();
+        drop(files);
+        assert!(!path.exists());
+        let err = download_all(vec![url(1), url(4)], 2, dir, None)
+            .await
+            .unwrap_err();
+        assert!(err.is::<DownloadError>());
+        assert_eq!(file_name(&url(1)), "1.png");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_media_cache() -> Result<()> {
+        let server = MockServer::start().await;
+        for i in 1..=2 {
+            Mock::given(method("GET"))
+                .and(path(format!("/media/{i}.png")))
+                .respond_with(ResponseTemplate::new(200).set_body_bytes(vec![i as u8; 4]))
+                .expect(1)
+                .mount(&server)
+                .await;
+        }
+        let url = |i| format!("{}/media/{i}.png", server.uri());
+        let spool_dir = std::env::temp_dir();
+        let cache = MediaCache {
+            dir: spool_dir.join(format!("mastotg-test-cache-{}", process::id())),
+            max_size: 6,
+        };
+        let download = |i| download_all(vec![url(i)], 1, spool_dir.clone(), Some(cache.clone()));
+        download(1).await?;
+        let files = download(1).await?;
+        assert_eq!(fs::read(files[0].path())?, [1; 4]);
+        // Caching the second evicts the first
+        download(2).await?;
+        assert!(!cache.path(&url(1)).exists());
+        assert!(cache.path(&url(2)).exists());
+        fs::remove_dir_all(&cache.dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,393 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! In-process metrics.
+//! Exposed in the Prometheus text format via `--metrics-addr` and summarized after each round.
+
+use std::fmt::Write;
+use std::ops::Sub;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::Result;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+use crate::failure::FailureClass;
+
+/// Global metrics of the process
+pub static METRICS: Metrics = Metrics::new();
+
+/// Upper bounds of the send latency histogram buckets. Unit: Milliseconds.
+const LATENCY_BUCKETS_MS: [u64; 8] = [100, 250, 500, 1000, 2500, 5000, 10000, 30000];
+/// Upper bounds of the end-to-end latency histogram buckets. Unit: Milliseconds.
+const E2E_LATENCY_BUCKETS_MS: [u64; 8] = [1000, 5000, 15000, 30000, 60000, 300000, 900000, 3600000];
+
+pub struct Metrics {
+    fetched: AtomicU64,
+    sent: AtomicU64,
+    skipped: AtomicU64,
+    retried: AtomicU64,
+    failed: AtomicU64,
+    /// Indexed by [`FailureClass::index`]
+    failures: [AtomicU64; FailureClass::ALL.len()],
+    /// Cumulative counts like Prometheus, plus the `+Inf` one at the end
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+    latency_sum_ms: AtomicU64,
+    /// From fetched to sent, including the time waiting in the queue
+    e2e_latency_buckets: [AtomicU64; E2E_LATENCY_BUCKETS_MS.len() + 1],
+    e2e_latency_sum_ms: AtomicU64,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Self {
+            fetched: AtomicU64::new(0),
+            sent: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+            retried: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            failures: [const { AtomicU64::new(0) }; FailureClass::ALL.len()],
+            latency_buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS_MS.len() + 1],
+            latency_sum_ms: AtomicU64::new(0),
+            e2e_latency_buckets: [const { AtomicU64::new(0) }; E2E_LATENCY_BUCKETS_MS.len() + 1],
+            e2e_latency_sum_ms: AtomicU64::new(0),
+        }
+    }
+
+    /// Posts fetched from the source
+    pub fn inc_fetched(&self, n: u64) {
+        self.fetched.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Posts skipped by the filter
+    pub fn inc_skipped(&self, n: u64) {
+        self.skipped.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Sends retried due to rate limits
+    pub fn inc_retried(&self) {
+        self.retried.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Posts failed to be sent
+    pub fn inc_failed(&self) {
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Failures of the class, including retried ones
+    pub fn inc_failure(&self, class: FailureClass) {
+        self.failures[class.index()].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Posts sent with the time taken
+    pub fn observe_sent(&self, latency_ms: u64) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        self.latency_sum_ms.fetch_add(latency_ms, Ordering::Relaxed);
+        observe(&self.latency_buckets, &LATENCY_BUCKETS_MS, latency_ms);
+    }
+
+    /// Posts sent with the time taken since they were fetched
+    pub fn observe_e2e(&self, latency_ms: u64) {
+        self.e2e_latency_sum_ms
+            .fetch_add(latency_ms, Ordering::Relaxed);
+        observe(
+            &self.e2e_latency_buckets,
+            &E2E_LATENCY_BUCKETS_MS,
+            latency_ms,
+        );
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            fetched: self.fetched.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+            retried: self.retried.load(Ordering::Relaxed),
+            failed: self.failed.load(Ordering::Relaxed),
+            failures: self
+                .failures
+                .iter()
+                .map(|count| count.load(Ordering::Relaxed))
+                .collect(),
+            latency_buckets: self
+                .latency_buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            latency_sum_ms: self.latency_sum_ms.load(Ordering::Relaxed),
+            e2e_latency_buckets: self
+                .e2e_latency_buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+            e2e_latency_sum_ms: self.e2e_latency_sum_ms.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// End-to-end timer of the posts fetched together
+#[derive(Debug, Clone, Copy)]
+pub struct E2eTimer {
+    fetched_at: Instant,
+    slow_ms: u64,
+}
+
+impl E2eTimer {
+    /// Posts taking longer than `slow_secs` are warned about
+    pub fn new(fetched_at: Instant, slow_secs: u64) -> Self {
+        Self {
+            fetched_at,
+            slow_ms: slow_secs * 1000,
+        }
+    }
+
+    /// Observe the post just sent
+    pub fn observe(&self, post_id: &str) {
+        let e2e_ms = self.fetched_at.elapsed().as_millis() as u64;
+        METRICS.observe_e2e(e2e_ms);
+        if e2e_ms > self.slow_ms {
+            tracing::warn!(
+                post = post_id,
+                e2e_ms,
+                "Slow post took more than {} seconds from fetched to sent",
+                self.slow_ms / 1000
+            );
+        }
+    }
+}
+
+/// Count the value into the cumulative buckets
+fn observe(buckets: &[AtomicU64], bounds: &[u64], value: u64) {
+    let i = bounds
+        .iter()
+        .position(|le| value <= *le)
+        .unwrap_or(bounds.len());
+    buckets[i..]
+        .iter()
+        .for_each(|bucket| _ = bucket.fetch_add(1, Ordering::Relaxed));
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Snapshot {
+    pub fetched: u64,
+    pub sent: u64,
+    pub skipped: u64,
+    pub retried: u64,
+    pub failed: u64,
+    /// Indexed by [`FailureClass::index`]
+    pub failures: Vec<u64>,
+    pub latency_buckets: Vec<u64>,
+    pub latency_sum_ms: u64,
+    pub e2e_latency_buckets: Vec<u64>,
+    pub e2e_latency_sum_ms: u64,
+}
+
+impl Snapshot {
+    /// Mean send latency. Unit: Milliseconds.
+    pub fn mean_latency_ms(&self) -> u64 {
+        self.latency_sum_ms.checked_div(self.sent).unwrap_or(0)
+    }
+
+    /// Log the snapshot as a round summary
+    pub fn log_summary(&self) {
+        tracing::info!(
+            fetched = self.fetched,
+            sent = self.sent,
+            skipped = self.skipped,
+            retried = self.retried,
+            failed = self.failed,
+            mean_latency_ms = self.mean_latency_ms(),
+            "Round summary"
+        );
+    }
+
+    /// Compact one-line summary for humans, e.g., for the admin chat
+    pub fn summary_text(&self) -> String {
+        format!(
+            "{} fetched, {} sent, {} skipped, {} failed",
+            self.fetched, self.sent, self.skipped, self.failed
+        )
+    }
+
+    /// Format in the Prometheus text exposition format
+    pub fn to_prometheus(&self) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("fetched", "Posts fetched from the source", self.fetched),
+            ("sent", "Posts sent to the destination", self.sent),
+            ("skipped", "Posts skipped by the filter", self.skipped),
+            ("retried", "Sends retried due to rate limits", self.retried),
+            ("failed", "Posts failed to be sent", self.failed),
+        ];
+        for (name, help, value) in counters {
+            let name = format!("mastotg_posts_{name}_total");
+            writeln!(out, "# HELP {name} {help}").unwrap();
+            writeln!(out, "# TYPE {name} counter").unwrap();
+            writeln!(out, "{name} {value}").unwrap();
+        }
+
+        let name = "mastotg_failures_total";
+        writeln!(
+            out,
+            "# HELP {name} Failures by class, including retried ones"
+        )
+        .unwrap();
+        writeln!(out, "# TYPE {name} counter").unwrap();
+        for (class, count) in FailureClass::ALL.iter().zip(self.failures.iter()) {
+            writeln!(out, "{name}{{class=\"{class}\"}} {count}").unwrap();
+        }
+
+        write_histogram(
+            &mut out,
+            "mastotg_send_latency_seconds",
+            "Time taken to send a post",
+            &LATENCY_BUCKETS_MS,
+            &self.latency_buckets,
+            self.latency_sum_ms,
+        );
+        write_histogram(
+            &mut out,
+            "mastotg_e2e_latency_seconds",
+            "Time from a post fetched to sent",
+            &E2E_LATENCY_BUCKETS_MS,
+            &self.e2e_latency_buckets,
+            self.e2e_latency_sum_ms,
+        );
+        out
+    }
+}
+
+/// Write the histogram from cumulative buckets in ms
+fn write_histogram(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    bounds: &[u64],
+    buckets: &[u64],
+    sum_ms: u64,
+) {
+    writeln!(out, "# HELP {name} {help}").unwrap();
+    writeln!(out, "# TYPE {name} histogram").unwrap();
+    for (i, count) in buckets.iter().enumerate() {
+        let le = bounds
+            .get(i)
+            .map(|ms| (*ms as f64 / 1000.0).to_string())
+            .unwrap_or("+Inf".to_owned());
+        writeln!(out, "{name}_bucket{{le=\"{le}\"}} {count}").unwrap();
+    }
+    writeln!(out, "{name}_sum {}", sum_ms as f64 / 1000.0).unwrap();
+    // The `+Inf` bucket counts all
+    writeln!(out, "{name}_count {}", buckets.last().unwrap_or(&0)).unwrap();
+}
+
+impl Sub for &Snapshot {
+    type Output = Snapshot;
+
+    fn sub(self, rhs: Self) -> Snapshot {
+        Snapshot {
+            fetched: self.fetched - rhs.fetched,
+            sent: self.sent - rhs.sent,
+            skipped: self.skipped - rhs.skipped,
+            retried: self.retried - rhs.retried,
+            failed: self.failed - rhs.failed,
+            failures: self
+                .failures
+                .iter()
+                .zip(rhs.failures.iter())
+                .map(|(a, b)| a - b)
+                .collect(),
+            latency_buckets: self
+                .latency_buckets
+                .iter()
+                .zip(rhs.latency_buckets.iter())
+                .map(|(a, b)| a - b)
+                .collect(),
+            latency_sum_ms: self.latency_sum_ms - rhs.latency_sum_ms,
+            e2e_latency_buckets: self
+                .e2e_latency_buckets
+                .iter()
+                .zip(rhs.e2e_latency_buckets.iter())
+                .map(|(a, b)| a - b)
+                .collect(),
+            e2e_latency_sum_ms: self.e2e_latency_sum_ms - rhs.e2e_latency_sum_ms,
+        }
+    }
+}
+
+/// Serve the global metrics over HTTP until cancelled.
+/// Every request gets the metrics regardless of the path.
+pub async fn serve(addr: &str, cancel: CancellationToken) -> Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    tracing::info!("Serving metrics at {addr}");
+    loop {
+        let (mut stream, _) = tokio::select! {
+            res = listener.accept() => res?,
+            _ = cancel.cancelled() => break,
+        };
+        tokio::spawn(async move {
+            // Only the request head is expected. The content is ignored.
+            let mut buf = [0; 1024];
+            let _ = stream.read(&mut buf).await;
+            let body = METRICS.snapshot().to_prometheus();
+            let res = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            );
+            if let Err(e) = stream.write_all(res.as_bytes()).await {
+                tracing::debug!("Failed to write the metrics response: {e}");
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_delta() {
+        let metrics = Metrics::new();
+        metrics.inc_fetched(3);
+        let before = metrics.snapshot();
+        metrics.inc_fetched(2);
+        metrics.inc_skipped(1);
+        metrics.observe_sent(300);
+        metrics.observe_sent(40000);
+        let delta = &metrics.snapshot() - &before;
+        assert_eq!(delta.fetched, 2);
+        assert_eq!(delta.skipped, 1);
+        assert_eq!(delta.sent, 2);
+        assert_eq!(delta.latency_buckets, vec![0, 0, 1, 1, 1, 1, 1, 1, 2]);
+        assert_eq!(delta.mean_latency_ms(), 20150);
+        assert_eq!(
+            delta.summary_text(),
+            "2 fetched, 2 sent, 1 skipped, 0 failed"
+        );
+    }
+
+    #[test]
+    fn test_prometheus() {
+        let metrics = Metrics::new();
+        metrics.observe_sent(50);
+        metrics.inc_failure(FailureClass::Media);
+        metrics.observe_e2e(20000);
+        let text = metrics.snapshot().to_prometheus();
+        assert!(text.contains("mastotg_posts_sent_total 1\n"));
+        assert!(text.contains("mastotg_send_latency_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(text.contains("mastotg_send_latency_seconds_bucket{le=\"+Inf\"} 1\n"));
+        assert!(text.contains("mastotg_send_latency_seconds_sum 0.05\n"));
+        assert!(text.contains("mastotg_send_latency_seconds_count 1\n"));
+        assert!(text.contains("mastotg_e2e_latency_seconds_bucket{le=\"15\"} 0\n"));
+        assert!(text.contains("mastotg_e2e_latency_seconds_bucket{le=\"30\"} 1\n"));
+        assert!(text.contains("mastotg_failures_total{class=\"media\"} 1\n"));
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! OpenTelemetry export.
+//! Spans go to the collector via OTLP/HTTP, and so do the in-process metrics as observable counters.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use opentelemetry::metrics::MeterProvider;
+use opentelemetry::trace::TracerProvider;
+use opentelemetry_otlp::{MetricExporter, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::metrics::{PeriodicReader, SdkMeterProvider};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+use crate::metrics::{Snapshot, METRICS};
+
+const SERVICE_NAME: &str = "mastotg";
+
+/// Flushes and shuts down the exporters when dropped
+pub struct OtelGuard {
+    tracer_provider: SdkTracerProvider,
+    meter_provider: SdkMeterProvider,
+}
+
+impl Drop for OtelGuard {
+    fn drop(&mut self) {
+        if let Err(e) = self.tracer_provider.shutdown() {
+            eprintln!("Failed to shut down the OpenTelemetry tracer provider: {e}");
+        }
+        if let Err(e) = self.meter_provider.shutdown() {
+            eprintln!("Failed to shut down the OpenTelemetry meter provider: {e}");
+        }
+    }
+}
+
+/// Set up the exporters.
+/// `endpoint` is the base URL of the OTLP/HTTP receiver, e.g., `http://localhost:4318`.
+/// Must be called outside the async runtime since the exporters use blocking clients.
+pub fn init<S>(endpoint: &str) -> Result<(impl Layer<S>, OtelGuard)>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let endpoint = endpoint.trim_end_matches('/');
+    let resource = Resource::builder().with_service_name(SERVICE_NAME).build();
+
+    let span_exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{endpoint}/v1/traces"))
+        .build()?;
+    let tracer_provider = SdkTracerProvider::builder()
+        .with_batch_exporter(span_exporter)
+        .with_resource(resource.clone())
+        .build();
+    let layer = tracing_opentelemetry::layer().with_tracer(tracer_provider.tracer(SERVICE_NAME));
+
+    let metric_exporter = MetricExporter::builder()
+        .with_http()
+        .with_endpoint(format!("{endpoint}/v1/metrics"))
+        .build()?;
+    let reader = PeriodicReader::builder(metric_exporter)
+        .with_interval(Duration::from_secs(60))
+        .build();
+    let meter_provider = SdkMeterProvider::builder()
+        .with_reader(reader)
+        .with_resource(resource)
+        .build();
+    register_metrics(&meter_provider);
+
+    Ok((
+        layer,
+        OtelGuard {
+            tracer_provider,
+            meter_provider,
+        },
+    ))
+}
+
+/// Name, description, and getter of an exported counter
+type Counter = (&'static str, &'static str, fn(&Snapshot) -> u64);
+
+const COUNTERS: [Counter; 7] = [
+    (
+        "mastotg.posts.fetched",
+        "Posts fetched from the source",
+        |s| s.fetched,
+    ),
+    ("mastotg.posts.sent", "Posts sent to the destination", |s| {
+        s.sent
+    }),
+    (
+        "mastotg.posts.skipped",
+        "Posts skipped by the filter",
+        |s| s.skipped,
+    ),
+    (
+        "mastotg.posts.retried",
+        "Sends retried due to rate limits",
+        |s| s.retried,
+    ),
+    ("mastotg.posts.failed", "Posts failed to be sent", |s| {
+        s.failed
+    }),
+    (
+        "mastotg.send_latency.sum",
+        "Total time taken to send posts in ms",
+        |s| s.latency_sum_ms,
+    ),
+    ("mastotg.send_latency.count", "Number of timed sends", |s| {
+        s.sent
+    }),
+];
+
+/// Report the in-process metrics on every collection
+fn register_metrics(meter_provider: &SdkMeterProvider) {
+    let meter = meter_provider.meter(SERVICE_NAME);
+    for (name, description, get) in COUNTERS {
+        meter
+            .u64_observable_counter(name)
+            .with_description(description)
+            .with_callback(move |observer| observer.observe(get(&METRICS.snapshot()), &[]))
+            .build();
+    }
+}
@@ -0,0 +1,186 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fitting of re-uploaded images into the Telegram photo limits.
+//! Images over the limits are padded, resized, and re-encoded as JPEG at the best quality that fits,
+//! instead of being rejected or degraded by Telegram.
+
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::{self, FilterType};
+use image::io::Reader;
+use image::{DynamicImage, ImageFormat, Rgba, RgbaImage};
+use tokio::task;
+
+use crate::media::SpoolFile;
+
+/// Max size of photos. Unit: Bytes.
+pub const PHOTO_MAX_SIZE: u64 = 10 * 1024 * 1024;
+/// Max sum of the width and the height of photos. Unit: Pixels.
+pub const PHOTO_MAX_SIDES: u32 = 10000;
+/// Max ratio of the longer side to the shorter side of photos
+pub const PHOTO_MAX_RATIO: u32 = 20;
+
+/// JPEG qualities to try in order before shrinking the image
+const QUALITIES: [u8; 4] = [92, 85, 75, 65];
+
+#[derive(Debug, Clone, Default)]
+pub struct PhotoOptions {
+    /// Remove EXIF, e.g., GPS locations, from JPEG images even if they are within the limits.
+    /// Done losslessly without re-encoding.
+    pub strip_exif: bool,
+}
+
+/// Fit the image into the photo limits.
+/// Returns `None` if the image can be sent as is.
+pub async fn fit_photo(
+    image: &SpoolFile,
+    spool_dir: &Path,
+    opts: &PhotoOptions,
+) -> Result<Option<SpoolFile>> {
+    let path = image.path().to_owned();
+    let strip_exif = opts.strip_exif;
+    let Some(body) = task::spawn_blocking(move || fit(&path, strip_exif)).await?? else {
+        return Ok(None);
+    };
+    let out = SpoolFile::with_ext(spool_dir, "jpg");
+    tokio::fs::write(out.path(), body).await?;
+    Ok(Some(out))
+}
+
+fn fit(path: &Path, strip_exif: bool) -> Result<Option<Vec<u8>>> {
+    let size = fs::metadata(path)?.len();
+    let reader = Reader::open(path)?.with_guessed_format()?;
+    let format = reader.format();
+    let (width, height) = reader.into_dimensions()?;
+    if size <= PHOTO_MAX_SIZE && within_limits(width, height) {
+        if strip_exif && format == Some(ImageFormat::Jpeg) {
+            return Ok(strip_jpeg_exif(&fs::read(path)?));
+        }
+        return Ok(None);
+    }
+    let img = Reader::open(path)?.with_guessed_format()?.decode()?;
+    tracing::debug!("Fitting the image of {width}x{height} and {size} bytes into the photo limits");
+    encode_within_limits(img).map(Some)
+}
+
+fn within_limits(width: u32, height: u32) -> bool {
+    let (long, short) = (width.max(height), width.min(height));
+    width + height <= PHOTO_MAX_SIDES && long <= short.saturating_mul(PHOTO_MAX_RATIO)
+}
+
+fn encode_within_limits(img: DynamicImage) -> Result<Vec<u8>> {
+    let mut img = pad_to_ratio(img);
+    let sides = img.width() + img.height();
+    if sides > PHOTO_MAX_SIDES {
+        let scale = PHOTO_MAX_SIDES as f64 / sides as f64;
+        img = shrink(&img, scale);
+    }
+    // Shrinking a few times is enough for any image with a sane size
+    for _ in 0..4 {
+        for quality in QUALITIES {
+            let mut buf = Vec::new();
+            JpegEncoder::new_with_quality(&mut Cursor::new(&mut buf), quality)
+                .encode_image(&img)?;
+            if buf.len() as u64 <= PHOTO_MAX_SIZE {
+                return Ok(buf);
+            }
+        }
+        img = shrink(&img, 0.75);
+    }
+    bail!("image too large to fit into the photo limits")
+}
+
+/// Pad the shorter side with white, so the content is kept unlike cropping.
+/// Transparency is flattened onto the white as JPEG has no alpha.
+fn pad_to_ratio(img: DynamicImage) -> DynamicImage {
+    let (width, height) = (img.width(), img.height());
+    let (long, short) = (width.max(height), width.min(height));
+    let short = short.max(long.div_ceil(PHOTO_MAX_RATIO));
+    let (padded_w, padded_h) = if width >= height {
+        (long, short)
+    } else {
+        (short, long)
+    };
+    let mut bg = RgbaImage::from_pixel(padded_w, padded_h, Rgba([255, 255, 255, 255]));
+    let x = (padded_w - width) / 2;
+    let y = (padded_h - height) / 2;
+    imageops::overlay(&mut bg, &img.to_rgba8(), x as i64, y as i64);
+    DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(bg).to_rgb8())
+}
+
+fn shrink(img: &DynamicImage, scale: f64) -> DynamicImage {
+    let fit = |n: u32| ((n as f64 * scale).floor() as u32).max(1);
+    img.resize(fit(img.width()), fit(img.height()), FilterType::Lanczos3)
+}
+
+/// Remove the EXIF `APP1` segments of the JPEG.
+/// Returns `None` if there is none or the JPEG is malformed.
+pub fn strip_jpeg_exif(jpeg: &[u8]) -> Option<Vec<u8>> {
+    if !jpeg.starts_with(&[0xff, 0xd8]) {
+        return None;
+    }
+    let mut out = jpeg[..2].to_vec();
+    let mut pos = 2;
+    let mut stripped = false;
+    while pos + 4 <= jpeg.len() {
+        if jpeg[pos] != 0xff {
+            return None;
+        }
+        let marker = jpeg[pos + 1];
+        // The entropy-coded data follows the start of scan till the end
+        if marker == 0xda {
+            break;
+        }
+        let len = u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]) as usize;
+        let end = pos + 2 + len;
+        if len < 2 || end > jpeg.len() {
+            return None;
+        }
+        if marker == 0xe1 && jpeg[pos + 4..end].starts_with(b"Exif\0\0") {
+            stripped = true;
+        } else {
+            out.extend_from_slice(&jpeg[pos..end]);
+        }
+        pos = end;
+    }
+    out.extend_from_slice(&jpeg[pos..]);
+    stripped.then_some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use image::RgbImage;
+
+    use super::*;
+
+    fn jpeg(width: u32, height: u32) -> Result<Vec<u8>> {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(width, height));
+        let mut buf = Vec::new();
+        JpegEncoder::new(&mut Cursor::new(&mut buf)).encode_image(&img)?;
+        Ok(buf)
+    }
+
+    #[test]
+    fn test_fit_photo() -> Result<()> {
+        let img = DynamicImage::ImageRgb8(RgbImage::new(3000, 100));
+        let body = encode_within_limits(img)?;
+        let img = image::load_from_memory(&body)?;
+        assert_eq!((img.width(), img.height()), (3000, 150));
+        assert!(!within_limits(12000, 1000));
+        assert!(within_limits(2000, 100));
+
+        let plain = jpeg(8, 8)?;
+        assert_eq!(strip_jpeg_exif(&plain), None);
+        let mut with_exif = plain[..2].to_vec();
+        with_exif.extend_from_slice(&[0xff, 0xe1, 0x00, 0x0a]);
+        with_exif.extend_from_slice(b"Exif\0\0MM");
+        with_exif.extend_from_slice(&plain[2..]);
+        assert_eq!(strip_jpeg_exif(&with_exif), Some(plain));
+        Ok(())
+    }
+}
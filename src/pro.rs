@@ -3,15 +3,59 @@
 
 //! Post produers
 
-use std::io::{self, BufReader};
+#[cfg(feature = "archive")]
+mod archive;
+#[cfg(feature = "feeds")]
+mod atom;
+mod dir;
+#[cfg(feature = "feeds")]
+mod feed;
+#[cfg(feature = "misskey")]
+mod misskey;
+#[cfg(feature = "websub")]
+mod push;
+#[cfg(feature = "feeds")]
+mod rss;
 
-use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, BufReader, Read};
+use std::mem;
+use std::sync::Arc;
+
+use anyhow::{anyhow, ensure, Result};
 use async_trait::async_trait;
+use futures::stream::{self, StreamExt};
 use regex::Regex;
-use tokio::task;
+use reqwest::Url;
+use serde::de::{self, DeserializeOwned, DeserializeSeed, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+use crate::as2::{Activity, CheckContext, CheckType, Deleted, Page, UnknownActivity};
+use crate::capture::HttpCapture;
+use crate::cons::ErrorPolicy;
+use crate::cursor::{retain_after, Cursor};
+use crate::db::DbConn;
+use crate::http;
+use crate::record::FixtureRecorder;
+use crate::redact::redact;
+use crate::utils::{cancellable, check_res, fnv1a, media_type_of_url};
 
-use crate::as2::{CheckContext, CheckType, Page};
-use crate::utils::check_res;
+#[cfg(feature = "archive")]
+pub use archive::ArchivePro;
+#[cfg(feature = "feeds")]
+pub use atom::AtomPro;
+pub use dir::DirPro;
+#[cfg(feature = "misskey")]
+pub use misskey::MisskeyPro;
+#[cfg(feature = "websub")]
+pub use push::PushPro;
+#[cfg(feature = "feeds")]
+pub use rss::RssPro;
 
 /// Producer trait
 #[async_trait]
@@ -21,70 +65,1165 @@ pub trait Pro {
     async fn fetch(&mut self) -> Result<Page>;
 }
 
+/// URL, body hash, and `prev` link of a fetched page
+type PageHash = (String, String, Option<String>);
+
 /// URI producer.
 /// Make HTTP requests for `http(s)://`.
 /// Read the stdin for `stdio://in`.
 pub struct UriPro {
     uri: String,
+    cancel: CancellationToken,
+    recorder: Option<FixtureRecorder>,
+    capture: Option<Arc<HttpCapture>>,
+    fetched: bool,
+    redirected: Option<String>,
+    on_invalid: ErrorPolicy,
+    db: Option<DbConn>,
+    skip_unchanged: bool,
+    /// Hashes of the changed pages fetched in the round, with their URLs and `prev` links
+    hashes: Vec<PageHash>,
+    /// Max number of pages fetched ahead of the returned one
+    prefetch: usize,
+    /// Pages fetched ahead in the background after the first one
+    ahead: Option<Ahead>,
+    /// Whether the last page is fetched
+    ended: bool,
+    /// Number of items per chunk to stream the page from the stdin
+    stream_items: Option<usize>,
+    /// Chunks of the page being streamed
+    stream: Option<mpsc::Receiver<Result<ItemChunk>>>,
+    /// Whether pages come from the newest by `next` links,
+    /// after the `first` page of a collection is followed, e.g., for GoToSocial
+    newest_first: bool,
+    /// Newest handled activity, where the newest-first pages stop
+    cursor: Option<Cursor>,
 }
 
 impl UriPro {
-    pub fn new(uri: String) -> Self {
-        Self { uri }
+    pub fn new(uri: String, cancel: CancellationToken, recorder: Option<FixtureRecorder>) -> Self {
+        Self {
+            uri,
+            cancel,
+            recorder,
+            capture: None,
+            fetched: false,
+            redirected: None,
+            on_invalid: ErrorPolicy::Fail,
+            db: None,
+            skip_unchanged: false,
+            hashes: vec![],
+            prefetch: 0,
+            ahead: None,
+            ended: false,
+            stream_items: None,
+            stream: None,
+            newest_first: false,
+            cursor: None,
+        }
+    }
+
+    /// Drop the invalid items of pages instead of failing the pages.
+    /// The database keeps the dead letters.
+    pub fn with_on_invalid(mut self, on_invalid: ErrorPolicy, db: DbConn) -> Self {
+        self.on_invalid = on_invalid;
+        self.db = Some(db);
+        self
+    }
+
+    /// Skip pages whose bodies are identical to the ones in the last round,
+    /// for servers without ETags polled frequently
+    pub fn with_skip_unchanged(mut self, skip_unchanged: bool, db: DbConn) -> Self {
+        self.skip_unchanged = skip_unchanged;
+        self.db = Some(db);
+        self
+    }
+
+    /// Fetch pages ahead while the earlier ones are being prepared, e.g., during backfills.
+    /// Mostly for servers whose attachments come without media types to be queried.
+    pub fn with_prefetch(mut self, prefetch: usize) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// Parse the page from the stdin as a stream, and return its items in chunks of the number,
+    /// e.g., for archive imports of tens of MB.
+    /// The `prev` link of the streamed page is not followed.
+    pub fn with_stream_items(mut self, stream_items: Option<usize>) -> Self {
+        self.stream_items = stream_items;
+        self
+    }
+
+    /// Stop at the activity when pages come from the newest
+    pub fn with_cursor(mut self, cursor: Option<Cursor>) -> Self {
+        self.cursor = cursor;
+        self
+    }
+
+    /// Save the hashes of the fetched pages.
+    /// Should be called only after the pages are all sent,
+    /// otherwise the unsent posts are skipped as unchanged.
+    pub async fn save_hashes(&mut self) -> Result<()> {
+        let Some(db) = self.db.as_ref() else {
+            return Ok(());
+        };
+        for (url, hash, prev) in self.hashes.drain(..) {
+            db.save_page_hash(url, hash, prev).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns the `prev` link of the page if its body is unchanged since the last round
+    async fn unchanged(&self, body: &[u8]) -> Result<Option<Option<String>>> {
+        let db = match self.db.as_ref() {
+            Some(db) if self.skip_unchanged => db,
+            _ => return Ok(None),
+        };
+        let hash = format!("{:016x}", fnv1a(body));
+        let res = db
+            .query_page_hash(self.uri.clone())
+            .await?
+            .filter(|(saved, _)| *saved == hash)
+            .map(|(_, prev)| prev);
+        Ok(res)
+    }
+
+    /// Final URL of the first request if redirected, e.g., after instance domain migrations
+    pub fn redirected(&self) -> Option<&str> {
+        self.redirected.as_deref()
+    }
+
+    /// Capture the HTTP requests for debugging
+    pub fn with_capture(mut self, capture: Option<Arc<HttpCapture>>) -> Self {
+        self.capture = capture;
+        self
     }
 }
 
 impl UriPro {
-    async fn fetch_http(url: &str) -> Result<Page> {
-        let page: Page = check_res(reqwest::get(url).await?).await?.json().await?;
-        Ok(page)
+    /// Returns the body, and the final URL if redirected
+    async fn fetch_http(
+        url: &str,
+        capture: Option<&HttpCapture>,
+    ) -> Result<(Vec<u8>, Option<String>)> {
+        let res = http::client()
+            .get(url)
+            .header("accept", http::ACCEPT_AP)
+            .send()
+            .await?;
+        let final_url = res.url().as_str();
+        let redirected = (final_url != Url::parse(url)?.as_str()).then(|| final_url.to_owned());
+        let capture = match capture {
+            Some(capture) if capture.is_active() => capture,
+            _ => {
+                let body = check_res(res).await?.bytes().await?.to_vec();
+                return Ok((body, redirected));
+            }
+        };
+        // The body is needed even for failed requests
+        let status = res.status();
+        let body = res.bytes().await?;
+        capture
+            .http("outbox", "GET", url, status.as_u16(), &body)
+            .await;
+        ensure!(
+            status.is_success(),
+            "request to {} failed with status code {status}",
+            redact(url)
+        );
+        Ok((body.to_vec(), redirected))
     }
 
-    async fn fetch_stdin() -> Result<Page> {
+    async fn fetch_stdin() -> Result<Vec<u8>> {
         task::spawn_blocking(move || {
-            let r = BufReader::new(io::stdin());
-            let page: Page = serde_json::from_reader(r)?;
-            Ok(page)
+            let mut body = Vec::new();
+            BufReader::new(io::stdin()).read_to_end(&mut body)?;
+            Ok(body)
         })
         .await?
     }
+
+    /// Deserialize and validate a page except its items
+    fn parse_page(body: &[u8]) -> Result<Page> {
+        let page: Page = serde_json::from_slice(body)?;
+
+        page.check_context()?;
+        page.check_type()?;
+        Ok(page)
+    }
+
+    /// Validate the items of the page.
+    /// Invalid items fail the page, or are replaced according to the policy
+    /// with unknown activities, which consumers skip while still advancing the cursor.
+    /// The raw items are only taken when there are invalid ones.
+    async fn check_items(
+        &self,
+        raw_items: impl FnOnce() -> Result<Vec<Value>>,
+        page: &mut Page,
+    ) -> Result<()> {
+        let mut errors: Vec<_> = page
+            .ordered_items
+            .iter()
+            .enumerate()
+            .filter_map(|(i, item)| check_item(item).err().map(|e| (i, e)))
+            .collect();
+        if errors.is_empty() {
+            return Ok(());
+        }
+        if self.on_invalid == ErrorPolicy::Fail {
+            return Err(errors.swap_remove(0).1);
+        }
+        // The raw items are kept since the parsed ones miss the unknown fields
+        let raw = raw_items()?;
+        for (i, e) in errors {
+            let item = &mut page.ordered_items[i];
+            tracing::warn!(post = %item.id(), "Invalid item: {e:#}");
+            if let (ErrorPolicy::DeadLetter, Some(db)) = (self.on_invalid, self.db.as_ref()) {
+                let act = raw[i].to_string();
+                let error = redact(&format!("{e:#}"));
+                db.save_dead_letter(item.id().to_owned(), act, error)
+                    .await?;
+            }
+            *item = Activity::Unknown(UnknownActivity {
+                id: item.id().to_owned(),
+                r#type: format!("invalid {}", item.type_name()),
+            });
+        }
+        Ok(())
+    }
+
+    /// Take the next chunk of the page streamed from the stdin.
+    /// Returns an empty page after the last chunk.
+    async fn fetch_streamed(&mut self, chunk_size: usize) -> Result<Page> {
+        let rx = self.stream.get_or_insert_with(|| {
+            // Holds a chunk while the next one is being parsed, so memory is bounded by a few chunks
+            let (tx, rx) = mpsc::channel(1);
+            task::spawn_blocking(move || stream_items(BufReader::new(io::stdin()), chunk_size, tx));
+            rx
+        });
+        self.fetched = true;
+        let chunk = cancellable(&self.cancel, async { Ok(rx.recv().await) }).await?;
+        let Some((mut page, raw)) = chunk.transpose()? else {
+            return Ok(Page::empty(self.uri.clone()));
+        };
+        page.ordered_items = raw
+            .iter()
+            .map(Activity::deserialize)
+            .collect::<Result<_, _>>()?;
+        self.check_items(|| Ok(raw), &mut page).await?;
+        Ok(page)
+    }
 }
 
-#[async_trait]
-impl Pro for UriPro {
-    async fn fetch(&mut self) -> Result<Page> {
+/// Chunk of the items of a streamed page, with the page whose own items are left empty
+type ItemChunk = (Page, Vec<Value>);
+
+/// Parse the page from the reader as a stream, and send its items in chunks.
+/// The whole page is never in memory.
+/// The context and the type must come before the items, so they are validated first.
+fn stream_items(reader: impl Read, chunk_size: usize, tx: mpsc::Sender<Result<ItemChunk>>) {
+    let mut de = serde_json::Deserializer::from_reader(reader);
+    let seed = PageSeed {
+        chunk_size,
+        tx: &tx,
+    };
+    let res = seed.deserialize(&mut de).and_then(|()| de.end());
+    if let Err(e) = res {
+        // Nothing to do if the receiver has gone
+        let _ = tx.blocking_send(Err(e.into()));
+    }
+}
+
+struct PageSeed<'a> {
+    chunk_size: usize,
+    tx: &'a mpsc::Sender<Result<ItemChunk>>,
+}
+
+impl<'de> DeserializeSeed<'de> for PageSeed<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_map(self)
+    }
+}
+
+impl<'de> Visitor<'de> for PageSeed<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("an outbox page")
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<(), A::Error> {
+        let mut head = serde_json::Map::new();
+        let mut found = false;
+        while let Some(key) = map.next_key::<String>()? {
+            if key != "orderedItems" || found {
+                head.insert(key, map.next_value()?);
+                continue;
+            }
+            found = true;
+            let mut page = head.clone();
+            page.insert(key, Value::Array(vec![]));
+            let page: Page =
+                serde_json::from_value(Value::Object(page)).map_err(de::Error::custom)?;
+            page.check_context()
+                .and_then(|()| page.check_type())
+                .map_err(|e| de::Error::custom(format!("{e:#}")))?;
+            map.next_value_seed(ItemsSeed {
+                chunk_size: self.chunk_size,
+                tx: self.tx,
+                page,
+            })?;
+        }
+        if !found {
+            return Err(de::Error::missing_field("orderedItems"));
+        }
+        Ok(())
+    }
+}
+
+struct ItemsSeed<'a> {
+    chunk_size: usize,
+    tx: &'a mpsc::Sender<Result<ItemChunk>>,
+    page: Page,
+}
+
+impl ItemsSeed<'_> {
+    fn send<E: de::Error>(&self, items: Vec<Value>) -> Result<(), E> {
+        self.tx
+            .blocking_send(Ok((self.page.clone(), items)))
+            .map_err(|_| E::custom("streaming stopped"))
+    }
+}
+
+impl<'de> DeserializeSeed<'de> for ItemsSeed<'_> {
+    type Value = ();
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<(), D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> Visitor<'de> for ItemsSeed<'_> {
+    type Value = ();
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a list of activities")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<(), A::Error> {
+        let mut items = Vec::with_capacity(self.chunk_size);
+        while let Some(item) = seq.next_element()? {
+            items.push(item);
+            if items.len() >= self.chunk_size {
+                self.send(mem::take(&mut items))?;
+            }
+        }
+        if !items.is_empty() {
+            self.send(items)?;
+        }
+        Ok(())
+    }
+}
+
+/// Validate the item of a page, including its post and attachments.
+/// Attachments must be remote, as only archives may give local media.
+fn check_item(item: &Activity) -> Result<()> {
+    check_item_with(item, check_media_url)
+}
+
+/// Validate the item of a page with the check of the attachment URLs
+fn check_item_with(item: &Activity, check_url: impl Fn(&str) -> Result<()>) -> Result<()> {
+    let post = match item {
+        Activity::Create(act) => &act.object,
+        Activity::Update(act) => &act.object,
+        Activity::Announce(_) => return Ok(()),
+        Activity::Delete(act) => {
+            return match &act.object {
+                Deleted::Tombstone(obj) => obj.check_type(),
+                Deleted::Id(_) => Ok(()),
+            }
+        }
+        Activity::Unknown(act) => return act.check_type(),
+    };
+    post.check_type()?;
+    post.attachment.iter().try_for_each(|att| {
+        att.check_type()?;
+        check_url(&att.url)
+    })?;
+    Ok(())
+}
+
+/// Only remote HTTP(S) media are accepted
+fn check_media_url(url: &str) -> Result<()> {
+    let scheme = Url::parse(url).map(|u| u.scheme().to_owned());
+    ensure!(
+        matches!(scheme.as_deref(), Ok("http" | "https")),
+        "unsupported media URL {}",
+        redact(url)
+    );
+    Ok(())
+}
+
+/// `first` of a collection
+enum First {
+    Url(String),
+    /// Embedded page with the context of the collection
+    Page(Vec<u8>),
+}
+
+/// `first` of the body if it is a collection instead of a page
+fn collection_first(body: &[u8]) -> Result<Option<First>> {
+    let Ok(mut value) = serde_json::from_slice::<Value>(body) else {
+        return Ok(None);
+    };
+    if !matches!(
+        value["type"].as_str(),
+        Some("OrderedCollection" | "Collection")
+    ) {
+        return Ok(None);
+    }
+    Ok(match value["first"].take() {
+        Value::String(url) => Some(First::Url(url)),
+        mut page @ Value::Object(_) => {
+            page["@context"] = value["@context"].take();
+            Some(First::Page(serde_json::to_vec(&page)?))
+        }
+        _ => None,
+    })
+}
+
+/// Fill in the media types of attachments that come without them.
+/// Guessed from the URL extension first, then from the `Content-Type` of a HEAD request.
+/// Attachments that still have no media types are left to fail in the consumers.
+/// HEAD requests of the attachments of the page are sent concurrently, up to [`INFER_CONCURRENCY`] at once.
+async fn infer_media_types(page: &mut Page) -> Result<()> {
+    let client = http::client();
+    let posts = page.ordered_items.iter_mut().filter_map(|item| match item {
+        Activity::Create(act) => Some(&mut act.object),
+        Activity::Update(act) => Some(&mut act.object),
+        _ => None,
+    });
+    let mut unknown = Vec::new();
+    for post in posts {
+        for att in post.attachment.iter_mut() {
+            if !att.media_type.is_empty() {
+                continue;
+            }
+            if let Some(media_type) = media_type_of_url(&att.url) {
+                att.media_type = media_type.to_owned();
+                continue;
+            }
+            // The subtype is not used to send, so the type of the object is enough
+            if let Some(kind) = att.media_kind() {
+                att.media_type = format!("{kind}/*");
+                continue;
+            }
+            unknown.push((&post.id, att));
+        }
+    }
+    stream::iter(unknown)
+        .for_each_concurrent(INFER_CONCURRENCY, |(id, att)| async move {
+            match head_media_type(client, &att.url).await {
+                Ok(Some(media_type)) => att.media_type = media_type,
+                Ok(None) => {
+                    tracing::warn!(post = %id, "No media type found for the attachment {}", redact(&att.url))
+                }
+                Err(e) => {
+                    tracing::warn!(post = %id, "Failed to infer the media type of the attachment: {e:#}")
+                }
+            }
+        })
+        .await;
+    Ok(())
+}
+
+async fn head_media_type(client: &reqwest::Client, url: &str) -> Result<Option<String>> {
+    let res = check_res(client.head(url).send().await?).await?;
+    let media_type = res
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        // Drop parameters like `; charset=utf-8`
+        .and_then(|value| value.split(';').next())
+        .map(|value| value.trim().to_owned())
+        .filter(|value| value.contains('/'));
+    Ok(media_type)
+}
+
+impl UriPro {
+    /// Fetch, parse, and validate the page, and move to the next one
+    async fn fetch_page(&mut self) -> Result<Page> {
+        if let Some(chunk_size) = self.stream_items.filter(|_| self.uri == "stdio://in") {
+            return self.fetch_streamed(chunk_size).await;
+        }
+        if self.ended {
+            return Ok(Page::empty(self.uri.clone()));
+        }
         let re = Regex::new(r"^[^:/]+?(?:://)").unwrap();
-        let proto = re.find(&self.uri).map(|m| m.as_str());
+        let proto = re.find(&self.uri).map(|m| m.as_str().to_owned());
         let err = || anyhow!("invalid uri {}", self.uri);
-        let page = match proto {
-            Some("http://") | Some("https://") => Self::fetch_http(&self.uri).await,
+        let body = match proto.as_deref() {
+            Some("http://") | Some("https://") => loop {
+                let (mut body, redirected) = cancellable(
+                    &self.cancel,
+                    Self::fetch_http(&self.uri, self.capture.as_deref()),
+                )
+                .await?;
+                if !self.fetched {
+                    self.redirected = redirected;
+                }
+                self.fetched = true;
+                if !self.newest_first {
+                    if let Some(first) = collection_first(&body)? {
+                        tracing::debug!("The outbox is a collection and pages from the newest");
+                        self.newest_first = true;
+                        match first {
+                            First::Url(url) => {
+                                self.uri = url;
+                                continue;
+                            }
+                            First::Page(page) => body = page,
+                        }
+                    }
+                }
+                // Posts of unchanged pages are all handled in the last round
+                match self.unchanged(&body).await? {
+                    None => break Ok(body),
+                    Some(Some(prev)) => {
+                        tracing::debug!("Skipped the unchanged page {}", redact(&self.uri));
+                        self.uri = prev;
+                    }
+                    Some(None) => {
+                        tracing::debug!("Skipped the unchanged page {}", redact(&self.uri));
+                        return Ok(Page::empty(self.uri.clone()));
+                    }
+                }
+            },
             Some("stdio://") => {
                 if self.uri == "stdio://in" {
-                    Self::fetch_stdin().await
+                    cancellable(&self.cancel, Self::fetch_stdin()).await
                 } else {
                     Err(err())
                 }
             }
             _ => Err(err()),
         }?;
+        self.fetched = true;
+        let res = match Self::parse_page(&body) {
+            Ok(mut page) => {
+                let raw_items = || {
+                    let mut raw: Value = serde_json::from_slice(&body)?;
+                    Ok(match raw["orderedItems"].take() {
+                        Value::Array(items) => items,
+                        _ => vec![],
+                    })
+                };
+                self.check_items(raw_items, &mut page).await.map(|()| page)
+            }
+            Err(e) => Err(e),
+        };
+        if let Some(recorder) = self.recorder.as_ref() {
+            // Recording is for debugging and is not to fail the round
+            if let Err(e) = recorder.record(&body, res.as_ref().ok()).await {
+                tracing::warn!("Failed to record the fetched page: {e:#}");
+            }
+        }
+        let mut page = res?;
+        if self.skip_unchanged && proto.as_deref() != Some("stdio://") {
+            let hash = format!("{:016x}", fnv1a(&body));
+            self.hashes
+                .push((self.uri.clone(), hash, page.prev.clone()));
+        }
 
-        page.check_context()?;
-        page.check_type()?;
-        page.ordered_items.iter().try_for_each(|item| {
-            item.check_type()?;
-            let post = &item.object;
-            post.check_type()?;
-            post.attachment
-                .iter()
-                .try_for_each(|att| att.check_type())?;
-            post.tag.iter().try_for_each(|tag| tag.check_type())?;
-            anyhow::Ok(())
-        })?;
+        if !self.newest_first {
+            if let Some(next_uri) = page.prev.as_ref() {
+                self.uri = next_uri.clone()
+            }
+            return Ok(page);
+        }
+        // Older pages are not followed once the handled activity is reached
+        let len = page.ordered_items.len();
+        if let Some(cursor) = self.cursor.as_ref() {
+            retain_after(&mut page.ordered_items, cursor);
+        }
+        match page.next.as_ref() {
+            Some(next_uri) if page.ordered_items.len() == len && len > 0 => {
+                self.uri = next_uri.clone()
+            }
+            _ => self.ended = true,
+        }
+        Ok(page)
+    }
+}
+
+/// Pages fetched ahead in the background, in order.
+/// Each comes with the hashes to save once it is returned.
+struct Ahead {
+    pages: mpsc::Receiver<(JoinHandle<Result<Page>>, Vec<PageHash>)>,
+    task: JoinHandle<()>,
+}
+
+impl UriPro {
+    /// Fetch and prepare the page
+    async fn fetch_prepared(&mut self) -> Result<Page> {
+        let mut page = self.fetch_page().await?;
+        cancellable(&self.cancel, infer_media_types(&mut page)).await?;
+        Ok(page)
+    }
+
+    /// Mark the page as the last one if it links to no more
+    fn end_at(&mut self, page: &Page) {
+        // Streamed pages end with empty ones
+        self.ended = self.ended
+            || page.ordered_items.is_empty()
+            || (page.prev.is_none() && self.stream.is_none() && !self.newest_first);
+    }
+
+    /// Move the fetching of the later pages to the background.
+    /// The state of the round, e.g., the redirected URL, is kept here.
+    fn spawn_ahead(&mut self) {
+        let shell = Self::new(self.uri.clone(), self.cancel.clone(), None);
+        let mut pager = mem::replace(self, shell);
+        self.prefetch = pager.prefetch;
+        self.db = pager.db.clone();
+        self.redirected = pager.redirected.clone();
+        self.hashes = mem::take(&mut pager.hashes);
+
+        // Pages are fetched one by one as each links to the next,
+        // but prepared concurrently and still returned in order
+        let (tx, rx) = mpsc::channel(self.prefetch);
+        let task = tokio::spawn(async move {
+            while !pager.ended {
+                let res = pager.fetch_page().await;
+                let hashes = mem::take(&mut pager.hashes);
+                let cancel = pager.cancel.clone();
+                let prepare = match res {
+                    Ok(mut page) => {
+                        pager.end_at(&page);
+                        tokio::spawn(async move {
+                            cancellable(&cancel, infer_media_types(&mut page)).await?;
+                            Ok(page)
+                        })
+                    }
+                    Err(e) => {
+                        // Returned after the pages before it
+                        pager.ended = true;
+                        tokio::spawn(async move { Err(e) })
+                    }
+                };
+                if tx.send((prepare, hashes)).await.is_err() {
+                    break;
+                }
+            }
+        });
+        self.ahead = Some(Ahead { pages: rx, task });
+    }
+}
+
+#[async_trait]
+impl Pro for UriPro {
+    async fn fetch(&mut self) -> Result<Page> {
+        if self.prefetch == 0 {
+            return self.fetch_prepared().await;
+        }
+        let Some(ahead) = self.ahead.as_mut() else {
+            if self.ended {
+                return Ok(Page::empty(self.uri.clone()));
+            }
+            // The first page is returned as soon as it is ready, while the later ones are fetched
+            let page = self.fetch_prepared().await?;
+            self.end_at(&page);
+            if !self.ended {
+                self.spawn_ahead();
+            }
+            return Ok(page);
+        };
+        let next = async { Ok(ahead.pages.recv().await) };
+        match cancellable(&self.cancel, next).await? {
+            Some((prepare, hashes)) => {
+                self.hashes.extend(hashes);
+                cancellable(&self.cancel, async { prepare.await? }).await
+            }
+            None => Ok(Page::empty(self.uri.clone())),
+        }
+    }
+}
+
+impl Drop for Ahead {
+    fn drop(&mut self) {
+        self.task.abort();
+        self.pages.close();
+        while let Ok((prepare, _)) = self.pages.try_recv() {
+            prepare.abort();
+        }
+    }
+}
+
+/// Mastodon REST API producer.
+/// Pulls the statuses of the account with an access token instead of the public outbox,
+/// so unlisted and followers-only posts are included with the polls and the engagement stats.
+/// Statuses are converted to activities like the ones in the outbox.
+///
+/// Pages are queried from the oldest after `min_id`, and each page lists the newest first.
+/// The local public timeline of the server is queried in the same way.
+pub struct ApiPro {
+    /// Base URL of the server, e.g., `https://mastodon.social`
+    host: String,
+    /// Account of the statuses, or `None` for the local public timeline
+    acct: Option<String>,
+    /// Optional for the public timeline
+    token: Option<String>,
+    cancel: CancellationToken,
+    /// Looked up on the first fetch
+    account_id: Option<String>,
+    /// Statuses newer than it are queried. Moves to the newest fetched.
+    /// The latest page is queried if `None`.
+    min_id: Option<String>,
+    max_id: Option<u64>,
+    /// GUIDs of the statuses by their IDs to resolve the replied ones
+    uris: HashMap<String, String>,
+}
+
+/// Env var of the access token of [`ApiPro`]
+pub const ACCESS_TOKEN_ENV: &str = "MASTODON_ACCESS_TOKEN";
+
+/// Max number of concurrent HEAD requests to infer the media types of a page
+const INFER_CONCURRENCY: usize = 8;
+
+/// Max number of statuses per page allowed by Mastodon
+const API_PAGE_LIMIT: &str = "40";
+
+impl ApiPro {
+    pub fn new(host: String, acct: String, token: String, cancel: CancellationToken) -> Self {
+        Self {
+            host: host.trim_end_matches('/').to_owned(),
+            acct: Some(acct),
+            token: Some(token),
+            cancel,
+            account_id: None,
+            min_id: None,
+            max_id: None,
+            uris: HashMap::new(),
+        }
+    }
+
+    /// Query the local public timeline, with the token if the server requires one
+    pub fn local_timeline(host: String, token: Option<String>, cancel: CancellationToken) -> Self {
+        Self {
+            host: host.trim_end_matches('/').to_owned(),
+            acct: None,
+            token,
+            cancel,
+            account_id: None,
+            min_id: None,
+            max_id: None,
+            uris: HashMap::new(),
+        }
+    }
+
+    /// Query statuses newer than the ID, or the latest ones if `None`
+    pub fn with_min_id(mut self, min_id: Option<i64>) -> Self {
+        self.min_id = min_id.map(|id| id.to_string());
+        self
+    }
+
+    /// Query statuses older than the ID
+    pub fn with_max_id(mut self, max_id: Option<u64>) -> Self {
+        self.max_id = max_id;
+        self
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str, query: &[(&str, String)]) -> Result<T> {
+        let url = format!("{}/{path}", self.host);
+        let mut req = http::client().get(url).query(query);
+        if let Some(token) = self.token.as_ref() {
+            req = req.bearer_auth(token);
+        }
+        let res = req.send().await?;
+        Ok(check_res(res).await?.json().await?)
+    }
+
+    async fn account_id(&mut self, acct: String) -> Result<String> {
+        if let Some(id) = self.account_id.as_ref() {
+            return Ok(id.clone());
+        }
+        let account: ApiAccount = self
+            .get("api/v1/accounts/lookup", &[("acct", acct)])
+            .await?;
+        self.account_id = Some(account.id.clone());
+        Ok(account.id)
+    }
 
-        if let Some(next_uri) = page.prev.as_ref() {
-            self.uri = next_uri.clone()
+    /// GUID of the replied status.
+    /// Falls back to the API URL of the status if it can not be queried, e.g., deleted.
+    async fn status_uri(&mut self, id: &str) -> Result<String> {
+        if let Some(uri) = self.uris.get(id) {
+            return Ok(uri.clone());
         }
+        let path = format!("api/v1/statuses/{id}");
+        let uri = match self.get::<ApiStatusUri>(&path, &[]).await {
+            Ok(status) => status.uri,
+            Err(e) => {
+                tracing::warn!("Failed to query the replied status {id}: {e:#}");
+                format!("{}/{path}", self.host)
+            }
+        };
+        self.uris.insert(id.to_owned(), uri.clone());
+        Ok(uri)
+    }
 
+    async fn fetch_statuses(&mut self) -> Result<Page> {
+        let mut query = vec![("limit", API_PAGE_LIMIT.to_owned())];
+        let path = match self.acct.clone() {
+            Some(acct) => {
+                let account_id = self.account_id(acct).await?;
+                format!("api/v1/accounts/{account_id}/statuses")
+            }
+            None => {
+                query.push(("local", "true".to_owned()));
+                "api/v1/timelines/public".to_owned()
+            }
+        };
+        if let Some(min_id) = self.min_id.as_ref() {
+            query.push(("min_id", min_id.clone()));
+        }
+        if let Some(max_id) = self.max_id {
+            query.push(("max_id", max_id.to_string()));
+        }
+        let statuses: Vec<ApiStatus> = self.get(&path, &query).await?;
+        for status in statuses.iter() {
+            self.uris.insert(status.id.clone(), status.uri.clone());
+        }
+        let mut items = Vec::with_capacity(statuses.len());
+        for status in statuses.iter() {
+            let in_reply_to = match status.in_reply_to_id.as_deref() {
+                Some(id) => Some(self.status_uri(id).await?),
+                None => None,
+            };
+            items.push(status_activity(status, in_reply_to)?);
+        }
+        // Listed from the newest
+        if let Some(newest) = statuses.first() {
+            self.min_id = Some(newest.id.clone());
+        }
+        let mut page = Page::empty(format!("{}/{path}", self.host));
+        page.ordered_items = items;
         Ok(page)
     }
 }
+
+#[async_trait]
+impl Pro for ApiPro {
+    async fn fetch(&mut self) -> Result<Page> {
+        let cancel = self.cancel.clone();
+        let mut page = cancellable(&cancel, self.fetch_statuses()).await?;
+        cancellable(&cancel, infer_media_types(&mut page)).await?;
+        Ok(page)
+    }
+}
+
+/// Convert the status to the activity in the outbox.
+/// Boosts become `Announce`s, and others become `Create`s.
+fn status_activity(status: &ApiStatus, in_reply_to: Option<String>) -> Result<Activity> {
+    if let Some(reblog) = status.reblog.as_ref() {
+        let act = json!({
+            "type": "Announce",
+            "id": status.uri,
+            "object": reblog.uri,
+            "published": status.created_at,
+        });
+        return Ok(serde_json::from_value(act)?);
+    }
+    let actor = status.account.actor_id();
+    let public = "https://www.w3.org/ns/activitystreams#Public".to_owned();
+    let followers = format!("{actor}/followers");
+    let (to, cc) = match status.visibility.as_str() {
+        "public" => (vec![public], vec![followers]),
+        "unlisted" => (vec![followers], vec![public]),
+        "private" => (vec![followers], vec![]),
+        _ => (vec![], vec![]),
+    };
+    let attachment: Vec<_> = status
+        .media_attachments
+        .iter()
+        .map(|att| {
+            let r#type = match att.r#type.as_str() {
+                "image" => "Image",
+                "video" | "gifv" => "Video",
+                "audio" => "Audio",
+                _ => "Document",
+            };
+            json!({
+                "type": r#type,
+                "url": att.url,
+                "name": att.description,
+                "blurhash": att.blurhash,
+            })
+        })
+        .collect();
+    let tags = status
+        .tags
+        .iter()
+        .map(|tag| json!({"type": "Hashtag", "href": tag.url, "name": format!("#{}", tag.name)}));
+    let mentions = status.mentions.iter().map(|mention| {
+        let name = format!("@{}", mention.acct);
+        json!({"type": "Mention", "href": mention.url, "name": name})
+    });
+    let emojis = status.emojis.iter().map(|emoji| {
+        let name = format!(":{}:", emoji.shortcode);
+        json!({"type": "Emoji", "name": name, "icon": {"url": emoji.url}})
+    });
+    let tag: Vec<_> = tags.chain(mentions).chain(emojis).collect();
+    let mut post = json!({
+        "id": status.uri,
+        "type": if status.poll.is_some() { "Question" } else { "Note" },
+        "summary": Some(&status.spoiler_text).filter(|s| !s.is_empty()),
+        "inReplyTo": in_reply_to,
+        "published": status.created_at,
+        "updated": status.edited_at,
+        "url": status.url.as_ref().unwrap_or(&status.uri),
+        // Embedded for the attribution of the authors
+        "attributedTo": {
+            "id": actor,
+            "type": "Person",
+            "name": Some(&status.account.display_name).filter(|s| !s.is_empty()),
+            "preferredUsername": Some(&status.account.username).filter(|s| !s.is_empty()),
+        },
+        "to": to,
+        "cc": cc,
+        "sensitive": status.sensitive,
+        "content": status.content,
+        "contentMap": status.language.iter().map(|lang| (lang, &status.content)).collect::<HashMap<_, _>>(),
+        "attachment": attachment,
+        "tag": tag,
+        "replies": {"totalItems": status.replies_count},
+        "likes": {"totalItems": status.favourites_count},
+        "shares": {"totalItems": status.reblogs_count},
+    });
+    if let Some(poll) = status.poll.as_ref() {
+        let options: Vec<_> = poll
+            .options
+            .iter()
+            .map(|opt| {
+                let votes = opt.votes_count.unwrap_or(0);
+                json!({"name": opt.title, "replies": {"totalItems": votes}})
+            })
+            .collect();
+        let key = if poll.multiple { "anyOf" } else { "oneOf" };
+        post[key] = options.into();
+        post["endTime"] = poll.expires_at.clone().into();
+        post["closed"] = poll.expired.then(|| poll.expires_at.clone()).into();
+        post["votersCount"] = poll.voters_count.into();
+    }
+    let act = json!({
+        "type": "Create",
+        "id": format!("{}/activity", status.uri),
+        "object": post,
+    });
+    Ok(serde_json::from_value(act)?)
+}
+
+/// Status of the Mastodon REST API. Many unused fields are ignored.
+#[derive(Deserialize)]
+struct ApiStatus {
+    id: String,
+    /// GUID of the status
+    uri: String,
+    url: Option<String>,
+    created_at: String,
+    edited_at: Option<String>,
+    in_reply_to_id: Option<String>,
+    #[serde(default)]
+    sensitive: bool,
+    #[serde(default)]
+    spoiler_text: String,
+    /// `public`, `unlisted`, `private`, or `direct`
+    visibility: String,
+    content: String,
+    /// ISO 639 code of the language if known
+    #[serde(default)]
+    language: Option<String>,
+    account: ApiAccount,
+    #[serde(default)]
+    media_attachments: Vec<ApiAttachment>,
+    #[serde(default)]
+    tags: Vec<ApiTag>,
+    #[serde(default)]
+    mentions: Vec<ApiMention>,
+    #[serde(default)]
+    emojis: Vec<ApiEmoji>,
+    poll: Option<ApiPoll>,
+    reblog: Option<Box<ApiStatus>>,
+    #[serde(default)]
+    replies_count: u64,
+    #[serde(default)]
+    reblogs_count: u64,
+    #[serde(default)]
+    favourites_count: u64,
+}
+
+#[derive(Deserialize)]
+struct ApiStatusUri {
+    uri: String,
+}
+
+#[derive(Deserialize)]
+struct ApiAccount {
+    id: String,
+    #[serde(default)]
+    username: String,
+    #[serde(default)]
+    display_name: String,
+    #[serde(default)]
+    url: String,
+    /// GUID of the actor. Only given by Mastodon 4.2+.
+    uri: Option<String>,
+}
+
+impl ApiAccount {
+    /// GUID of the actor, guessed from the profile URL like Mastodon if not given
+    fn actor_id(&self) -> String {
+        if let Some(uri) = self.uri.as_ref() {
+            return uri.clone();
+        }
+        match Url::parse(&self.url) {
+            Ok(url) => format!(
+                "{}://{}/users/{}",
+                url.scheme(),
+                url.host_str().unwrap_or_default(),
+                self.username
+            ),
+            Err(_) => self.url.clone(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ApiAttachment {
+    /// `image`, `gifv`, `video`, `audio`, or `unknown`
+    r#type: String,
+    url: String,
+    description: Option<String>,
+    blurhash: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ApiTag {
+    name: String,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct ApiMention {
+    url: String,
+    acct: String,
+}
+
+#[derive(Deserialize)]
+struct ApiEmoji {
+    shortcode: String,
+    url: String,
+}
+
+#[derive(Deserialize)]
+struct ApiPoll {
+    expires_at: Option<String>,
+    #[serde(default)]
+    expired: bool,
+    #[serde(default)]
+    multiple: bool,
+    voters_count: Option<u64>,
+    options: Vec<ApiPollOption>,
+}
+
+#[derive(Deserialize)]
+struct ApiPollOption {
+    title: String,
+    /// Hidden until the poll ends if the results are hidden
+    votes_count: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::thread;
+
+    use super::*;
+
+    fn stream(body: String, chunk_size: usize) -> Vec<Result<ItemChunk>> {
+        let (tx, mut rx) = mpsc::channel(1);
+        let handle = thread::spawn(move || stream_items(body.as_bytes(), chunk_size, tx));
+        let mut chunks = vec![];
+        while let Some(chunk) = rx.blocking_recv() {
+            chunks.push(chunk);
+        }
+        handle.join().unwrap();
+        chunks
+    }
+
+    #[test]
+    fn test_stream_items() -> Result<()> {
+        let fixture = |name: &str| -> Result<String> {
+            let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures")
+                .join(name.to_owned() + ".json");
+            Ok(fs::read_to_string(path)?)
+        };
+        let items = ["create", "announce", "delete", "update", "create"]
+            .into_iter()
+            .map(fixture)
+            .collect::<Result<Vec<_>>>()?
+            .join(",");
+        let body = format!(
+            r#"{{"@context": "https://www.w3.org/ns/activitystreams", "id": "https://example.com/outbox?page=true",
+            "type": "OrderedCollectionPage", "orderedItems": [{items}], "totalItems": 5}}"#
+        );
+        let chunks = stream(body, 2).into_iter().collect::<Result<Vec<_>>>()?;
+        let lens: Vec<_> = chunks.iter().map(|(_, raw)| raw.len()).collect();
+        assert_eq!(lens, [2, 2, 1]);
+        let (page, raw) = &chunks[1];
+        assert_eq!(page.id, "https://example.com/outbox?page=true");
+        assert!(matches!(
+            Activity::deserialize(&raw[0])?,
+            Activity::Delete(_)
+        ));
+
+        // The type is validated before the items
+        let body = r#"{"@context": "https://www.w3.org/ns/activitystreams", "id": "https://example.com/outbox",
+            "type": "OrderedCollection", "orderedItems": [{}]}"#;
+        let chunks = stream(body.to_owned(), 2);
+        assert!(matches!(&chunks[..], [Err(_)]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_status_activity() -> Result<()> {
+        let status: ApiStatus = serde_json::from_value(json!({
+            "id": "110826550717756447",
+            "uri": "https://social.myl.moe/users/alice/statuses/110826550717756447",
+            "created_at": "2023-08-03T16:09:19.000Z",
+            "in_reply_to_id": null,
+            "visibility": "public",
+            "content": "<p>Hi</p>",
+            "account": {
+                "id": "2",
+                "username": "alice",
+                "display_name": "Alice",
+                "url": "https://social.myl.moe/@alice",
+            },
+        }))?;
+        let Activity::Create(act) = status_activity(&status, None)? else {
+            panic!("not a Create");
+        };
+        assert_eq!(act.object.content, "<p>Hi</p>");
+        // The author is embedded for the attribution
+        assert_eq!(
+            crate::render::render_attribution(&act.object).as_deref(),
+            Some("<b>Alice</b> (@alice@social.myl.moe)")
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,380 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Mastodon archive producer.
+//! Replays the history in `outbox.json` of the account archive from the oldest,
+//! either the `.tar.gz` downloaded from the server or the dir it is extracted to.
+//!
+//! Media in `media_attachments/` are given as `file://` URLs, which are uploaded instead of fetched.
+//! Tarballs are extracted to the temp dir once, and later runs reuse the extracted files
+//! until the tarball is replaced.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read};
+use std::path::{Component, Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use reqwest::Url;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+
+use super::{check_item_with, check_media_url, infer_media_types, Pro};
+use crate::as2::{Activity, Page};
+use crate::cursor::Cursor;
+use crate::media::allow_local_root;
+use crate::utils::{cancellable, fnv1a, int_id};
+
+/// Number of items per page to send, so states are saved as the replay goes with `--disk-queue`
+const PAGE_SIZE: usize = 40;
+
+pub struct ArchivePro {
+    /// Archive file or the dir it is extracted to
+    path: PathBuf,
+    cancel: CancellationToken,
+    min_id: Option<i64>,
+    /// Take the newest page first, e.g., to fast-forward to the latest post
+    latest_first: bool,
+    /// Pages in the order to take, which are popped one by one
+    pages: Option<Vec<Page>>,
+}
+
+impl ArchivePro {
+    pub fn new(path: PathBuf, cancel: CancellationToken) -> Self {
+        Self {
+            path,
+            cancel,
+            min_id: None,
+            latest_first: false,
+            pages: None,
+        }
+    }
+
+    /// Drop items not newer than the ID, or keep all if `None`
+    pub fn with_min_id(mut self, min_id: Option<i64>) -> Self {
+        self.min_id = min_id;
+        self
+    }
+
+    pub fn with_latest_first(mut self, latest_first: bool) -> Self {
+        self.latest_first = latest_first;
+        self
+    }
+
+    /// Dir the tarball is extracted to in the temp dir.
+    /// Keyed by the size and the modification time besides the path, so replaced tarballs are extracted again.
+    pub fn extracted_dir(path: &Path) -> Result<PathBuf> {
+        let meta = fs::metadata(path)?;
+        let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+        let key = format!("{}\0{}\0{mtime}", path.display(), meta.len());
+        let name = format!("mastotg-archive-{:016x}", fnv1a(key.as_bytes()));
+        Ok(std::env::temp_dir().join(name))
+    }
+
+    fn load(path: &Path, min_id: Option<i64>) -> Result<Vec<Page>> {
+        let root = match path.is_dir() {
+            true => path.to_owned(),
+            false => extract(path)?,
+        };
+        let root = allow_local_root(&root)?;
+        let outbox: Outbox = serde_json::from_reader(BufReader::new(
+            File::open(root.join("outbox.json")).context("no outbox.json in the archive")?,
+        ))?;
+        let mut items: Vec<_> = outbox
+            .ordered_items
+            .into_iter()
+            .filter_map(|item| item_activity(item, &root))
+            .collect();
+        if let Some(min_id) = min_id {
+            items.retain(|item| int_id(item.id()).map_or(true, |id| id > min_id));
+        }
+        // From the oldest, while each page lists the newest first like the outbox
+        items.sort_by_key(|item| {
+            let cursor = Cursor::of(item);
+            (cursor.int_id(), cursor.published)
+        });
+        tracing::info!("Replaying {} posts of the archive", items.len());
+        let url = Url::from_file_path(root.join("outbox.json"))
+            .map_err(|()| anyhow!("invalid archive path {}", root.display()))?;
+        let pages = items
+            .chunks(PAGE_SIZE)
+            .map(|chunk| {
+                let mut page = Page::empty(url.to_string());
+                page.ordered_items = chunk.iter().rev().cloned().collect();
+                page
+            })
+            .collect();
+        Ok(pages)
+    }
+}
+
+#[async_trait]
+impl Pro for ArchivePro {
+    async fn fetch(&mut self) -> Result<Page> {
+        if self.pages.is_none() {
+            let (path, min_id) = (self.path.clone(), self.min_id);
+            let load = async { task::spawn_blocking(move || Self::load(&path, min_id)).await? };
+            let mut pages = cancellable(&self.cancel, load).await?;
+            if !self.latest_first {
+                pages.reverse();
+            }
+            self.pages = Some(pages);
+        }
+        let Some(mut page) = self.pages.as_mut().unwrap().pop() else {
+            return Ok(Page::empty(self.path.display().to_string()));
+        };
+        cancellable(&self.cancel, infer_media_types(&mut page)).await?;
+        Ok(page)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Outbox {
+    #[serde(default)]
+    ordered_items: Vec<Value>,
+}
+
+/// Activity of the item with the media paths resolved to the files in the archive.
+/// Invalid items are skipped.
+fn item_activity(mut item: Value, root: &Path) -> Option<Activity> {
+    if let Some(atts) = item["object"]["attachment"].as_array_mut() {
+        for att in atts.iter_mut() {
+            let Some(url) = att["url"].as_str() else {
+                continue;
+            };
+            if let Some(url) = media_url(url, root) {
+                att["url"] = url.into();
+            }
+        }
+    }
+    let id = item["id"].as_str().unwrap_or_default().to_owned();
+    let act = serde_json::from_value(item).map_err(anyhow::Error::from);
+    let check_url = |url: &str| check_local_url(url, root);
+    match act.and_then(|act| check_item_with(&act, check_url).map(|()| act)) {
+        Ok(act) => Some(act),
+        Err(e) => {
+            tracing::warn!(post = %id, "Skipped the invalid archive item: {e:#}");
+            None
+        }
+    }
+}
+
+/// `file://` URL of the media in the archive by the path in the URL,
+/// e.g., `/media_attachments/files/.../a.png`
+fn media_url(url: &str, root: &Path) -> Option<String> {
+    let path = url.split(['?', '#']).next()?;
+    let path = &path[path.find("media_attachments/")?..];
+    let path = root.join(safe_path(path)?);
+    if !path.is_file() {
+        tracing::warn!("Media {url} not found in the archive");
+        return None;
+    }
+    Some(Url::from_file_path(path).ok()?.to_string())
+}
+
+/// Local media must be in the archive, resolving symlinks, and others must be remote
+fn check_local_url(url: &str, root: &Path) -> Result<()> {
+    let Some(path) = Url::parse(url).ok().filter(|u| u.scheme() == "file") else {
+        return check_media_url(url);
+    };
+    let path = path
+        .to_file_path()
+        .ok()
+        .and_then(|path| fs::canonicalize(path).ok());
+    match path {
+        Some(path) if path.starts_with(root) => Ok(()),
+        _ => bail!("media {url} out of the archive"),
+    }
+}
+
+/// Relative path without escaping the root
+fn safe_path(name: &str) -> Option<&Path> {
+    let path = Path::new(name);
+    path.components()
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        .then_some(path)
+}
+
+/// Extract the `.tar.gz` or `.tar` archive to the temp dir if not yet.
+/// Only `outbox.json` and `media_attachments/` are extracted.
+fn extract(path: &Path) -> Result<PathBuf> {
+    let root = ArchivePro::extracted_dir(path)?;
+    if root.is_dir() {
+        return Ok(root);
+    }
+    tracing::info!(
+        "Extracting the archive {} to {}",
+        path.display(),
+        root.display()
+    );
+    // Renamed after extracted, so interrupted extractions are not taken
+    let tmp = root.with_extension("tmp");
+    if tmp.exists() {
+        fs::remove_dir_all(&tmp)?;
+    }
+    let mut file = BufReader::new(File::open(path)?);
+    let mut magic = [0; 2];
+    file.read_exact(&mut magic)?;
+    let file = BufReader::new(File::open(path)?);
+    let wanted = |name: &str| name == "outbox.json" || name.starts_with("media_attachments/");
+    match magic {
+        [0x1f, 0x8b] => unpack(GzDecoder::new(file), &tmp, wanted)?,
+        _ => unpack(file, &tmp, wanted)?,
+    }
+    fs::rename(&tmp, &root)?;
+    Ok(root)
+}
+
+/// Unpack the regular files of the tar stream wanted by their names into the dir.
+/// GNU long names are supported, and other extensions are skipped.
+fn unpack(mut tar: impl Read, dir: &Path, wanted: impl Fn(&str) -> bool) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    let mut header = [0; 512];
+    let mut long_name = None;
+    loop {
+        match tar.read_exact(&mut header) {
+            Ok(()) => (),
+            // Some writers omit the end blocks
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let size = octal(&header[124..136])?;
+        let padding = size.next_multiple_of(512) - size;
+        let name = long_name.take().unwrap_or_else(|| {
+            let (name, prefix) = (cstr(&header[..100]), cstr(&header[345..500]));
+            match prefix.is_empty() {
+                true => name,
+                false => format!("{prefix}/{name}"),
+            }
+        });
+        let mut body = (&mut tar).take(size);
+        match header[156] {
+            b'L' => {
+                let mut buf = Vec::new();
+                body.read_to_end(&mut buf)?;
+                long_name = Some(cstr(&buf));
+            }
+            b'0' | 0 => {
+                let name = name.trim_start_matches("./");
+                match safe_path(name).filter(|_| wanted(name)) {
+                    Some(path) => {
+                        let path = dir.join(path);
+                        fs::create_dir_all(path.parent().unwrap())?;
+                        io::copy(&mut body, &mut File::create(path)?)?;
+                    }
+                    None => {
+                        io::copy(&mut body, &mut io::sink())?;
+                    }
+                }
+            }
+            _ => {
+                io::copy(&mut body, &mut io::sink())?;
+            }
+        }
+        io::copy(&mut (&mut tar).take(padding), &mut io::sink())?;
+    }
+    Ok(())
+}
+
+/// Text of the NUL-terminated field
+fn cstr(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Number of the octal field
+fn octal(field: &[u8]) -> Result<u64> {
+    if field[0] & 0x80 != 0 {
+        bail!("base-256 tar sizes are not supported");
+    }
+    let text = cstr(field);
+    let text = text.trim_matches(|c: char| c == ' ' || c == '\0');
+    if text.is_empty() {
+        return Ok(0);
+    }
+    u64::from_str_radix(text, 8).map_err(|e| anyhow!("invalid tar header: {e}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Tar stream of the files, with a GNU long name for long ones
+    fn tar(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut tar = Vec::new();
+        let mut entry = |name: &[u8], kind: u8, body: &[u8]| {
+            let mut header = [0; 512];
+            header[..name.len().min(100)].copy_from_slice(&name[..name.len().min(100)]);
+            header[124..135].copy_from_slice(format!("{:011o}", body.len()).as_bytes());
+            header[156] = kind;
+            tar.extend(header);
+            tar.extend(body);
+            tar.resize(tar.len().next_multiple_of(512), 0);
+        };
+        for (name, body) in files {
+            if name.len() > 100 {
+                entry(b"././@LongLink", b'L', name.as_bytes());
+            }
+            entry(name.as_bytes(), b'0', body);
+        }
+        tar.extend([0; 1024]);
+        tar
+    }
+
+    #[test]
+    fn test_unpack() -> Result<()> {
+        let long = format!("media_attachments/files/{}/a.png", "0".repeat(100));
+        let body = tar(&[
+            ("outbox.json", b"{}"),
+            ("actor.json", b"{}"),
+            (&long, b"png"),
+            ("media_attachments/../../escaped", b""),
+        ]);
+        let dir = std::env::temp_dir().join(format!("mastotg-unpack-{}", std::process::id()));
+        unpack(&body[..], &dir, |name| {
+            name == "outbox.json" || name.starts_with("media_attachments/")
+        })?;
+        let outbox = fs::read(dir.join("outbox.json"));
+        let media = fs::read(dir.join(&long));
+        let actor = dir.join("actor.json").exists();
+        fs::remove_dir_all(&dir)?;
+        assert_eq!(outbox?, b"{}");
+        assert_eq!(media?, b"png");
+        assert!(!actor);
+        Ok(())
+    }
+
+    #[test]
+    fn test_local_media() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("mastotg-local-{}", std::process::id()));
+        fs::create_dir_all(dir.join("media_attachments"))?;
+        fs::write(dir.join("media_attachments/a.png"), b"png")?;
+        let root = allow_local_root(&dir)?;
+        let item = |url: &str| {
+            let mut item: Value =
+                serde_json::from_str(include_str!("../../tests/fixtures/create.json")).unwrap();
+            item["object"]["attachment"] =
+                serde_json::json!([{"type": "Image", "mediaType": "image/png", "url": url}]);
+            item_activity(item, &root)
+        };
+        let in_archive = item("https://files.example.com/media_attachments/a.png");
+        let escaped = item("file:///etc/passwd");
+        let other = item("ftp://example.com/a.png");
+        fs::remove_dir_all(&dir)?;
+        let Some(Activity::Create(act)) = in_archive else {
+            panic!("not a Create");
+        };
+        assert!(act.object.attachment[0].url.starts_with("file://"));
+        assert!(escaped.is_none());
+        assert!(other.is_none());
+        Ok(())
+    }
+}
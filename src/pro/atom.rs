@@ -0,0 +1,227 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Atom producer.
+//! For servers that only expose Atom feeds of the accounts.
+
+use std::sync::LazyLock;
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use regex::Regex;
+use time::format_description::well_known::Rfc3339;
+use time::UtcOffset;
+use tokio_util::sync::CancellationToken;
+
+use super::feed::{Entry, Feed, Media};
+use super::Pro;
+use crate::as2::Page;
+use crate::render::escape;
+use crate::utils::parse_time;
+
+pub struct AtomPro {
+    feed: Feed,
+}
+
+impl AtomPro {
+    pub fn new(url: String, cancel: CancellationToken) -> Self {
+        Self {
+            feed: Feed::new(url, cancel),
+        }
+    }
+
+    /// Drop entries not newer than the ID, or keep all if `None`
+    pub fn with_min_id(mut self, min_id: Option<i64>) -> Self {
+        self.feed.min_id = min_id;
+        self
+    }
+}
+
+#[async_trait]
+impl Pro for AtomPro {
+    async fn fetch(&mut self) -> Result<Page> {
+        self.feed.fetch(parse_feed).await
+    }
+}
+
+fn attr_of(elem: &BytesStart, name: &str) -> Result<Option<String>> {
+    match elem.try_get_attribute(name)? {
+        Some(attr) => Ok(Some(attr.unescape_value()?.into_owned())),
+        None => Ok(None),
+    }
+}
+
+/// RFC3339 in UTC of the Atom date
+fn rfc3339_of(date: &str) -> Option<String> {
+    parse_time(date)?
+        .to_offset(UtcOffset::UTC)
+        .format(&Rfc3339)
+        .ok()
+}
+
+/// Whether the title is only the start of the content, which microblogs use as required by Atom
+fn is_excerpt(title: &str, content: &str) -> bool {
+    static RE_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+    let text = RE_TAG.replace_all(content, "");
+    let title = title.trim_end_matches(['…', '.']).trim();
+    text.trim().starts_with(&escape(title))
+}
+
+/// Parse the entries of the Atom feed
+pub(super) fn parse_feed(body: &str) -> Result<Vec<Entry>> {
+    let mut reader = Reader::from_str(body);
+    reader.trim_text(true);
+    let mut entries = Vec::new();
+    let mut entry: Option<Entry> = None;
+    let mut summary = None;
+    let mut text = String::new();
+    // `type` of the text construct, which is `text`, `html`, or `xhtml`
+    let mut text_type = None;
+    loop {
+        match reader.read_event().context("invalid feed")? {
+            Event::Eof => break,
+            Event::Start(elem) => match elem.name().as_ref() {
+                b"entry" => {
+                    entry = Some(Entry::default());
+                    summary = None;
+                }
+                b"content" | b"summary" if entry.is_some() => {
+                    text.clear();
+                    text_type = attr_of(&elem, "type")?;
+                    if text_type.as_deref() == Some("xhtml") {
+                        // Kept as HTML
+                        let end = elem.to_end().into_owned();
+                        let html = reader.read_text(end.name())?.trim().to_owned();
+                        match elem.name().as_ref() {
+                            b"content" => entry.as_mut().unwrap().content = Some(html),
+                            _ => summary = Some(html),
+                        }
+                    }
+                }
+                _ => text.clear(),
+            },
+            Event::Empty(elem) => {
+                let Some(cur) = entry.as_mut() else {
+                    continue;
+                };
+                match elem.name().as_ref() {
+                    b"link" => {
+                        let Some(href) = attr_of(&elem, "href")? else {
+                            continue;
+                        };
+                        match attr_of(&elem, "rel")?.as_deref() {
+                            None | Some("alternate") => cur.link = Some(href),
+                            Some("enclosure") => cur.media.push(Media {
+                                url: href,
+                                media_type: attr_of(&elem, "type")?,
+                                medium: None,
+                                description: attr_of(&elem, "title")?,
+                            }),
+                            _ => (),
+                        }
+                    }
+                    b"category" => cur.categories.extend(attr_of(&elem, "term")?),
+                    _ => (),
+                }
+            }
+            Event::Text(elem) => text += &elem.unescape()?,
+            Event::CData(elem) => text += &String::from_utf8_lossy(&elem),
+            Event::End(elem) => {
+                let Some(cur) = entry.as_mut() else {
+                    continue;
+                };
+                let value = || Some(text.trim().to_owned()).filter(|s| !s.is_empty());
+                let html = || match text_type.as_deref() {
+                    Some("html") => value(),
+                    _ => value().map(|text| escape(&text)),
+                };
+                match elem.name().as_ref() {
+                    b"entry" => {
+                        let mut cur = entry.take().unwrap();
+                        cur.content = cur.content.or(summary.take());
+                        if let (Some(title), Some(content)) = (&cur.title, &cur.content) {
+                            if is_excerpt(title, content) {
+                                cur.title = None;
+                            }
+                        }
+                        entries.push(cur);
+                    }
+                    b"id" => cur.id = value(),
+                    b"title" => cur.title = value(),
+                    b"published" => cur.published = value().as_deref().and_then(rfc3339_of),
+                    b"updated" => cur.updated = value().as_deref().and_then(rfc3339_of),
+                    b"content" if text_type.as_deref() != Some("xhtml") => cur.content = html(),
+                    b"summary" if text_type.as_deref() != Some("xhtml") => summary = html(),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::as2::Activity;
+    use crate::pro::feed::page_of;
+
+    #[test]
+    fn test_parse_feed() -> Result<()> {
+        let body = r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>myl</title>
+  <link rel="self" href="https://gts.myl.moe/@myl/feed.atom"/>
+  <entry>
+    <id>https://gts.myl.moe/users/myl/statuses/01H7Z</id>
+    <title>Older post…</title>
+    <link rel="alternate" href="https://gts.myl.moe/@myl/statuses/01H7Z"/>
+    <published>2023-08-03T16:09:19Z</published>
+    <updated>2023-08-03T16:09:19Z</updated>
+    <content type="html">&lt;p&gt;Older post that is long&lt;/p&gt;</content>
+  </entry>
+  <entry>
+    <id>https://gts.myl.moe/users/myl/statuses/01H80</id>
+    <title>A &amp; B</title>
+    <link href="https://gts.myl.moe/@myl/statuses/01H80"/>
+    <link rel="enclosure" href="https://gts.myl.moe/a.png" type="image/png" title="Alt"/>
+    <updated>2023-08-03T18:10:19+02:00</updated>
+    <summary>1 &lt; 2</summary>
+    <category term="mygo"/>
+  </entry>
+  <entry>
+    <id>https://gts.myl.moe/users/myl/statuses/01H81</id>
+    <title>Xhtml</title>
+    <updated>2023-08-03T16:11:19Z</updated>
+    <content type="xhtml"><div xmlns="http://www.w3.org/1999/xhtml"><p>Hi</p></div></content>
+  </entry>
+</feed>"#;
+        let page = page_of("https://gts.myl.moe/@myl/feed.atom", parse_feed(body)?);
+        let posts: Vec<_> = page
+            .ordered_items
+            .iter()
+            .map(|item| match item {
+                Activity::Create(act) => &act.object,
+                _ => panic!("not a Create"),
+            })
+            .collect();
+        assert_eq!(posts.len(), 3);
+        assert_eq!(
+            posts[0].content,
+            r#"<div xmlns="http://www.w3.org/1999/xhtml"><p>Hi</p></div>"#
+        );
+        assert_eq!(posts[1].published, "2023-08-03T16:10:19Z");
+        assert_eq!(posts[1].r#type, "Article");
+        assert_eq!(posts[1].name.as_deref(), Some("A & B"));
+        assert_eq!(posts[1].content, "1 &lt; 2");
+        assert_eq!(posts[1].attachment[0].name.as_deref(), Some("Alt"));
+        assert_eq!(posts[1].tag[0].as_hashtag().unwrap().name, "#mygo");
+        // The title of a microblog post is its start
+        assert_eq!(posts[2].r#type, "Note");
+        assert_eq!(posts[2].name, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Directory producer.
+//! Other tools hand posts over by dropping files into a directory:
+//! `*page.json` as outbox pages, and `*post.json` as posts or their activities.
+//! Write the files with other names and then rename them, so partial ones are not read.
+//!
+//! Files are removed once the round sending their posts finishes,
+//! and invalid ones are renamed with the `.invalid` suffix to be checked by hand.
+
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+#[cfg(feature = "watch")]
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::fs;
+#[cfg(feature = "watch")]
+use tokio::sync::mpsc;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+use super::{check_item, infer_media_types, Pro, UriPro};
+use crate::as2::{Activity, CheckType, Create, Page, Post};
+use crate::cursor::Cursor;
+use crate::utils::{cancellable, int_id};
+
+/// Interval to poll the directory for dropped files if not watched
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct DirPro {
+    dir: PathBuf,
+    cancel: CancellationToken,
+    min_id: Option<i64>,
+    /// Files taken in the round, which are removed after it
+    taken: Vec<PathBuf>,
+    fetched: bool,
+}
+
+impl DirPro {
+    pub fn new(dir: PathBuf, cancel: CancellationToken) -> Self {
+        Self {
+            dir,
+            cancel,
+            min_id: None,
+            taken: vec![],
+            fetched: false,
+        }
+    }
+
+    /// Drop items not newer than the ID, or keep all if `None`
+    pub fn with_min_id(mut self, min_id: Option<i64>) -> Self {
+        self.min_id = min_id;
+        self
+    }
+
+    /// Remove the files taken in the round.
+    /// Call it only after the posts are sent.
+    pub async fn remove_taken(&mut self) -> Result<()> {
+        for path in self.taken.drain(..) {
+            fs::remove_file(&path).await?;
+        }
+        Ok(())
+    }
+
+    /// Files dropped into the directory, e.g., to be known by [`DirPro::watch`] after the round
+    pub async fn dropped(dir: &Path) -> Result<Vec<PathBuf>> {
+        dropped_files(dir).await
+    }
+
+    /// Wait until files other than the known ones are dropped into the directory.
+    /// Returns at once if there already are, e.g., the ones dropped during the last round.
+    /// The directory is watched via the OS notifications with the `watch` feature,
+    /// and polled without it or if failed to watch.
+    pub async fn watch(dir: &Path, known: &[PathBuf]) -> Result<()> {
+        // Watched before listed, so no files are dropped in between unnoticed
+        #[cfg(feature = "watch")]
+        let (_watcher, mut changed) = match notified(dir) {
+            Ok((watcher, changed)) => (Some(watcher), Some(changed)),
+            Err(e) => {
+                tracing::warn!("Polling the directory as failed to watch it: {e:#}");
+                (None, None)
+            }
+        };
+        loop {
+            let now = dropped_files(dir).await?;
+            if now.iter().any(|path| !known.contains(path)) {
+                return Ok(());
+            }
+            #[cfg(feature = "watch")]
+            if let Some(changed) = changed.as_mut() {
+                if changed.recv().await.is_some() {
+                    continue;
+                }
+            }
+            time::sleep(WATCH_INTERVAL).await;
+        }
+    }
+
+    async fn take_files(&mut self) -> Result<Page> {
+        let mut items = Vec::new();
+        for path in dropped_files(&self.dir).await? {
+            let body = fs::read(&path).await?;
+            match parse_file(&path, &body) {
+                Ok(parsed) => items.extend(parsed),
+                Err(e) => {
+                    tracing::warn!("Skipped the invalid file {}: {e:#}", path.display());
+                    let mut invalid = path.clone().into_os_string();
+                    invalid.push(".invalid");
+                    fs::rename(&path, invalid).await?;
+                    continue;
+                }
+            }
+            self.taken.push(path);
+        }
+        tracing::debug!("Took {} dropped files", self.taken.len());
+        // From the newest like the outbox, by the integer IDs first like the cursor
+        items.sort_by_key(|item| {
+            let cursor = Cursor::of(item);
+            std::cmp::Reverse((cursor.int_id(), cursor.published))
+        });
+        if let Some(min_id) = self.min_id {
+            items.retain(|item| int_id(item.id()).map_or(true, |id| id > min_id));
+        }
+        let mut page = Page::empty(format!("file://{}", self.dir.display()));
+        page.ordered_items = items;
+        Ok(page)
+    }
+}
+
+#[async_trait]
+impl Pro for DirPro {
+    async fn fetch(&mut self) -> Result<Page> {
+        if self.fetched {
+            return Ok(Page::empty(format!("file://{}", self.dir.display())));
+        }
+        let cancel = self.cancel.clone();
+        let mut page = cancellable(&cancel, self.take_files()).await?;
+        cancellable(&cancel, infer_media_types(&mut page)).await?;
+        self.fetched = true;
+        Ok(page)
+    }
+}
+
+/// Dropped files in the directory sorted by the names
+async fn dropped_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = Vec::new();
+    let mut entries = fs::read_dir(dir).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let path = entry.path();
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if (name.ends_with("page.json") || name.ends_with("post.json"))
+            && entry.file_type().await?.is_file()
+        {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Watch the directory, receiving once it changes
+#[cfg(feature = "watch")]
+fn notified(dir: &Path) -> Result<(RecommendedWatcher, mpsc::UnboundedReceiver<()>)> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| {
+        if res.is_ok() {
+            let _ = tx.send(());
+        }
+    })?;
+    watcher.watch(dir, RecursiveMode::NonRecursive)?;
+    Ok((watcher, rx))
+}
+
+/// Items of the dropped file
+fn parse_file(path: &Path, body: &[u8]) -> Result<Vec<Activity>> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let items = if name.ends_with("page.json") {
+        UriPro::parse_page(body)?.ordered_items
+    } else {
+        vec![post_activity(body)?]
+    };
+    items.iter().try_for_each(check_item)?;
+    Ok(items)
+}
+
+/// Activity of the post, or the activity itself
+fn post_activity(body: &[u8]) -> Result<Activity> {
+    if let Ok(post) = serde_json::from_slice::<Post>(body) {
+        if post.check_type().is_ok() {
+            return Ok(Activity::Create(Create {
+                id: format!("{}/activity", post.id),
+                object: post,
+            }));
+        }
+    }
+    match serde_json::from_slice(body)? {
+        Activity::Unknown(_) => Err(anyhow!("neither a post nor a known activity")),
+        act => Ok(act),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_post_activity() -> Result<()> {
+        let post = r#"{
+            "id": "https://social.myl.moe/users/myl/statuses/110826550717756447",
+            "type": "Note",
+            "inReplyTo": null,
+            "published": "2023-08-03T16:09:19Z",
+            "url": "https://social.myl.moe/@myl/110826550717756447",
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "sensitive": false,
+            "content": "<p>Hi</p>",
+            "attachment": [],
+            "tag": []
+        }"#;
+        let Activity::Create(act) = post_activity(post.as_bytes())? else {
+            panic!("not a Create");
+        };
+        assert_eq!(int_id(&act.id)?, 110826550717756447);
+        assert_eq!(act.object.content, "<p>Hi</p>");
+        assert!(post_activity(br#"{"id": "x", "type": "Like"}"#).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_watch() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("mastotg-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).await?;
+        let old = dir.join("1.post.json");
+        fs::write(&old, "{}").await?;
+        // Files dropped since the known ones were listed are taken at once
+        time::timeout(Duration::from_millis(100), DirPro::watch(&dir, &[])).await??;
+
+        let known = DirPro::dropped(&dir).await?;
+        let new = dir.join("2.post.json");
+        let drop_new = async {
+            time::sleep(Duration::from_millis(200)).await;
+            fs::write(&new, "{}").await
+        };
+        let (res, written) = tokio::join!(
+            time::timeout(Duration::from_secs(5), DirPro::watch(&dir, &known)),
+            drop_new
+        );
+        written?;
+        res??;
+        fs::remove_dir_all(&dir).await?;
+        Ok(())
+    }
+}
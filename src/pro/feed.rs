@@ -0,0 +1,164 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Common parts of the feed producers.
+//! Feed entries are converted to `Create`s like the ones in the outbox.
+
+use anyhow::Result;
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+
+use super::infer_media_types;
+use crate::as2::{Activity, Page};
+use crate::cursor::Cursor;
+use crate::http;
+use crate::utils::{cancellable, check_res, int_id};
+
+/// Feeds have no paging, so the whole feed is the first page and the second one is empty.
+/// Entries older than `min_id` are dropped when they have integer IDs.
+/// Others are skipped by the cursor in the state like the posts of servers without integer IDs.
+pub(super) struct Feed {
+    pub url: String,
+    pub cancel: CancellationToken,
+    pub min_id: Option<i64>,
+    fetched: bool,
+}
+
+impl Feed {
+    pub fn new(url: String, cancel: CancellationToken) -> Self {
+        Self {
+            url,
+            cancel,
+            min_id: None,
+            fetched: false,
+        }
+    }
+
+    /// Fetch the feed and parse its body into entries
+    pub async fn fetch(&mut self, parse: fn(&str) -> Result<Vec<Entry>>) -> Result<Page> {
+        if self.fetched {
+            return Ok(Page::empty(self.url.clone()));
+        }
+        let body = cancellable(&self.cancel, async {
+            let res = http::client().get(&self.url).send().await?;
+            Ok(check_res(res).await?.text().await?)
+        })
+        .await?;
+        self.take(parse(&body)?).await
+    }
+
+    /// Page of the entries, e.g., pushed ones, which ends the fetching like the fetched feed
+    pub async fn take(&mut self, entries: Vec<Entry>) -> Result<Page> {
+        let mut page = page_of(&self.url, entries);
+        if let Some(min_id) = self.min_id {
+            page.ordered_items
+                .retain(|item| int_id(item.id()).map_or(true, |id| id > min_id));
+        }
+        cancellable(&self.cancel, infer_media_types(&mut page)).await?;
+        self.fetched = true;
+        Ok(page)
+    }
+}
+
+/// Entry of a feed
+#[derive(Default)]
+pub(super) struct Entry {
+    pub id: Option<String>,
+    pub link: Option<String>,
+    pub title: Option<String>,
+    /// RFC3339
+    pub published: Option<String>,
+    /// RFC3339
+    pub updated: Option<String>,
+    /// HTML
+    pub content: Option<String>,
+    pub categories: Vec<String>,
+    pub media: Vec<Media>,
+    pub sensitive: bool,
+}
+
+#[derive(Default)]
+pub(super) struct Media {
+    pub url: String,
+    pub media_type: Option<String>,
+    /// `image`, `video`, or `audio`
+    pub medium: Option<String>,
+    pub description: Option<String>,
+}
+
+/// Page of the converted entries from the newest.
+/// Entries without IDs, links, or valid dates are skipped.
+pub(super) fn page_of(url: &str, entries: Vec<Entry>) -> Page {
+    let mut items: Vec<_> = entries.into_iter().filter_map(entry_activity).collect();
+    items.sort_by_key(|item| std::cmp::Reverse(Cursor::of(item).published));
+    let mut page = Page::empty(url.to_owned());
+    page.ordered_items = items;
+    page
+}
+
+/// Convert the entry to the activity like the ones in the outbox.
+/// Entries with titles become `Article`s.
+fn entry_activity(entry: Entry) -> Option<Activity> {
+    let Some(id) = entry.id.clone().or(entry.link.clone()) else {
+        tracing::warn!("Skipped the feed entry without an ID or a link");
+        return None;
+    };
+    let Some(published) = entry.published.clone().or(entry.updated.clone()) else {
+        tracing::warn!(post = %id, "Skipped the feed entry without a valid date");
+        return None;
+    };
+    let attachment: Vec<_> = entry
+        .media
+        .iter()
+        .map(|media| {
+            let kind = media.medium.clone().or(media
+                .media_type
+                .as_ref()
+                .and_then(|t| Some(t.split_once('/')?.0.to_owned())));
+            let r#type = match kind.as_deref() {
+                Some("image") => "Image",
+                Some("video") => "Video",
+                Some("audio") => "Audio",
+                _ => "Document",
+            };
+            json!({
+                "type": r#type,
+                // Inferred by the producer if missing
+                "mediaType": media.media_type.clone().unwrap_or_default(),
+                "url": media.url,
+                "name": media.description,
+            })
+        })
+        .collect();
+    let tag: Vec<_> = entry
+        .categories
+        .iter()
+        .map(|name| json!({"type": "Hashtag", "name": format!("#{name}")}))
+        .collect();
+    let post = json!({
+        "id": id,
+        "type": if entry.title.is_some() { "Article" } else { "Note" },
+        "name": entry.title,
+        "inReplyTo": null,
+        "published": published,
+        "updated": entry.updated.filter(|updated| *updated != published),
+        "url": entry.link.as_ref().unwrap_or(&id),
+        "to": ["https://www.w3.org/ns/activitystreams#Public"],
+        "sensitive": entry.sensitive,
+        "content": entry.content.unwrap_or_default(),
+        "attachment": attachment,
+        "tag": tag,
+    });
+    let act = json!({
+        "type": "Create",
+        "id": format!("{id}/activity"),
+        "object": post,
+    });
+    match serde_json::from_value(act) {
+        Ok(act) => Some(act),
+        Err(e) => {
+            tracing::warn!(post = %id, "Skipped the invalid feed entry: {e}");
+            None
+        }
+    }
+}
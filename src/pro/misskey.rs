@@ -0,0 +1,313 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Misskey producer.
+//! Notes of an account on Misskey or its forks like Sharkey are queried from `/api/users/notes`,
+//! and converted to activities like the ones in the outbox.
+//!
+//! Note IDs are not integers, so notes are queried by the published time of the cursor,
+//! and handled ones are skipped by the cursor like the posts of other servers without integer IDs.
+
+use std::collections::HashMap;
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use time::OffsetDateTime;
+use tokio_util::sync::CancellationToken;
+
+use super::{infer_media_types, Pro};
+use crate::as2::{Activity, Page};
+use crate::cursor::Cursor;
+use crate::http;
+use crate::render::escape;
+use crate::utils::{cancellable, check_res, parse_time};
+
+/// Max number of notes per page allowed by Misskey
+const PAGE_LIMIT: u64 = 100;
+
+pub struct MisskeyPro {
+    /// Base URL of the server, e.g., `https://misskey.io`
+    host: String,
+    acct: String,
+    cancel: CancellationToken,
+    /// Looked up on the first fetch
+    user_id: Option<String>,
+    /// Notes published after it are queried.
+    /// The latest page is queried if `None`.
+    since_date: Option<OffsetDateTime>,
+    /// Notes newer than it are queried. Moves to the newest fetched.
+    since_id: Option<String>,
+}
+
+impl MisskeyPro {
+    pub fn new(host: String, acct: String, cancel: CancellationToken) -> Self {
+        Self {
+            host: host.trim_end_matches('/').to_owned(),
+            acct,
+            cancel,
+            user_id: None,
+            since_date: None,
+            since_id: None,
+        }
+    }
+
+    /// Query notes published after the time, or the latest ones if `None`
+    pub fn with_since(mut self, since: Option<OffsetDateTime>) -> Self {
+        self.since_date = since;
+        self
+    }
+
+    async fn post<T: DeserializeOwned>(&self, endpoint: &str, body: Value) -> Result<T> {
+        let url = format!("{}/api/{endpoint}", self.host);
+        let res = http::client().post(url).json(&body).send().await?;
+        Ok(check_res(res).await?.json().await?)
+    }
+
+    async fn user_id(&mut self) -> Result<String> {
+        if let Some(id) = self.user_id.as_ref() {
+            return Ok(id.clone());
+        }
+        let (username, user_host) = self.acct.split_once('@').unwrap_or((&self.acct, ""));
+        // Authority of the server, which users of the server are queried without
+        let local = self.host.split_once("://").map_or(&*self.host, |(_, s)| s);
+        let user_host = Some(user_host).filter(|h| !h.is_empty() && *h != local);
+        let user: MkUser = self
+            .post(
+                "users/show",
+                json!({"username": username, "host": user_host}),
+            )
+            .await?;
+        self.user_id = Some(user.id.clone());
+        Ok(user.id)
+    }
+
+    async fn fetch_notes(&mut self) -> Result<Page> {
+        let user_id = self.user_id().await?;
+        let mut body = json!({
+            "userId": user_id,
+            "limit": PAGE_LIMIT,
+            "withReplies": true,
+            "withRenotes": true,
+        });
+        if let Some(since_id) = self.since_id.as_ref() {
+            body["sinceId"] = since_id.clone().into();
+        } else if let Some(since) = self.since_date {
+            let ms = since.unix_timestamp_nanos() / 1_000_000;
+            body["sinceDate"] = (ms as i64).into();
+        }
+        let notes: Vec<MkNote> = self.post("users/notes", body).await?;
+        let mut items: Vec<_> = notes
+            .iter()
+            .map(|note| note_activity(note, &self.host))
+            .collect::<Result<_>>()?;
+        // Listed from the oldest after `sinceId` and from the newest otherwise
+        items.sort_by_key(|item| std::cmp::Reverse(Cursor::of(item).published));
+        let newest = notes.iter().max_by_key(|note| parse_time(&note.created_at));
+        if let Some(newest) = newest {
+            self.since_id = Some(newest.id.clone());
+        }
+        let mut page = Page::empty(format!("{}/api/users/notes", self.host));
+        page.ordered_items = items;
+        Ok(page)
+    }
+}
+
+#[async_trait]
+impl Pro for MisskeyPro {
+    async fn fetch(&mut self) -> Result<Page> {
+        let cancel = self.cancel.clone();
+        let mut page = cancellable(&cancel, self.fetch_notes()).await?;
+        cancellable(&cancel, infer_media_types(&mut page)).await?;
+        Ok(page)
+    }
+}
+
+/// GUID of the note, which is the URL of it on the server for local notes
+fn note_uri(note: &MkNote, host: &str) -> String {
+    note.uri
+        .clone()
+        .unwrap_or_else(|| format!("{host}/notes/{}", note.id))
+}
+
+/// HTML of the MFM text.
+/// Decorations are kept as is, since only the plain text is sure of them.
+fn mfm_html(text: &str) -> String {
+    let paragraphs: Vec<_> = text
+        .split("\n\n")
+        .filter(|p| !p.trim().is_empty())
+        .map(|p| format!("<p>{}</p>", escape(p.trim()).replace('\n', "<br>")))
+        .collect();
+    paragraphs.concat()
+}
+
+/// Convert the note to the activity in the outbox.
+/// Pure renotes become `Announce`s, and others, including quotes, become `Create`s.
+fn note_activity(note: &MkNote, host: &str) -> Result<Activity> {
+    let uri = note_uri(note, host);
+    if let (None, Some(renote), true) = (&note.text, &note.renote, note.files.is_empty()) {
+        let act = json!({
+            "type": "Announce",
+            "id": format!("{uri}/activity"),
+            "object": note_uri(renote, host),
+            "published": note.created_at,
+        });
+        return Ok(serde_json::from_value(act)?);
+    }
+    let actor = format!("{host}/users/{}", note.user_id);
+    let public = "https://www.w3.org/ns/activitystreams#Public".to_owned();
+    let followers = format!("{actor}/followers");
+    let (to, cc) = match note.visibility.as_str() {
+        "public" => (vec![public], vec![followers]),
+        "home" => (vec![followers], vec![public]),
+        "followers" => (vec![followers], vec![]),
+        _ => (vec![], vec![]),
+    };
+    let mut content = mfm_html(note.text.as_deref().unwrap_or_default());
+    if let Some(renote) = note.renote.as_ref() {
+        let url = escape(&note_uri(renote, host));
+        content += &format!(r#"<p>RE: <a href="{url}">{url}</a></p>"#);
+    }
+    let attachment: Vec<_> = note
+        .files
+        .iter()
+        .map(|file| {
+            let r#type = match file.r#type.split('/').next() {
+                Some("image") => "Image",
+                Some("video") => "Video",
+                Some("audio") => "Audio",
+                _ => "Document",
+            };
+            json!({
+                "type": r#type,
+                "mediaType": file.r#type,
+                "url": file.url,
+                "name": file.comment,
+                "sensitive": file.is_sensitive,
+                "blurhash": file.blurhash,
+            })
+        })
+        .collect();
+    let tag: Vec<_> = note
+        .tags
+        .iter()
+        .map(|tag| {
+            let href = format!("{host}/tags/{tag}");
+            json!({"type": "Hashtag", "href": href, "name": format!("#{tag}")})
+        })
+        .collect();
+    let in_reply_to = note.reply_id.as_ref().map(|id| match note.reply.as_ref() {
+        Some(reply) => note_uri(reply, host),
+        None => format!("{host}/notes/{id}"),
+    });
+    let likes: u64 = note.reactions.values().sum();
+    let mut post = json!({
+        "id": uri,
+        "type": if note.poll.is_some() { "Question" } else { "Note" },
+        "summary": note.cw.as_ref().filter(|cw| !cw.is_empty()),
+        "inReplyTo": in_reply_to,
+        "published": note.created_at,
+        "updated": note.updated_at,
+        "url": note.url.as_ref().unwrap_or(&uri),
+        "attributedTo": actor,
+        "to": to,
+        "cc": cc,
+        "sensitive": note.files.iter().any(|file| file.is_sensitive),
+        "content": content,
+        "attachment": attachment,
+        "tag": tag,
+        "replies": {"totalItems": note.replies_count},
+        "likes": {"totalItems": likes},
+        "shares": {"totalItems": note.renote_count},
+    });
+    if let Some(poll) = note.poll.as_ref() {
+        let options: Vec<_> = poll
+            .choices
+            .iter()
+            .map(|choice| json!({"name": choice.text, "replies": {"totalItems": choice.votes}}))
+            .collect();
+        let key = if poll.multiple { "anyOf" } else { "oneOf" };
+        post[key] = options.into();
+        post["endTime"] = poll.expires_at.clone().into();
+        let closed = poll
+            .expires_at
+            .as_deref()
+            .and_then(parse_time)
+            .is_some_and(|end| end <= OffsetDateTime::now_utc());
+        post["closed"] = closed.then(|| poll.expires_at.clone()).into();
+    }
+    let act = json!({
+        "type": "Create",
+        "id": format!("{uri}/activity"),
+        "object": post,
+    });
+    serde_json::from_value(act).map_err(|e| anyhow!("invalid note {}: {e}", note.id))
+}
+
+/// Note of the Misskey API. Many unused fields are ignored.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MkNote {
+    id: String,
+    created_at: String,
+    updated_at: Option<String>,
+    user_id: String,
+    /// MFM
+    text: Option<String>,
+    cw: Option<String>,
+    /// `public`, `home`, `followers`, or `specified`
+    visibility: String,
+    /// GUID of the note from other servers
+    uri: Option<String>,
+    url: Option<String>,
+    reply_id: Option<String>,
+    reply: Option<Box<MkNote>>,
+    renote: Option<Box<MkNote>>,
+    #[serde(default)]
+    files: Vec<MkFile>,
+    #[serde(default)]
+    tags: Vec<String>,
+    poll: Option<MkPoll>,
+    #[serde(default)]
+    replies_count: u64,
+    #[serde(default)]
+    renote_count: u64,
+    /// Counts by the reactions
+    #[serde(default)]
+    reactions: HashMap<String, u64>,
+}
+
+#[derive(Deserialize)]
+struct MkUser {
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MkFile {
+    /// MIME media type
+    r#type: String,
+    url: String,
+    comment: Option<String>,
+    #[serde(default)]
+    is_sensitive: bool,
+    blurhash: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MkPoll {
+    #[serde(default)]
+    multiple: bool,
+    expires_at: Option<String>,
+    choices: Vec<MkPollChoice>,
+}
+
+#[derive(Deserialize)]
+struct MkPollChoice {
+    text: String,
+    #[serde(default)]
+    votes: u64,
+}
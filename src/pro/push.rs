@@ -0,0 +1,63 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Producer of the feed entries pushed by the WebSub hub.
+//! Rounds without pushes, e.g., the first one and the ones by the loop interval,
+//! poll the feed instead, so entries missed while the subscription is down are caught up.
+
+use std::sync::Arc;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use tokio_util::sync::CancellationToken;
+
+use super::feed::{Entry, Feed};
+use super::{atom, rss, Pro};
+use crate::as2::Page;
+use crate::websub::Subscriber;
+
+pub struct PushPro {
+    feed: Feed,
+    subscriber: Arc<Subscriber>,
+}
+
+impl PushPro {
+    pub fn new(url: String, subscriber: Arc<Subscriber>, cancel: CancellationToken) -> Self {
+        Self {
+            feed: Feed::new(url, cancel),
+            subscriber,
+        }
+    }
+
+    /// Drop entries not newer than the ID, or keep all if `None`
+    pub fn with_min_id(mut self, min_id: Option<i64>) -> Self {
+        self.feed.min_id = min_id;
+        self
+    }
+}
+
+#[async_trait]
+impl Pro for PushPro {
+    async fn fetch(&mut self) -> Result<Page> {
+        let pushed = self.subscriber.take_pushed();
+        if pushed.is_empty() {
+            return self.feed.fetch(parse_feed).await;
+        }
+        let mut entries = Vec::new();
+        for body in pushed {
+            match parse_feed(&body) {
+                Ok(pushed) => entries.extend(pushed),
+                Err(e) => tracing::warn!("Skipped the invalid WebSub push: {e:#}"),
+            }
+        }
+        self.feed.take(entries).await
+    }
+}
+
+/// Parse the Atom or RSS feed
+fn parse_feed(body: &str) -> Result<Vec<Entry>> {
+    match body.contains("<rss") {
+        true => rss::parse_feed(body),
+        false => atom::parse_feed(body),
+    }
+}
@@ -0,0 +1,174 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! RSS producer.
+//! Mastodon serves the public posts of an account at `https://<host>/@<user>.rss`,
+//! and other RSS 2.0 feeds work as long as their items have dates.
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::reader::Reader;
+use time::format_description::well_known::{Rfc2822, Rfc3339};
+use time::OffsetDateTime;
+use tokio_util::sync::CancellationToken;
+
+use super::feed::{Entry, Feed, Media};
+use super::Pro;
+use crate::as2::Page;
+
+pub struct RssPro {
+    feed: Feed,
+}
+
+impl RssPro {
+    pub fn new(url: String, cancel: CancellationToken) -> Self {
+        Self {
+            feed: Feed::new(url, cancel),
+        }
+    }
+
+    /// Drop items not newer than the ID, or keep all if `None`
+    pub fn with_min_id(mut self, min_id: Option<i64>) -> Self {
+        self.feed.min_id = min_id;
+        self
+    }
+}
+
+#[async_trait]
+impl Pro for RssPro {
+    async fn fetch(&mut self) -> Result<Page> {
+        self.feed.fetch(parse_feed).await
+    }
+}
+
+/// Media of `<media:content>` of Media RSS or `<enclosure>`
+fn media_of(elem: &BytesStart) -> Result<Media> {
+    let attr = |name: &str| -> Result<Option<String>> {
+        match elem.try_get_attribute(name)? {
+            Some(attr) => Ok(Some(attr.unescape_value()?.into_owned())),
+            None => Ok(None),
+        }
+    };
+    Ok(Media {
+        url: attr("url")?.ok_or(anyhow!("no url of the media"))?,
+        media_type: attr("type")?,
+        medium: attr("medium")?,
+        description: None,
+    })
+}
+
+/// RFC3339 of the RFC2822 `pubDate`
+fn rfc3339_of(date: &str) -> Option<String> {
+    let date = OffsetDateTime::parse(date, &Rfc2822).ok()?;
+    date.format(&Rfc3339).ok()
+}
+
+/// Parse the items of the RSS 2.0 feed
+pub(super) fn parse_feed(body: &str) -> Result<Vec<Entry>> {
+    let mut reader = Reader::from_str(body);
+    reader.trim_text(true);
+    let mut entries = Vec::new();
+    let mut item: Option<Entry> = None;
+    let mut media: Option<Media> = None;
+    let mut text = String::new();
+    loop {
+        match reader.read_event().context("invalid feed")? {
+            Event::Eof => break,
+            Event::Start(elem) => match elem.name().as_ref() {
+                b"item" => item = Some(Entry::default()),
+                b"media:content" if item.is_some() => media = Some(media_of(&elem)?),
+                _ => text.clear(),
+            },
+            Event::Empty(elem) => {
+                if let (b"media:content" | b"enclosure", Some(item)) =
+                    (elem.name().as_ref(), item.as_mut())
+                {
+                    item.media.push(media_of(&elem)?);
+                }
+            }
+            Event::Text(elem) => text += &elem.unescape()?,
+            Event::CData(elem) => text += &String::from_utf8_lossy(&elem),
+            Event::End(elem) => {
+                let Some(cur) = item.as_mut() else {
+                    continue;
+                };
+                let value = || Some(text.trim().to_owned()).filter(|s| !s.is_empty());
+                match elem.name().as_ref() {
+                    b"item" => entries.extend(item.take()),
+                    b"guid" => cur.id = value(),
+                    b"link" => cur.link = value(),
+                    b"title" => cur.title = value(),
+                    b"pubDate" => cur.published = value().as_deref().and_then(rfc3339_of),
+                    b"description" => cur.content = value(),
+                    b"category" => cur.categories.extend(value()),
+                    b"media:description" => {
+                        if let Some(media) = media.as_mut() {
+                            media.description = value();
+                        }
+                    }
+                    // Rated `adult` by Media RSS
+                    b"media:rating" => cur.sensitive |= value().as_deref() == Some("adult"),
+                    b"media:content" => cur.media.extend(media.take()),
+                    _ => (),
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::as2::Activity;
+    use crate::pro::feed::page_of;
+    use crate::utils::int_id;
+
+    #[test]
+    fn test_parse_feed() -> Result<()> {
+        let body = r#"<?xml version="1.0" encoding="UTF-8"?>
+<rss version="2.0" xmlns:media="http://search.yahoo.com/mrss/">
+  <channel>
+    <title>myl</title>
+    <link>https://social.myl.moe/@myl</link>
+    <item>
+      <guid isPermaLink="true">https://social.myl.moe/@myl/110826550717756447</guid>
+      <link>https://social.myl.moe/@myl/110826550717756447</link>
+      <pubDate>Thu, 03 Aug 2023 16:09:19 +0000</pubDate>
+      <description>&lt;p&gt;Older&lt;/p&gt;</description>
+    </item>
+    <item>
+      <guid isPermaLink="true">https://social.myl.moe/@myl/110826550717756448</guid>
+      <link>https://social.myl.moe/@myl/110826550717756448</link>
+      <pubDate>Thu, 03 Aug 2023 16:10:19 +0000</pubDate>
+      <description><![CDATA[<p>Newer</p>]]></description>
+      <media:content url="https://files.myl.moe/a.png" type="image/png" medium="image">
+        <media:rating scheme="urn:simple">adult</media:rating>
+        <media:description type="plain">Alt</media:description>
+      </media:content>
+      <category>mygo</category>
+    </item>
+    <item>
+      <title>No date</title>
+      <link>https://blog.myl.moe/a</link>
+    </item>
+  </channel>
+</rss>"#;
+        let page = page_of("https://social.myl.moe/@myl.rss", parse_feed(body)?);
+        assert_eq!(page.ordered_items.len(), 2);
+        let Activity::Create(act) = &page.ordered_items[0] else {
+            panic!("not a Create");
+        };
+        let post = &act.object;
+        assert_eq!(int_id(&act.id)?, 110826550717756448);
+        assert_eq!(post.published, "2023-08-03T16:10:19Z");
+        assert_eq!(post.content, "<p>Newer</p>");
+        assert!(post.sensitive);
+        assert_eq!(post.attachment[0].media_type, "image/png");
+        assert_eq!(post.attachment[0].name.as_deref(), Some("Alt"));
+        assert_eq!(post.tag[0].as_hashtag().unwrap().name, "#mygo");
+        Ok(())
+    }
+}
@@ -0,0 +1,103 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Progress reporting of backfills, i.e., rounds starting from `min_id` 0
+
+use std::time::Duration;
+
+use tokio::time::Instant;
+
+pub struct Progress {
+    /// Total number of posts if known
+    total: Option<u64>,
+    done: u64,
+    start: Instant,
+}
+
+impl Progress {
+    pub fn new() -> Self {
+        Self {
+            total: None,
+            done: 0,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn set_total(&mut self, total: u64) {
+        self.total = Some(total);
+    }
+
+    pub fn has_total(&self) -> bool {
+        self.total.is_some()
+    }
+
+    /// Mark posts as processed, either sent or skipped
+    pub fn advance(&mut self, n: u64) {
+        self.done += n;
+    }
+
+    /// Log the processed count, the rate, and the ETA if the total is known
+    pub fn log(&self) {
+        let elapsed = self.start.elapsed();
+        let rate = self.done as f64 / elapsed.as_secs_f64().max(1.0) * 60.0;
+        match (self.total, self.eta(elapsed)) {
+            (Some(total), Some(eta)) => tracing::info!(
+                "Backfill progress: {}/{total} posts ({:.1}%), {rate:.1} posts/min, ETA {}",
+                self.done,
+                self.done as f64 / total.max(1) as f64 * 100.0,
+                fmt_duration(eta),
+            ),
+            _ => tracing::info!(
+                "Backfill progress: {} posts, {rate:.1} posts/min",
+                self.done
+            ),
+        }
+    }
+
+    /// Remaining time at the average rate so far.
+    /// The rate includes the waits for flood control so the pacing is accounted for.
+    fn eta(&self, elapsed: Duration) -> Option<Duration> {
+        let total = self.total?;
+        if self.done == 0 {
+            return None;
+        }
+        let remaining = total.saturating_sub(self.done);
+        Some(elapsed.mul_f64(remaining as f64 / self.done as f64))
+    }
+}
+
+impl Default for Progress {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Format like `1h02m03s`
+fn fmt_duration(du: Duration) -> String {
+    let secs = du.as_secs();
+    let (h, m, s) = (secs / 3600, secs / 60 % 60, secs % 60);
+    if h > 0 {
+        format!("{h}h{m:02}m{s:02}s")
+    } else if m > 0 {
+        format!("{m}m{s:02}s")
+    } else {
+        format!("{s}s")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eta() {
+        let mut progress = Progress::new();
+        assert_eq!(progress.eta(Duration::from_secs(60)), None);
+        progress.set_total(1000);
+        progress.advance(200);
+        let eta = progress.eta(Duration::from_secs(600)).unwrap();
+        assert_eq!(eta, Duration::from_secs(2400));
+        assert_eq!(fmt_duration(eta), "40m00s");
+        assert_eq!(fmt_duration(Duration::from_secs(3723)), "1h02m03s");
+    }
+}
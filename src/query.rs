@@ -10,16 +10,25 @@ use reqwest::Url;
 use serde::Deserialize;
 use serde_with::{serde_as, DefaultOnError};
 
-use crate::utils::check_res;
+use crate::as2::{CheckType, IdRef, Post};
+use crate::http;
+use crate::utils::{check_res, int_id};
 
 pub async fn query_outbox_url(host: &str, acct: &str) -> Result<String> {
+    let profile_url = query_profile_url(host, acct).await?;
+    let profile = query_profile(&profile_url).await?;
+    Ok(profile.outbox)
+}
+
+/// Query the actor profile URL from the WebFinger API
+pub async fn query_profile_url(host: &str, acct: &str) -> Result<String> {
     let mut webfinger_u = Url::parse(host)?;
     let webfinger_path = Path::new(webfinger_u.path()).join(".well-known/webfinger");
     webfinger_u.set_path(webfinger_path.to_str().unwrap());
     webfinger_u
         .query_pairs_mut()
         .append_pair("resource", &format!("acct:{}", acct));
-    let webfinger_info: WebFinger = check_res(reqwest::get(webfinger_u).await?)
+    let webfinger_info: WebFinger = check_res(http::client().get(webfinger_u).send().await?)
         .await?
         .json()
         .await?;
@@ -37,20 +46,113 @@ pub async fn query_outbox_url(host: &str, acct: &str) -> Result<String> {
         .ok_or(anyhow!(
             "profile link with context type {ctx_type} not found"
         ))?;
+    Ok(profile_url)
+}
 
-    let client = reqwest::Client::new();
+async fn query_profile(profile_url: &str) -> Result<Profile> {
+    let client = http::client();
     let profile: Profile = check_res(
         client
             .get(profile_url)
-            .header("accept", ctx_type)
+            .header("accept", http::ACCEPT_AP)
+            .send()
+            .await?,
+    )
+    .await?
+    .json()
+    .await?;
+    Ok(profile)
+}
+
+/// Query the GUIDs of the pinned posts from the `featured` collection of the actor.
+/// The latest pinned comes first like Mastodon.
+/// Returns an empty list if the server has no such collection.
+pub async fn query_featured(profile_url: &str) -> Result<Vec<String>> {
+    let profile = query_profile(profile_url).await?;
+    let Some(featured_url) = profile.featured else {
+        return Ok(vec![]);
+    };
+    let client = http::client();
+    let featured: Featured = check_res(
+        client
+            .get(featured_url)
+            .header("accept", http::ACCEPT_AP)
             .send()
             .await?,
     )
     .await?
     .json()
     .await?;
-    let url = profile.outbox;
-    Ok(url)
+    let ids = featured
+        .ordered_items
+        .iter()
+        .map(|item| item.id().to_owned())
+        .collect();
+    Ok(ids)
+}
+
+/// Query the total number of items of a collection, e.g., the outbox.
+/// Returns `None` if the server does not tell.
+pub async fn query_total_items(collection_url: &str) -> Result<Option<u64>> {
+    let client = http::client();
+    let collection: Collection = check_res(
+        client
+            .get(collection_url)
+            .header("accept", http::ACCEPT_AP)
+            .send()
+            .await?,
+    )
+    .await?
+    .json()
+    .await?;
+    Ok(collection.total_items)
+}
+
+/// Query the latest version of the post, e.g., to get the final results of polls
+pub async fn query_post(id: &str) -> Result<Post> {
+    let client = http::client();
+    let post: Post = check_res(
+        client
+            .get(id)
+            .header("accept", http::ACCEPT_AP)
+            .send()
+            .await?,
+    )
+    .await?
+    .json()
+    .await?;
+    post.check_type()?;
+    Ok(post)
+}
+
+/// Query the preview card of a Mastodon post from the client API.
+/// Returns `None` if the post has no card.
+pub async fn query_card(post: &Post) -> Result<Option<Card>> {
+    let mut u = Url::parse(&post.url)?;
+    let id = int_id(&post.id)?;
+    u.set_path(&format!("api/v1/statuses/{id}"));
+    u.set_query(None);
+    let status: Status = check_res(http::client().get(u).send().await?)
+        .await?
+        .json()
+        .await?;
+    Ok(status.card)
+}
+
+/// Link preview generated by Mastodon
+#[derive(Deserialize, Debug, Clone)]
+pub struct Card {
+    pub url: String,
+    pub title: String,
+    #[serde(default)]
+    pub description: String,
+    /// URL of the preview image
+    pub image: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    card: Option<Card>,
 }
 
 #[serde_as]
@@ -70,4 +172,19 @@ struct WebFingerLink {
 #[derive(Deserialize)]
 struct Profile {
     outbox: String,
+    featured: Option<String>,
+}
+
+/// Posts are embedded by Mastodon but may be only GUIDs elsewhere
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Featured {
+    #[serde(default, alias = "items")]
+    ordered_items: Vec<IdRef>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Collection {
+    total_items: Option<u64>,
 }
@@ -0,0 +1,245 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Fixture recorder.
+//! Saves anonymized copies of fetched JSON so real-world posts can become test fixtures.
+//!
+//! A post is recorded when no fixture of its code path exists in the directory yet.
+//! The code path is named after what the consumer does with the post, e.g., `post_image_reply`.
+//! Pages that fail to be parsed are always recorded as `page_invalid_<hash>`.
+//!
+//! Anonymization replaces hosts, usernames, accounts, and mentions in all strings, and names of actors.
+//! Other texts are kept as they are, so check them before committing the fixtures.
+
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::path::PathBuf;
+use std::sync::LazyLock;
+
+use anyhow::Result;
+use regex::Regex;
+use serde_json::Value;
+use tokio::fs;
+
+use crate::as2::{Page, Post};
+use crate::utils::{fnv1a, int_id};
+
+pub struct FixtureRecorder {
+    dir: PathBuf,
+}
+
+impl FixtureRecorder {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Record the fetched body.
+    /// `page` is `None` if the body failed to be parsed as a valid page.
+    pub async fn record(&self, body: &[u8], page: Option<&Page>) -> Result<()> {
+        let Ok(mut raw) = serde_json::from_slice::<Value>(body) else {
+            tracing::warn!("Fetched body is not JSON and is not recorded");
+            return Ok(());
+        };
+        anonymize(&mut raw);
+
+        let page = match page {
+            Some(page) => page,
+            None => {
+                let mut hasher = DefaultHasher::new();
+                body.hash(&mut hasher);
+                let name = format!("page_invalid_{:016x}", hasher.finish());
+                return self.write(&name, &raw).await;
+            }
+        };
+
+        let mut new_path = false;
+        let raw_items = raw["orderedItems"].as_array().cloned().unwrap_or_default();
+        for (item, raw_item) in page.ordered_items.iter().zip(raw_items) {
+            let Some(act) = item.as_create() else {
+                continue;
+            };
+            let name = code_path(&act.object);
+            if fs::try_exists(self.path(&name)).await? {
+                continue;
+            }
+            self.write(&name, &raw_item["object"]).await?;
+            new_path = true;
+        }
+        if new_path {
+            let name = page_name(page.ordered_items[0].id());
+            self.write(&name, &raw).await?;
+        }
+        Ok(())
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.join(name.to_owned() + ".json")
+    }
+
+    async fn write(&self, name: &str, value: &Value) -> Result<()> {
+        let path = self.path(name);
+        let mut buf = serde_json::to_vec_pretty(value)?;
+        buf.push(b'\n');
+        fs::write(&path, buf).await?;
+        tracing::info!("Recorded fixture {}", path.display());
+        Ok(())
+    }
+}
+
+/// Name of the page after its first item, which is the integer ID if any or the hash of the GUID
+fn page_name(first_id: &str) -> String {
+    match int_id(first_id) {
+        Ok(id) => format!("page_{id}"),
+        Err(_) => format!("page_{:016x}", fnv1a(first_id.as_bytes())),
+    }
+}
+
+/// Name of the code path the post goes through
+fn code_path(post: &Post) -> String {
+    let mut name = "post".to_owned();
+    let media_types: Vec<_> = post
+        .attachment
+        .iter()
+        .map(|att| att.media_type.split('/').next().unwrap_or_default())
+        .collect();
+    match media_types.as_slice() {
+        [] => name += "_text",
+        [media_type] => name += &format!("_{media_type}"),
+        [..] if media_types.iter().all(|t| *t == "image") => name += "_multi_grouped_images",
+        [..] => name += "_multi_mixed_media",
+    }
+    if post.is_poll() {
+        name += "_poll";
+    }
+    if post.is_event() {
+        name += "_event";
+    }
+    if post.is_article() {
+        name += "_article";
+    } else if post.title().is_some() {
+        name += "_titled";
+    }
+    if post.in_reply_to.is_some() {
+        name += "_reply";
+    }
+    if post.is_sensitive() {
+        name += "_sensitive";
+    }
+    if !post.hashtag_names().is_empty() {
+        name += "_tag";
+    }
+    static RE_LINK: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"<a\s[^>]*>"#).unwrap());
+    if RE_LINK
+        .find_iter(&post.content)
+        .any(|m| !m.as_str().contains("hashtag"))
+    {
+        name += "_link";
+    }
+    name
+}
+
+/// Actor types whose names are replaced
+const ACTOR_TYPES: [&str; 5] = ["Person", "Service", "Application", "Group", "Organization"];
+
+/// Replace hosts, usernames, and accounts in all strings of the JSON, and names of actors
+fn anonymize(value: &mut Value) {
+    match value {
+        Value::String(s) => {
+            static RE_HOST: LazyLock<Regex> =
+                LazyLock::new(|| Regex::new(r#"(https?://|tag:)[^/\s"'<>,]+"#).unwrap());
+            static RE_USER: LazyLock<Regex> =
+                LazyLock::new(|| Regex::new(r#"/(users/|@)[^/\s"'<>]+"#).unwrap());
+            // `@user@host` of mentions and `acct:user@host` of WebFinger
+            static RE_ACCT: LazyLock<Regex> =
+                LazyLock::new(|| Regex::new(r"(@|acct:)[\w.-]+@[\w-]+(\.[\w-]+)*").unwrap());
+            // Text of mention links like `@<span>user</span>`, which has no host to match
+            static RE_MENTION: LazyLock<Regex> = LazyLock::new(|| {
+                Regex::new(r#"(<span class="h-card"[^>]*>\s*<a\s[^>]*>).*?(</a>)"#).unwrap()
+            });
+            let acct_replaced = RE_ACCT.replace_all(s, "${1}alice@example.com");
+            let mention_replaced =
+                RE_MENTION.replace_all(&acct_replaced, "${1}@<span>alice</span>${2}");
+            let host_replaced = RE_HOST.replace_all(&mention_replaced, "${1}example.com");
+            *s = RE_USER
+                .replace_all(&host_replaced, "/${1}alice")
+                .into_owned();
+        }
+        Value::Array(items) => items.iter_mut().for_each(anonymize),
+        Value::Object(obj) => {
+            let is_actor = obj
+                .get("type")
+                .and_then(Value::as_str)
+                .is_some_and(|t| ACTOR_TYPES.contains(&t));
+            if is_actor && obj.get("name").is_some_and(Value::is_string) {
+                obj.insert("name".to_owned(), "Alice".into());
+            }
+            if obj.get("preferredUsername").is_some_and(Value::is_string) {
+                obj.insert("preferredUsername".to_owned(), "alice".into());
+            }
+            obj.values_mut().for_each(anonymize);
+        }
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check_de;
+
+    #[test]
+    fn test_code_path() -> Result<()> {
+        assert_eq!(code_path(&check_de!(Post, "post_text")), "post_text");
+        assert_eq!(
+            code_path(&check_de!(Post, "post_link")),
+            "post_text_reply_link"
+        );
+        assert_eq!(
+            code_path(&check_de!(Post, "post_tag")),
+            "post_text_reply_tag"
+        );
+        assert_eq!(
+            code_path(&check_de!(Post, "post_multi_grouped_images")),
+            "post_multi_grouped_images"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_anonymize() {
+        let mut value = serde_json::json!({
+            "id": "https://social.myl.moe/users/myl/statuses/1",
+            "url": "https://social.myl.moe/@myl/1",
+            "conversation": "tag:myl.moe,2023-08-03:objectId=1:objectType=Conversation",
+            "content": "<p><span class=\"h-card\" translate=\"no\"><a href=\"https://social.myl.moe/@myl\" class=\"u-url mention\">@<span>myl</span></a></span> cc @friend@other.host.example and <a href=\"https://social.myl.moe/tags/rust\" class=\"mention hashtag\" rel=\"tag\">#<span>rust</span></a></p>",
+            "subject": "acct:myl@myl.moe",
+            "attributedTo": {"type": "Person", "name": "myl", "preferredUsername": "myl"},
+            "attachment": [{"type": "Document", "name": "A cat"}],
+        });
+        anonymize(&mut value);
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "id": "https://example.com/users/alice/statuses/1",
+                "url": "https://example.com/@alice/1",
+                "conversation": "tag:example.com,2023-08-03:objectId=1:objectType=Conversation",
+                "content": "<p><span class=\"h-card\" translate=\"no\"><a href=\"https://example.com/@alice\" class=\"u-url mention\">@<span>alice</span></a></span> cc @alice@example.com and <a href=\"https://example.com/tags/rust\" class=\"mention hashtag\" rel=\"tag\">#<span>rust</span></a></p>",
+                "subject": "acct:alice@example.com",
+                "attributedTo": {"type": "Person", "name": "Alice", "preferredUsername": "alice"},
+                "attachment": [{"type": "Document", "name": "A cat"}],
+            })
+        );
+    }
+
+    #[test]
+    fn test_page_name() {
+        assert_eq!(
+            page_name("https://example.com/users/alice/statuses/1/activity"),
+            "page_1"
+        );
+        assert_eq!(
+            page_name("https://example.com/notes/9abc"),
+            format!("page_{:016x}", fnv1a(b"https://example.com/notes/9abc"))
+        );
+    }
+}
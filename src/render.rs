@@ -0,0 +1,1057 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Rendering posts into the message texts sent by consumers.
+//!
+//! Golden cases live in `tests/fixtures/golden` and are checked by [`crate::snapshot`].
+
+use std::sync::LazyLock;
+
+use anyhow::{anyhow, bail, Result};
+use clap::ValueEnum;
+use quick_xml::events::Event;
+use quick_xml::name::QName;
+use quick_xml::reader::Reader;
+use regex::Regex;
+use reqwest::Url;
+use time::format_description::{self, OwnedFormatItem};
+use time::{Duration, OffsetDateTime, UtcOffset};
+
+use crate::as2::{ActorRef, Place, Post};
+use crate::labels::{fill, Labels};
+use crate::query::Card;
+use crate::utils::parse_time;
+
+/// Max length of Telegram message texts. Unit: Chars.
+pub const TEXT_LIMIT: usize = 4096;
+
+/// Max length of Telegram media captions. Unit: Chars.
+pub const CAPTION_LIMIT: usize = 1024;
+
+/// Max length of long-form posts, which are split into a few msgs. Unit: Chars.
+pub const ARTICLE_LIMIT: usize = TEXT_LIMIT * 4;
+
+/// Render the post into the HTML subset supported by Telegram.
+/// Posts with content warnings show the warnings in clear and the rest as spoilers, collapsed like on Mastodon.
+pub fn render_post(post: &Post, labels: &Labels) -> Result<String> {
+    let text = render_body(post, labels)?;
+    Ok(match post.content_warning() {
+        Some(cw) => format!("⚠️ {}\n{}", escape(cw.trim()), render_spoiler(&text)),
+        None => text,
+    })
+}
+
+/// Hide the text as a spoiler.
+/// Telegram spoilers can not contain code, so code is shown as plain text in them.
+fn render_spoiler(text: &str) -> String {
+    let text = ["<pre>", "</pre>", "<code>", "</code>"]
+        .iter()
+        .fold(text.to_owned(), |text, tag| text.replace(tag, ""));
+    format!("<tg-spoiler>{text}</tg-spoiler>")
+}
+
+/// Render the post without the content warning
+fn render_body(post: &Post, labels: &Labels) -> Result<String> {
+    if post.is_article() {
+        return render_article(post, labels);
+    }
+    if post.is_event() {
+        return render_event(post, labels);
+    }
+    let mut text = render_title(post);
+    let body = clean_body(&post.content)?;
+    // Tracks may have only the title without descriptions
+    if body.is_empty() {
+        text.truncate(text.trim_end().len());
+    }
+    text += &body;
+    text += &render_hidden_hashtags(post);
+    if post.is_poll() {
+        text += "\n\n";
+        text += &render_poll(post, labels);
+    }
+    Ok(text)
+}
+
+/// Render the post with the attribution and the footer if enabled, as the message text
+pub fn render_message(
+    post: &Post,
+    attribution: bool,
+    footer: &Footer,
+    labels: &Labels,
+    now: OffsetDateTime,
+) -> Result<String> {
+    let mut content = render_post(post, labels)?;
+    if attribution {
+        if let Some(author) = render_attribution(post) {
+            content = format!("{author}\n{content}");
+        }
+    }
+    if let Some(footer) = footer.render(post, labels, now) {
+        content += "\n\n";
+        content += &footer;
+    }
+    Ok(content)
+}
+
+/// Placeholders of templates
+const TEMPLATE_FIELDS: &[&str] = &["author", "content", "footer", "url"];
+
+/// Template of the message texts, e.g., `{author}\n{content}\n\n{footer}`, replacing the default layout.
+/// `{author}`, `{content}`, `{footer}`, and `{url}` are filled in regardless of the options enabling them,
+/// and the lines of only the placeholders filled with nothing are dropped.
+/// Other texts are in the HTML subset supported by Telegram.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Template(String);
+
+impl Template {
+    /// Parse the template, where `\n` is also taken as a line break, e.g., in config files
+    pub fn parse(s: &str) -> Result<Self> {
+        let template = s.replace("\\n", "\n");
+        let mut rest = template.as_str();
+        while let Some(start) = rest.find('{') {
+            let end = rest[start..]
+                .find('}')
+                .ok_or(anyhow!("unclosed placeholder in template {s}"))?;
+            let name = &rest[start + 1..start + end];
+            if !TEMPLATE_FIELDS.contains(&name) {
+                bail!(
+                    "unknown placeholder {{{name}}} in template, expected one of {}",
+                    TEMPLATE_FIELDS.join(", ")
+                );
+            }
+            rest = &rest[start + end + 1..];
+        }
+        Ok(Self(template))
+    }
+
+    /// Render the post as the message text at the time
+    pub fn render(
+        &self,
+        post: &Post,
+        footer: &Footer,
+        labels: &Labels,
+        now: OffsetDateTime,
+    ) -> Result<String> {
+        let values = [
+            render_attribution(post).unwrap_or_default(),
+            render_post(post, labels)?,
+            footer.render(post, labels, now).unwrap_or_default(),
+            escape(&post.url),
+        ];
+        let mut lines: Vec<String> = Vec::new();
+        for line in self.0.lines() {
+            // Filled in one pass, so placeholders in the values are kept as they are
+            let mut filled = String::new();
+            let mut rest = line;
+            while let Some(start) = rest.find('{') {
+                let end = start + rest[start..].find('}').unwrap();
+                let i = TEMPLATE_FIELDS
+                    .iter()
+                    .position(|name| *name == &rest[start + 1..end])
+                    .unwrap();
+                filled += &rest[..start];
+                filled += &values[i];
+                rest = &rest[end + 1..];
+            }
+            filled += rest;
+            let blank = filled.trim().is_empty();
+            if blank && (!line.trim().is_empty() || lines.last().is_some_and(|l| l.is_empty())) {
+                continue;
+            }
+            lines.push(if blank { String::new() } else { filled });
+        }
+        Ok(lines.join("\n").trim().to_owned())
+    }
+}
+
+/// Render the long-form post with its title, shortened to fit in a few msgs
+fn render_article(post: &Post, labels: &Labels) -> Result<String> {
+    let mut text = render_title(post);
+    text += &clean_body(&post.content)?;
+    text += &render_hidden_hashtags(post);
+    Ok(shorten(&text, ARTICLE_LIMIT, &post.url, &labels.read_more))
+}
+
+/// Event with its time, place, and join link above the description, shortened to fit in a message
+fn render_event(post: &Post, labels: &Labels) -> Result<String> {
+    let mut lines = Vec::new();
+    if let Some(time) = render_event_time(post) {
+        lines.push(format!("🕒 {}", escape(&time)));
+    }
+    if let Some(place) = post.location.as_ref().and_then(Place::text) {
+        lines.push(format!("📍 {}", escape(&place)));
+    }
+    lines.push(format!(
+        r#"🔗 <a href="{}">{}</a>"#,
+        escape(&post.url),
+        escape(&labels.event_join)
+    ));
+    let mut text = render_title(post) + &lines.join("\n");
+    let body = clean_body(&post.content)?;
+    if !body.trim().is_empty() {
+        text += "\n\n";
+        text += &body;
+    }
+    text += &render_hidden_hashtags(post);
+    Ok(shorten(&text, TEXT_LIMIT, &post.url, &labels.read_more))
+}
+
+/// Time span of the event in the offset of the start, e.g., `2023-09-01 18:00 – 21:00 +02:00`.
+/// The end only has the time if on the same day.
+fn render_event_time(post: &Post) -> Option<String> {
+    let start = post.start_time.as_deref()?;
+    let Some(start_at) = parse_time(start) else {
+        return Some(start.to_owned());
+    };
+    let date_time = format_description::parse_borrowed::<1>(DEFAULT_TIMESTAMP_FORMAT).unwrap();
+    let time = format_description::parse_borrowed::<1>("[hour]:[minute]").unwrap();
+    let mut text = start_at.format(&date_time).ok()?;
+    if let Some(end_at) = post.end_time.as_deref().and_then(parse_time) {
+        let end_at = end_at.to_offset(start_at.offset());
+        let format = match end_at.date() == start_at.date() {
+            true => &time,
+            false => &date_time,
+        };
+        text += " – ";
+        text += &end_at.format(format).ok()?;
+    }
+    let offset = start_at.offset();
+    if offset.is_utc() {
+        text += " UTC";
+    } else {
+        let format =
+            format_description::parse_borrowed::<1>("[offset_hour sign:mandatory]:[offset_minute]");
+        text += " ";
+        text += &offset.format(&format.unwrap()).ok()?;
+    }
+    Some(text)
+}
+
+/// Line of the listed hashtags that are not in the content if any
+fn render_hidden_hashtags(post: &Post) -> String {
+    let names = post.hidden_hashtags();
+    if names.is_empty() {
+        return String::new();
+    }
+    let tags: Vec<_> = names
+        .iter()
+        .map(|name| format!("#{}", escape(name)))
+        .collect();
+    format!("\n\n{}", tags.join(" "))
+}
+
+/// Bold title as the first line if any, followed by the album of tracks
+fn render_title(post: &Post) -> String {
+    let Some(title) = post.title() else {
+        return String::new();
+    };
+    match post.track_meta().and_then(|meta| meta.album) {
+        Some(album) => format!("<b>{}</b>\n<i>{}</i>\n\n", escape(title), escape(&album)),
+        None => format!("<b>{}</b>\n\n", escape(title)),
+    }
+}
+
+/// Cut the text to fit in the limit, and link to the full post.
+/// The text is cut like the first part of [`split_text`],
+/// at a line break, or at a space or a char if the first line is too long, with the tags balanced.
+fn shorten(text: &str, limit: usize, url: &str, read_more: &str) -> String {
+    if text.chars().count() <= limit {
+        return text.to_owned();
+    }
+    let more = format!("\n\n<a href=\"{url}\">{}</a>", escape(read_more));
+    let budget = limit.saturating_sub(more.chars().count());
+    let head = split_text(text, budget, budget).into_iter().next();
+    head.unwrap_or_default().trim_end().to_owned() + &more
+}
+
+/// Split the text into parts fitting in the limits, the first part in `first` and others in `rest`.
+/// Cuts are at line breaks, or at spaces if a line is too long.
+/// Tags open at a cut are closed and reopened in the next part, so each part is balanced.
+pub fn split_text(text: &str, first: usize, rest: usize) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut text = text.trim();
+    // Tags open at the last cut, as the names and the start tags
+    let mut open: Vec<(String, String)> = Vec::new();
+    while !text.is_empty() {
+        let limit = if parts.is_empty() { first } else { rest };
+        let prefix: String = open.iter().map(|(_, tag)| tag.as_str()).collect();
+        let whole = prefix.chars().count() + text.chars().count();
+        if whole <= limit {
+            parts.push(prefix + text);
+            break;
+        }
+        // Shrink the budget until the part with the closing tags fits
+        let mut budget = limit.saturating_sub(prefix.chars().count());
+        loop {
+            let cut = cut_at(text, budget);
+            let tags = open_tags(open.clone(), &text[..cut]);
+            let closing: String = tags
+                .iter()
+                .rev()
+                .map(|(name, _)| format!("</{name}>"))
+                .collect();
+            let part = prefix.clone() + text[..cut].trim_end() + &closing;
+            if part.chars().count() <= limit || budget == 0 {
+                parts.push(part);
+                text = text[cut..].trim_start();
+                open = tags;
+                break;
+            }
+            budget = budget.saturating_sub(closing.chars().count().max(1));
+        }
+    }
+    parts
+}
+
+/// Byte index to cut the text at within the char budget.
+/// Not in a tag or an entity, and at least one char is taken.
+fn cut_at(text: &str, budget: usize) -> usize {
+    let in_tag = |i: usize| text[..i].rfind('<') > text[..i].rfind('>');
+    let in_entity = |i: usize| text[..i].rfind('&') > text[..i].rfind([';', ' ', '\n']);
+    let fine = |i: &usize| *i > 0 && text.is_char_boundary(*i) && !in_tag(*i) && !in_entity(*i);
+    let end = text
+        .char_indices()
+        .nth(budget)
+        .map_or(text.len(), |(i, _)| i);
+    let head = &text[..end];
+    head.rfind('\n')
+        .filter(fine)
+        .or_else(|| head.rmatch_indices(' ').map(|(i, _)| i).find(fine))
+        .or_else(|| (0..=end).rev().find(fine))
+        // Tags longer than the budget are kept whole
+        .or_else(|| (end..text.len()).find(fine))
+        .unwrap_or(text.len())
+}
+
+/// Tags still open after the HTML, given the ones open before
+fn open_tags(mut open: Vec<(String, String)>, html: &str) -> Vec<(String, String)> {
+    let mut rest = html;
+    while let Some(start) = rest.find('<') {
+        let Some(len) = rest[start..].find('>') else {
+            break;
+        };
+        let tag = &rest[start..start + len + 1];
+        rest = &rest[start + len + 1..];
+        let inner = tag[1..tag.len() - 1].trim();
+        if let Some(name) = inner.strip_prefix('/') {
+            if let Some(i) = open.iter().rposition(|(open, _)| open == name.trim()) {
+                open.truncate(i);
+            }
+        } else if !inner.ends_with('/') {
+            let name = inner.split_whitespace().next().unwrap_or_default();
+            open.push((name.to_owned(), tag.to_owned()));
+        }
+    }
+    open
+}
+
+/// Style of the published time in the footer
+#[derive(Copy, Clone, Debug, PartialEq, Eq, ValueEnum)]
+pub enum TimestampStyle {
+    /// In the configured timezone and format, e.g., `2023-08-03 16:09`
+    Absolute,
+    /// Relative to when the post is sent, e.g., `3 hours ago`
+    Relative,
+}
+
+/// Footer appended to the posts
+#[derive(Clone)]
+pub struct Footer {
+    /// Show the engagement stats
+    pub engagement: bool,
+    /// Show the published time
+    pub timestamp: Option<TimestampStyle>,
+    /// Timezone of absolute timestamps
+    pub offset: UtcOffset,
+    /// Format of absolute timestamps
+    pub format: OwnedFormatItem,
+}
+
+impl Default for Footer {
+    fn default() -> Self {
+        Self {
+            engagement: false,
+            timestamp: None,
+            offset: UtcOffset::UTC,
+            format: format_description::parse_owned::<1>(DEFAULT_TIMESTAMP_FORMAT).unwrap(),
+        }
+    }
+}
+
+pub const DEFAULT_TIMESTAMP_FORMAT: &str = "[year]-[month]-[day] [hour]:[minute]";
+
+impl Footer {
+    /// Render the footer at the time.
+    /// `None` if there is nothing to show.
+    pub fn render(&self, post: &Post, labels: &Labels, now: OffsetDateTime) -> Option<String> {
+        let timestamp = self.timestamp.and_then(|style| {
+            let published = post.published_at()?;
+            match style {
+                TimestampStyle::Absolute => {
+                    published.to_offset(self.offset).format(&self.format).ok()
+                }
+                TimestampStyle::Relative => Some(render_relative(now - published, labels)),
+            }
+        });
+        let engagement = self.engagement.then(|| render_engagement(post)).flatten();
+        let parts: Vec<_> = [timestamp, engagement].into_iter().flatten().collect();
+        (!parts.is_empty()).then(|| parts.join(" · "))
+    }
+}
+
+/// Coarse relative time like social apps
+fn render_relative(elapsed: Duration, labels: &Labels) -> String {
+    let plural = |n: i64, one: &str, other: &str| {
+        let label = if n == 1 { one } else { other };
+        escape(&fill(label, "n", &n.to_string()))
+    };
+    match elapsed.whole_minutes() {
+        ..=0 => escape(&labels.just_now),
+        m @ 1..=59 => plural(m, &labels.minute_ago, &labels.minutes_ago),
+        m if m < 60 * 24 => plural(m / 60, &labels.hour_ago, &labels.hours_ago),
+        m => plural(m / (60 * 24), &labels.day_ago, &labels.days_ago),
+    }
+}
+
+/// Parse UTC offsets like `UTC`, `+08:00`, or `-5`
+pub fn parse_offset(s: &str) -> Result<UtcOffset> {
+    let err = || anyhow!("invalid timezone {s} (expected UTC or an offset like +08:00)");
+    if s.eq_ignore_ascii_case("utc") || s == "Z" {
+        return Ok(UtcOffset::UTC);
+    }
+    let (sign, rest) = match s.as_bytes().first() {
+        Some(b'+') => (1, &s[1..]),
+        Some(b'-') => (-1, &s[1..]),
+        _ => return Err(err()),
+    };
+    let (hours, minutes) = rest.split_once(':').unwrap_or((rest, "0"));
+    let hours: i8 = hours.parse().map_err(|_| err())?;
+    let minutes: i8 = minutes.parse().map_err(|_| err())?;
+    UtcOffset::from_hms(sign * hours, sign * minutes, 0).map_err(|_| err())
+}
+
+/// Footer of the engagement stats, e.g., `💬 2 · 🔁 3 · ⭐ 12`.
+/// Unknown stats are omitted, and `None` if all are unknown.
+pub fn render_engagement(post: &Post) -> Option<String> {
+    let stats = post.engagement();
+    let parts: Vec<_> = [
+        ("💬", stats.replies),
+        ("🔁", stats.shares),
+        ("⭐", stats.likes),
+    ]
+    .into_iter()
+    .filter_map(|(icon, n)| n.map(|n| format!("{icon} {n}")))
+    .collect();
+    (!parts.is_empty()).then(|| parts.join(" · "))
+}
+
+/// Author line to prepend for sources mixing authors, e.g., `<b>Alice</b> (@alice@example.com)`.
+/// Only the handle is shown if the actor is not embedded.
+pub fn render_attribution(post: &Post) -> Option<String> {
+    let author = post.author()?;
+    let url = Url::parse(author.id()).ok()?;
+    let host = url.host_str()?;
+    let (name, username) = match author {
+        ActorRef::Actor(actor) => (actor.name.as_deref(), actor.preferred_username.clone()),
+        ActorRef::Id(_) => (None, None),
+    };
+    // Mastodon-like GUIDs end with the username, e.g., `/users/alice`
+    let username = username.or_else(|| {
+        let last = url.path_segments()?.next_back()?;
+        Some(last.trim_start_matches('@').to_owned()).filter(|s| !s.is_empty())
+    })?;
+    let handle = escape(&format!("@{username}@{host}"));
+    Some(match name.filter(|s| !s.is_empty()) {
+        Some(name) => format!("<b>{}</b> ({handle})", escape(name)),
+        None => handle,
+    })
+}
+
+/// Label of the `pos`-th post of a self-thread, e.g., `🧵 (2/…)`.
+/// The total is unknown when mirroring.
+pub fn render_thread_label(pos: u32) -> String {
+    format!("🧵 ({pos}/…)")
+}
+
+/// Preview card of the link, e.g., `<b>Title</b>` and the description below
+pub fn render_card(card: &Card) -> String {
+    let mut text = format!(
+        r#"<a href="{}"><b>{}</b></a>"#,
+        escape(&card.url),
+        escape(&card.title)
+    );
+    if !card.description.is_empty() {
+        text += "\n";
+        text += &escape(&card.description);
+    }
+    text
+}
+
+/// Lines linking to the attachments that can not be sent
+pub fn render_unavailable(post: &Post, labels: &Labels) -> String {
+    let prefix = escape(&labels.media_unavailable);
+    post.attachment
+        .iter()
+        .map(|att| {
+            let url = escape(&att.url);
+            format!(r#"{prefix}: <a href="{url}">{url}</a>"#)
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Render the poll options with their votes as a plain list
+fn render_poll(post: &Post, labels: &Labels) -> String {
+    let (options, multiple) = post.poll_options();
+    let mark = if multiple { "☐" } else { "○" };
+    let mut lines: Vec<_> = options
+        .iter()
+        .map(|opt| format!("{mark} {} ({})", escape(&opt.name), opt.votes()))
+        .collect();
+    if let Some(end_time) = post.end_time.as_ref() {
+        let label = match post.is_closed() {
+            true => &labels.poll_ended,
+            false => &labels.poll_ends,
+        };
+        lines.push(escape(&fill(label, "time", end_time)));
+    }
+    lines.join("\n")
+}
+
+/// Escape texts for the Telegram HTML
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Clean the HTML body into the HTML subset supported by Telegram.
+/// Rich HTML of long-form posts is converted, e.g., headings into bold lines and list items into bullets.
+pub fn clean_body(body: &str) -> Result<String> {
+    let mut texts = String::new();
+    let mut reader = Reader::from_str(body);
+    // Void elements like <img> and <hr> are not closed in HTML
+    reader.check_end_names(false);
+    // In a <a>. Texts inside ignored.
+    let mut in_link = false;
+    // In a <a> as a hashtag.
+    let mut in_hashtag = false;
+    // Lists in, with the next number if ordered
+    let mut lists: Vec<Option<u32>> = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(elem) => match elem.name().as_ref() {
+                b"p" | b"div" | b"hr" => break_block(&mut texts),
+                b"h1" | b"h2" | b"h3" | b"h4" | b"h5" | b"h6" => {
+                    break_block(&mut texts);
+                    texts += "<b>";
+                }
+                b"blockquote" | b"pre" => {
+                    break_block(&mut texts);
+                    texts += &format!("<{}>", String::from_utf8_lossy(elem.name().as_ref()));
+                }
+                b"ul" | b"ol" => {
+                    if lists.is_empty() {
+                        break_block(&mut texts);
+                    }
+                    let ordered = elem.name().as_ref() == b"ol";
+                    lists.push(ordered.then_some(1));
+                }
+                b"li" => {
+                    texts.truncate(texts.trim_end_matches([' ', '\t']).len());
+                    if !texts.is_empty() && !texts.ends_with('\n') {
+                        texts += "\n";
+                    }
+                    texts += &"  ".repeat(lists.len().saturating_sub(1));
+                    match lists.last_mut() {
+                        Some(Some(n)) => {
+                            texts += &format!("{n}. ");
+                            *n += 1;
+                        }
+                        _ => texts += "• ",
+                    }
+                }
+                b"br" => texts += "\n",
+                b"a" => {
+                    let mut is_hashtag = false;
+                    let mut href_opt = None;
+                    elem.html_attributes().try_for_each(|res| {
+                        let attr = res?;
+                        match attr.key {
+                            QName(b"class") => {
+                                is_hashtag = attr
+                                    .decode_and_unescape_value(&reader)?
+                                    .find("hashtag")
+                                    .is_some()
+                            }
+                            QName(b"href") => {
+                                href_opt = Some(attr.decode_and_unescape_value(&reader)?)
+                            }
+                            _ => (),
+                        }
+                        anyhow::Ok(())
+                    })?;
+                    if is_hashtag && !in_hashtag {
+                        in_hashtag = true;
+                    } else if !in_link {
+                        let href = href_opt.ok_or(anyhow!("no href in the <a> tag"))?;
+                        texts += &format!(r#"<a href="{}">{href}"#, href);
+                        in_link = true;
+                    } else {
+                        bail!("unknown <a> tag");
+                    }
+                }
+                name => {
+                    if let Some(tag) = inline_tag(name) {
+                        texts += &format!("<{tag}>");
+                    }
+                }
+            },
+            Event::Text(elem) if !in_link => {
+                texts += &escape(&elem.unescape()?);
+            }
+            Event::End(elem) => match elem.name().as_ref() {
+                b"a" => {
+                    if in_hashtag {
+                        in_hashtag = false;
+                    } else if in_link {
+                        texts += "</a>";
+                        in_link = false;
+                    } else {
+                        anyhow::bail!("unknown <a> tag");
+                    }
+                }
+                b"h1" | b"h2" | b"h3" | b"h4" | b"h5" | b"h6" => texts += "</b>",
+                b"blockquote" | b"pre" => {
+                    texts.truncate(texts.trim_end().len());
+                    texts += &format!("</{}>", String::from_utf8_lossy(elem.name().as_ref()));
+                }
+                b"ul" | b"ol" => {
+                    lists.pop();
+                }
+                name => {
+                    if let Some(tag) = inline_tag(name) {
+                        texts += &format!("</{tag}>");
+                    }
+                }
+            },
+            Event::Empty(elem) => match elem.name().as_ref() {
+                b"br" => texts += "\n",
+                b"hr" => break_block(&mut texts),
+                _ => (),
+            },
+            _ => (),
+        }
+    }
+    Ok(texts)
+}
+
+/// End the paragraph before a block if any
+fn break_block(texts: &mut String) {
+    texts.truncate(texts.trim_end().len());
+    if !texts.is_empty() && !texts.ends_with("<blockquote>") && !texts.ends_with("<pre>") {
+        *texts += "\n\n";
+    }
+}
+
+/// Telegram tag of the inline HTML tag if supported
+fn inline_tag(name: &[u8]) -> Option<&'static str> {
+    Some(match name {
+        b"b" | b"strong" => "b",
+        b"i" | b"em" => "i",
+        b"u" | b"ins" => "u",
+        b"s" | b"del" | b"strike" => "s",
+        b"code" => "code",
+        _ => return None,
+    })
+}
+
+/// Convert the Telegram HTML subset into plain text.
+/// Links keep the texts of mentions and hashtags, and otherwise become their URLs,
+/// with the texts also kept if they are not the shortened URLs.
+pub fn to_plain(html: &str) -> String {
+    static RE_LINK: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r#"<a\s[^>]*href="([^"]*)"[^>]*>(.*?)</a>"#).unwrap());
+    static RE_TAG: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"<[^>]*>").unwrap());
+    let text = RE_LINK.replace_all(html, |cap: &regex::Captures| {
+        let href = &cap[1];
+        let text = RE_TAG.replace_all(&cap[2], "");
+        let shown = text.trim_end_matches('…');
+        if text.starts_with(['@', '#']) || href.is_empty() {
+            text.into_owned()
+        } else if href.contains(shown) {
+            href.to_owned()
+        } else {
+            format!("{text} ({href})")
+        }
+    });
+    RE_TAG
+        .replace_all(&text, "")
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&amp;", "&")
+}
+
+/// Element whose content is rewritten as a whole when closed
+enum Frame {
+    Root,
+    Quote,
+    Link(String),
+}
+
+/// Convert the Telegram HTML subset into Markdown, which both Discord and CommonMark renderers take.
+/// Unknown tags are dropped with their contents kept.
+pub fn to_markdown(html: &str) -> String {
+    static RE_TAG: LazyLock<Regex> =
+        LazyLock::new(|| Regex::new(r"<(/?)([a-zA-Z][\w-]*)([^>]*)>").unwrap());
+    static RE_HREF: LazyLock<Regex> = LazyLock::new(|| Regex::new(r#"href="([^"]*)""#).unwrap());
+    let mut frames = vec![(Frame::Root, String::new())];
+    let mut pre = false;
+    let mut last = 0;
+    for cap in RE_TAG.captures_iter(html) {
+        let m = cap.get(0).unwrap();
+        let text = unescape(&html[last..m.start()]);
+        let buf = &mut frames.last_mut().unwrap().1;
+        match pre {
+            true => *buf += &text,
+            false => *buf += &escape_md(&text),
+        }
+        last = m.end();
+
+        let closing = &cap[1] == "/";
+        let mark = match &cap[2] {
+            "b" | "strong" => "**",
+            "i" | "em" => "*",
+            "u" | "ins" => "__",
+            "s" | "strike" | "del" => "~~",
+            "tg-spoiler" => "||",
+            "code" if !pre => "`",
+            "pre" => {
+                pre = !closing;
+                match closing {
+                    true => "\n```",
+                    false => "```\n",
+                }
+            }
+            "blockquote" if !closing => {
+                frames.push((Frame::Quote, String::new()));
+                continue;
+            }
+            "blockquote" if matches!(frames.last().unwrap().0, Frame::Quote) => {
+                let (_, quote) = frames.pop().unwrap();
+                let quote: Vec<_> = quote.lines().map(|line| format!("> {line}")).collect();
+                frames.last_mut().unwrap().1 += &quote.join("\n");
+                continue;
+            }
+            "a" if !closing => {
+                let href = RE_HREF
+                    .captures(&cap[3])
+                    .map(|c| unescape(&c[1]))
+                    .unwrap_or_default();
+                frames.push((Frame::Link(href), String::new()));
+                continue;
+            }
+            "a" if matches!(frames.last().unwrap().0, Frame::Link(_)) => {
+                let (Frame::Link(href), text) = frames.pop().unwrap() else {
+                    unreachable!()
+                };
+                let link = match href.is_empty() || text == escape_md(&href) {
+                    true => text,
+                    false => format!("[{text}]({href})"),
+                };
+                frames.last_mut().unwrap().1 += &link;
+                continue;
+            }
+            _ => continue,
+        };
+        frames.last_mut().unwrap().1 += mark;
+    }
+    frames.last_mut().unwrap().1 += &escape_md(&unescape(&html[last..]));
+    // Unclosed elements are kept as they are
+    frames.into_iter().map(|(_, buf)| buf).collect()
+}
+
+/// Unescape the entities of the Telegram HTML
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Escape the markdown marks in texts
+fn escape_md(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(c, '\\' | '*' | '_' | '~' | '`' | '|') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::check_de;
+
+    #[test]
+    fn test_body_text() -> Result<()> {
+        let post = check_de!(Post, "post_text");
+        let body = clean_body(&post.content)?;
+        let body_expected = concat!(
+            "哈哈哈哈，追番的乐趣原来就是这样啊੭ ᐕ)੭\n",
+            "虽然还是没有更多的信息，但是实在是名场面啊，很很的破防！\n",
+            "mygo 好！"
+        );
+        assert_eq!(body, body_expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_body_link() -> Result<()> {
+        let post = check_de!(Post, "post_link");
+        let body = clean_body(&post.content)?;
+        let body_expected = concat!(
+            r#"已经 deploy <a href="https://github.com/myl7/mastotg">https://github.com/myl7/mastotg</a> 了，应该是 generally available 了"#,
+            "\n",
+            "功能的话还差个 reply 关系（这条作为样例试试再看怎么处理"
+        );
+        assert_eq!(body, body_expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_body_tag() -> Result<()> {
+        let post = check_de!(Post, "post_tag");
+        let body = clean_body(&post.content)?;
+        let body_expected = concat!(
+            "另：信息已经不重要了，具体的前因后果就等 ave mujica 里讲吧，或许可以 mygo 结尾留个引子？\n",
+            "#mygo"
+        );
+        assert_eq!(body, body_expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_engagement() -> Result<()> {
+        let post = check_de!(Post, "post_article");
+        assert_eq!(render_engagement(&post).as_deref(), Some("💬 2 · ⭐ 12"));
+        assert_eq!(render_engagement(&check_de!(Post, "post_text")), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_footer() -> Result<()> {
+        let post = check_de!(Post, "post_article");
+        let now = post.published_at().unwrap() + Duration::hours(3);
+        let labels = Labels::default();
+        let mut footer = Footer {
+            engagement: true,
+            timestamp: Some(TimestampStyle::Absolute),
+            offset: parse_offset("+08:00")?,
+            ..Footer::default()
+        };
+        assert_eq!(
+            footer.render(&post, &labels, now).as_deref(),
+            Some("2023-08-20 17:00 · 💬 2 · ⭐ 12")
+        );
+        footer.timestamp = Some(TimestampStyle::Relative);
+        footer.engagement = false;
+        assert_eq!(
+            footer.render(&post, &labels, now).as_deref(),
+            Some("3 hours ago")
+        );
+        let zh = Labels::builtin("zh").unwrap();
+        assert_eq!(footer.render(&post, &zh, now).as_deref(), Some("3 小时前"));
+        assert_eq!(Footer::default().render(&post, &labels, now), None);
+        assert_eq!(parse_offset("-5")?, UtcOffset::from_hms(-5, 0, 0)?);
+        assert!(parse_offset("Asia/Shanghai").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_attribution() -> Result<()> {
+        let mut post = check_de!(Post, "post_text");
+        assert_eq!(
+            render_attribution(&post).as_deref(),
+            Some("@myl@social.myl.moe")
+        );
+        post.attributed_to = serde_json::from_str(
+            r#"[
+                {"type": "Group", "id": "https://lemmy.example/c/mygo"},
+                {"type": "Person", "id": "https://lemmy.example/u/anon", "name": "Anon <3", "preferredUsername": "anon"}
+            ]"#,
+        )?;
+        assert_eq!(
+            render_attribution(&post).as_deref(),
+            Some("<b>Anon &lt;3</b> (@anon@lemmy.example)")
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_card() {
+        let card = Card {
+            url: "https://github.com/myl7/mastotg".to_owned(),
+            title: "myl7/mastotg".to_owned(),
+            description: "Forward posts from Mastodon to Telegram channels".to_owned(),
+            image: None,
+        };
+        assert_eq!(
+            render_card(&card),
+            "<a href=\"https://github.com/myl7/mastotg\"><b>myl7/mastotg</b></a>\nForward posts from Mastodon to Telegram channels"
+        );
+    }
+
+    #[test]
+    fn test_render_unavailable() -> Result<()> {
+        let mut post = check_de!(Post, "post_multi_grouped_images");
+        post.attachment.truncate(1);
+        post.attachment[0].url = "https://example.com/a.png?x=1&y=2".to_owned();
+        assert_eq!(
+            render_unavailable(&post, &Labels::default()),
+            r#"media unavailable: <a href="https://example.com/a.png?x=1&amp;y=2">https://example.com/a.png?x=1&amp;y=2</a>"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_render_spoiler() {
+        assert_eq!(
+            render_spoiler("a <b>b</b>\n<pre><code>c &lt; d</code></pre>"),
+            "<tg-spoiler>a <b>b</b>\nc &lt; d</tg-spoiler>"
+        );
+    }
+
+    #[test]
+    fn test_body_article() -> Result<()> {
+        let body = concat!(
+            "<h2>Notes</h2>\n<p>A <strong>bold</strong> &amp; <em>fine</em>&nbsp;day<br>",
+            "<img src=\"https://example.com/1.png\"></p>\n",
+            "<ul><li>one</li><li>two<ol><li>sub</li></ol></li></ul>",
+            "<blockquote><p>quoted</p></blockquote><hr><pre><code>a &lt; b</code></pre>"
+        );
+        let body_expected = concat!(
+            "<b>Notes</b>\n\n",
+            "A <b>bold</b> &amp; <i>fine</i>\u{a0}day\n\n",
+            "• one\n• two\n  1. sub\n\n",
+            "<blockquote>quoted</blockquote>\n\n",
+            "<pre><code>a &lt; b</code></pre>"
+        );
+        assert_eq!(clean_body(body)?, body_expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_split_text() {
+        assert_eq!(split_text("short", 10, 10), ["short"]);
+        let text = "line one\nline two\nline three";
+        assert_eq!(
+            split_text(text, 10, 20),
+            ["line one", "line two\nline three"]
+        );
+        // Tags open at the cut are closed and reopened
+        let text = "<b>aaaa bbbb cccc</b> dd";
+        assert_eq!(
+            split_text(text, 15, 15),
+            ["<b>aaaa</b>", "<b>bbbb</b>", "<b>cccc</b> dd"]
+        );
+        let text = r#"<a href="https://example.com">https://example.com</a> x"#;
+        let parts = split_text(text, 40, 40);
+        assert_eq!(parts.len(), 4);
+        for part in parts {
+            assert!(part.chars().count() <= 40, "{part}");
+            assert_eq!(part.matches("<a ").count(), part.matches("</a>").count());
+        }
+    }
+
+    #[test]
+    fn test_template() -> Result<()> {
+        let post = check_de!(Post, "post_text");
+        let labels = Labels::default();
+        let now = OffsetDateTime::now_utc();
+        let footer = Footer::default();
+        let author = render_attribution(&post).unwrap();
+        let content = render_post(&post, &labels)?;
+        let template =
+            Template::parse(r#"{author}\n\n{content}\n\n{footer}\n<a href="{url}">Source</a>"#)?;
+        assert_eq!(
+            template.render(&post, &footer, &labels, now)?,
+            format!(
+                "{author}\n\n{content}\n\n<a href=\"{}\">Source</a>",
+                post.url
+            )
+        );
+        let template = Template::parse("{content}")?;
+        assert_eq!(template.render(&post, &footer, &labels, now)?, content);
+        assert!(Template::parse("{title}").is_err());
+        assert!(Template::parse("{content").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shorten() {
+        let text = "line 1\nline 2\n".to_owned() + &"line 3 ".repeat(10);
+        assert_eq!(shorten(&text, 100, "u", "Read more"), text);
+        assert_eq!(
+            shorten(&text, 50, "u", "Read more"),
+            "line 1\nline 2\n\n<a href=\"u\">Read more</a>"
+        );
+        // Texts of a single long line are cut at a space
+        let text = "<b>word</b> ".repeat(20);
+        assert_eq!(
+            shorten(&text, 50, "u", "Read more"),
+            "<b>word</b>\n\n<a href=\"u\">Read more</a>"
+        );
+        // Or at a char if without spaces
+        let text = "a".repeat(60);
+        assert_eq!(
+            shorten(&text, 50, "u", "Read more"),
+            "a".repeat(23) + "\n\n<a href=\"u\">Read more</a>"
+        );
+    }
+
+    #[test]
+    fn test_to_plain() {
+        let html = concat!(
+            "<b>Title</b>\n",
+            r#"Hi <a href="https://a.example/@x">@x</a> <a href="https://a.example/tags/rust">#rust</a> "#,
+            r#"see <a href="https://b.example/long/path">b.example/long…</a> "#,
+            r#"and <a href="https://c.example/">this</a> &amp; &lt;that&gt;"#,
+        );
+        assert_eq!(
+            to_plain(html),
+            concat!(
+                "Title\n",
+                "Hi @x #rust see https://b.example/long/path and this (https://c.example/) & <that>",
+            )
+        );
+    }
+
+    #[test]
+    fn test_to_markdown() {
+        let html = concat!(
+            "<b>Title</b>\n",
+            "Hi *all* &amp; <i>you</i>, see <a href=\"https://a.example/x?a=1&amp;b=2\">this</a> ",
+            "and <a href=\"https://b.example\">https://b.example</a>\n",
+            "<blockquote>quoted\nlines</blockquote>\n",
+            "<pre>let a_b = 1;</pre> <code>x</code>",
+        );
+        assert_eq!(
+            to_markdown(html),
+            concat!(
+                "**Title**\n",
+                "Hi \\*all\\* & *you*, see [this](https://a.example/x?a=1&b=2) ",
+                "and https://b.example\n",
+                "> quoted\n> lines\n",
+                "```\nlet a_b = 1;\n``` `x`",
+            )
+        );
+    }
+}
@@ -0,0 +1,1719 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Runner of rounds.
+//! A round is a producer and a consumer connected by a bounded queue.
+
+use std::collections::HashSet;
+use std::env;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+
+#[cfg(feature = "telegram")]
+use anyhow::bail;
+use anyhow::{anyhow, ensure, Context, Result};
+use async_trait::async_trait;
+use reqwest::Url;
+use rusqlite::Connection;
+use tokio::signal;
+use tokio::sync::mpsc;
+use tokio::time::{self, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::as2::{Activity, Create, Delete, Page};
+use crate::auth::host_key;
+use crate::breaker::{round_hosts, Breakers};
+use crate::cadence::Cadence;
+use crate::capture::HttpCapture;
+use crate::cli::{Cli, CliInput, CliOutput};
+use crate::config::Hangup;
+#[cfg(feature = "bsky")]
+use crate::cons::BskyCon;
+use crate::cons::Con;
+#[cfg(feature = "discord")]
+use crate::cons::DiscordCon;
+use crate::cons::ErrorPolicy;
+#[cfg(feature = "hugo")]
+use crate::cons::HugoCon;
+#[cfg(feature = "mastodon")]
+use crate::cons::MastodonCon;
+#[cfg(feature = "ndjson")]
+use crate::cons::NdjsonCon;
+#[cfg(feature = "ntfy")]
+use crate::cons::NtfyCon;
+#[cfg(feature = "webhook")]
+use crate::cons::WebhookCon;
+#[cfg(feature = "xmpp")]
+use crate::cons::XmppCon;
+#[cfg(feature = "telegram")]
+use crate::cons::{FanOut, Filtered, TgCon};
+use crate::cursor::{retain_after, Cursor};
+use crate::db::{migration, AuditAction, AuditRecord, DbConn, State};
+use crate::exit::Exit;
+use crate::failure::FailureClass;
+use crate::filter::Filter;
+#[cfg(feature = "telegram")]
+use crate::filter::FilterConfig;
+use crate::hooks::Hooks;
+#[cfg(feature = "telegram")]
+use crate::labels::Labels;
+#[cfg(feature = "telegram")]
+use crate::media::MediaCache;
+use crate::metrics::{self, E2eTimer, METRICS};
+#[cfg(feature = "reencode")]
+use crate::photo::PhotoOptions;
+#[cfg(feature = "archive")]
+use crate::pro::ArchivePro;
+#[cfg(feature = "misskey")]
+use crate::pro::MisskeyPro;
+#[cfg(feature = "websub")]
+use crate::pro::PushPro;
+use crate::pro::{ApiPro, DirPro, Pro, UriPro, ACCESS_TOKEN_ENV};
+#[cfg(feature = "feeds")]
+use crate::pro::{AtomPro, RssPro};
+use crate::progress::Progress;
+#[cfg(feature = "telegram")]
+use crate::query::{query_featured, query_post, query_profile_url};
+use crate::query::{query_outbox_url, query_total_items};
+use crate::record::FixtureRecorder;
+#[cfg(feature = "telegram")]
+use crate::redact::redact;
+#[cfg(feature = "telegram")]
+use crate::render::{Footer, Template};
+#[cfg(feature = "transcode")]
+use crate::transcode::{Transcoder, UPLOAD_LIMIT};
+use crate::utils::Cancelled;
+#[cfg(feature = "websub")]
+use crate::websub::{Subscriber, WEBSUB_SECRET_ENV};
+
+/// Everything a run needs
+pub struct Ctx {
+    /// CLI at the start. Options reloaded from the config file are in [`Live`] instead.
+    pub cli: Cli,
+    pub db: DbConn,
+    live: RwLock<Arc<Live>>,
+    pub hooks: Hooks,
+    pub capture: Option<Arc<HttpCapture>>,
+    /// Posting cadence of the source for the adaptive loop interval
+    pub cadence: Mutex<Cadence>,
+    /// Cancelled on shutdown.
+    /// Rounds derive child tokens from it.
+    pub cancel: CancellationToken,
+    /// Subscriber of the feed for `--input websub`
+    #[cfg(feature = "websub")]
+    pub websub: Option<Arc<Subscriber>>,
+    /// Key of the state of the source, which is empty for the main one
+    pub key: String,
+    /// Contexts of the extra sources of `--source`, which share the database and the cancellation
+    pub sources: Vec<Ctx>,
+}
+
+impl Ctx {
+    /// Build the context from the cleaned CLI and the migrated database
+    pub fn new(cli: Cli, db: DbConn) -> Result<Self> {
+        let live = Live::new(&cli)?;
+        let hooks = Hooks::new(
+            cli.on_sent_webhook.clone(),
+            cli.on_error_webhook.clone(),
+            cli.heartbeat_url.clone(),
+        );
+        let capture = cli
+            .debug_http
+            .clone()
+            .map(|dir| Arc::new(HttpCapture::new(dir)));
+        let cadence = Cadence::new(
+            Duration::from_secs(cli.min_loop_interval),
+            Duration::from_secs(cli.max_loop_interval),
+        );
+        #[cfg(feature = "websub")]
+        let websub = match cli.input {
+            Some(CliInput::Websub) => Some(Arc::new(
+                Subscriber::new(
+                    cli.host.clone().unwrap(),
+                    cli.websub_callback.clone().unwrap(),
+                    cli.websub_lease,
+                )
+                .with_secret(env::var(WEBSUB_SECRET_ENV).ok()),
+            )),
+            _ => None,
+        };
+        let cancel = CancellationToken::new();
+        let sources = cli
+            .build_sources()?
+            .into_iter()
+            .map(|(key, cli)| {
+                let mut source = Ctx::new(cli, db.clone())?;
+                source.key = key;
+                source.cancel = cancel.clone();
+                Ok(source)
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            cli,
+            db,
+            live: RwLock::new(Arc::new(live)),
+            hooks,
+            capture,
+            cadence: Mutex::new(cadence),
+            cancel,
+            #[cfg(feature = "websub")]
+            websub,
+            key: String::new(),
+            sources,
+        })
+    }
+
+    /// Options currently in effect
+    pub fn live(&self) -> Arc<Live> {
+        self.live.read().unwrap().clone()
+    }
+
+    /// Take the reloadable options of the cleaned CLI.
+    /// The current ones are kept if the new ones are invalid or change the options needing a restart.
+    pub fn reload(&self, cli: &Cli) -> Result<()> {
+        let fixed = self.cli.fixed_changes(cli);
+        ensure!(
+            fixed.is_empty(),
+            "options {} can not be changed without restarting",
+            fixed.join(", ")
+        );
+        let live = Arc::new(Live::new(cli)?);
+        for source in self.sources.iter() {
+            *source.live.write().unwrap() = live.clone();
+        }
+        *self.live.write().unwrap() = live;
+        Ok(())
+    }
+}
+
+/// Options that can be reloaded from the config file without restarting.
+/// Consumers are built from them for each page, so the outputs and their destinations are reloaded too.
+pub struct Live {
+    /// CLI the options are from.
+    /// Only the options not listed by [`Cli::fixed_changes`] are read from it,
+    /// which are shared by the sources.
+    pub cli: Cli,
+    pub filter: Box<dyn Filter>,
+    #[cfg(feature = "telegram")]
+    pub footer: Footer,
+    #[cfg(feature = "telegram")]
+    pub labels: Labels,
+    /// Telegram channels with their filters
+    #[cfg(feature = "telegram")]
+    pub tg_chans: Vec<(String, Option<FilterConfig>)>,
+    /// Templates of the texts by the chats
+    #[cfg(feature = "telegram")]
+    pub tg_templates: Vec<(String, Template)>,
+    #[cfg(feature = "telegram")]
+    pub tg_topic_tags: Vec<(String, i32)>,
+}
+
+impl Live {
+    fn new(cli: &Cli) -> Result<Self> {
+        Ok(Self {
+            cli: cli.clone(),
+            filter: cli.build_filter()?,
+            #[cfg(feature = "telegram")]
+            footer: cli.build_footer()?,
+            #[cfg(feature = "telegram")]
+            labels: cli.build_labels()?,
+            #[cfg(feature = "telegram")]
+            tg_chans: cli.tg_chans()?,
+            #[cfg(feature = "telegram")]
+            tg_templates: cli.tg_templates()?,
+            #[cfg(feature = "telegram")]
+            tg_topic_tags: cli.tg_topic_tags()?,
+        })
+    }
+}
+
+/// Run the future while reloading the config file on SIGHUP
+async fn reloading<T>(ctx: &Ctx, hangup: &mut Hangup, fut: impl Future<Output = T>) -> T {
+    tokio::pin!(fut);
+    loop {
+        tokio::select! {
+            res = &mut fut => return res,
+            _ = hangup.recv() => {
+                let res = Cli::try_parse_with_config(env::args_os().collect()).and_then(|mut cli| {
+                    cli.clean()?;
+                    ctx.reload(&cli)
+                });
+                match res {
+                    Ok(()) => tracing::info!("Reloaded the config file"),
+                    Err(e) => tracing::error!("Failed to reload the config file and kept the current one: {e:#}"),
+                }
+            }
+        }
+    }
+}
+
+/// Run rounds until finished or shut down
+pub async fn run(ctx: &Ctx) -> Result<()> {
+    let cli = &ctx.cli;
+
+    // The main source first and then the extra ones in order
+    let pipelines: Vec<&Ctx> = std::iter::once(ctx).chain(ctx.sources.iter()).collect();
+    let mut states = Vec::with_capacity(pipelines.len());
+    for pipe in pipelines.iter() {
+        states.push(init_state(pipe).await?);
+    }
+
+    #[cfg(feature = "telegram")]
+    if cli.output.contains(&CliOutput::TgSend) {
+        for (con, _) in tg_cons(ctx, &ctx.live(), ctx.cancel.clone()) {
+            con.check_chat().await?;
+        }
+    }
+    // Mirrors saved before the outputs had their own ID maps are of the only output that could save them
+    if let Some(output) = cli.output.iter().find(|output| output.saves_id_map()) {
+        id_map_db(ctx, &ctx.live(), *output).adopt_unkeyed().await?;
+    }
+
+    if let Some(addr) = cli.metrics_addr.clone() {
+        let cancel = ctx.cancel.clone();
+        tokio::spawn(async move {
+            if let Err(e) = metrics::serve(&addr, cancel).await {
+                tracing::error!("Metrics endpoint stopped: {e}");
+            }
+        });
+    }
+
+    #[cfg(feature = "websub")]
+    if let Some(sub) = ctx.websub.clone() {
+        let addr = cli.websub_addr.clone();
+        let cancel = ctx.cancel.clone();
+        tokio::spawn(async move {
+            let serve = sub.clone().serve(&addr, cancel.clone());
+            let (res, ()) = tokio::join!(serve, sub.maintain(cancel.clone()));
+            if let Err(e) = res {
+                tracing::error!("WebSub callback stopped: {e}");
+            }
+        });
+    }
+
+    let shutdown = ctx.cancel.clone();
+    tokio::spawn(async move {
+        if signal::ctrl_c().await.is_ok() {
+            tracing::info!("Shutting down after the in-flight requests are aborted");
+            shutdown.cancel();
+        }
+    });
+
+    let mut hangup = Hangup::new(cli.config.is_some())?;
+    let mut breakers = cli.circuit_breaker.map(|threshold| {
+        Breakers::new(
+            threshold,
+            Duration::from_secs(cli.breaker_cooldown),
+            Duration::from_secs(cli.max_breaker_cooldown),
+        )
+    });
+    loop {
+        // Files dropped during the rounds are taken by the next one at once
+        let dropped = match ctx.cli.input {
+            Some(CliInput::Dir) => {
+                let dir = Path::new(ctx.cli.host.as_deref().unwrap());
+                DirPro::dropped(dir).await.unwrap_or_default()
+            }
+            _ => vec![],
+        };
+        let mut failed = None;
+        for (pipe, state) in pipelines.iter().zip(states.iter_mut()) {
+            // A failed source is not to stop the others
+            if let Err(e) = run_pipeline(ctx, pipe, state, &mut hangup, breakers.as_mut()).await {
+                if pipelines.len() == 1 {
+                    return Err(e);
+                }
+                tracing::error!(source = pipe.key, "Round failed: {e:#}");
+                failed.get_or_insert(e);
+            }
+            if ctx.cancel.is_cancelled() {
+                break;
+            }
+        }
+
+        if ctx.cancel.is_cancelled() {
+            break;
+        }
+        if let Some(interval) = ctx.live().cli.loop_interval {
+            let interval = next_interval(ctx, Duration::from_secs(interval));
+            let interval = match breakers.as_mut().and_then(Breakers::take_cooldown) {
+                Some(cooldown) => cooldown.max(interval),
+                None => interval,
+            };
+            // Rounds are also run once posts are pushed or dropped
+            let woken = async {
+                #[cfg(feature = "websub")]
+                if let Some(sub) = ctx.websub.as_ref() {
+                    return sub.pushed().await;
+                }
+                match ctx.cli.input {
+                    Some(CliInput::Dir) => {
+                        let dir = Path::new(ctx.cli.host.as_deref().unwrap());
+                        if let Err(e) = DirPro::watch(dir, &dropped).await {
+                            tracing::error!("Failed to watch the directory: {e}");
+                            std::future::pending::<()>().await
+                        }
+                    }
+                    _ => std::future::pending().await,
+                }
+            };
+            let sleep = async {
+                tokio::select! {
+                    _ = time::sleep(interval) => true,
+                    _ = woken => true,
+                    _ = ctx.cancel.cancelled() => false,
+                }
+            };
+            if !reloading(ctx, &mut hangup, sleep).await {
+                break;
+            }
+        } else {
+            // Exit with the first failure after all sources have run once
+            return failed.map_or(Ok(()), Err);
+        }
+    }
+    Ok(())
+}
+
+/// Interval to the next round, adapted to the posting cadence if enabled
+fn next_interval(ctx: &Ctx, base: Duration) -> Duration {
+    if !ctx.live().cli.adaptive_interval {
+        return base;
+    }
+    match ctx
+        .cadence
+        .lock()
+        .unwrap()
+        .interval(::time::OffsetDateTime::now_utc())
+    {
+        Some(interval) => {
+            tracing::debug!(
+                "Next round in {} seconds by the posting cadence",
+                interval.as_secs()
+            );
+            interval
+        }
+        None => base,
+    }
+}
+
+/// Init the state of the source from the CLI or the database
+async fn init_state(ctx: &Ctx) -> Result<State> {
+    if ctx.cli.min_id >= 0 {
+        return Ok(State::new(ctx.cli.min_id));
+    }
+    let state = ctx
+        .db
+        .load_state(ctx.key.clone())
+        .await?
+        .inspect(|s| {
+            tracing::debug!("Loaded state min_id {} from the database", s.min_id);
+        })
+        .unwrap_or_else(|| {
+            tracing::debug!("No state loaded from the database");
+            State::default()
+        });
+    Ok(state)
+}
+
+/// Run a round of the source and save its state.
+/// The config file is reloaded for the main context, which shares the options with the sources.
+async fn run_pipeline(
+    main: &Ctx,
+    ctx: &Ctx,
+    state: &mut State,
+    hangup: &mut Hangup,
+    mut breakers: Option<&mut Breakers>,
+) -> Result<()> {
+    let live = ctx.live();
+    let round_cancel = ctx.cancel.child_token();
+    let deadline = live.cli.round_timeout.map(|secs| {
+        let round_cancel = round_cancel.clone();
+        tokio::spawn(async move {
+            time::sleep(Duration::from_secs(secs)).await;
+            tracing::warn!("Round deadline of {secs} seconds reached");
+            round_cancel.cancel();
+        })
+    });
+    #[cfg(feature = "telegram")]
+    let metrics_before = METRICS.snapshot();
+    let res = reloading(main, hangup, run_round(ctx, state.clone(), &round_cancel)).await;
+    if let Some(deadline) = deadline {
+        deadline.abort();
+    }
+    #[cfg(feature = "telegram")]
+    if let Some(chat) = live.cli.summary_chat.as_ref() {
+        let mut text = (&METRICS.snapshot() - &metrics_before).summary_text();
+        if let Err(e) = res.as_ref() {
+            text += &format!("\nRound failed: {}", redact(&format!("{e:#}")));
+        }
+        let con = TgCon::new(chat, ctx.db.clone(), ctx.hooks.clone(), ctx.cancel.clone())
+            .with_capture(ctx.capture.clone());
+        if let Err(e) = con.send_notice(&text).await {
+            tracing::warn!("Failed to post the round summary: {e}");
+        }
+    }
+    ctx.hooks.ping(res.is_ok()).await;
+    if let Some(capture) = ctx.capture.as_ref() {
+        capture.stop();
+    }
+    let tolerated = match (res.as_ref(), breakers.as_mut()) {
+        (Ok(_), Some(breakers)) => {
+            breakers.on_success(&round_hosts(&ctx.cli));
+            false
+        }
+        // Left to the next round, or the cooling-off after it if the breaker opens
+        (Err(e), Some(breakers)) if ctx.live().cli.loop_interval.is_some() => {
+            breakers.on_failure(e, &round_hosts(&ctx.cli))
+        }
+        _ => false,
+    };
+    // Posts handled before a failure are saved by the round, so they are not sent again by the next one
+    if res.is_err() {
+        if let Some(saved) = ctx.db.load_state(ctx.key.clone()).await? {
+            *state = saved;
+        }
+    }
+    if !tolerated {
+        *state = res?;
+        ctx.db.save_state(ctx.key.clone(), state.clone()).await?;
+        #[cfg(feature = "telegram")]
+        if live.cli.output.contains(&CliOutput::TgSend) {
+            let cons: Vec<_> = tg_cons(ctx, &live, ctx.cancel.clone())
+                .into_iter()
+                .map(|(con, _)| con)
+                .collect();
+            for con in cons.iter() {
+                if let Err(e) = con.refresh_polls().await {
+                    tracing::warn!("Failed to refresh the ended polls: {e:#}");
+                }
+            }
+            // Pins of the channels are of the main source
+            if (live.cli.sync_pins && ctx.key.is_empty()) || live.cli.forward_featured {
+                if let Err(e) = sync_featured(ctx, &live, &cons).await {
+                    tracing::warn!("Failed to sync the pinned posts: {e:#}");
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fetch and send the posts newer than the state.
+/// Returns the state to be saved for the next round.
+#[tracing::instrument(name = "round", skip_all, fields(source = ctx.key, min_id = state.min_id))]
+pub async fn run_round(ctx: &Ctx, state: State, cancel: &CancellationToken) -> Result<State> {
+    tracing::debug!("Starts to run a round");
+    let metrics_before = METRICS.snapshot();
+
+    let min_id = state.min_id;
+    // Whether to fast forward to the latest post without sending.
+    // Use the mode to get the `min_id` that ignores all previous posts.
+    let ff_latest = min_id < 0;
+    let uri = match ctx.cli.input.as_ref() {
+        None | Some(CliInput::Stdin) => r"stdio://in".to_owned(),
+        Some(CliInput::Fetch) | Some(CliInput::QueryFetch) => {
+            let base_url = outbox_url(ctx).await.context(Exit::Source)?;
+            let min_id_query = if !ff_latest {
+                Some(("min_id", min_id.to_string()))
+            } else {
+                None
+            };
+            let max_id_query = ctx.cli.max_id.map(|id| ("max_id", id.to_string()));
+            let mut u = Url::parse(&base_url)?;
+            {
+                let mut q = u.query_pairs_mut();
+                if let Some((k, v)) = min_id_query {
+                    q.append_pair(k, &v);
+                }
+                if let Some((k, v)) = max_id_query {
+                    q.append_pair(k, &v);
+                }
+                q.append_pair("page", "true");
+            }
+            let url = u.to_string();
+            tracing::debug!("The page is at {url}");
+            url
+        }
+        // Producers of the other inputs are not of URIs
+        _ => String::new(),
+    };
+
+    let (tx, rx) = mpsc::channel(ctx.cli.queue_size.into());
+    let disk_queue = ctx.cli.disk_queue.then_some(&ctx.db);
+    let queued = match disk_queue {
+        Some(db) => db.query_queued_seqs().await?,
+        None => vec![],
+    };
+    // Chunks of a streamed page are all taken even without following the paging
+    let streamed = ctx.cli.stream_items.is_some() && uri == "stdio://in";
+    let mut pro = match ctx.cli.input {
+        Some(CliInput::Api) => {
+            let token = access_token(ctx)
+                .await?
+                .with_context(|| {
+                    format!("{ACCESS_TOKEN_ENV} or `mastotg auth` is required when input=api")
+                })
+                .context(Exit::Config)?;
+            let pro = ApiPro::new(
+                ctx.cli.host.clone().unwrap(),
+                ctx.cli.acct.clone().unwrap(),
+                token,
+                cancel.clone(),
+            )
+            .with_min_id((!ff_latest).then_some(min_id))
+            .with_max_id(ctx.cli.max_id);
+            RoundPro::Api(pro)
+        }
+        Some(CliInput::Timeline) => {
+            let pro = ApiPro::local_timeline(
+                ctx.cli.host.clone().unwrap(),
+                access_token(ctx).await?,
+                cancel.clone(),
+            )
+            .with_min_id((!ff_latest).then_some(min_id))
+            .with_max_id(ctx.cli.max_id);
+            RoundPro::Api(pro)
+        }
+        #[cfg(feature = "feeds")]
+        Some(CliInput::Rss) => {
+            let pro = RssPro::new(ctx.cli.host.clone().unwrap(), cancel.clone())
+                .with_min_id((!ff_latest).then_some(min_id));
+            RoundPro::Rss(pro)
+        }
+        #[cfg(feature = "feeds")]
+        Some(CliInput::Atom) => {
+            let pro = AtomPro::new(ctx.cli.host.clone().unwrap(), cancel.clone())
+                .with_min_id((!ff_latest).then_some(min_id));
+            RoundPro::Atom(pro)
+        }
+        #[cfg(feature = "websub")]
+        Some(CliInput::Websub) => {
+            let pro = PushPro::new(
+                ctx.cli.host.clone().unwrap(),
+                ctx.websub.clone().unwrap(),
+                cancel.clone(),
+            )
+            .with_min_id((!ff_latest).then_some(min_id));
+            RoundPro::Push(pro)
+        }
+        Some(CliInput::Dir) => {
+            let dir = PathBuf::from(ctx.cli.host.clone().unwrap());
+            let pro = DirPro::new(dir, cancel.clone()).with_min_id((!ff_latest).then_some(min_id));
+            RoundPro::Dir(pro)
+        }
+        #[cfg(feature = "archive")]
+        Some(CliInput::Archive) => {
+            let path = PathBuf::from(ctx.cli.host.clone().unwrap());
+            let pro = ArchivePro::new(path, cancel.clone())
+                .with_min_id((!ff_latest).then_some(min_id))
+                .with_latest_first(ff_latest);
+            RoundPro::Archive(pro)
+        }
+        #[cfg(feature = "misskey")]
+        Some(CliInput::Misskey) => {
+            // Queried from the start without a cursor
+            let since = match &state.cursor {
+                Some(cursor) => cursor.published,
+                None => Some(::time::OffsetDateTime::UNIX_EPOCH),
+            };
+            let pro = MisskeyPro::new(
+                ctx.cli.host.clone().unwrap(),
+                ctx.cli.acct.clone().unwrap(),
+                cancel.clone(),
+            )
+            .with_since(since.filter(|_| !ff_latest));
+            RoundPro::Misskey(pro)
+        }
+        _ => {
+            let pro = UriPro::new(
+                uri,
+                cancel.clone(),
+                ctx.cli.record_fixtures.clone().map(FixtureRecorder::new),
+            )
+            .with_capture(ctx.capture.clone())
+            .with_on_invalid(ctx.cli.on_invalid, ctx.db.clone())
+            .with_skip_unchanged(ctx.cli.skip_unchanged, ctx.db.clone())
+            .with_prefetch(match ctx.cli.no_follow_paging {
+                true => 0,
+                false => ctx.cli.prefetch_pages,
+            })
+            .with_stream_items(ctx.cli.stream_items.map(usize::from))
+            .with_cursor(state.cursor.clone());
+            RoundPro::Uri(pro)
+        }
+    };
+    let (ff_cursor, next) = tokio::try_join!(
+        async {
+            if !queued.is_empty() {
+                tracing::info!("Resuming {} pages left in the queue", queued.len());
+                return replay_queue(&ctx.db, queued, tx).await.map(|_| None);
+            }
+            let res = produce(
+                &mut pro,
+                ff_latest,
+                ctx.cli.no_follow_paging && !streamed,
+                disk_queue,
+                tx,
+            )
+            .await;
+            match res.as_ref() {
+                Err(e) => {
+                    FailureClass::of(e).record(&ctx.db).await?;
+                    // Resolved again in the next round in case the persisted URL is stale
+                    if let Some(key) = input_key(ctx) {
+                        ctx.db.delete_canonical_url(key).await?;
+                    }
+                }
+                Ok(_) => {
+                    if let RoundPro::Uri(pro) = &pro {
+                        if let Some(url) = pro.redirected() {
+                            save_redirected(ctx, url).await?;
+                        }
+                    }
+                }
+            }
+            res.context(Exit::Source)
+        },
+        async {
+            consume_queue(ctx, rx, state, cancel)
+                .await
+                .context(Exit::Dest)
+        },
+    )?;
+    // Pages of a cancelled round may be partially sent,
+    // and dropped files are kept when fast-forwarding as nothing is sent
+    match (cancel.is_cancelled(), &mut pro) {
+        (false, RoundPro::Uri(pro)) => pro.save_hashes().await?,
+        (false, RoundPro::Dir(pro)) if !ff_latest => pro.remove_taken().await?,
+        _ => (),
+    }
+    let next = match ff_cursor {
+        // Servers without integer IDs are then queried from the start and skipped by the cursor
+        Some(cursor) => State {
+            min_id: cursor.int_id().unwrap_or(0),
+            cursor: Some(cursor),
+        },
+        None => next,
+    };
+
+    tracing::info!("Finished running a round with min_id {}", next.min_id);
+    (&METRICS.snapshot() - &metrics_before).log_summary();
+    Ok(next)
+}
+
+/// Producer of the input
+enum RoundPro {
+    Uri(UriPro),
+    Api(ApiPro),
+    #[cfg(feature = "feeds")]
+    Rss(RssPro),
+    #[cfg(feature = "feeds")]
+    Atom(AtomPro),
+    #[cfg(feature = "misskey")]
+    Misskey(MisskeyPro),
+    #[cfg(feature = "websub")]
+    Push(PushPro),
+    Dir(DirPro),
+    #[cfg(feature = "archive")]
+    Archive(ArchivePro),
+}
+
+#[async_trait]
+impl Pro for RoundPro {
+    async fn fetch(&mut self) -> Result<Page> {
+        match self {
+            Self::Uri(pro) => pro.fetch().await,
+            Self::Api(pro) => pro.fetch().await,
+            #[cfg(feature = "feeds")]
+            Self::Rss(pro) => pro.fetch().await,
+            #[cfg(feature = "feeds")]
+            Self::Atom(pro) => pro.fetch().await,
+            #[cfg(feature = "misskey")]
+            Self::Misskey(pro) => pro.fetch().await,
+            #[cfg(feature = "websub")]
+            Self::Push(pro) => pro.fetch().await,
+            Self::Dir(pro) => pro.fetch().await,
+            #[cfg(feature = "archive")]
+            Self::Archive(pro) => pro.fetch().await,
+        }
+    }
+}
+
+/// Access token of the API-based inputs from `MASTODON_ACCESS_TOKEN`,
+/// or the one stored by `mastotg auth` for the host
+async fn access_token(ctx: &Ctx) -> Result<Option<String>> {
+    if let Ok(token) = env::var(ACCESS_TOKEN_ENV) {
+        return Ok(Some(token));
+    }
+    let host = host_key(ctx.cli.host.as_ref().unwrap());
+    let token = ctx.db.query_access_token(host).await?;
+    if let Some(token) = token.as_ref() {
+        crate::redact::register_secret(token.clone());
+    }
+    Ok(token)
+}
+
+/// Key of the input to persist its canonical outbox URL
+fn input_key(ctx: &Ctx) -> Option<String> {
+    match ctx.cli.input {
+        Some(CliInput::Fetch) => ctx.cli.host.clone(),
+        Some(CliInput::QueryFetch) => Some(format!("acct:{}", ctx.cli.acct.as_ref().unwrap())),
+        _ => None,
+    }
+}
+
+/// Outbox URL of the fetched input.
+/// Resolved URLs are persisted, so later rounds skip the WebFinger queries and the redirects.
+async fn outbox_url(ctx: &Ctx) -> Result<String> {
+    let key = input_key(ctx).unwrap();
+    if let Some(url) = ctx.db.query_canonical_url(key.clone()).await? {
+        return Ok(url);
+    }
+    let host = ctx.cli.host.as_ref().unwrap();
+    match ctx.cli.input {
+        Some(CliInput::QueryFetch) => {
+            let url = query_outbox_url(host, ctx.cli.acct.as_ref().unwrap()).await?;
+            ctx.db.save_canonical_url(key, url.clone()).await?;
+            Ok(url)
+        }
+        _ => Ok(host.to_owned()),
+    }
+}
+
+/// Persist the outbox URL the first page is redirected to, e.g., after instance domain migrations
+async fn save_redirected(ctx: &Ctx, page_url: &str) -> Result<()> {
+    let mut u = Url::parse(page_url)?;
+    u.set_query(None);
+    u.set_fragment(None);
+    let url = u.to_string();
+    if url == outbox_url(ctx).await? {
+        return Ok(());
+    }
+    tracing::info!("The outbox is redirected to {url}");
+    ctx.db
+        .save_canonical_url(input_key(ctx).unwrap(), url)
+        .await
+}
+
+/// Producer side of a round.
+/// Fetches pages and pushes them into the bounded queue.
+/// Waits when the queue is full, so a fast source can not run ahead of the consumer.
+/// Returns the cursor of the latest activity when fast forwarding.
+///
+/// Activities already fetched in the round are dropped,
+/// since posts arriving mid-round may shift them to the next page.
+///
+/// With the disk-backed queue, pages are kept in the database and only their references are queued.
+async fn produce(
+    pro: &mut impl Pro,
+    ff_latest: bool,
+    no_follow_paging: bool,
+    disk_queue: Option<&DbConn>,
+    tx: mpsc::Sender<(QueuedPage, Instant)>,
+) -> Result<Option<Cursor>> {
+    let mut seen = HashSet::new();
+    loop {
+        let res = pro.fetch().await;
+        let fetched_at = Instant::now();
+        let mut page = match res {
+            Err(e) if e.is::<Cancelled>() => {
+                tracing::warn!("Fetching cancelled");
+                break;
+            }
+            res => res?,
+        };
+        if page.ordered_items.is_empty() {
+            break;
+        }
+
+        if ff_latest {
+            let latest = Cursor::latest(&page.ordered_items).unwrap();
+            tracing::info!("Ignore from the latest activity {}", latest.id);
+            return Ok(Some(latest));
+        }
+
+        page.ordered_items.retain(|act| {
+            let new = seen.insert(act.id().to_owned());
+            if !new {
+                tracing::info!(post = %act.id(), "Skipped the duplicate fetched again");
+            }
+            new
+        });
+        let post_len = page.ordered_items.len();
+        if post_len > 0 {
+            tracing::info!("Fetched {post_len} posts from the page");
+            METRICS.inc_fetched(post_len as u64);
+            let page = match disk_queue {
+                Some(db) => QueuedPage::enqueue(db, page).await?,
+                None => QueuedPage::Mem(page),
+            };
+            if tx.send((page, fetched_at)).await.is_err() {
+                // The consumer has stopped and its error is reported by itself
+                break;
+            }
+        }
+
+        if no_follow_paging {
+            break;
+        }
+    }
+    Ok(None)
+}
+
+/// Queue the pages left in the database by the last interrupted round
+async fn replay_queue(
+    db: &DbConn,
+    seqs: Vec<i64>,
+    tx: mpsc::Sender<(QueuedPage, Instant)>,
+) -> Result<()> {
+    for seq in seqs {
+        let Some(page) = db.query_queued_page(seq).await? else {
+            continue;
+        };
+        let page: Page = serde_json::from_str(&page)?;
+        let bounds = page_bounds(&page);
+        if tx
+            .send((QueuedPage::Disk { seq, bounds }, Instant::now()))
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Page waiting to be sent
+enum QueuedPage {
+    Mem(Page),
+    /// Kept in the database, so large backfills are memory-bounded and resumable
+    Disk {
+        seq: i64,
+        /// Bounds of the page, so the page order is detected without loading
+        bounds: (Cursor, Cursor),
+    },
+}
+
+impl QueuedPage {
+    async fn enqueue(db: &DbConn, page: Page) -> Result<Self> {
+        let bounds = page_bounds(&page);
+        let seq = db.enqueue_page(serde_json::to_string(&page)?).await?;
+        Ok(Self::Disk { seq, bounds })
+    }
+
+    fn bounds(&self) -> (Cursor, Cursor) {
+        match self {
+            Self::Mem(page) => page_bounds(page),
+            Self::Disk { bounds, .. } => bounds.clone(),
+        }
+    }
+
+    /// Returns the page and its sequence number in the database if any
+    async fn load(self, db: &DbConn) -> Result<(Page, Option<i64>)> {
+        match self {
+            Self::Mem(page) => Ok((page, None)),
+            Self::Disk { seq, .. } => {
+                let page = db
+                    .query_queued_page(seq)
+                    .await?
+                    .ok_or(anyhow!("queued page {seq} not found"))?;
+                Ok((serde_json::from_str(&page)?, Some(seq)))
+            }
+        }
+    }
+}
+
+/// Consumer side of a round.
+/// Takes pages from the queue until the producer finishes.
+/// Returns the state after the last consumed page.
+/// A cancelled page is not counted, so its posts are retried in the next run.
+///
+/// Pages are sent from the oldest to the newest.
+/// Mastodon pages queried by `min_id` come oldest-first and are sent as they come,
+/// while servers paging from the newest are detected by the first two pages,
+/// and their pages are buffered until the round ends.
+async fn consume_queue(
+    ctx: &Ctx,
+    mut rx: mpsc::Receiver<(QueuedPage, Instant)>,
+    state: State,
+    cancel: &CancellationToken,
+) -> Result<State> {
+    let mut pages = PageConsumer {
+        ctx,
+        // Starting from the very first post means a backfill
+        progress: (state.min_id == 0 && state.cursor.is_none()).then(Progress::new),
+        next: state.clone(),
+        state,
+        cancel,
+    };
+    let mut buffered: Vec<(QueuedPage, Instant)> = Vec::new();
+    let mut newest_first = None;
+    while let Some(item) = rx.recv().await {
+        match newest_first {
+            None if buffered.is_empty() => {
+                buffered.push(item);
+                continue;
+            }
+            None => {
+                let (newest, _) = item.0.bounds();
+                let (_, prev_oldest) = buffered[0].0.bounds();
+                let reversed = !newest.is_after(&prev_oldest);
+                newest_first = Some(reversed);
+                if reversed {
+                    tracing::debug!("Pages come newest-first and are buffered");
+                    buffered.push(item);
+                    continue;
+                }
+                let first = buffered.pop().unwrap();
+                if !pages.take(first).await? || !pages.take(item).await? {
+                    break;
+                }
+            }
+            Some(true) => buffered.push(item),
+            Some(false) => {
+                if !pages.take(item).await? {
+                    break;
+                }
+            }
+        }
+    }
+    for item in buffered.into_iter().rev() {
+        if !pages.take(item).await? {
+            break;
+        }
+    }
+    Ok(pages.next)
+}
+
+/// Cursors of the newest and the oldest activities of the non-empty page
+fn page_bounds(page: &Page) -> (Cursor, Cursor) {
+    let items = &page.ordered_items;
+    (
+        Cursor::of(items.first().unwrap()),
+        Cursor::of(items.last().unwrap()),
+    )
+}
+
+/// Sends pages one by one and tracks the state
+struct PageConsumer<'a> {
+    ctx: &'a Ctx,
+    /// State at the start of the round
+    state: State,
+    /// State after the last consumed page
+    next: State,
+    progress: Option<Progress>,
+    cancel: &'a CancellationToken,
+}
+
+impl PageConsumer<'_> {
+    /// Returns false if cancelled
+    async fn take(&mut self, (page, fetched_at): (QueuedPage, Instant)) -> Result<bool> {
+        let ctx = self.ctx;
+        let (mut page, seq) = page.load(&ctx.db).await?;
+        {
+            let mut cadence = ctx.cadence.lock().unwrap();
+            page.ordered_items
+                .iter()
+                .filter_map(|item| match item {
+                    Activity::Create(act) => act.object.published_at(),
+                    _ => None,
+                })
+                .for_each(|published| cadence.observe(published));
+        }
+        let cursor = Cursor::latest(&page.ordered_items).unwrap();
+        let iid = cursor.int_id();
+        // Without integer IDs the server returns handled posts again,
+        // and queued pages may be partly sent before the interruption
+        let replayed = iid.is_none() || seq.is_some();
+        if let (true, Some(prev)) = (replayed, self.state.cursor.as_ref()) {
+            retain_after(&mut page.ordered_items, prev);
+        }
+        let post_len = page.ordered_items.len();
+        if let (Some(progress), Some(part_of)) = (self.progress.as_mut(), page.part_of.as_ref()) {
+            if !progress.has_total() {
+                match query_total_items(part_of).await {
+                    Ok(Some(total)) => progress.set_total(total),
+                    Ok(None) => (),
+                    Err(e) => tracing::warn!("Failed to query the total number of posts: {e}"),
+                }
+            }
+        }
+
+        let span = tracing::info_span!("page", first_id = %cursor.id, posts = post_len);
+        let timer = E2eTimer::new(fetched_at, ctx.cli.slow_post_secs);
+        // Posts sent before a cancellation are found by their audit records
+        let mark = ctx.db.audit_mark().await?;
+        let sendable: Vec<_> = page
+            .ordered_items
+            .iter()
+            .filter(|act| !matches!(act, Activity::Delete(_)))
+            .map(|act| {
+                (
+                    act.id().to_owned(),
+                    audit_id(act).to_owned(),
+                    Cursor::of(act),
+                )
+            })
+            .collect();
+        match consume(ctx, page, timer, self.cancel)
+            .instrument(span)
+            .await
+        {
+            Err(e) if e.is::<Cancelled>() => {
+                tracing::warn!("Sending cancelled");
+                if let Some(cursor) = handled_cursor(ctx, &sendable, mark).await? {
+                    tracing::info!("Keeping the posts sent before the cancellation");
+                    self.advance(cursor, seq.is_some()).await?;
+                }
+                return Ok(false);
+            }
+            Err(e) => {
+                // Saved now since the failed round returns no state
+                if let Some(cursor) = handled_cursor(ctx, &sendable, mark).await? {
+                    tracing::info!("Keeping the posts sent before the failure");
+                    self.advance(cursor, false).await?;
+                }
+                ctx.db
+                    .save_state(ctx.key.clone(), self.next.clone())
+                    .await?;
+                return Err(e);
+            }
+            Ok(()) => (),
+        }
+        self.advance(cursor, seq.is_some()).await?;
+        // Saved per page, so an interrupted round resumes from the next queued page
+        if let Some(seq) = seq {
+            ctx.db.delete_queued_page(seq).await?;
+        }
+        if let Some(progress) = self.progress.as_mut() {
+            progress.advance(post_len as u64);
+            progress.log();
+        }
+        Ok(true)
+    }
+
+    /// Move the state to the handled activity, and save it if the page is queued on the disk
+    async fn advance(&mut self, cursor: Cursor, queued: bool) -> Result<()> {
+        self.next = State {
+            min_id: cursor.int_id().unwrap_or(self.next.min_id),
+            cursor: Some(cursor),
+        };
+        if queued {
+            let ctx = self.ctx;
+            ctx.db
+                .save_state(ctx.key.clone(), self.next.clone())
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// GUID of the activity in the audit records, which is of the post for `Create`s
+fn audit_id(act: &Activity) -> &str {
+    match act {
+        Activity::Create(act) => &act.object.id,
+        act => act.id(),
+    }
+}
+
+/// Cursor of the newest activity of the page handled before sending it is cancelled or fails.
+/// Posts are sent from the oldest, so the handled ones are the oldest up to the first one not sent.
+/// Multiple outputs are tracked by their deliveries.
+/// Fanning out to multiple Telegram channels or Discord is not tracked, as the consumers send concurrently,
+/// so the page is sent again, where the consumers skip the posts in their ID maps.
+async fn handled_cursor(
+    ctx: &Ctx,
+    sendable: &[(String, String, Cursor)],
+    mark: i64,
+) -> Result<Option<Cursor>> {
+    let live = ctx.live();
+    let cli = &live.cli;
+    #[cfg(feature = "telegram")]
+    {
+        #[cfg(feature = "discord")]
+        let discord = cli.discord;
+        #[cfg(not(feature = "discord"))]
+        let discord = false;
+        if cli.output == [CliOutput::TgSend] && (live.tg_chans.len() > 1 || discord) {
+            return Ok(None);
+        }
+    }
+    let records = ctx.db.query_audit_since(mark).await?;
+    let skipped: HashSet<_> = records
+        .iter()
+        .filter(|record| record.action == AuditAction::Skipped)
+        .map(|record| record.id.as_str())
+        .collect();
+    // Failed posts are only passed if the consumers go on after failures
+    let passes_failed = cli.on_error != ErrorPolicy::Fail;
+    let audited: HashSet<_> = records
+        .iter()
+        .filter(|record| passes_failed || record.action != AuditAction::Failed)
+        .map(|record| record.id.as_str())
+        .collect();
+    let ids: Vec<_> = sendable.iter().map(|(id, _, _)| id.clone()).collect();
+    let mut delivered = Vec::new();
+    if cli.output.len() > 1 {
+        for output in cli.output.iter() {
+            delivered.push(ctx.db.query_delivered(output.name(), ids.clone()).await?);
+        }
+    }
+    // From the oldest like the consumers, stopping at the first post not sent,
+    // so the cursor never passes it even if newer ones are skipped by the filter
+    let handled = sendable
+        .iter()
+        .rev()
+        .take_while(|(id, audit_id, _)| {
+            skipped.contains(audit_id.as_str())
+                || match delivered.is_empty() {
+                    true => audited.contains(audit_id.as_str()),
+                    false => delivered.iter().all(|ids| ids.contains(id)),
+                }
+        })
+        .last();
+    Ok(handled.map(|(_, _, cursor)| cursor.clone()))
+}
+
+/// Telegram consumers of the channels configured by the CLI, with the filters of the channels if any.
+/// Mirrored msgs of each channel are saved to the ID map keyed by its chat.
+#[cfg(feature = "telegram")]
+fn tg_cons(
+    ctx: &Ctx,
+    live: &Live,
+    cancel: CancellationToken,
+) -> Vec<(TgCon, Option<FilterConfig>)> {
+    live.tg_chans
+        .iter()
+        .map(|(chan, filter)| {
+            let template = live
+                .tg_templates
+                .iter()
+                .find(|(chat, _)| chat == chan)
+                .map(|(_, template)| template.clone());
+            let db = ctx.db.with_chan(chan.clone());
+            let con = tg_con(ctx, live, chan, db, cancel.clone())
+                .with_template(template)
+                .with_topic(live.cli.tg_topic)
+                .with_topic_tags(live.tg_topic_tags.clone());
+            (con, filter.clone())
+        })
+        .collect()
+}
+
+/// Telegram consumer of the channel configured by the CLI
+#[cfg(feature = "telegram")]
+fn tg_con(ctx: &Ctx, live: &Live, chan: &str, db: DbConn, cancel: CancellationToken) -> TgCon {
+    let cli = &live.cli;
+    let con = TgCon::new(chan, db, ctx.hooks.clone(), cancel)
+        .with_capture(ctx.capture.clone())
+        .with_footer(live.footer.clone())
+        .with_labels(live.labels.clone())
+        .with_attribution(cli.attribution)
+        .with_threads(cli.conversation_threads)
+        .with_thread_labels(cli.thread_labels)
+        .with_cards(cli.preview_cards)
+        .with_placeholder(cli.blurhash_placeholder)
+        .with_reupload(cli.reupload_media.then_some(cli.download_concurrency))
+        .with_spool_dir(cli.spool_dir.clone())
+        .with_media_cache(cli.media_cache_dir.clone().map(|dir| MediaCache {
+            dir,
+            max_size: cli.media_cache_size * 1024 * 1024,
+        }))
+        .with_error_policy(cli.on_error);
+    #[cfg(feature = "transcode")]
+    let con = con.with_transcoder(cli.transcode_videos.then(|| Transcoder {
+        ffmpeg: cli.ffmpeg.clone(),
+        ffprobe: cli.ffprobe.clone(),
+        max_size: UPLOAD_LIMIT,
+    }));
+    #[cfg(feature = "reencode")]
+    let con = con.with_photo(cli.fit_photos.then_some(PhotoOptions {
+        strip_exif: cli.strip_exif,
+    }));
+    con
+}
+
+/// Connection with the ID map of the output, so outputs used together keep their mirrors apart.
+/// Telegram channels are keyed by their chats, where the first one stands for the output.
+fn id_map_db(
+    ctx: &Ctx,
+    #[cfg_attr(not(feature = "telegram"), allow(unused_variables))] live: &Live,
+    output: CliOutput,
+) -> DbConn {
+    match output {
+        #[cfg(feature = "telegram")]
+        CliOutput::TgSend => {
+            let chan = live.tg_chans.first().map(|(chan, _)| chan.clone());
+            ctx.db.with_chan(chan.unwrap_or_default())
+        }
+        output => ctx.db.with_chan(output.name()),
+    }
+}
+
+/// Mastodon consumer configured by the CLI
+#[cfg(feature = "mastodon")]
+async fn mastodon_con(ctx: &Ctx, live: &Live, cancel: CancellationToken) -> Result<MastodonCon> {
+    let host = live.cli.target_host.as_ref().unwrap();
+    let db = id_map_db(ctx, live, CliOutput::Mastodon);
+    let con = MastodonCon::from_env(host, db, ctx.hooks.clone(), cancel)
+        .await?
+        .with_max_chars(live.cli.target_max_chars)
+        .with_error_policy(live.cli.on_error);
+    #[cfg(feature = "telegram")]
+    let con = con
+        .with_footer(live.footer.clone())
+        .with_labels(live.labels.clone())
+        .with_attribution(live.cli.attribution);
+    Ok(con)
+}
+
+/// Bluesky consumer configured by the CLI
+#[cfg(feature = "bsky")]
+fn bsky_con(ctx: &Ctx, live: &Live, cancel: CancellationToken) -> Result<BskyCon> {
+    let con = BskyCon::from_env(
+        &live.cli.bsky_pds,
+        live.cli.bsky_handle.clone().unwrap(),
+        id_map_db(ctx, live, CliOutput::Bsky),
+        ctx.hooks.clone(),
+        cancel,
+    )?
+    .with_error_policy(live.cli.on_error);
+    #[cfg(feature = "telegram")]
+    let con = con
+        .with_footer(live.footer.clone())
+        .with_labels(live.labels.clone())
+        .with_attribution(live.cli.attribution);
+    Ok(con)
+}
+
+/// XMPP consumer configured by the CLI
+#[cfg(feature = "xmpp")]
+fn xmpp_con(ctx: &Ctx, live: &Live, cancel: CancellationToken) -> Result<XmppCon> {
+    let con = XmppCon::from_env(
+        live.cli.xmpp_jid.clone().unwrap(),
+        live.cli.xmpp_to.clone().unwrap(),
+        id_map_db(ctx, live, CliOutput::Xmpp),
+        ctx.hooks.clone(),
+        cancel,
+    )?
+    .with_room(live.cli.xmpp_room.then(|| live.cli.xmpp_nick.clone()))
+    .with_server(live.cli.xmpp_server.clone())
+    .with_error_policy(live.cli.on_error);
+    #[cfg(feature = "telegram")]
+    let con = con
+        .with_footer(live.footer.clone())
+        .with_labels(live.labels.clone())
+        .with_attribution(live.cli.attribution);
+    Ok(con)
+}
+
+/// Discord consumer configured by the CLI
+#[cfg(feature = "discord")]
+fn discord_con(ctx: &Ctx, live: &Live, cancel: CancellationToken) -> Result<DiscordCon> {
+    Ok(
+        DiscordCon::from_env(ctx.db.clone(), ctx.hooks.clone(), cancel)?
+            .with_footer(live.footer.clone())
+            .with_labels(live.labels.clone())
+            .with_attribution(live.cli.attribution)
+            .with_error_policy(live.cli.on_error),
+    )
+}
+
+/// Forward the pinned posts not mirrored yet if enabled,
+/// and then sync the pinned msgs of the channels with the `featured` collection of the account
+#[cfg(feature = "telegram")]
+async fn sync_featured(ctx: &Ctx, live: &Live, cons: &[TgCon]) -> Result<()> {
+    let featured = query_featured(&profile_url(ctx).await?).await?;
+    if live.cli.forward_featured {
+        forward_featured(ctx, live, &featured).await?;
+    }
+    if live.cli.sync_pins && ctx.key.is_empty() {
+        for con in cons {
+            con.sync_pins(featured.clone()).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Send the pinned posts not mirrored yet like a page of the outbox.
+/// The state is kept, since the posts are usually older than it.
+#[cfg(feature = "telegram")]
+async fn forward_featured(ctx: &Ctx, live: &Live, featured: &[String]) -> Result<()> {
+    let db = id_map_db(ctx, live, CliOutput::TgSend);
+    let mut items = Vec::new();
+    for id in featured {
+        if db.query_id_map(id.clone()).await?.is_some() {
+            continue;
+        }
+        match query_post(id).await {
+            Ok(post) => items.push(Activity::Create(Create {
+                id: format!("{id}/activity"),
+                object: post,
+            })),
+            Err(e) => tracing::warn!(post = %id, "Failed to query the pinned post: {e:#}"),
+        }
+    }
+    if items.is_empty() {
+        return Ok(());
+    }
+    tracing::info!("Forwarding {} pinned posts not mirrored yet", items.len());
+    items.sort_by_key(|item| std::cmp::Reverse(Cursor::of(item).published));
+    let mut page = Page::empty(format!("{}#featured", profile_url(ctx).await?));
+    page.ordered_items = items;
+    let mut pages = PageConsumer {
+        ctx,
+        state: State::new(0),
+        next: State::new(0),
+        progress: None,
+        cancel: &ctx.cancel,
+    };
+    pages.take((QueuedPage::Mem(page), Instant::now())).await?;
+    Ok(())
+}
+
+/// Actor profile URL of the account.
+/// For `--input fetch`, it is the outbox URL without the trailing `/outbox` like Mastodon.
+#[cfg(feature = "telegram")]
+async fn profile_url(ctx: &Ctx) -> Result<String> {
+    let host = ctx.cli.host.as_deref().unwrap_or_default();
+    match ctx.cli.input {
+        Some(CliInput::QueryFetch) | Some(CliInput::Api) => {
+            query_profile_url(host, ctx.cli.acct.as_ref().unwrap()).await
+        }
+        Some(CliInput::Fetch) => {
+            let mut u = Url::parse(host)?;
+            let path = u.path().trim_end_matches('/');
+            let actor_path = path
+                .strip_suffix("/outbox")
+                .ok_or(anyhow!("actor of the outbox {host} unknown"))?
+                .to_owned();
+            u.set_path(&actor_path);
+            u.set_query(None);
+            Ok(u.to_string())
+        }
+        _ => bail!("pinned posts can only be synced for fetched inputs"),
+    }
+}
+
+/// Apply pending migrations
+pub fn init_db(conn: &mut Connection) -> Result<()> {
+    let report = migration::migrations::runner().run(conn)?;
+    let migs = report.applied_migrations();
+    if !migs.is_empty() {
+        let s = migs
+            .iter()
+            .map(|m| format!("{m}"))
+            .collect::<Vec<_>>()
+            .join(", ");
+        tracing::info!("Applied migrations: {s}");
+    } else {
+        tracing::debug!("No migrations applied");
+    }
+    Ok(())
+}
+
+async fn consume(ctx: &Ctx, page: Page, timer: E2eTimer, cancel: &CancellationToken) -> Result<()> {
+    let live = ctx.live();
+    let mut items = Vec::new();
+    let mut removals = Vec::new();
+    for act in page.ordered_items {
+        let (id, reason) = match act {
+            Activity::Create(item) if live.filter.allow(&item.object).is_allowed() => {
+                items.push(item);
+                continue;
+            }
+            Activity::Delete(item) => {
+                removals.push(item);
+                continue;
+            }
+            Activity::Create(item) => (item.object.id, "the filter".to_owned()),
+            act => (act.id().to_owned(), format!("its type {}", act.type_name())),
+        };
+        METRICS.inc_skipped(1);
+        tracing::info!(post = %id, "Skipped the post by {reason}");
+        let record = AuditRecord::new(id, AuditAction::Skipped);
+        ctx.db.append_audit(record).await?;
+    }
+    if items.is_empty() && removals.is_empty() {
+        return Ok(());
+    }
+
+    let outputs = match live.cli.output.as_slice() {
+        [] => &[CliOutput::Print][..],
+        outputs => outputs,
+    };
+    // A single output needs no tracking, since the page is retried as a whole
+    if let [output] = outputs {
+        let dest = dest(ctx, &live, *output, timer, cancel).await?;
+        let res = deliver(ctx, &dest, items, removals).await;
+        dest.close().await;
+        return res;
+    }
+    let ids: Vec<String> = removals
+        .iter()
+        .map(|item| item.id.clone())
+        .chain(items.iter().map(|item| item.id.clone()))
+        .collect();
+    let mut first_err = None;
+    for output in outputs {
+        let name = output.name();
+        let delivered = ctx.db.query_delivered(name.clone(), ids.clone()).await?;
+        if delivered.len() == ids.len() {
+            tracing::info!("Skipped the page already delivered to output {name}");
+            continue;
+        }
+        let dest = match dest(ctx, &live, *output, timer, cancel).await {
+            Ok(dest) => dest,
+            Err(e) if e.is::<Cancelled>() => return Err(e),
+            Err(e) => {
+                tracing::error!("Failed to deliver the page to output {name}: {e:#}");
+                first_err.get_or_insert(e);
+                continue;
+            }
+        };
+        // Delivered one by one and recorded as each succeeds, so retries only deliver the rest.
+        // Posts are from the oldest like in the consumers.
+        let removals = removals
+            .iter()
+            .filter(|item| !delivered.contains(&item.id))
+            .map(|item| (item.id.clone(), vec![], vec![item.clone()]));
+        let items = items
+            .iter()
+            .rev()
+            .filter(|item| !delivered.contains(&item.id))
+            .map(|item| (item.id.clone(), vec![item.clone()], vec![]));
+        for (id, items, removals) in removals.chain(items) {
+            match deliver(ctx, &dest, items, removals).await {
+                Ok(()) => ctx.db.save_delivered(name.clone(), vec![id]).await?,
+                Err(e) if e.is::<Cancelled>() => return Err(e),
+                Err(e) => {
+                    tracing::error!("Failed to deliver the page to output {name}: {e:#}");
+                    first_err.get_or_insert(e);
+                    break;
+                }
+            }
+        }
+        dest.close().await;
+    }
+    match first_err {
+        // Retried in the next round only for the failed outputs
+        Some(e) => Err(e),
+        None => ctx.db.delete_delivered(ids).await,
+    }
+}
+
+/// Where an output delivers to, built once per page and shared by its posts
+enum Dest {
+    /// Printing to stdout, which needs the whole removals instead of their GUIDs
+    Print(E2eTimer),
+    // Only printing is left without output features
+    #[allow(dead_code)]
+    Con {
+        con: Arc<dyn Con + Send + Sync>,
+        /// Log the number of the sent posts
+        log: fn(usize),
+    },
+}
+
+impl Dest {
+    async fn close(&self) {
+        if let Dest::Con { con, .. } = self {
+            con.close().await;
+        }
+    }
+}
+
+/// Build the consumer of the output.
+/// Only printing is left without output features, which takes none of the args but the timer.
+#[allow(unused_variables)]
+async fn dest(
+    ctx: &Ctx,
+    live: &Live,
+    output: CliOutput,
+    timer: E2eTimer,
+    cancel: &CancellationToken,
+) -> Result<Dest> {
+    let dest = match output {
+        CliOutput::Print => Dest::Print(timer),
+        #[cfg(feature = "webhook")]
+        CliOutput::Webhook => {
+            let con = WebhookCon::new(
+                live.cli.webhook_url.clone().unwrap(),
+                ctx.db.clone(),
+                ctx.hooks.clone(),
+                cancel.clone(),
+            )
+            .with_timer(timer)
+            .with_error_policy(live.cli.on_error);
+            Dest::Con {
+                con: Arc::new(con),
+                log: |n| tracing::info!("Sent {n} posts to the webhook"),
+            }
+        }
+        #[cfg(feature = "ndjson")]
+        CliOutput::Ndjson => {
+            let con = NdjsonCon::new(
+                live.cli.ndjson_file.clone().unwrap(),
+                ctx.db.clone(),
+                ctx.hooks.clone(),
+            )
+            .with_timer(timer)
+            .with_rotation(
+                live.cli.ndjson_max_size.map(|size| size * 1024 * 1024),
+                live.cli.ndjson_keep,
+            );
+            Dest::Con {
+                con: Arc::new(con),
+                log: |n| tracing::info!("Wrote {n} posts to the NDJSON file"),
+            }
+        }
+        #[cfg(feature = "hugo")]
+        CliOutput::Hugo => {
+            let con = HugoCon::new(
+                live.cli.export_dir.clone().unwrap(),
+                ctx.db.clone(),
+                ctx.hooks.clone(),
+            )
+            .with_timer(timer);
+            Dest::Con {
+                con: Arc::new(con),
+                log: |n| tracing::info!("Exported {n} posts to the site"),
+            }
+        }
+        #[cfg(feature = "mastodon")]
+        CliOutput::Mastodon => {
+            let con = mastodon_con(ctx, live, cancel.clone())
+                .await?
+                .with_timer(timer);
+            Dest::Con {
+                con: Arc::new(con),
+                log: |n| tracing::info!("Sent {n} posts to the target account"),
+            }
+        }
+        #[cfg(feature = "bsky")]
+        CliOutput::Bsky => {
+            let con = bsky_con(ctx, live, cancel.clone())?.with_timer(timer);
+            Dest::Con {
+                con: Arc::new(con),
+                log: |n| tracing::info!("Sent {n} posts to the Bluesky account"),
+            }
+        }
+        #[cfg(feature = "xmpp")]
+        CliOutput::Xmpp => {
+            let con = xmpp_con(ctx, live, cancel.clone())?.with_timer(timer);
+            Dest::Con {
+                con: Arc::new(con),
+                log: |n| tracing::info!("Sent {n} posts to the XMPP chat"),
+            }
+        }
+        #[cfg(feature = "ntfy")]
+        CliOutput::Ntfy => {
+            let con = NtfyCon::new(
+                &live.cli.ntfy_server,
+                live.cli.ntfy_topic.clone().unwrap(),
+                ctx.db.clone(),
+                ctx.hooks.clone(),
+                cancel.clone(),
+            )
+            .with_timer(timer)
+            .with_error_policy(live.cli.on_error);
+            Dest::Con {
+                con: Arc::new(con),
+                log: |n| tracing::info!("Pushed {n} posts to the ntfy topic"),
+            }
+        }
+        #[cfg(feature = "telegram")]
+        CliOutput::TgSend => {
+            // Msg IDs are saved by the Telegram consumers to the ID maps of their channels
+            let mut cons: Vec<Arc<dyn Con + Send + Sync>> = Vec::new();
+            for (tg, filter) in tg_cons(ctx, live, cancel.clone()) {
+                let tg = tg.with_timer(timer);
+                cons.push(match filter {
+                    Some(filter) => Arc::new(Filtered::new(tg, filter.build())),
+                    None => Arc::new(tg),
+                });
+            }
+            #[cfg(feature = "discord")]
+            if live.cli.discord {
+                cons.push(Arc::new(
+                    discord_con(ctx, live, cancel.clone())?.with_timer(timer),
+                ));
+            }
+            let con = match cons.len() {
+                1 => cons.pop().unwrap(),
+                _ => Arc::new(FanOut::new(cons)),
+            };
+            Dest::Con {
+                con,
+                log: |n| tracing::info!("Sent {n} posts to the Telegram channels"),
+            }
+        }
+    };
+    Ok(dest)
+}
+
+/// Deliver the posts and the removals to the destination
+async fn deliver(ctx: &Ctx, dest: &Dest, items: Vec<Create>, removals: Vec<Delete>) -> Result<()> {
+    match dest {
+        Dest::Print(timer) => print(ctx, items, removals, *timer).await,
+        Dest::Con { con, log } => {
+            let post_len = send_to(con.as_ref(), items, removals).await?;
+            if post_len > 0 {
+                log(post_len);
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Send the removals and then the posts with the consumer.
+/// Returns the number of the posts.
+async fn send_to(
+    con: &(dyn Con + Send + Sync),
+    items: Vec<Create>,
+    removals: Vec<Delete>,
+) -> Result<usize> {
+    if !removals.is_empty() {
+        let ids = removals
+            .into_iter()
+            .map(|item| item.object.id().to_owned())
+            .collect();
+        con.remove(ids).await?;
+    }
+    let post_len = items.len();
+    if post_len > 0 {
+        // The consumer saves the sent to the database by itself
+        con.send(items).await?;
+    }
+    Ok(post_len)
+}
+
+/// Print the removals and the posts as activities to stdout
+async fn print(
+    ctx: &Ctx,
+    items: Vec<Create>,
+    removals: Vec<Delete>,
+    timer: E2eTimer,
+) -> Result<()> {
+    for item in removals {
+        println!("{}", serde_json::to_string_pretty(&Activity::Delete(item))?);
+    }
+    // Pages list the newest first, so they are reversed like in the Telegram consumer
+    for item in items.into_iter().rev() {
+        let id = item.object.id.clone();
+        let start = Instant::now();
+        println!("{}", serde_json::to_string_pretty(&Activity::Create(item))?);
+        let duration_ms = start.elapsed().as_millis() as u64;
+        METRICS.observe_sent(duration_ms);
+        timer.observe(&id);
+        let mut record = AuditRecord::new(id, AuditAction::Printed);
+        record.dest = Some("stdout".to_owned());
+        record.duration_ms = Some(duration_ms);
+        ctx.db.append_audit(record.clone()).await?;
+        ctx.hooks.fire(&record).await;
+    }
+    Ok(())
+}
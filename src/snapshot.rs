@@ -0,0 +1,154 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Snapshots of rendered posts.
+//! A directory of captured posts, e.g., the fixtures saved by `--record-fixtures`,
+//! is rendered as the message texts, to preview the formatting or to guard it in tests.
+//!
+//! `<name>.txt` is the rendering of the post `<name>.json`.
+
+use std::fs;
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use time::OffsetDateTime;
+
+use crate::as2::{Activity, CheckType, Post};
+use crate::labels::Labels;
+use crate::render::{render_message, Footer, Template};
+
+/// Options of the rendering
+#[derive(Default)]
+pub struct Snapshot {
+    pub attribution: bool,
+    pub footer: Footer,
+    pub labels: Labels,
+    /// Template replacing the default layout
+    pub template: Option<Template>,
+}
+
+impl Snapshot {
+    /// Render the posts in the directory, given as posts or their `Create` or `Update` activities.
+    /// Other JSON files, e.g., pages, are skipped.
+    /// Returns the names and the texts sorted by the names.
+    pub fn render_dir(&self, dir: &Path) -> Result<Vec<(String, String)>> {
+        let mut outputs = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let name = file_stem(&path)?;
+            let body = fs::read(&path)?;
+            let Some(post) = post_of(&body) else {
+                tracing::debug!("Skipped {name} without a post");
+                continue;
+            };
+            let now = OffsetDateTime::now_utc();
+            let text = match self.template.as_ref() {
+                Some(template) => template.render(&post, &self.footer, &self.labels, now),
+                None => render_message(&post, self.attribution, &self.footer, &self.labels, now),
+            }
+            .with_context(|| format!("failed to render {name}"))?;
+            outputs.push((name, text));
+        }
+        outputs.sort();
+        Ok(outputs)
+    }
+}
+
+fn post_of(body: &[u8]) -> Option<Post> {
+    if let Ok(post) = serde_json::from_slice::<Post>(body) {
+        if post.check_type().is_ok() {
+            return Some(post);
+        }
+    }
+    match serde_json::from_slice(body).ok()? {
+        Activity::Create(act) => Some(act.object),
+        Activity::Update(act) => Some(act.object),
+        _ => None,
+    }
+}
+
+fn file_stem(path: &Path) -> Result<String> {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .map(str::to_owned)
+        .ok_or(anyhow!("invalid file name {}", path.display()))
+}
+
+/// Write the texts to the directory as `<name>.txt`
+pub fn write_dir(dir: &Path, outputs: &[(String, String)]) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    for (name, text) in outputs {
+        fs::write(dir.join(format!("{name}.txt")), format!("{text}\n"))?;
+    }
+    Ok(())
+}
+
+/// Compare the texts with the `<name>.txt` in the directory.
+/// Only the names with the files are compared, so the directory can keep a subset as the cases.
+/// Returns the diffs of the mismatched ones.
+pub fn check_dir(dir: &Path, outputs: &[(String, String)]) -> Result<Vec<String>> {
+    let mut cases = fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.path()))
+        .filter(|path| {
+            path.as_ref().map_or(true, |path| {
+                path.extension().is_some_and(|ext| ext == "txt")
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    cases.sort();
+    let mut diffs = Vec::new();
+    for path in cases {
+        let name = file_stem(&path)?;
+        let expected = fs::read_to_string(&path)?;
+        let expected = expected.strip_suffix('\n').unwrap_or(&expected);
+        match outputs.iter().find(|(output, _)| *output == name) {
+            Some((_, actual)) if actual == expected => (),
+            Some((_, actual)) => diffs.push(format!("{name}:\n{}", diff_lines(expected, actual))),
+            None => diffs.push(format!("{name}: no post rendered")),
+        }
+    }
+    Ok(diffs)
+}
+
+/// Line-level diff with `-` for the expected and `+` for the actual
+pub fn diff_lines(expected: &str, actual: &str) -> String {
+    let expected: Vec<_> = expected.lines().collect();
+    let actual: Vec<_> = actual.lines().collect();
+    let mut out = String::new();
+    for i in 0..expected.len().max(actual.len()) {
+        match (expected.get(i), actual.get(i)) {
+            (Some(e), Some(a)) if e == a => out += &format!("  {e}\n"),
+            (e, a) => {
+                if let Some(e) = e {
+                    out += &format!("- {e}\n");
+                }
+                if let Some(a) = a {
+                    out += &format!("+ {a}\n");
+                }
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_golden() -> Result<()> {
+        let fixture_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let golden_dir = fixture_dir.join("golden");
+        let outputs = Snapshot::default().render_dir(&fixture_dir)?;
+        assert!(
+            fs::read_dir(&golden_dir)?.next().is_some(),
+            "no golden cases found"
+        );
+        let diffs = check_dir(&golden_dir, &outputs)?;
+        assert!(diffs.is_empty(), "\n{}", diffs.join("\n"));
+        Ok(())
+    }
+}
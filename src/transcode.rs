@@ -0,0 +1,192 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! Transcoding of videos Telegram can not preview, e.g., WebM/VP9, to H.264 MP4.
+//! Done by the external `ffmpeg` and `ffprobe` binaries on the re-uploaded media.
+
+use std::path::Path;
+use std::process::Stdio;
+
+use anyhow::{anyhow, ensure, Result};
+use tokio::fs;
+use tokio::process::Command;
+
+use crate::media::SpoolFile;
+
+/// Max size of files uploaded by bots. Unit: Bytes.
+pub const UPLOAD_LIMIT: u64 = 50 * 1024 * 1024;
+
+/// Bitrate of the transcoded audio. Unit: Kbps.
+const AUDIO_KBPS: u64 = 128;
+
+#[derive(Debug, Clone)]
+pub struct Transcoder {
+    pub ffmpeg: String,
+    pub ffprobe: String,
+    /// Max size of the transcoded video. Unit: Bytes.
+    pub max_size: u64,
+}
+
+/// What `ffprobe` tells about the video
+#[derive(Debug, Default, PartialEq)]
+struct Probe {
+    codec: String,
+    container: String,
+    /// Unit: Seconds
+    duration: Option<f64>,
+}
+
+impl Probe {
+    fn parse(output: &str) -> Self {
+        let mut probe = Self::default();
+        for line in output.lines() {
+            match line.split_once('=') {
+                Some(("codec_name", v)) => probe.codec = v.to_owned(),
+                Some(("format_name", v)) => probe.container = v.to_owned(),
+                Some(("duration", v)) => probe.duration = v.parse().ok(),
+                _ => (),
+            }
+        }
+        probe
+    }
+
+    /// Whether Telegram can preview the video
+    fn is_compatible(&self) -> bool {
+        self.codec == "h264" && self.container.split(',').any(|f| f == "mp4")
+    }
+}
+
+impl Transcoder {
+    /// Transcode the video if Telegram can not preview it.
+    /// Returns `None` if the video is already compatible.
+    /// The transcoded video has the `.mp4` extension.
+    pub async fn transcode(
+        &self,
+        video: &SpoolFile,
+        spool_dir: &Path,
+    ) -> Result<Option<SpoolFile>> {
+        let probe = self.probe(video.path()).await?;
+        if probe.is_compatible() {
+            return Ok(None);
+        }
+        tracing::debug!(
+            "Transcoding the video of codec {} in {}",
+            probe.codec,
+            probe.container
+        );
+        let out = SpoolFile::with_ext(spool_dir, "mp4");
+        self.ffmpeg(video.path(), out.path(), None).await?;
+        if fs::metadata(out.path()).await?.len() <= self.max_size {
+            return Ok(Some(out));
+        }
+        // Too large with the default quality, so limit the bitrate by the duration
+        let duration = probe
+            .duration
+            .filter(|d| *d > 0.0)
+            .ok_or(anyhow!("transcoded video too large with no known duration"))?;
+        // Leave 5% for the container overhead
+        let total_kbps = (self.max_size as f64 * 8.0 * 0.95 / 1000.0 / duration) as u64;
+        ensure!(
+            total_kbps > AUDIO_KBPS * 2,
+            "video too long to be transcoded under the size limit"
+        );
+        self.ffmpeg(video.path(), out.path(), Some(total_kbps - AUDIO_KBPS))
+            .await?;
+        let size = fs::metadata(out.path()).await?.len();
+        ensure!(
+            size <= self.max_size,
+            "transcoded video of {size} bytes still too large"
+        );
+        Ok(Some(out))
+    }
+
+    async fn probe(&self, path: &Path) -> Result<Probe> {
+        let output = run(Command::new(&self.ffprobe)
+            .args([
+                "-v",
+                "error",
+                "-select_streams",
+                "v:0",
+                "-show_entries",
+                "stream=codec_name:format=format_name,duration",
+                "-of",
+                "default=noprint_wrappers=1",
+            ])
+            .arg(path))
+        .await?;
+        Ok(Probe::parse(&output))
+    }
+
+    async fn ffmpeg(&self, input: &Path, output: &Path, video_kbps: Option<u64>) -> Result<()> {
+        let mut cmd = Command::new(&self.ffmpeg);
+        cmd.args(["-y", "-hide_banner", "-loglevel", "error", "-i"])
+            .arg(input)
+            .args([
+                "-map", "0:v:0", "-map", "0:a:0?", "-c:v", "libx264", "-preset", "veryfast",
+            ]);
+        match video_kbps {
+            Some(kbps) => cmd.args([
+                "-b:v".to_owned(),
+                format!("{kbps}k"),
+                "-maxrate".to_owned(),
+                format!("{kbps}k"),
+                "-bufsize".to_owned(),
+                format!("{}k", kbps * 2),
+            ]),
+            None => cmd.args(["-crf", "23"]),
+        };
+        cmd.args([
+            // Players fail with other pixel formats and odd sizes
+            "-pix_fmt",
+            "yuv420p",
+            "-vf",
+            "scale=trunc(iw/2)*2:trunc(ih/2)*2",
+            "-c:a",
+            "aac",
+            "-b:a",
+            &format!("{AUDIO_KBPS}k"),
+            "-movflags",
+            "+faststart",
+            "-f",
+            "mp4",
+        ])
+        .arg(output);
+        run(&mut cmd).await?;
+        Ok(())
+    }
+}
+
+/// Run the command and return its stdout
+async fn run(cmd: &mut Command) -> Result<String> {
+    let output = cmd
+        .stdin(Stdio::null())
+        .kill_on_drop(true)
+        .output()
+        .await
+        .map_err(|e| anyhow!("failed to run {:?}: {e}", cmd.as_std().get_program()))?;
+    ensure!(
+        output.status.success(),
+        "{:?} failed with {}: {}",
+        cmd.as_std().get_program(),
+        output.status,
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_probe() {
+        let probe = Probe::parse("codec_name=vp9\nformat_name=matroska,webm\nduration=12.5\n");
+        assert_eq!(probe.codec, "vp9");
+        assert_eq!(probe.duration, Some(12.5));
+        assert!(!probe.is_compatible());
+        let probe =
+            Probe::parse("codec_name=h264\nformat_name=mov,mp4,m4a,3gp,3g2,mj2\nduration=N/A\n");
+        assert_eq!(probe.duration, None);
+        assert!(probe.is_compatible());
+    }
+}
@@ -3,27 +3,110 @@
 
 //! Helpers of which you do not need to check the code to know the meaning
 
+use std::fmt;
+use std::fs::{File, OpenOptions, TryLockError};
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
 use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
 use regex::Regex;
 use reqwest::Response;
+use sha2::Sha256;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+use tokio_util::sync::CancellationToken;
+
+use crate::redact::redact;
 
 /// Check if the response is a success
 pub async fn check_res(res: Response) -> Result<Response> {
     if res.status().is_success() {
         Ok(res)
     } else {
-        let url = res.url().as_str().to_owned();
-        Err(anyhow!(
-            "request to {url} failed with status code {} and body {}",
-            res.status(),
-            res.text().await?,
-        ))
+        let url = redact(res.url().as_str());
+        let status = res.status().as_u16();
+        let body = redact(&res.text().await?);
+        Err(HttpStatusError { url, status, body }.into())
+    }
+}
+
+/// Error of a non-success HTTP response.
+/// The URL and the body are redacted.
+#[derive(Debug)]
+pub struct HttpStatusError {
+    pub url: String,
+    pub status: u16,
+    pub body: String,
+}
+
+impl fmt::Display for HttpStatusError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "request to {} failed with status code {} and body {}",
+            self.url, self.status, self.body
+        )
+    }
+}
+
+impl std::error::Error for HttpStatusError {}
+
+/// Times to retry a request when rate limited before failing with the response
+pub const RATE_LIMIT_RETRIES: usize = 5;
+
+/// Wait when rate limited without a reset time, and the max wait with one
+const RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(300);
+
+/// Wait before retrying a rate limited request, until the reset time if given
+pub fn rate_limit_wait(reset: Option<OffsetDateTime>) -> Duration {
+    match reset {
+        Some(reset) => {
+            let secs = (reset - OffsetDateTime::now_utc()).as_seconds_f64();
+            Duration::from_secs_f64(secs.max(1.0)).min(MAX_RATE_LIMIT_WAIT)
+        }
+        None => RATE_LIMIT_WAIT,
+    }
+}
+
+/// Signature of the body in the header value form, e.g., `sha256=5d5b...`
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.update(body);
+    let hex: String = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect();
+    format!("sha256={hex}")
+}
+
+/// URL of the instance given with or without the scheme, which defaults to HTTPS
+pub fn with_scheme(host: &str) -> String {
+    if !host.starts_with("https://") && !host.starts_with("http://") {
+        format!("https://{host}")
+    } else {
+        host.to_owned()
     }
 }
 
-/// Extract the integer ID from the activity/note GUID
+/// 64-bit FNV-1a hash, which is stable across builds unlike the std hasher
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, &b| {
+        (hash ^ b as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Extract the integer ID from the activity/note GUID.
+/// Fragments like `#updates/<timestamp>` of edits are ignored.
 pub fn int_id(guid: &str) -> Result<i64> {
-    let m = Regex::new(r"/(\d+?)(?:/activity)?$")
+    let m = Regex::new(r"/(\d+?)(?:/activity)?(?:#.*)?$")
         .unwrap()
         .captures(guid)
         .ok_or(anyhow!("no integer id in the activity guid"))?;
@@ -31,6 +114,76 @@ pub fn int_id(guid: &str) -> Result<i64> {
     Ok(int)
 }
 
+/// Parse the RFC3339 time of AS2 objects like `2023-08-03T16:09:19Z`
+pub fn parse_time(s: &str) -> Option<OffsetDateTime> {
+    OffsetDateTime::parse(s, &Rfc3339).ok()
+}
+
+/// Guess the MIME media type of common media files from the extension of the URL
+pub fn media_type_of_url(url: &str) -> Option<&'static str> {
+    let path = url.split(['?', '#']).next()?;
+    let (_, ext) = path.rsplit_once('.')?;
+    Some(match ext.to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "mp4" | "m4v" => "video/mp4",
+        "webm" => "video/webm",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        "m4a" => "audio/mp4",
+        "ogg" | "oga" | "opus" => "audio/ogg",
+        "wav" => "audio/wav",
+        "flac" => "audio/flac",
+        _ => return None,
+    })
+}
+
+/// Error returned when an await is aborted by a [`CancellationToken`]
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Await the future unless the token is cancelled first
+pub async fn cancellable<T>(
+    cancel: &CancellationToken,
+    fut: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    tokio::select! {
+        res = fut => res,
+        _ = cancel.cancelled() => Err(Cancelled.into()),
+    }
+}
+
+/// Take the exclusive lock of the file, which is created if missing.
+/// Returns `None` if another process holds it.
+/// The lock is released when the returned file is closed, even if the process is killed.
+pub fn try_lock_file(path: &Path) -> Result<Option<File>> {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+    match file.try_lock() {
+        Ok(()) => (),
+        Err(TryLockError::WouldBlock) => return Ok(None),
+        Err(TryLockError::Error(e)) => return Err(e.into()),
+    }
+    // For admins to find the holder
+    file.set_len(0)?;
+    writeln!(file, "{}", process::id())?;
+    Ok(Some(file))
+}
+
 /// De a test fixture
 #[cfg(test)]
 #[macro_export]
@@ -44,3 +197,57 @@ macro_rules! check_de {
         res
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use super::*;
+
+    #[test]
+    fn test_try_lock_file() -> Result<()> {
+        let path = env::temp_dir().join(format!("mastotg-test-{}.lock", process::id()));
+        let lock = try_lock_file(&path)?;
+        assert!(lock.is_some());
+        assert!(try_lock_file(&path)?.is_none());
+        drop(lock);
+        assert!(try_lock_file(&path)?.is_some());
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_scheme() {
+        assert_eq!(
+            with_scheme("social.example.com"),
+            "https://social.example.com"
+        );
+        assert_eq!(
+            with_scheme("http://localhost:3000"),
+            "http://localhost:3000"
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_wait() {
+        let now = OffsetDateTime::now_utc();
+        assert_eq!(rate_limit_wait(None), RATE_LIMIT_WAIT);
+        assert_eq!(
+            rate_limit_wait(Some(now - time::Duration::hours(1))),
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            rate_limit_wait(Some(now + time::Duration::hours(1))),
+            MAX_RATE_LIMIT_WAIT
+        );
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+}
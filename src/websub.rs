@@ -0,0 +1,480 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! WebSub subscriber.
+//! The hub of the feed pushes new entries to the callback, which wakes up the loop for a round,
+//! as a lighter alternative to polling the feed frequently.
+//!
+//! Pushes are signed by the hub with the secret sent on subscribing, and unsigned ones are rejected.
+//! The callback URL should still have a secret path, e.g., `https://example.com/websub/<random>`,
+//! so the verification of the subscription can not be forged.
+
+use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use anyhow::{anyhow, bail, ensure, Result};
+use hmac::digest::KeyInit;
+use hmac::{Hmac, Mac};
+use quick_xml::events::Event;
+use quick_xml::reader::Reader;
+use reqwest::Url;
+use sha2::{Sha256, Sha512};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+
+use crate::http;
+use crate::utils::check_res;
+
+/// Env var of the secret for the hub to sign the pushes with
+pub const WEBSUB_SECRET_ENV: &str = "WEBSUB_SECRET";
+
+/// Max size of the pushed bodies
+const MAX_BODY: usize = 4 << 20;
+/// Max size of the request heads
+const MAX_HEAD: usize = 16 << 10;
+/// Time to read a request, so idle connections are not kept
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Subscriber {
+    feed_url: String,
+    /// Public URL of the callback
+    callback: String,
+    /// Secret the hub signs the pushes with
+    secret: String,
+    /// Hub and topic discovered from the feed
+    hub_topic: OnceLock<(String, String)>,
+    /// Lease requested, and then the one confirmed by the hub. Unit: Seconds.
+    lease: AtomicU64,
+    /// Bodies pushed since the last round
+    pushed: Mutex<Vec<String>>,
+    notify: Notify,
+}
+
+/// Request to the callback
+pub struct Request {
+    pub method: String,
+    /// Path and query
+    pub target: String,
+    /// `X-Hub-Signature` of the body
+    pub signature: Option<String>,
+    pub body: String,
+}
+
+impl Subscriber {
+    pub fn new(feed_url: String, callback: String, lease: u64) -> Self {
+        Self {
+            feed_url,
+            callback,
+            secret: random_secret(),
+            hub_topic: OnceLock::new(),
+            lease: AtomicU64::new(lease),
+            pushed: Mutex::new(vec![]),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Secret for the hub to sign the pushes with, which is random if not given.
+    /// A fixed one keeps the pushes valid across restarts before the subscription is renewed.
+    pub fn with_secret(mut self, secret: Option<String>) -> Self {
+        if let Some(secret) = secret {
+            self.secret = secret;
+        }
+        self
+    }
+
+    /// Take the bodies pushed since the last call
+    pub fn take_pushed(&self) -> Vec<String> {
+        mem::take(&mut self.pushed.lock().unwrap())
+    }
+
+    /// Wait until a body is pushed.
+    /// Returns at once if one is pushed since the last wait.
+    pub async fn pushed(&self) {
+        self.notify.notified().await
+    }
+
+    /// Discover the hub and the topic from the `Link` headers or the links of the feed
+    async fn discover(&self) -> Result<&(String, String)> {
+        if let Some(hub_topic) = self.hub_topic.get() {
+            return Ok(hub_topic);
+        }
+        let res = http::client().get(&self.feed_url).send().await?;
+        let res = check_res(res).await?;
+        let mut links: Vec<_> = res
+            .headers()
+            .get_all("link")
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(parse_link_header)
+            .collect();
+        links.extend(feed_links(&res.text().await?)?);
+        let find = |rel: &str| {
+            links
+                .iter()
+                .find(|(r, _)| r == rel)
+                .map(|(_, href)| href.clone())
+        };
+        let hub = find("hub").ok_or(anyhow!("no WebSub hub of the feed"))?;
+        let topic = find("self").unwrap_or(self.feed_url.clone());
+        tracing::info!("Found the WebSub hub {hub} of {topic}");
+        Ok(self.hub_topic.get_or_init(|| (hub, topic)))
+    }
+
+    /// Ask the hub to subscribe, which is then verified by the hub
+    pub async fn subscribe(&self) -> Result<()> {
+        let (hub, topic) = self.discover().await?;
+        let lease = self.lease.load(Ordering::Relaxed).to_string();
+        let form = [
+            ("hub.callback", self.callback.as_str()),
+            ("hub.mode", "subscribe"),
+            ("hub.topic", topic),
+            ("hub.lease_seconds", &lease),
+            ("hub.secret", &self.secret),
+        ];
+        let res = http::client().post(hub).form(&form).send().await?;
+        check_res(res).await?;
+        Ok(())
+    }
+
+    /// Subscribe and renew the subscription before the lease expires until cancelled
+    pub async fn maintain(&self, cancel: CancellationToken) {
+        loop {
+            let wait = match self.subscribe().await {
+                Ok(()) => {
+                    let lease = self.lease.load(Ordering::Relaxed);
+                    Duration::from_secs((lease * 9 / 10).max(60))
+                }
+                Err(e) => {
+                    tracing::error!("Failed to subscribe to the WebSub hub: {e:#}");
+                    Duration::from_secs(60)
+                }
+            };
+            tokio::select! {
+                _ = time::sleep(wait) => (),
+                _ = cancel.cancelled() => break,
+            }
+        }
+    }
+
+    /// Respond to the request with the status code and the body
+    pub fn handle(&self, req: &Request) -> (u16, String) {
+        let Ok(url) = Url::parse(&format!("http://localhost{}", req.target)) else {
+            return (400, String::new());
+        };
+        let callback_path = Url::parse(&self.callback).map(|u| u.path().to_owned());
+        if callback_path.ok().as_deref() != Some(url.path()) {
+            return (404, String::new());
+        }
+        match req.method.as_str() {
+            "GET" => self.verify(&url),
+            "POST" => {
+                let signature = req.signature.as_deref();
+                if !verify_signature(&self.secret, signature, req.body.as_bytes()) {
+                    tracing::warn!("Rejected a WebSub push with an invalid signature");
+                    return (403, String::new());
+                }
+                tracing::info!("Received a WebSub push of {} bytes", req.body.len());
+                self.pushed.lock().unwrap().push(req.body.clone());
+                self.notify.notify_one();
+                (200, String::new())
+            }
+            _ => (405, String::new()),
+        }
+    }
+
+    /// Answer the challenge of the hub for the subscription of the topic
+    fn verify(&self, url: &Url) -> (u16, String) {
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(k, _)| k == name)
+                .map(|(_, v)| v.into_owned())
+        };
+        let topic = self.hub_topic.get().map(|(_, topic)| topic.as_str());
+        if param("hub.topic").as_deref() != topic {
+            return (404, String::new());
+        }
+        match param("hub.mode").as_deref() {
+            Some("subscribe") => {
+                let Some(challenge) = param("hub.challenge") else {
+                    return (400, String::new());
+                };
+                if let Some(lease) = param("hub.lease_seconds").and_then(|s| s.parse().ok()) {
+                    self.lease.store(lease, Ordering::Relaxed);
+                }
+                tracing::info!("WebSub subscription verified");
+                (200, challenge)
+            }
+            Some("denied") => {
+                let reason = param("hub.reason").unwrap_or_default();
+                tracing::error!("WebSub subscription denied by the hub: {reason}");
+                (200, String::new())
+            }
+            // Never unsubscribes
+            _ => (404, String::new()),
+        }
+    }
+
+    /// Serve the callback until cancelled
+    pub async fn serve(self: Arc<Self>, addr: &str, cancel: CancellationToken) -> Result<()> {
+        let listener = TcpListener::bind(addr).await?;
+        tracing::info!("Serving the WebSub callback at {addr}");
+        loop {
+            let (mut stream, _) = tokio::select! {
+                res = listener.accept() => res?,
+                _ = cancel.cancelled() => break,
+            };
+            let sub = self.clone();
+            tokio::spawn(async move {
+                let req = time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await;
+                let (status, body) = match req.unwrap_or_else(|_| Err(anyhow!("timed out"))) {
+                    Ok(req) => sub.handle(&req),
+                    Err(e) => {
+                        tracing::debug!("Invalid request to the WebSub callback: {e}");
+                        (400, String::new())
+                    }
+                };
+                let res = format!(
+                    "HTTP/1.1 {status} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                    reason_of(status),
+                    body.len()
+                );
+                if let Err(e) = stream.write_all(res.as_bytes()).await {
+                    tracing::debug!("Failed to write the WebSub response: {e}");
+                }
+            });
+        }
+        Ok(())
+    }
+}
+
+fn reason_of(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "",
+    }
+}
+
+/// Read the request with its body of `Content-Length`
+async fn read_request(stream: &mut TcpStream) -> Result<Request> {
+    let mut buf = Vec::new();
+    let head_end = loop {
+        if let Some(i) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        ensure!(buf.len() <= MAX_HEAD, "too large request head");
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await?;
+        ensure!(n > 0, "connection closed before the request head ends");
+        buf.extend_from_slice(&chunk[..n]);
+    };
+    let head = String::from_utf8_lossy(&buf[..head_end]).into_owned();
+    let mut lines = head.lines();
+    let mut start = lines.next().unwrap_or_default().split(' ');
+    let (Some(method), Some(target)) = (start.next(), start.next()) else {
+        bail!("invalid request line");
+    };
+    let headers: Vec<_> = lines.filter_map(|line| line.split_once(':')).collect();
+    let header = |name: &str| {
+        headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.trim())
+    };
+    let len = header("content-length")
+        .map(|value| value.parse::<usize>())
+        .transpose()?
+        .unwrap_or(0);
+    ensure!(len <= MAX_BODY, "too large request body");
+    let mut body = buf.split_off(head_end);
+    while body.len() < len {
+        let mut chunk = [0; 4096];
+        let n = stream.read(&mut chunk).await?;
+        ensure!(n > 0, "connection closed before the request body ends");
+        body.extend_from_slice(&chunk[..n]);
+    }
+    body.truncate(len);
+    Ok(Request {
+        method: method.to_owned(),
+        target: target.to_owned(),
+        signature: header("x-hub-signature").map(str::to_owned),
+        body: String::from_utf8(body)?,
+    })
+}
+
+/// Check the signature like `sha256=<hex>` of the body.
+/// SHA-1, which the spec still allows, is not accepted.
+fn verify_signature(secret: &str, signature: Option<&str>, body: &[u8]) -> bool {
+    let Some((algo, hex)) = signature.and_then(|s| s.split_once('=')) else {
+        return false;
+    };
+    let Some(sig) = decode_hex(hex) else {
+        return false;
+    };
+    match algo {
+        "sha256" => verify_mac::<Hmac<Sha256>>(secret, body, &sig),
+        "sha512" => verify_mac::<Hmac<Sha512>>(secret, body, &sig),
+        _ => false,
+    }
+}
+
+/// Compare the MAC in constant time
+fn verify_mac<M: Mac + KeyInit>(secret: &str, body: &[u8], sig: &[u8]) -> bool {
+    let mut mac = <M as Mac>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key size");
+    mac.update(body);
+    mac.verify_slice(sig).is_ok()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Random secret of 32 bytes from the OS
+fn random_secret() -> String {
+    let mut bytes = [0; 32];
+    getrandom::getrandom(&mut bytes).expect("OS random source is available");
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Relations and URLs of the `Link` header like `<https://hub.example>; rel="hub"`
+fn parse_link_header(value: &str) -> Vec<(String, String)> {
+    value
+        .split(',')
+        .filter_map(|link| {
+            let (url, params) = link.split_once(';')?;
+            let url = url.trim().strip_prefix('<')?.strip_suffix('>')?;
+            let rel = params
+                .split(';')
+                .filter_map(|param| param.trim().strip_prefix("rel="))
+                .next()?
+                .trim_matches('"');
+            Some((rel.to_owned(), url.to_owned()))
+        })
+        .collect()
+}
+
+/// Relations and URLs of the `<link>`s of the feed, or `<atom:link>`s of RSS feeds
+fn feed_links(body: &str) -> Result<Vec<(String, String)>> {
+    let mut reader = Reader::from_str(body);
+    let mut links = Vec::new();
+    loop {
+        match reader.read_event()? {
+            Event::Eof => break,
+            Event::Start(elem) | Event::Empty(elem)
+                if matches!(elem.name().as_ref(), b"link" | b"atom:link") =>
+            {
+                let (Some(rel), Some(href)) = (
+                    elem.try_get_attribute("rel")?,
+                    elem.try_get_attribute("href")?,
+                ) else {
+                    continue;
+                };
+                links.push((
+                    rel.unescape_value()?.into_owned(),
+                    href.unescape_value()?.into_owned(),
+                ));
+            }
+            // Entries come after the links of the feed
+            Event::Start(elem) if matches!(elem.name().as_ref(), b"entry" | b"item") => break,
+            _ => (),
+        }
+    }
+    Ok(links)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_random_secret() {
+        let secret = random_secret();
+        assert_eq!(secret.len(), 64);
+        assert_ne!(secret, random_secret());
+    }
+
+    #[test]
+    fn test_handle() {
+        let sub = Subscriber::new(
+            "https://social.example/@myl.atom".to_owned(),
+            "https://mirror.example/websub/secret".to_owned(),
+            86400,
+        );
+        let _ = sub.hub_topic.set((
+            "https://hub.example".to_owned(),
+            "https://social.example/@myl.atom".to_owned(),
+        ));
+        let sub = sub.with_secret(Some("s3cret".to_owned()));
+        let req = |method: &str, target: &str| Request {
+            method: method.to_owned(),
+            target: target.to_owned(),
+            // HMAC-SHA256 of the body with the secret
+            signature: Some(
+                "sha256=1b9591b5322baa7463554820f41fe27e650c767e2fbc4a934c5aa90e9cc27c7c"
+                    .to_owned(),
+            ),
+            body: "<feed/>".to_owned(),
+        };
+        let verify = "/websub/secret?hub.mode=subscribe&hub.topic=https%3A%2F%2Fsocial.example%2F%40myl.atom&hub.challenge=abc&hub.lease_seconds=600";
+        assert_eq!(sub.handle(&req("GET", verify)), (200, "abc".to_owned()));
+        assert_eq!(sub.lease.load(Ordering::Relaxed), 600);
+        let other = "/websub/secret?hub.mode=subscribe&hub.topic=https%3A%2F%2Fother.example&hub.challenge=abc";
+        assert_eq!(sub.handle(&req("GET", other)).0, 404);
+
+        assert_eq!(sub.handle(&req("POST", "/websub/guess")).0, 404);
+        assert!(sub.take_pushed().is_empty());
+        assert_eq!(sub.handle(&req("POST", "/websub/secret")).0, 200);
+        assert_eq!(sub.take_pushed(), ["<feed/>"]);
+        assert!(sub.take_pushed().is_empty());
+
+        // Unsigned and forged pushes are rejected
+        let unsigned = Request {
+            signature: None,
+            ..req("POST", "/websub/secret")
+        };
+        assert_eq!(sub.handle(&unsigned).0, 403);
+        let forged = Request {
+            signature: Some(format!("sha256={}", "00".repeat(32))),
+            ..req("POST", "/websub/secret")
+        };
+        assert_eq!(sub.handle(&forged).0, 403);
+        assert!(sub.take_pushed().is_empty());
+    }
+
+    #[test]
+    fn test_links() -> Result<()> {
+        assert_eq!(
+            parse_link_header(
+                r#"<https://hub.example/>; rel="hub", <https://social.example/@myl.atom>; rel="self""#
+            ),
+            [
+                ("hub".to_owned(), "https://hub.example/".to_owned()),
+                (
+                    "self".to_owned(),
+                    "https://social.example/@myl.atom".to_owned()
+                ),
+            ]
+        );
+        let body = r#"<rss xmlns:atom="http://www.w3.org/2005/Atom"><channel>
+            <atom:link rel="hub" href="https://hub.example/"/>
+            <item><link>https://social.example/@myl/1</link></item></channel></rss>"#;
+        assert_eq!(
+            feed_links(body)?,
+            [("hub".to_owned(), "https://hub.example/".to_owned())]
+        );
+        Ok(())
+    }
+}
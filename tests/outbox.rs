@@ -0,0 +1,1690 @@
+// Copyright (C) myl7
+// SPDX-License-Identifier: Apache-2.0
+
+//! End-to-end cursor behavior of rounds against a mock Mastodon server
+
+// Helpers of the producer and consumer tests are left unused without their features
+#![cfg_attr(
+    not(all(
+        feature = "feeds",
+        feature = "websub",
+        feature = "misskey",
+        feature = "archive",
+        feature = "webhook",
+        feature = "ndjson"
+    )),
+    allow(unused_imports, dead_code)
+)]
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use clap::Parser;
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use tokio_util::sync::CancellationToken;
+use wiremock::matchers::{
+    body_partial_json, body_string_contains, header, method, path, query_param,
+};
+use wiremock::{Mock, MockServer, ResponseTemplate};
+
+use mastotg::auth::{self, host_key};
+use mastotg::cli::Cli;
+use mastotg::cursor::Cursor;
+use mastotg::db::{AuditAction, AuditRecord, Credential, DbConn, State};
+#[cfg(feature = "archive")]
+use mastotg::pro::ArchivePro;
+use mastotg::pro::{Pro, UriPro};
+use mastotg::query::query_featured;
+#[cfg(feature = "telegram")]
+use mastotg::render::Template;
+use mastotg::runner::{init_db, run, run_round, Ctx};
+use mastotg::utils::sign;
+#[cfg(feature = "websub")]
+use mastotg::websub::{Request, Subscriber};
+
+fn ctx(args: &[&str]) -> Result<Ctx> {
+    let mut cli = Cli::try_parse_from(["mastotg", "-f", ":memory:"].iter().chain(args))?;
+    cli.clean()?;
+    let mut conn = Connection::open_in_memory()?;
+    init_db(&mut conn)?;
+    Ctx::new(cli, DbConn::new(conn))
+}
+
+fn create(base: &str, id: i64, in_reply_to: Option<i64>) -> Value {
+    let status = |id| format!("{base}/users/myl/statuses/{id}");
+    json!({
+        "id": status(id) + "/activity",
+        "type": "Create",
+        "object": {
+            "id": status(id),
+            "type": "Note",
+            "inReplyTo": in_reply_to.map(status),
+            "published": "2023-08-03T16:09:19Z",
+            "url": format!("{base}/@myl/{id}"),
+            "to": ["https://www.w3.org/ns/activitystreams#Public"],
+            "content": format!("<p>Post {id}</p>"),
+            "attachment": [],
+            "tag": [],
+        },
+    })
+}
+
+/// Page with posts ordered from the newest to the oldest like Mastodon
+fn page(base: &str, items: Vec<Value>, prev_min_id: Option<i64>) -> Value {
+    json!({
+        "@context": ["https://www.w3.org/ns/activitystreams", {"ostatus": "http://ostatus.org#"}],
+        "id": format!("{base}/users/myl/outbox?page=true"),
+        "type": "OrderedCollectionPage",
+        "prev": prev_min_id.map(|id| format!("{base}/users/myl/outbox?min_id={id}&page=true")),
+        "orderedItems": items,
+    })
+}
+
+async fn mount_outbox(server: &MockServer, min_id: Option<&str>, body: Value, times: u64) {
+    let mut mock = Mock::given(method("GET")).and(path("/users/myl/outbox"));
+    if let Some(min_id) = min_id {
+        mock = mock.and(query_param("min_id", min_id));
+    }
+    mock.and(query_param("page", "true"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(body))
+        .expect(times)
+        .mount(server)
+        .await;
+}
+
+/// Two pages of posts then an empty page
+async fn mount_pages(server: &MockServer) {
+    let base = server.uri();
+    let page1 = page(
+        &base,
+        vec![create(&base, 200, Some(100)), create(&base, 100, None)],
+        Some(200),
+    );
+    mount_outbox(server, Some("0"), page1, 1).await;
+    let page2 = page(&base, vec![create(&base, 300, None)], Some(300));
+    mount_outbox(server, Some("200"), page2, 1).await;
+    mount_outbox(server, Some("300"), page(&base, vec![], None), 1).await;
+}
+
+#[tokio::test]
+async fn test_query_fetch_follows_paging() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    Mock::given(method("GET"))
+        .and(path("/.well-known/webfinger"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "links": [
+                {"rel": "http://webfinger.net/rel/profile-page", "type": "text/html", "href": format!("{base}/@myl")},
+                {"rel": "self", "type": "application/activity+json", "href": format!("{base}/users/myl")},
+                {"rel": "http://ostatus.org/schema/1.0/subscribe", "template": "unused"},
+            ],
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/users/myl"))
+        .and(header("accept", "application/activity+json"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "outbox": format!("{base}/users/myl/outbox"),
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    mount_pages(&server).await;
+
+    let ctx = ctx(&["-i", "query-fetch", "-s", &base, "-u", "myl"])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_prefetch_pages() -> Result<()> {
+    let server = MockServer::start().await;
+    mount_pages(&server).await;
+
+    let outbox = format!("{}/users/myl/outbox", server.uri());
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox, "--prefetch-pages", "2"])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_prefetch_returns_first_page() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let page1 = page(&base, vec![create(&base, 200, None)], Some(200));
+    mount_outbox(&server, Some("0"), page1, 1).await;
+    let page2 = page(&base, vec![create(&base, 300, None)], None);
+    Mock::given(method("GET"))
+        .and(path("/users/myl/outbox"))
+        .and(query_param("min_id", "200"))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(page2)
+                .set_delay(Duration::from_secs(1)),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let outbox = format!("{base}/users/myl/outbox?min_id=0&page=true");
+    let mut pro = UriPro::new(outbox, CancellationToken::new(), None).with_prefetch(2);
+    // The later page is fetched in the background and not waited for
+    let start = Instant::now();
+    let page = pro.fetch().await?;
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert_eq!(page.ordered_items.len(), 1);
+    let page = pro.fetch().await?;
+    assert_eq!(
+        page.ordered_items[0].id(),
+        format!("{base}/users/myl/statuses/300/activity")
+    );
+    assert!(pro.fetch().await?.ordered_items.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_record_failure_ignored() -> Result<()> {
+    let server = MockServer::start().await;
+    mount_pages(&server).await;
+
+    // The fixture dir is under a file and can not be written
+    let file = std::env::temp_dir().join(format!("mastotg-record-{}", std::process::id()));
+    std::fs::write(&file, "")?;
+    let dir = file.join("fixtures");
+    let outbox = format!("{}/users/myl/outbox", server.uri());
+    let dir_arg = dir.to_string_lossy();
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox, "--record-fixtures", &dir_arg])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+    std::fs::remove_file(file)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_no_follow_paging() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let page1 = page(&base, vec![create(&base, 200, None)], Some(200));
+    mount_outbox(&server, Some("0"), page1, 1).await;
+    mount_outbox(&server, Some("200"), page(&base, vec![], None), 0).await;
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox, "--no-follow-paging"])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 200);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_ff_latest() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let latest = page(
+        &base,
+        vec![create(&base, 300, None), create(&base, 200, None)],
+        Some(300),
+    );
+    mount_outbox(&server, None, latest, 1).await;
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox])?;
+    let state = run_round(&ctx, State::default(), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_filtered_posts_advance_cursor() -> Result<()> {
+    let server = MockServer::start().await;
+    mount_pages(&server).await;
+
+    let outbox = format!("{}/users/myl/outbox", server.uri());
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox, "--no-replies"])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+
+    let status = |id| format!("{}/users/myl/statuses/{id}", server.uri());
+    let actions =
+        |records: Vec<AuditRecord>| records.into_iter().map(|r| r.action).collect::<Vec<_>>();
+    assert_eq!(
+        actions(ctx.db.query_audit(status(200)).await?),
+        [AuditAction::Skipped]
+    );
+    assert_eq!(
+        actions(ctx.db.query_audit(status(300)).await?),
+        [AuditAction::Printed]
+    );
+    Ok(())
+}
+
+#[test]
+fn test_tg_chan_targets() -> Result<()> {
+    let parse = |chan: &str| -> Result<Vec<String>> {
+        let mut cli = Cli::try_parse_from(["mastotg", "-f", ":memory:", "--tg-chan", chan])?;
+        cli.clean()?;
+        Ok(cli.tg_chan)
+    };
+    assert_eq!(parse("myl7s")?, ["@myl7s"]);
+    assert_eq!(parse("-1001234567890")?, ["-1001234567890"]);
+    assert_eq!(parse("https://t.me/myl7s/42")?, ["@myl7s"]);
+    assert_eq!(parse("t.me/s/myl7s")?, ["@myl7s"]);
+    assert_eq!(parse(r#"-42={"tag": "mygo"}"#)?, [r#"-42={"tag": "mygo"}"#]);
+    assert_eq!(parse("https://t.me/myl7s?single=1")?, ["@myl7s"]);
+    assert_eq!(
+        parse(r#"https://t.me/myl7s?single=1&embed=1={"tag": "mygo"}"#)?,
+        [r#"@myl7s={"tag": "mygo"}"#]
+    );
+    assert!(parse("https://t.me/+AbCdEf").is_err());
+    assert!(parse("t.me/joinchat/AbCdEf").is_err());
+    assert!(parse("my chan").is_err());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_reloaded_filter() -> Result<()> {
+    let server = MockServer::start().await;
+    mount_pages(&server).await;
+
+    let outbox = format!("{}/users/myl/outbox", server.uri());
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox])?;
+    let args = ["mastotg", "-f", ":memory:", "-i", "fetch", "-s", &outbox];
+    let mut cli = Cli::try_parse_from(args.iter().chain(&["--no-replies"]))?;
+    cli.clean()?;
+    ctx.reload(&cli)?;
+    run_round(&ctx, State::new(0), &ctx.cancel).await?;
+
+    let status = |id| format!("{}/users/myl/statuses/{id}", server.uri());
+    let records = ctx.db.query_audit(status(200)).await?;
+    assert_eq!(records[0].action, AuditAction::Skipped);
+    Ok(())
+}
+
+#[cfg(feature = "telegram")]
+#[test]
+fn test_reloaded_template() -> Result<()> {
+    let outbox = "https://example.com/users/myl/outbox";
+    let base = [
+        "-i",
+        "fetch",
+        "-s",
+        outbox,
+        "-o",
+        "tg-send",
+        "--tg-chan",
+        "@myl7s",
+    ];
+    let ctx = ctx(&[&base[..], &["--tg-template", "@myl7s={content}"]].concat())?;
+    let reloaded = |args: &[&str]| -> Result<Cli> {
+        let args = [&["mastotg", "-f", ":memory:"][..], &base, args].concat();
+        let mut cli = Cli::try_parse_from(args)?;
+        cli.clean()?;
+        Ok(cli)
+    };
+    let template = |ctx: &Ctx| ctx.live().tg_templates[0].1.clone();
+
+    ctx.reload(&reloaded(&["--tg-template", "@myl7s={content}\\n{url}"])?)?;
+    assert_eq!(template(&ctx), Template::parse("{content}\n{url}")?);
+
+    // Outputs with their channels are reloaded too
+    ctx.reload(&reloaded(&["--tg-chan", "@myl7s2"])?)?;
+    assert!(ctx.live().tg_templates.is_empty());
+    assert_eq!(ctx.live().tg_chans.len(), 2);
+
+    // Sources need a restart, so the current options are kept
+    let mut cli = reloaded(&["--tg-template", "@myl7s={url}"])?;
+    cli.host = Some("https://example.com/users/myl2/outbox".to_owned());
+    let e = ctx.reload(&cli).unwrap_err();
+    assert!(e.to_string().contains("host"), "{e}");
+    assert!(ctx.live().tg_templates.is_empty());
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_no_replies_keep_threads() -> Result<()> {
+    let server = MockServer::start().await;
+    mount_pages(&server).await;
+
+    let outbox = format!("{}/users/myl/outbox", server.uri());
+    let ctx = ctx(&[
+        "-i",
+        "fetch",
+        "-s",
+        &outbox,
+        "--no-replies",
+        "--keep-threads",
+    ])?;
+    run_round(&ctx, State::new(0), &ctx.cancel).await?;
+
+    let status = |id| format!("{}/users/myl/statuses/{id}", server.uri());
+    let records = ctx.db.query_audit(status(200)).await?;
+    assert_eq!(records[0].action, AuditAction::Printed);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_duplicates_across_pages_skipped() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let page1 = page(
+        &base,
+        vec![create(&base, 200, None), create(&base, 100, None)],
+        Some(200),
+    );
+    mount_outbox(&server, Some("0"), page1, 1).await;
+    // Post 200 shifted to the next page by a post arriving mid-round
+    let page2 = page(
+        &base,
+        vec![create(&base, 300, None), create(&base, 200, None)],
+        Some(300),
+    );
+    mount_outbox(&server, Some("200"), page2, 1).await;
+    mount_outbox(&server, Some("300"), page(&base, vec![], None), 1).await;
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+
+    let records = ctx
+        .db
+        .query_audit(format!("{base}/users/myl/statuses/200"))
+        .await?;
+    assert_eq!(records.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_disk_queue_resumed() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    mount_pages(&server).await;
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox, "--disk-queue"])?;
+    // Left by an interrupted round, and sent before fetching again
+    let left = page(&base, vec![create(&base, 50, None)], Some(50));
+    ctx.db.enqueue_page(left.to_string()).await?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 50);
+    assert_eq!(ctx.db.load_state(String::new()).await?.unwrap().min_id, 50);
+
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+    assert!(ctx.db.query_queued_seqs().await?.is_empty());
+    let records = ctx
+        .db
+        .query_audit(format!("{base}/users/myl/statuses/300"))
+        .await?;
+    assert_eq!(records.len(), 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_redirected_outbox_persisted() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let location = format!("{base}/users/myl/outbox?min_id=0&page=true");
+    Mock::given(method("GET"))
+        .and(path("/old/outbox"))
+        .respond_with(ResponseTemplate::new(301).insert_header("location", location.as_str()))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let page1 = page(&base, vec![create(&base, 100, None)], Some(100));
+    mount_outbox(&server, Some("0"), page1, 1).await;
+    mount_outbox(&server, Some("100"), page(&base, vec![], None), 2).await;
+
+    let old = format!("{base}/old/outbox");
+    let ctx = ctx(&["-i", "fetch", "-s", &old])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(
+        ctx.db.query_canonical_url(old.clone()).await?,
+        Some(format!("{base}/users/myl/outbox"))
+    );
+    // Without the redirect hop
+    run_round(&ctx, state, &ctx.cancel).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_invalid_items_skipped() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let mut invalid = create(&base, 200, None);
+    invalid["object"]["type"] = json!("Relationship");
+    let page1 = page(&base, vec![invalid, create(&base, 100, None)], Some(200));
+    mount_outbox(&server, Some("0"), page1, 2).await;
+    mount_outbox(&server, Some("200"), page(&base, vec![], None), 1).await;
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let ctx_fail = ctx(&["-i", "fetch", "-s", &outbox])?;
+    assert!(run_round(&ctx_fail, State::new(0), &ctx_fail.cancel)
+        .await
+        .is_err());
+
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox, "--on-invalid", "dead-letter"])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 200);
+    let status = |id| format!("{base}/users/myl/statuses/{id}");
+    assert_eq!(
+        ctx.db.query_audit(status(100)).await?[0].action,
+        AuditAction::Printed
+    );
+    assert_eq!(
+        ctx.db.query_audit(status(200) + "/activity").await?[0].action,
+        AuditAction::Skipped
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_on_sent_webhook() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let page1 = page(&base, vec![create(&base, 200, None)], Some(200));
+    mount_outbox(&server, Some("0"), page1, 1).await;
+    Mock::given(method("POST"))
+        .and(path("/hooks/sent"))
+        .and(body_partial_json(json!({
+            "event": "sent",
+            "id": format!("{base}/users/myl/statuses/200"),
+            "dest": "stdout",
+        })))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let hook = format!("{base}/hooks/sent");
+    let ctx = ctx(&[
+        "-i",
+        "fetch",
+        "-s",
+        &outbox,
+        "--no-follow-paging",
+        "--on-sent-webhook",
+        &hook,
+    ])?;
+    run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_heartbeat() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    mount_outbox(&server, Some("0"), page(&base, vec![], None), 1).await;
+    Mock::given(method("GET"))
+        .and(path("/ping"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let heartbeat = format!("{base}/ping");
+    let ctx = ctx(&[
+        "-i",
+        "fetch",
+        "-s",
+        &outbox,
+        "-m",
+        "0",
+        "--heartbeat-url",
+        &heartbeat,
+    ])?;
+    run(&ctx).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_heartbeat_fail() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    Mock::given(method("GET"))
+        .and(path("/users/myl/outbox"))
+        .respond_with(ResponseTemplate::new(500))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/ping/fail"))
+        .respond_with(ResponseTemplate::new(200))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let heartbeat = format!("{base}/ping");
+    let ctx = ctx(&[
+        "-i",
+        "fetch",
+        "-s",
+        &outbox,
+        "-m",
+        "0",
+        "--heartbeat-url",
+        &heartbeat,
+    ])?;
+    assert!(run(&ctx).await.is_err());
+    assert_eq!(
+        ctx.db.query_failure_stats().await?,
+        [("network".to_owned(), 1)]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multiple_sources() -> Result<()> {
+    let main = MockServer::start().await;
+    let main_base = main.uri();
+    let page1 = page(&main_base, vec![create(&main_base, 200, None)], Some(200));
+    mount_outbox(&main, Some("0"), page1, 1).await;
+    let other = MockServer::start().await;
+    let other_base = other.uri();
+    let page1 = page(&other_base, vec![create(&other_base, 300, None)], Some(300));
+    mount_outbox(&other, Some("0"), page1, 1).await;
+
+    let outbox = format!("{main_base}/users/myl/outbox");
+    let source = format!("name=other,input=fetch,host={other_base}/users/myl/outbox,min-id=0");
+    // Sources are named
+    assert!(ctx(&["-i", "fetch", "-s", &outbox, "--source", "input=fetch"]).is_err());
+    let ctx = ctx(&[
+        "-i",
+        "fetch",
+        "-s",
+        &outbox,
+        "-m",
+        "0",
+        "--no-follow-paging",
+        "--source",
+        &source,
+    ])?;
+    run(&ctx).await?;
+    // States are saved by the sources
+    assert_eq!(ctx.db.load_state(String::new()).await?.unwrap().min_id, 200);
+    let state = ctx.db.load_state("other".to_owned()).await?.unwrap();
+    assert_eq!(state.min_id, 300);
+    Ok(())
+}
+
+#[cfg(feature = "webhook")]
+#[tokio::test]
+async fn test_multiple_sources_failed_keeps_sent() -> Result<()> {
+    let main = MockServer::start().await;
+    let base = main.uri();
+    let items = vec![
+        create(&base, 300, None),
+        create(&base, 200, None),
+        create(&base, 100, None),
+    ];
+    mount_outbox(&main, Some("0"), page(&base, items.clone(), None), 1).await;
+    // Later rounds fetch from the post handled before the failure
+    Mock::given(method("GET"))
+        .and(path("/users/myl/outbox"))
+        .and(query_param("min_id", "100"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(
+            &base,
+            items[..2].to_vec(),
+            None,
+        )))
+        .expect(1..)
+        .mount(&main)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/users/myl/outbox"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(&base, vec![], None)))
+        .mount(&main)
+        .await;
+    // The second post is rejected once, which fails the first round of the main source
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .and(body_string_contains("statuses/200"))
+        .respond_with(ResponseTemplate::new(400))
+        .up_to_n_times(1)
+        .mount(&main)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .and(body_string_contains("statuses/100"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&main)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(204))
+        .mount(&main)
+        .await;
+    let other = MockServer::start().await;
+    Mock::given(method("GET"))
+        .and(path("/users/myl/outbox"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(&other.uri(), vec![], None)))
+        .mount(&other)
+        .await;
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let hook = format!("{base}/hook");
+    let source = format!(
+        "name=other,input=fetch,host={}/users/myl/outbox",
+        other.uri()
+    );
+    let ctx = ctx(&[
+        "-i",
+        "fetch",
+        "-s",
+        &outbox,
+        "-m",
+        "0",
+        "-o",
+        "webhook",
+        "--webhook-url",
+        &hook,
+        "--no-follow-paging",
+        "--loop-interval",
+        "1",
+        "--source",
+        &source,
+    ])?;
+    let canceller = ctx.cancel.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        canceller.cancel();
+    });
+    run(&ctx).await?;
+    assert_eq!(ctx.db.load_state(String::new()).await?.unwrap().min_id, 300);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_debug_http_capture() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let page1 = page(&base, vec![create(&base, 200, None)], Some(200));
+    mount_outbox(&server, Some("0"), page1, 1).await;
+
+    let dir = std::env::temp_dir().join(format!("mastotg-debug-http-{}", std::process::id()));
+    let outbox = format!("{base}/users/myl/outbox");
+    let ctx = ctx(&[
+        "-i",
+        "fetch",
+        "-s",
+        &outbox,
+        "--no-follow-paging",
+        "--debug-http",
+        dir.to_str().unwrap(),
+    ])?;
+    run_round(&ctx, State::new(0), &ctx.cancel).await?;
+
+    let pair: Value = serde_json::from_slice(&std::fs::read(dir.join("0000_outbox.json"))?)?;
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(pair["request"]["method"], "GET");
+    assert_eq!(pair["response"]["status"], 200);
+    assert_eq!(
+        pair["response"]["body"]["orderedItems"][0]["id"],
+        format!("{base}/users/myl/statuses/200/activity")
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_announce_skipped() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let announce = json!({
+        "id": format!("{base}/users/myl/statuses/300/activity"),
+        "type": "Announce",
+        "object": "https://example.com/users/alice/statuses/1",
+    });
+    let page1 = page(&base, vec![announce, create(&base, 200, None)], Some(300));
+    mount_outbox(&server, Some("0"), page1, 1).await;
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox, "--no-follow-paging"])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+    let records = ctx
+        .db
+        .query_audit(format!("{base}/users/myl/statuses/300/activity"))
+        .await?;
+    assert_eq!(records[0].action, AuditAction::Skipped);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_infers_media_types() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let mut item = create(&base, 100, None);
+    item["object"]["attachment"] = json!([
+        {"type": "Document", "url": format!("{base}/media/1.PNG")},
+        {"type": "Document", "url": format!("{base}/media/2")},
+        {"type": "Document", "url": format!("{base}/media/3")},
+    ]);
+    let outbox = format!("{base}/users/myl/outbox?min_id=0&page=true");
+    mount_outbox(&server, Some("0"), page(&base, vec![item], None), 1).await;
+    Mock::given(method("HEAD"))
+        .and(path("/media/2"))
+        .respond_with(ResponseTemplate::new(200).insert_header("content-type", "video/mp4"))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("HEAD"))
+        .and(path("/media/3"))
+        .respond_with(ResponseTemplate::new(404))
+        .mount(&server)
+        .await;
+
+    let mut pro = UriPro::new(outbox, CancellationToken::new(), None);
+    let page = pro.fetch().await?;
+    let post = &page.ordered_items[0].as_create().unwrap().object;
+    let media_types: Vec<_> = post
+        .attachment
+        .iter()
+        .map(|att| &att.media_type[..])
+        .collect();
+    assert_eq!(media_types, ["image/png", "video/mp4", ""]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_opaque_ids_skip_handled() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    // GoToSocial-like GUIDs without integer IDs
+    let status = |id: &str| format!("{base}/users/myl/statuses/{id}");
+    let item = |id: &str, published: &str| {
+        let mut item = create(&base, 0, None);
+        item["id"] = json!(status(id) + "/activity");
+        item["object"]["id"] = json!(status(id));
+        item["object"]["published"] = json!(published);
+        item
+    };
+    let old = item("01H7ZQ", "2023-08-03T16:09:19Z");
+    let new = item("01H80A", "2023-08-04T08:00:00Z");
+    Mock::given(method("GET"))
+        .and(path("/users/myl/outbox"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(
+            &base,
+            vec![old.clone()],
+            None,
+        )))
+        .up_to_n_times(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/users/myl/outbox"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(page(&base, vec![new, old], None)))
+        .mount(&server)
+        .await;
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox, "--no-follow-paging"])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 0);
+    assert_eq!(
+        state.cursor.as_ref().unwrap().id,
+        status("01H7ZQ") + "/activity"
+    );
+    ctx.db.save_state(String::new(), state).await?;
+    let state = ctx.db.load_state(String::new()).await?.unwrap();
+    let state = run_round(&ctx, state, &ctx.cancel).await?;
+    assert_eq!(state.cursor.unwrap().id, status("01H80A") + "/activity");
+
+    for id in ["01H7ZQ", "01H80A"] {
+        assert_eq!(ctx.db.query_audit(status(id)).await?.len(), 1);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_newest_first_pages_sent_chronologically() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    // Pages from the newest like servers ignoring `min_id`
+    let page1 = page(
+        &base,
+        vec![create(&base, 400, None), create(&base, 300, None)],
+        Some(1),
+    );
+    mount_outbox(&server, Some("0"), page1, 1).await;
+    let page2 = page(
+        &base,
+        vec![create(&base, 200, None), create(&base, 100, None)],
+        Some(2),
+    );
+    mount_outbox(&server, Some("1"), page2, 1).await;
+    mount_outbox(&server, Some("2"), page(&base, vec![], None), 1).await;
+    Mock::given(method("POST"))
+        .and(path("/hooks/sent"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(4)
+        .mount(&server)
+        .await;
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let hook = format!("{base}/hooks/sent");
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox, "--on-sent-webhook", &hook])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 400);
+
+    let sent: Vec<String> = server
+        .received_requests()
+        .await
+        .unwrap()
+        .into_iter()
+        .filter(|req| req.url.path() == "/hooks/sent")
+        .map(|req| {
+            req.body_json::<Value>().unwrap()["id"]
+                .as_str()
+                .unwrap()
+                .to_owned()
+        })
+        .collect();
+    let status = |id| format!("{base}/users/myl/statuses/{id}");
+    assert_eq!(sent, [100, 200, 300, 400].map(status));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_query_featured() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    Mock::given(method("GET"))
+        .and(path("/users/myl"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "id": format!("{base}/users/myl"),
+            "type": "Person",
+            "outbox": format!("{base}/users/myl/outbox"),
+            "featured": format!("{base}/users/myl/collections/featured"),
+        })))
+        .mount(&server)
+        .await;
+    Mock::given(method("GET"))
+        .and(path("/users/myl/collections/featured"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "id": format!("{base}/users/myl/collections/featured"),
+            "type": "OrderedCollection",
+            "totalItems": 2,
+            "orderedItems": [
+                create(&base, 102, None)["object"],
+                format!("{base}/users/myl/statuses/100"),
+            ],
+        })))
+        .mount(&server)
+        .await;
+
+    let featured = query_featured(&format!("{base}/users/myl")).await?;
+    assert_eq!(
+        featured,
+        [
+            format!("{base}/users/myl/statuses/102"),
+            format!("{base}/users/myl/statuses/100"),
+        ]
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_unchanged_pages_skipped() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let page1 = page(&base, vec![create(&base, 100, None)], Some(100));
+    mount_outbox(&server, Some("0"), page1, 2).await;
+    mount_outbox(&server, Some("100"), page(&base, vec![], None), 2).await;
+
+    let ctx = ctx(&[])?;
+    let outbox = format!("{base}/users/myl/outbox?min_id=0&page=true");
+    let fetch_all = || async {
+        let mut pro = UriPro::new(outbox.clone(), CancellationToken::new(), None)
+            .with_skip_unchanged(true, ctx.db.clone());
+        let mut posts = 0;
+        loop {
+            let page = pro.fetch().await?;
+            if page.ordered_items.is_empty() {
+                break;
+            }
+            posts += page.ordered_items.len();
+        }
+        pro.save_hashes().await?;
+        anyhow::Ok(posts)
+    };
+    assert_eq!(fetch_all().await?, 1);
+    assert_eq!(fetch_all().await?, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_statuses() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let status = |id: &str, in_reply_to_id: Option<&str>, visibility: &str| {
+        json!({
+            "id": id,
+            "uri": format!("{base}/users/myl/statuses/{id}"),
+            "url": format!("{base}/@myl/{id}"),
+            "created_at": "2023-08-03T16:09:19.000Z",
+            "in_reply_to_id": in_reply_to_id,
+            "visibility": visibility,
+            "content": format!("<p>Post {id}</p>"),
+            "account": {"id": "1", "username": "myl", "url": format!("{base}/@myl")},
+            "media_attachments": [],
+        })
+    };
+    Mock::given(method("GET"))
+        .and(path("/api/v1/accounts/lookup"))
+        .and(query_param("acct", format!("myl@{}", server.address())))
+        .and(header("authorization", "Bearer token"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"id": "1"})))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let statuses = json!([
+        status("300", Some("200"), "unlisted"),
+        status("200", None, "public"),
+    ]);
+    for (min_id, body) in [("0", statuses), ("300", json!([]))] {
+        Mock::given(method("GET"))
+            .and(path("/api/v1/accounts/1/statuses"))
+            .and(query_param("min_id", min_id))
+            .and(header("authorization", "Bearer token"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    std::env::set_var("MASTODON_ACCESS_TOKEN", "token");
+    let ctx = ctx(&["-i", "api", "-s", &base, "-u", "myl"])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_auth() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    Mock::given(method("POST"))
+        .and(path("/api/v1/apps"))
+        .and(body_string_contains(
+            "scopes=read%3Aaccounts+read%3Astatuses",
+        ))
+        .respond_with(
+            ResponseTemplate::new(200)
+                .set_body_json(json!({"id": "1", "client_id": "cid", "client_secret": "csecret"})),
+        )
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/oauth/token"))
+        .and(body_string_contains("grant_type=authorization_code"))
+        .and(body_string_contains("client_secret=csecret"))
+        .and(body_string_contains("code=abc"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(
+            json!({"access_token": "stored-token", "token_type": "Bearer", "scope": "read"}),
+        ))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let host = format!("{base}/");
+    let app = auth::register_app(&host, auth::DEFAULT_SCOPES).await?;
+    let url = auth::authorize_url(&host, &app, auth::DEFAULT_SCOPES)?;
+    assert!(url.starts_with(&format!("{base}/oauth/authorize?client_id=cid&")));
+    let token = auth::obtain_token(&host, &app, "abc\n", auth::DEFAULT_SCOPES).await?;
+    assert_eq!(token, "stored-token");
+
+    let ctx = ctx(&["-i", "timeline", "-s", &host])?;
+    ctx.db
+        .save_credential(Credential {
+            host: host_key(&host),
+            client_id: app.client_id,
+            client_secret: app.client_secret,
+            access_token: token,
+            scopes: auth::DEFAULT_SCOPES.to_owned(),
+        })
+        .await?;
+    assert_eq!(
+        ctx.db.query_access_token(base).await?.as_deref(),
+        Some("stored-token")
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_local_timeline() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let status = |id: &str, username: &str| {
+        json!({
+            "id": id,
+            "uri": format!("{base}/users/{username}/statuses/{id}"),
+            "created_at": "2023-08-03T16:09:19.000Z",
+            "in_reply_to_id": null,
+            "visibility": "public",
+            "content": format!("<p>Post {id}</p>"),
+            "account": {"id": id, "username": username, "url": format!("{base}/@{username}")},
+        })
+    };
+    let statuses = json!([status("300", "alice"), status("200", "myl")]);
+    for (min_id, body) in [("0", statuses), ("300", json!([]))] {
+        Mock::given(method("GET"))
+            .and(path("/api/v1/timelines/public"))
+            .and(query_param("local", "true"))
+            .and(query_param("min_id", min_id))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    let ctx = ctx(&["-i", "timeline", "-s", &base])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+    Ok(())
+}
+
+#[cfg(feature = "feeds")]
+#[tokio::test]
+async fn test_rss() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let item = |id: i64, minute: i64| {
+        format!(
+            "<item><guid>{base}/@myl/{id}</guid><link>{base}/@myl/{id}</link>\
+            <pubDate>Thu, 03 Aug 2023 16:{minute:02}:19 +0000</pubDate>\
+            <description>&lt;p&gt;Post {id}&lt;/p&gt;</description></item>"
+        )
+    };
+    let feed = format!(
+        r#"<rss version="2.0"><channel><title>myl</title>{}{}{}</channel></rss>"#,
+        item(300, 12),
+        item(200, 11),
+        item(100, 10),
+    );
+    Mock::given(method("GET"))
+        .and(path("/@myl.rss"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(feed))
+        .expect(2)
+        .mount(&server)
+        .await;
+
+    let url = format!("{base}/@myl.rss");
+    let ctx = ctx(&["-i", "rss", "-s", &url])?;
+    let state = run_round(&ctx, State::new(100), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+    // Fast forwarding takes the newest item
+    let state = run_round(&ctx, State::new(-1), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+    Ok(())
+}
+
+#[cfg(feature = "websub")]
+#[tokio::test]
+async fn test_websub_push() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let entry = |id: i64, minute: i64| {
+        format!(
+            "<entry><id>{base}/users/myl/statuses/{id}</id>\
+            <updated>2023-08-03T16:{minute:02}:19Z</updated>\
+            <content type=\"html\">&lt;p&gt;Post {id}&lt;/p&gt;</content></entry>"
+        )
+    };
+    let feed = |entries: &[String]| {
+        format!(
+            r#"<feed xmlns="http://www.w3.org/2005/Atom"><title>myl</title>{}</feed>"#,
+            entries.concat()
+        )
+    };
+    // Polled only when nothing is pushed
+    Mock::given(method("GET"))
+        .and(path("/@myl.atom"))
+        .respond_with(ResponseTemplate::new(200).set_body_string(feed(&[entry(200, 11)])))
+        .expect(1)
+        .mount(&server)
+        .await;
+
+    let url = format!("{base}/@myl.atom");
+    let mut ctx = ctx(&[
+        "-i",
+        "websub",
+        "-s",
+        &url,
+        "--websub-callback",
+        "https://mastotg.myl.moe/websub/secret",
+        "--loop-interval",
+        "60",
+    ])?;
+    let sub = Subscriber::new(
+        url.clone(),
+        "https://mastotg.myl.moe/websub/secret".to_owned(),
+        86400,
+    )
+    .with_secret(Some("s3cret".to_owned()));
+    ctx.websub = Some(Arc::new(sub));
+    let sub = ctx.websub.as_ref().unwrap();
+    let body = feed(&[entry(300, 12), entry(100, 10)]);
+    let push = |target: &str, secret: &str| Request {
+        method: "POST".to_owned(),
+        target: target.to_owned(),
+        signature: Some(sign(secret, body.as_bytes())),
+        body: body.clone(),
+    };
+    // Pushes to other paths or with wrong signatures are rejected
+    assert_eq!(sub.handle(&push("/websub/other", "s3cret")).0, 404);
+    assert_eq!(sub.handle(&push("/websub/secret", "guess")).0, 403);
+    assert_eq!(sub.handle(&push("/websub/secret", "s3cret")).0, 200);
+    let state = run_round(&ctx, State::new(100), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 300);
+    let state = run_round(&ctx, State::new(100), &ctx.cancel).await?;
+    assert_eq!(state.min_id, 200);
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dir_drops() -> Result<()> {
+    let base = "https://social.myl.moe";
+    let dir = std::env::temp_dir().join(format!("mastotg-dir-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let page1 = page(
+        base,
+        vec![create(base, 200, None), create(base, 100, None)],
+        None,
+    );
+    std::fs::write(dir.join("1.page.json"), page1.to_string())?;
+    // Bare posts are taken as their `Create`s
+    let post = create(base, 300, None)["object"].take();
+    std::fs::write(dir.join("2.post.json"), post.to_string())?;
+    std::fs::write(dir.join("3.post.json"), "{}")?;
+    std::fs::write(dir.join("notes.txt"), "")?;
+
+    let ctx = ctx(&["-i", "dir", "-s", dir.to_str().unwrap()])?;
+    let left = || -> Result<Vec<String>> {
+        let mut left: Vec<_> = std::fs::read_dir(&dir)?
+            .map(|entry| Ok(entry?.file_name().into_string().unwrap()))
+            .collect::<Result<_>>()?;
+        left.sort();
+        Ok(left)
+    };
+    // Fast-forwarding the first run sends nothing, so no files are removed
+    let ff_state = run_round(&ctx, State::default(), &ctx.cancel).await?;
+    let ff_left = left()?;
+    let state = run_round(&ctx, State::new(100), &ctx.cancel).await?;
+    let left = left()?;
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(ff_state.min_id, 300);
+    assert_eq!(
+        ff_left,
+        [
+            "1.page.json",
+            "2.post.json",
+            "3.post.json.invalid",
+            "notes.txt"
+        ]
+    );
+    assert_eq!(state.min_id, 300);
+    assert_eq!(left, ["3.post.json.invalid", "notes.txt"]);
+    Ok(())
+}
+
+#[cfg(feature = "archive")]
+#[tokio::test]
+async fn test_archive() -> Result<()> {
+    use std::io::Write;
+
+    let base = "https://social.myl.moe";
+    let mut with_media = create(base, 300, None);
+    with_media["object"]["attachment"] = json!([{
+        "type": "Document",
+        "mediaType": "image/png",
+        "url": "/media_attachments/files/000/000/001/original/a.png",
+    }]);
+    // Listed from the oldest in archives, and replayed in pages
+    let mut items: Vec<_> = (200..250).map(|id| create(base, id, None)).collect();
+    items.insert(0, create(base, 100, None));
+    items.push(with_media);
+    let outbox = json!({
+        "@context": "https://www.w3.org/ns/activitystreams",
+        "id": "outbox.json",
+        "type": "OrderedCollection",
+        "orderedItems": items,
+    });
+    let mut tar = Vec::new();
+    let files = [
+        ("outbox.json", outbox.to_string().into_bytes()),
+        (
+            "media_attachments/files/000/000/001/original/a.png",
+            b"png".to_vec(),
+        ),
+    ];
+    for (name, body) in files {
+        let mut header = [0; 512];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[124..135].copy_from_slice(format!("{:011o}", body.len()).as_bytes());
+        header[156] = b'0';
+        tar.extend(header);
+        tar.extend(body);
+        tar.resize(tar.len().next_multiple_of(512), 0);
+    }
+    tar.extend([0; 1024]);
+    let path = std::env::temp_dir().join(format!("mastotg-archive-{}.tar.gz", std::process::id()));
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&tar)?;
+    std::fs::write(&path, gz.finish()?)?;
+
+    let ctx = ctx(&["-i", "archive", "-s", path.to_str().unwrap()])?;
+    // Fast-forwarded to the latest post, not the latest of the oldest page
+    let ff = run_round(&ctx, State::default(), &ctx.cancel).await;
+    let res = run_round(&ctx, State::new(100), &ctx.cancel).await;
+    let extracted = ArchivePro::extracted_dir(&path)?;
+    std::fs::remove_file(&path)?;
+    // Media are extracted
+    assert!(extracted
+        .join("media_attachments/files/000/000/001/original/a.png")
+        .is_file());
+    std::fs::remove_dir_all(extracted)?;
+    assert_eq!(ff?.min_id, 300);
+    assert_eq!(res?.min_id, 300);
+    Ok(())
+}
+
+#[cfg(feature = "misskey")]
+#[tokio::test]
+async fn test_misskey_notes() -> Result<()> {
+    let server = MockServer::start().await;
+    let note = |id: &str, second: u32| {
+        json!({
+            "id": id,
+            "createdAt": format!("2023-08-03T16:09:{second:02}.000Z"),
+            "userId": "9h1",
+            "text": format!("Note {id}\n\nwith #mygo"),
+            "cw": null,
+            "visibility": "public",
+            "files": [],
+            "tags": ["mygo"],
+        })
+    };
+    Mock::given(method("POST"))
+        .and(path("/api/users/show"))
+        .and(body_partial_json(json!({"username": "myl", "host": null})))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({"id": "9h1"})))
+        .expect(1)
+        .mount(&server)
+        .await;
+    // Listed from the oldest after the since
+    let notes = json!([note("9h2", 19), note("9h3", 20)]);
+    let pages = [
+        (json!({"userId": "9h1", "sinceDate": 0}), notes),
+        (json!({"userId": "9h1", "sinceId": "9h3"}), json!([])),
+    ];
+    for (body, res) in pages {
+        Mock::given(method("POST"))
+            .and(path("/api/users/notes"))
+            .and(body_partial_json(body))
+            .respond_with(ResponseTemplate::new(200).set_body_json(res))
+            .expect(1)
+            .mount(&server)
+            .await;
+    }
+
+    let ctx = ctx(&["-i", "misskey", "-s", &server.uri(), "-u", "myl"])?;
+    let state = run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    let cursor = state.cursor.unwrap();
+    assert_eq!(cursor.id, format!("{}/notes/9h3/activity", server.uri()));
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_fetch_collection_first_pages() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    Mock::given(method("GET"))
+        .and(path("/users/myl/outbox"))
+        .and(header("accept", "application/activity+json"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(json!({
+            "@context": "https://www.w3.org/ns/activitystreams",
+            "id": format!("{base}/users/myl/outbox"),
+            "type": "OrderedCollection",
+            "first": format!("{base}/users/myl/outbox/page1"),
+        })))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let mut page1 = page(
+        &base,
+        vec![create(&base, 400, None), create(&base, 300, None)],
+        None,
+    );
+    page1["next"] = format!("{base}/users/myl/outbox/page2").into();
+    let mut page2 = page(
+        &base,
+        vec![create(&base, 200, None), create(&base, 100, None)],
+        None,
+    );
+    page2["next"] = format!("{base}/users/myl/outbox/page3").into();
+    for (name, body, times) in [
+        ("page1", page1, 1),
+        ("page2", page2, 1),
+        ("page3", json!({}), 0),
+    ] {
+        Mock::given(method("GET"))
+            .and(path(format!("/users/myl/outbox/{name}")))
+            .respond_with(ResponseTemplate::new(200).set_body_json(body))
+            .expect(times)
+            .mount(&server)
+            .await;
+    }
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let ctx = ctx(&["-i", "fetch", "-s", &outbox])?;
+    // Stops at the handled post instead of following all older pages
+    let handled = State {
+        min_id: 200,
+        cursor: Some(Cursor::new(
+            format!("{base}/users/myl/statuses/200/activity"),
+            None,
+        )),
+    };
+    let state = run_round(&ctx, handled, &ctx.cancel).await?;
+    assert_eq!(state.min_id, 400);
+    Ok(())
+}
+
+#[cfg(feature = "ndjson")]
+#[tokio::test]
+async fn test_ndjson() -> Result<()> {
+    let server = MockServer::start().await;
+    mount_pages(&server).await;
+    let base = server.uri();
+    let dir = std::env::temp_dir().join(format!("mastotg-ndjson-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let file = dir.join("posts.ndjson");
+    let file_arg = file.to_string_lossy();
+
+    let outbox = format!("{base}/users/myl/outbox");
+    let ctx = ctx(&[
+        "-i",
+        "fetch",
+        "-s",
+        &outbox,
+        "-o",
+        "ndjson",
+        "--ndjson-file",
+        &file_arg,
+    ])?;
+    run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    let lines: Vec<Value> = std::fs::read_to_string(&file)?
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    let ids: Vec<_> = lines
+        .iter()
+        .map(|line| line["object"]["id"].clone())
+        .collect();
+    std::fs::remove_dir_all(&dir)?;
+    let status = |id| format!("{base}/users/myl/statuses/{id}");
+    assert_eq!(ids, [status(100), status(200), status(300)]);
+    Ok(())
+}
+
+#[cfg(feature = "webhook")]
+#[tokio::test]
+async fn test_cancelled_send_keeps_sent() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let items = vec![
+        create(&base, 300, None),
+        create(&base, 200, None),
+        create(&base, 100, None),
+    ];
+    mount_outbox(&server, Some("0"), page(&base, items, None), 1).await;
+    // The second post hangs until the round is cancelled
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .and(body_string_contains("statuses/200"))
+        .respond_with(ResponseTemplate::new(204).set_delay(Duration::from_secs(30)))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let outbox = format!("{base}/users/myl/outbox");
+    let hook = format!("{base}/hook");
+    let ctx = ctx(&[
+        "-i",
+        "fetch",
+        "-s",
+        &outbox,
+        "-o",
+        "webhook",
+        "--webhook-url",
+        &hook,
+        "--no-follow-paging",
+    ])?;
+    let cancel = CancellationToken::new();
+    let canceller = cancel.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        canceller.cancel();
+    });
+    let state = run_round(&ctx, State::new(0), &cancel).await?;
+    // The first post is kept as sent, while the rest are sent in the next round
+    assert_eq!(state.min_id, 100);
+    Ok(())
+}
+
+#[cfg(feature = "webhook")]
+#[tokio::test]
+async fn test_cancelled_send_behind_filtered() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    // The newest post is skipped by the filter before the others are sent
+    let items = vec![
+        create(&base, 300, Some(100)),
+        create(&base, 200, None),
+        create(&base, 100, None),
+    ];
+    mount_outbox(&server, Some("0"), page(&base, items, None), 1).await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .and(body_string_contains("statuses/200"))
+        .respond_with(ResponseTemplate::new(204).set_delay(Duration::from_secs(30)))
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let outbox = format!("{base}/users/myl/outbox");
+    let hook = format!("{base}/hook");
+    let ctx = ctx(&[
+        "-i",
+        "fetch",
+        "-s",
+        &outbox,
+        "-o",
+        "webhook",
+        "--webhook-url",
+        &hook,
+        "--no-follow-paging",
+        "--no-replies",
+    ])?;
+    let cancel = CancellationToken::new();
+    let canceller = cancel.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        canceller.cancel();
+    });
+    let state = run_round(&ctx, State::new(0), &cancel).await?;
+    // Not passing the unsent post to the skipped one
+    assert_eq!(state.min_id, 100);
+    Ok(())
+}
+
+#[cfg(feature = "webhook")]
+#[tokio::test]
+async fn test_failed_send_keeps_sent() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    let items = vec![
+        create(&base, 300, None),
+        create(&base, 200, None),
+        create(&base, 100, None),
+    ];
+    mount_outbox(&server, Some("0"), page(&base, items, None), 1).await;
+    // The second post is rejected, which fails the round by default
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .and(body_string_contains("statuses/200"))
+        .respond_with(ResponseTemplate::new(400))
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(1)
+        .mount(&server)
+        .await;
+    let outbox = format!("{base}/users/myl/outbox");
+    let hook = format!("{base}/hook");
+    let ctx = ctx(&[
+        "-i",
+        "fetch",
+        "-s",
+        &outbox,
+        "-o",
+        "webhook",
+        "--webhook-url",
+        &hook,
+        "--no-follow-paging",
+    ])?;
+    assert!(run_round(&ctx, State::new(0), &ctx.cancel).await.is_err());
+    // The first post is kept as sent, and the next round retries from the failed one
+    let state = ctx.db.load_state(String::new()).await?.unwrap();
+    assert_eq!(state.min_id, 100);
+    Ok(())
+}
+
+#[cfg(feature = "webhook")]
+#[tokio::test]
+async fn test_error_webhook_on_given_up() -> Result<()> {
+    for (policy, fired) in [("fail", 0), ("skip", 1)] {
+        let server = MockServer::start().await;
+        let base = server.uri();
+        let page1 = page(&base, vec![create(&base, 100, None)], None);
+        mount_outbox(&server, Some("0"), page1, 1).await;
+        Mock::given(method("POST"))
+            .and(path("/hook"))
+            .respond_with(ResponseTemplate::new(400))
+            .expect(1)
+            .mount(&server)
+            .await;
+        // Posts failed under the fail policy are retried by the next round instead of given up
+        Mock::given(method("POST"))
+            .and(path("/error"))
+            .and(body_partial_json(json!({"event": "failed"})))
+            .respond_with(ResponseTemplate::new(200))
+            .expect(fired)
+            .mount(&server)
+            .await;
+        let outbox = format!("{base}/users/myl/outbox");
+        let hook = format!("{base}/hook");
+        let error_hook = format!("{base}/error");
+        let ctx = ctx(&[
+            "-i",
+            "fetch",
+            "-s",
+            &outbox,
+            "-o",
+            "webhook",
+            "--webhook-url",
+            &hook,
+            "--no-follow-paging",
+            "--on-error",
+            policy,
+            "--on-error-webhook",
+            &error_hook,
+        ])?;
+        let _ = run_round(&ctx, State::new(0), &ctx.cancel).await;
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "webhook", feature = "ndjson"))]
+#[tokio::test]
+async fn test_tee_outputs() -> Result<()> {
+    let server = MockServer::start().await;
+    let base = server.uri();
+    // Fetched in both rounds
+    let page1 = vec![create(&base, 200, Some(100)), create(&base, 100, None)];
+    for (min_id, items, prev) in [("0", page1, Some(200)), ("200", vec![], None)] {
+        Mock::given(method("GET"))
+            .and(path("/users/myl/outbox"))
+            .and(query_param("min_id", min_id))
+            .respond_with(ResponseTemplate::new(200).set_body_json(page(&base, items, prev)))
+            .mount(&server)
+            .await;
+    }
+    // The webhook takes the first post, rejects the second once, and then takes all
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .and(body_string_contains("statuses/200"))
+        .respond_with(ResponseTemplate::new(400))
+        .up_to_n_times(1)
+        .expect(1)
+        .mount(&server)
+        .await;
+    Mock::given(method("POST"))
+        .and(path("/hook"))
+        .respond_with(ResponseTemplate::new(204))
+        .expect(2)
+        .mount(&server)
+        .await;
+    let dir = std::env::temp_dir().join(format!("mastotg-tee-{}", std::process::id()));
+    std::fs::create_dir_all(&dir)?;
+    let file = dir.join("posts.ndjson");
+    let file_arg = file.to_string_lossy();
+    let outbox = format!("{base}/users/myl/outbox");
+    let hook = format!("{base}/hook");
+    let args = [
+        "-i",
+        "fetch",
+        "-s",
+        &outbox,
+        "--webhook-url",
+        &hook,
+        "--ndjson-file",
+        &file_arg,
+        "--on-error",
+        "fail",
+    ];
+    let tee = |outputs: &[&'static str]| {
+        let outputs = outputs.iter().flat_map(|output| ["-o", *output]);
+        ctx(&args.into_iter().chain(outputs).collect::<Vec<_>>())
+    };
+    let ctx = tee(&["ndjson,webhook", "ndjson"])?;
+    assert_eq!(ctx.cli.output.len(), 2);
+
+    assert!(run_round(&ctx, State::new(0), &ctx.cancel).await.is_err());
+    // The page is retried only for the posts failed to the webhook, so nothing is sent twice
+    run_round(&ctx, State::new(0), &ctx.cancel).await?;
+    let lines = std::fs::read_to_string(&file)?.lines().count();
+    std::fs::remove_dir_all(&dir)?;
+    assert_eq!(lines, 2);
+    Ok(())
+}